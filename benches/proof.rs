@@ -0,0 +1,73 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Proof generation time and proof size across tree sizes.
+//!
+//! Run with `cargo bench --bench proof`. The size of each proof is printed before its
+//! timings.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use prollytree::config::TreeConfig;
+use prollytree::proof::{ProofSize, ProofStats};
+use prollytree::storage::InMemoryNodeStorage;
+use prollytree::tree::{ProllyTree, Tree};
+
+const TREE_SIZES: [u32; 3] = [1_000, 10_000, 100_000];
+
+fn build_tree(size: u32) -> ProllyTree<32, InMemoryNodeStorage<32>> {
+    let mut tree = ProllyTree::new(InMemoryNodeStorage::default(), TreeConfig::default());
+    for i in 0..size {
+        tree.insert(i.to_be_bytes().to_vec(), vec![0; 32]);
+    }
+    tree
+}
+
+fn report<P: ProofSize>(name: &str, size: u32, generate: impl FnOnce() -> P) {
+    let (_, stats) = ProofStats::measure(generate);
+    println!(
+        "{name}/{size}: {} nodes, {} bytes",
+        stats.nodes, stats.bytes
+    );
+}
+
+fn bench_proofs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("proof");
+    for size in TREE_SIZES {
+        let tree = build_tree(size);
+        let key = (size / 2).to_be_bytes().to_vec();
+        let keys: Vec<Vec<u8>> = (0..size)
+            .step_by(100)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        let range = key.clone()..(size / 2 + 100).to_be_bytes().to_vec();
+
+        report("inclusion", size, || tree.generate_proof(&key));
+        report("multiproof", size, || tree.generate_multiproof(&keys));
+        report("range", size, || tree.generate_range_proof(range.clone()));
+
+        group.bench_with_input(BenchmarkId::new("inclusion", size), &key, |b, key| {
+            b.iter(|| tree.generate_proof(key))
+        });
+        group.bench_with_input(BenchmarkId::new("multiproof", size), &keys, |b, keys| {
+            b.iter(|| tree.generate_multiproof(keys))
+        });
+        group.bench_with_input(BenchmarkId::new("range", size), &range, |b, range| {
+            b.iter(|| tree.generate_range_proof(range.clone()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_proofs);
+criterion_main!(benches);
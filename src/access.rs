@@ -0,0 +1,149 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Read and write counters for a single key prefix.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixAccess {
+    /// The key prefix, hex encoded so that binary keys survive the JSON export.
+    pub prefix: String,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+/// Access counts aggregated by key prefix of a fixed length.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessHeatmap {
+    /// The number of key bytes used to group the counters.
+    pub depth: usize,
+    /// One entry per distinct prefix, ordered by prefix.
+    pub entries: Vec<PrefixAccess>,
+}
+
+impl AccessHeatmap {
+    /// Returns the entries ordered from the most to the least accessed prefix.
+    pub fn hottest(&self) -> Vec<&PrefixAccess> {
+        let mut entries: Vec<&PrefixAccess> = self.entries.iter().collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.reads + entry.writes));
+        entries
+    }
+
+    /// Serializes the heatmap to JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Opt-in tracker counting reads and writes per key prefix.
+///
+/// Keys are truncated to `max_prefix_len` bytes before being counted, which bounds
+/// the memory used by the tracker to the number of distinct prefixes of that length.
+#[derive(Debug)]
+pub(crate) struct AccessTracker {
+    max_prefix_len: usize,
+    counters: Mutex<BTreeMap<Vec<u8>, (u64, u64)>>,
+}
+
+impl AccessTracker {
+    pub(crate) fn new(max_prefix_len: usize) -> Self {
+        AccessTracker {
+            max_prefix_len,
+            counters: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[..key.len().min(self.max_prefix_len)]
+    }
+
+    pub(crate) fn record_read(&self, key: &[u8]) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.entry(self.prefix(key).to_vec()).or_default().0 += 1;
+    }
+
+    pub(crate) fn record_write(&self, key: &[u8]) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.entry(self.prefix(key).to_vec()).or_default().1 += 1;
+    }
+
+    /// Aggregates the tracked counters by the first `depth` bytes of each prefix.
+    /// A depth larger than the tracked prefix length is clamped to it.
+    pub(crate) fn heatmap(&self, depth: usize) -> AccessHeatmap {
+        let depth = depth.min(self.max_prefix_len);
+        let counters = self.counters.lock().unwrap();
+        let mut aggregated: BTreeMap<&[u8], (u64, u64)> = BTreeMap::new();
+        for (prefix, (reads, writes)) in counters.iter() {
+            let entry = aggregated
+                .entry(&prefix[..prefix.len().min(depth)])
+                .or_default();
+            entry.0 += reads;
+            entry.1 += writes;
+        }
+
+        AccessHeatmap {
+            depth,
+            entries: aggregated
+                .into_iter()
+                .map(|(prefix, (reads, writes))| PrefixAccess {
+                    prefix: hex::encode(prefix),
+                    reads,
+                    writes,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heatmap_aggregates_by_depth() {
+        let tracker = AccessTracker::new(4);
+        tracker.record_write(b"usr/alice");
+        tracker.record_write(b"usr/bob");
+        tracker.record_read(b"usr/alice");
+        tracker.record_read(b"log/1");
+
+        let heatmap = tracker.heatmap(3);
+        assert_eq!(heatmap.depth, 3);
+        assert_eq!(
+            heatmap.entries,
+            vec![
+                PrefixAccess {
+                    prefix: hex::encode(b"log"),
+                    reads: 1,
+                    writes: 0,
+                },
+                PrefixAccess {
+                    prefix: hex::encode(b"usr"),
+                    reads: 1,
+                    writes: 2,
+                },
+            ]
+        );
+        assert_eq!(heatmap.hottest()[0].prefix, hex::encode(b"usr"));
+
+        // depth is clamped to the tracked prefix length
+        assert_eq!(tracker.heatmap(16).depth, 4);
+
+        let json = heatmap.to_json();
+        let decoded: AccessHeatmap = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, heatmap);
+    }
+}
@@ -0,0 +1,75 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Buffered writes applied to a tree in one step.
+
+use std::collections::BTreeMap;
+
+/// A set of inserts and deletes buffered in memory until they are applied to a tree with
+/// `ProllyTree::apply_batch`.
+///
+/// The tree is not touched until the batch is applied, so dropping the batch (or calling
+/// `clear`) discards its writes. Later writes to a key replace earlier ones in the same
+/// batch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    /// The pending write per key: `Some(value)` for inserts, `None` for deletes.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffers an insert of a key-value pair.
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Buffers a delete of a key.
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    /// Returns the pending write of a key.
+    ///
+    /// # Returns
+    /// - `None` if the batch does not touch the key, `Some(None)` if it deletes the key and
+    ///   `Some(Some(value))` if it inserts `value`.
+    pub fn get(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        self.writes.get(key).map(Option::as_deref)
+    }
+
+    /// Returns the number of keys the batch writes.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Returns `true` if the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Discards every buffered write.
+    pub fn clear(&mut self) {
+        self.writes.clear();
+    }
+
+    /// Consumes the batch, returning the pending write per key in ascending key order.
+    pub(crate) fn into_writes(self) -> impl Iterator<Item = (Vec<u8>, Option<Vec<u8>>)> {
+        self.writes.into_iter()
+    }
+}
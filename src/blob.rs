@@ -0,0 +1,269 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Out-of-line storage for large values.
+//!
+//! When a tree stores some values outside of its leaves, as decided by its `value_policy`
+//! and `value_chunk_threshold`, every value stored in a leaf starts with a tag byte. Small
+//! values are stored inline after the tag. Larger values are written to storage as blobs:
+//! separate nodes addressed by their hash. Blobs above the chunk threshold are first cut
+//! into content-defined chunks. The leaf only holds a manifest of the blob hashes:
+//!
+//! ```text
+//! inline:   0x00 | value
+//! chunked:  0x01 | total length: u64 (big endian) | chunk hash | chunk hash | ...
+//! ```
+//!
+//! Chunk boundaries are chosen with a gear rolling hash over the value bytes, so editing a
+//! large value only changes the chunks around the edit and leaves stay small.
+
+use crate::config::TreeConfig;
+use crate::digest::{HashAlgorithm, ValueDigest};
+use crate::node::ProllyNode;
+use crate::storage::{NodeReader, NodeStorage};
+
+const TAG_INLINE: u8 = 0;
+const TAG_CHUNKED: u8 = 1;
+
+/// Random constants for the gear hash, generated with splitmix64.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Returns `true` if trees with this configuration store tagged values in their leaves.
+pub(crate) fn blobs_enabled<const N: usize>(config: &TreeConfig<N>) -> bool {
+    config.external_value_threshold().is_some()
+}
+
+/// Encodes a value for storage in a leaf of a tree with the given configuration, writing
+/// it to `storage` as a blob if it must not be stored inline.
+pub(crate) fn encode_value<const N: usize, S: NodeStorage<N>>(
+    value: Vec<u8>,
+    config: &TreeConfig<N>,
+    storage: &mut S,
+) -> Vec<u8> {
+    let Some(limit) = config.external_value_threshold() else {
+        return value;
+    };
+    if value.len() <= limit {
+        let mut stored = Vec::with_capacity(value.len() + 1);
+        stored.push(TAG_INLINE);
+        stored.extend_from_slice(&value);
+        return stored;
+    }
+
+    let boundaries = match config.value_chunk_threshold {
+        Some(chunk_size) if value.len() > chunk_size => chunk_boundaries(&value, chunk_size),
+        _ => vec![value.len()],
+    };
+
+    let mut manifest = Vec::with_capacity(9 + N * boundaries.len());
+    manifest.push(TAG_CHUNKED);
+    manifest.extend_from_slice(&(value.len() as u64).to_be_bytes());
+
+    let mut start = 0;
+    for end in boundaries {
+        let chunk = chunk_node::<N>(value[start..end].to_vec(), config.hash_algorithm);
+        let hash = chunk.get_hash();
+        manifest.extend_from_slice(hash.as_bytes());
+        storage.insert_node(hash, chunk);
+        start = end;
+    }
+    manifest
+}
+
+/// Decodes a value stored in a leaf, loading its chunks from `storage` if needed.
+///
+/// Returns `None` if the stored value is malformed or one of its chunks is missing.
+pub(crate) fn decode_value<const N: usize, S: NodeReader<N>>(
+    stored: &[u8],
+    storage: &S,
+) -> Option<Vec<u8>> {
+    match stored.split_first() {
+        Some((&TAG_INLINE, value)) => Some(value.to_vec()),
+        Some((&TAG_CHUNKED, _)) => {
+            let total_len = u64::from_be_bytes(stored.get(1..9)?.try_into().ok()?) as usize;
+            let mut value = Vec::with_capacity(total_len);
+            for hash in chunk_hashes::<N>(stored) {
+                let chunk = storage.get_node_by_hash(&hash)?;
+                value.extend_from_slice(chunk.values.first()?);
+            }
+            (value.len() == total_len).then_some(value)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the length of the value a stored value keeps in chunks outside of the leaf, or
+/// zero if it is stored inline.
+pub(crate) fn external_len(stored: &[u8]) -> u64 {
+    match stored.split_first() {
+        Some((&TAG_CHUNKED, rest)) => rest
+            .first_chunk::<8>()
+            .map_or(0, |len| u64::from_be_bytes(*len)),
+        _ => 0,
+    }
+}
+
+/// Returns the hashes of the chunks referenced by a stored value, if it is chunked.
+pub(crate) fn chunk_hashes<const N: usize>(stored: &[u8]) -> Vec<ValueDigest<N>> {
+    match stored.split_first() {
+        Some((&TAG_CHUNKED, rest)) if rest.len() >= 8 => rest[8..]
+            .chunks_exact(N)
+            .map(ValueDigest::raw_hash)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The node a chunk is stored in: a leaf without keys holding the chunk as its only value,
+/// so that its hash only commits to the chunk bytes.
+fn chunk_node<const N: usize>(chunk: Vec<u8>, hash_algorithm: HashAlgorithm) -> ProllyNode<N> {
+    ProllyNode {
+        values: vec![chunk],
+        hash_algorithm,
+        ..Default::default()
+    }
+}
+
+/// Returns the end offsets of the content-defined chunks of `data`.
+///
+/// Chunks average about `target` bytes and are kept between a quarter of and four times
+/// that size.
+fn chunk_boundaries(data: &[u8], target: usize) -> Vec<usize> {
+    let target = target.max(4);
+    let min_size = target / 4;
+    let max_size = target * 4;
+    let mask = target.next_power_of_two() as u64 - 1;
+
+    let mut boundaries = Vec::new();
+    let mut start = 0;
+    let mut hash: u64 = 0;
+    for (i, byte) in data.iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let size = i + 1 - start;
+        if (size >= min_size && hash & mask == 0) || size >= max_size {
+            boundaries.push(i + 1);
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        boundaries.push(data.len());
+    }
+    boundaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ValuePolicy;
+    use crate::storage::InMemoryNodeStorage;
+
+    fn document(len: usize) -> Vec<u8> {
+        let mut state: u32 = 1;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn config(value_chunk_threshold: Option<usize>, value_policy: ValuePolicy) -> TreeConfig<32> {
+        TreeConfig {
+            value_chunk_threshold,
+            value_policy,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let mut storage = InMemoryNodeStorage::<32>::default();
+        let chunked = config(Some(256), ValuePolicy::Inline);
+
+        let small = b"small".to_vec();
+        let stored = encode_value(small.clone(), &chunked, &mut storage);
+        assert_eq!(stored, [&[TAG_INLINE], small.as_slice()].concat());
+        assert_eq!(decode_value(&stored, &storage), Some(small));
+
+        let large = document(10_000);
+        let stored = encode_value(large.clone(), &chunked, &mut storage);
+        assert_eq!(stored[0], TAG_CHUNKED);
+        assert!(stored.len() < large.len() / 4);
+        assert!(chunk_hashes::<32>(&stored).len() > 1);
+        assert_eq!(decode_value(&stored, &storage), Some(large.clone()));
+
+        // a missing chunk is reported instead of returning a truncated value
+        let mut other = InMemoryNodeStorage::<32>::new();
+        let missing = encode_value(document(5_000), &chunked, &mut other);
+        assert_eq!(decode_value(&missing, &storage), None);
+
+        // without any external storage values are left untouched
+        let inline = config(None, ValuePolicy::Inline);
+        assert_eq!(encode_value(large.clone(), &inline, &mut storage), large);
+    }
+
+    #[test]
+    fn test_value_policy() {
+        let mut storage = InMemoryNodeStorage::<32>::default();
+
+        // values above the policy threshold become a single blob
+        let external = config(None, ValuePolicy::ExternalAbove(16));
+        let stored = encode_value(b"short".to_vec(), &external, &mut storage);
+        assert_eq!(stored[0], TAG_INLINE);
+        let stored = encode_value(document(1_000), &external, &mut storage);
+        assert_eq!(chunk_hashes::<32>(&stored).len(), 1);
+        assert_eq!(decode_value(&stored, &storage), Some(document(1_000)));
+
+        // blobs above the chunk threshold are still chunked
+        let both = config(Some(256), ValuePolicy::External);
+        assert_eq!(both.external_value_threshold(), Some(0));
+        let stored = encode_value(b"x".to_vec(), &both, &mut storage);
+        assert_eq!(chunk_hashes::<32>(&stored).len(), 1);
+        let stored = encode_value(document(4_000), &both, &mut storage);
+        assert!(chunk_hashes::<32>(&stored).len() > 1);
+        assert_eq!(decode_value(&stored, &storage), Some(document(4_000)));
+    }
+
+    #[test]
+    fn test_chunk_boundaries_are_stable() {
+        let original = document(20_000);
+        let mut edited = original.clone();
+        edited[10_000] ^= 0xff;
+
+        let a = chunk_boundaries(&original, 512);
+        let b = chunk_boundaries(&edited, 512);
+        assert_eq!(a.last(), Some(&original.len()));
+        assert!(a.windows(2).all(|w| w[1] - w[0] <= 2048));
+
+        // only the chunks around the edit move
+        let shared = a.iter().filter(|end| b.contains(end)).count();
+        assert!(shared + 3 >= a.len());
+    }
+}
@@ -0,0 +1,174 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use crate::blob::encode_value;
+use crate::config::TreeConfig;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::NodeStorage;
+use crate::ttl;
+
+/// Builds a prolly tree bottom-up from key-value pairs arriving in ascending key order.
+///
+/// Each level buffers the entries of the node it is currently filling and cuts it with the
+/// same content-defined chunking used by `ProllyNode::balance`. Chunk boundaries only depend
+/// on the entries from the start of a chunk onward, so every chunk except the last one in
+/// the buffer is final and can be written to storage right away. Only one partial node per
+/// level is held in memory.
+pub(crate) struct BulkLoader<'a, const N: usize, S: NodeStorage<N>> {
+    storage: &'a mut S,
+    config: &'a TreeConfig<N>,
+    levels: Vec<ProllyNode<N>>,
+    last_key: Option<Vec<u8>>,
+}
+
+impl<'a, const N: usize, S: NodeStorage<N>> BulkLoader<'a, N, S> {
+    pub(crate) fn new(storage: &'a mut S, config: &'a TreeConfig<N>) -> Self {
+        BulkLoader {
+            storage,
+            config,
+            levels: Vec::new(),
+            last_key: None,
+        }
+    }
+
+    /// Appends the next key-value pair; keys must be strictly increasing.
+    pub(crate) fn push(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Error> {
+        self.push_with_expiry(key, value, ttl::NEVER)
+    }
+
+    /// Like `push`, for a key expiring at the given timestamp. The expiry is ignored
+    /// unless the tree has expiring keys.
+    pub(crate) fn push_with_expiry(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: u64,
+    ) -> Result<(), Error> {
+        if let Some(last_key) = &self.last_key {
+            if &key <= last_key {
+                return Err(Error::UnsortedKeys);
+            }
+        }
+        self.last_key = Some(key.clone());
+        let mut value = encode_value(value, self.config, self.storage);
+        if self.config.expiring_keys {
+            value = ttl::wrap(value, expires_at);
+        }
+        self.push_to_level(0, key, value, 1);
+        Ok(())
+    }
+
+    /// Flushes the partial nodes of every level and returns the root node.
+    pub(crate) fn finish(mut self) -> ProllyNode<N> {
+        let mut level = 0;
+        while level < self.levels.len() {
+            let empty = self.empty_node(level as u8);
+            let node = std::mem::replace(&mut self.levels[level], empty);
+            if level + 1 == self.levels.len() {
+                // the topmost level holds a single node: the root
+                return node;
+            }
+            if !node.keys.is_empty() {
+                self.emit(level, node);
+            }
+            level += 1;
+        }
+        self.empty_node(0)
+    }
+
+    /// Appends an entry to the node being filled at `level`; `count` is the number of
+    /// key-value pairs below the entry and is only tracked for internal levels.
+    fn push_to_level(&mut self, level: usize, key: Vec<u8>, value: Vec<u8>, count: u64) {
+        if level == self.levels.len() {
+            let node = self.empty_node(level as u8);
+            self.levels.push(node);
+        }
+
+        let node = &mut self.levels[level];
+        node.keys.push(key);
+        node.values.push(value);
+        if level > 0 {
+            node.counts.push(count);
+        }
+        if node.keys.len() <= node.min_chunk_size {
+            return;
+        }
+
+        let chunks = node.chunk_content();
+        if chunks.len() <= 1 {
+            return;
+        }
+
+        // all chunks but the last one are final
+        let (last_start, _) = chunks[chunks.len() - 1];
+        let keys = node.keys.split_off(last_start);
+        let values = node.values.split_off(last_start);
+        let counts = if level > 0 {
+            node.counts.split_off(last_start)
+        } else {
+            Vec::new()
+        };
+        let full_keys = std::mem::replace(&mut node.keys, keys);
+        let full_values = std::mem::replace(&mut node.values, values);
+        let full_counts = std::mem::replace(&mut node.counts, counts);
+
+        for (start, end) in &chunks[..chunks.len() - 1] {
+            let mut sibling = self.empty_node(level as u8);
+            sibling.keys = full_keys[*start..*end].to_vec();
+            sibling.values = full_values[*start..*end].to_vec();
+            if level > 0 {
+                sibling.counts = full_counts[*start..*end].to_vec();
+            }
+            self.emit(level, sibling);
+        }
+    }
+
+    /// Writes a completed node to storage and adds a reference to it to the level above.
+    fn emit(&mut self, level: usize, node: ProllyNode<N>) {
+        let hash = node.get_hash();
+        let first_key = node.keys[0].clone();
+        let count = if node.is_leaf {
+            node.keys.len() as u64
+        } else {
+            node.counts.iter().sum()
+        };
+        self.storage.insert_node(hash.clone(), node);
+        self.push_to_level(level + 1, first_key, hash.as_bytes().to_vec(), count);
+    }
+
+    fn empty_node(&self, level: u8) -> ProllyNode<N> {
+        ProllyNode {
+            keys: Vec::new(),
+            key_schema: self.config.key_schema.clone(),
+            values: Vec::new(),
+            value_schema: self.config.value_schema.clone(),
+            is_leaf: level == 0,
+            level,
+            base: self.config.base,
+            modulus: self.config.modulus,
+            min_chunk_size: self.config.min_chunk_size,
+            max_chunk_size: self.config.max_chunk_size,
+            pattern: self.config.pattern,
+            split: false,
+            merged: false,
+            encode_types: Vec::new(),
+            encode_values: Vec::new(),
+            counts: Vec::new(),
+            compression: self.config.compression,
+            hash_algorithm: self.config.hash_algorithm,
+            chunking: self.config.chunking,
+        }
+    }
+}
@@ -0,0 +1,343 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Strategies for splitting the entries of a node into chunks.
+//!
+//! Every node is split into chunks when it is balanced, and each chunk becomes a node of its
+//! own. A [`Chunker`] decides where the chunk boundaries fall. Boundaries must only depend
+//! on the entries from the start of a chunk onward, which keeps the tree shape independent
+//! of the insertion order and lets trees be built bottom-up in a single pass.
+
+use crate::node::ProllyNode;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+use twox_hash::XxHash64;
+
+/// seed for the hash function
+const HASH_SEED: u64 = 0;
+
+/// Decides where the boundaries between the chunks of a node fall.
+pub trait Chunker {
+    /// Splits the entries of a node into chunks.
+    ///
+    /// # Parameters
+    /// - `keys`: The keys of the node, in ascending order.
+    /// - `values`: The values associated with the keys.
+    ///
+    /// # Returns
+    /// - The `(start, end)` index ranges of the chunks, covering all entries in order.
+    fn chunk(&self, keys: &[Vec<u8>], values: &[Vec<u8>]) -> Vec<(usize, usize)>;
+}
+
+/// Chunking strategy used when balancing the nodes of a tree.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChunkingStrategy {
+    /// Cuts chunks where a rolling hash over a window of entries matches the pattern.
+    #[default]
+    RollingHash,
+    /// Cuts chunks with FastCDC style normalized chunking, which keeps chunk sizes closer
+    /// to the average and suits workloads with random updates.
+    FastCdc,
+    /// Cuts chunks every given number of entries, which suits append-only workloads where
+    /// only the last node changes.
+    FixedSize(usize),
+}
+
+impl<const N: usize> ProllyNode<N> {
+    /// Returns the chunker configured for this node.
+    pub fn chunker(&self) -> Box<dyn Chunker> {
+        match self.chunking {
+            ChunkingStrategy::RollingHash => Box::new(RollingHashChunker {
+                base: self.base,
+                modulus: self.modulus,
+                min_chunk_size: self.min_chunk_size,
+                max_chunk_size: self.max_chunk_size,
+                pattern: self.pattern,
+            }),
+            ChunkingStrategy::FastCdc => Box::new(FastCdcChunker {
+                min_chunk_size: self.min_chunk_size,
+                max_chunk_size: self.max_chunk_size,
+                pattern: self.pattern,
+            }),
+            ChunkingStrategy::FixedSize(size) => Box::new(FixedSizeChunker { size }),
+        }
+    }
+
+    /// Splits the entries of the node into chunks with its configured chunker.
+    pub(crate) fn chunk_content(&self) -> Vec<(usize, usize)> {
+        self.chunker().chunk(&self.keys, &self.values)
+    }
+}
+
+/// Cuts a chunk where a polynomial rolling hash over the last `min_chunk_size` entries
+/// matches `pattern`, or when the chunk reaches `max_chunk_size` entries.
+pub struct RollingHashChunker {
+    pub base: u64,
+    pub modulus: u64,
+    pub min_chunk_size: usize,
+    pub max_chunk_size: usize,
+    pub pattern: u64,
+}
+
+impl Chunker for RollingHashChunker {
+    fn chunk(&self, keys: &[Vec<u8>], values: &[Vec<u8>]) -> Vec<(usize, usize)> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        let mut last_start = 0;
+
+        while start < keys.len() {
+            let mut end = start + self.min_chunk_size;
+
+            // Ensure that 'end' does not exceed the length of the keys vector
+            if end > keys.len() {
+                end = keys.len();
+            }
+
+            // Initialize the rolling hash for the first window
+            let mut hash = self.initialize_rolling_hash(&keys[start..end], &values[start..end]);
+
+            while end < keys.len() && end - start < self.max_chunk_size {
+                // Check if the current hash matches the pattern
+                if hash & self.pattern == self.pattern {
+                    break;
+                }
+
+                // Slide the window by one element to the right
+                if end < keys.len() {
+                    hash = self.update_rolling_hash(
+                        hash,
+                        &keys[start],
+                        &values[start],
+                        &keys[end],
+                        &values[end],
+                        (end - start) as u64,
+                    );
+                    start += 1;
+                    end += 1;
+                } else {
+                    break;
+                }
+            }
+
+            chunks.push((last_start, end));
+            last_start = end;
+            start = end;
+        }
+
+        chunks
+    }
+}
+
+impl RollingHashChunker {
+    /// Computes the rolling hash of a window of entries.
+    pub fn initialize_rolling_hash(&self, keys: &[Vec<u8>], values: &[Vec<u8>]) -> u64 {
+        let mut hash = 0;
+        for (key, value) in keys.iter().zip(values) {
+            hash = (hash * self.base
+                + Self::hash_item(key, self.modulus)
+                + Self::hash_item(value, self.modulus))
+                % self.modulus;
+        }
+        hash
+    }
+
+    fn update_rolling_hash(
+        &self,
+        old_hash: u64,
+        old_key: &[u8],
+        old_value: &[u8],
+        new_key: &[u8],
+        new_value: &[u8],
+        window_size: u64,
+    ) -> u64 {
+        let (base, modulus) = (self.base, self.modulus);
+        let old_key_hash = Self::hash_item(old_key, modulus);
+        let old_value_hash = Self::hash_item(old_value, modulus);
+        let new_key_hash = Self::hash_item(new_key, modulus);
+        let new_value_hash = Self::hash_item(new_value, modulus);
+
+        let base_exp_window_size = Self::mod_exp(base, window_size, modulus);
+
+        let hash = (old_hash * base + new_key_hash + new_value_hash) % modulus;
+        let hash = (hash + modulus - (old_key_hash * base_exp_window_size) % modulus) % modulus;
+
+        (hash + modulus - (old_value_hash * base_exp_window_size) % modulus) % modulus
+    }
+
+    fn mod_exp(base: u64, exp: u64, modulus: u64) -> u64 {
+        let mut result = 1;
+        let mut base = base % modulus;
+        let mut exp = exp;
+
+        while exp > 0 {
+            if exp % 2 == 1 {
+                result = (result * base) % modulus;
+            }
+            exp >>= 1;
+            base = (base * base) % modulus;
+        }
+
+        result
+    }
+
+    fn hash_item(item: &[u8], modulus: u64) -> u64 {
+        let mut hasher = XxHash64::with_seed(HASH_SEED);
+        item.hash(&mut hasher);
+        hasher.finish() % modulus
+    }
+}
+
+/// Cuts chunks with a gear hash over the entries, using FastCDC's normalized chunking: a
+/// stricter mask before the average chunk size and a looser one after it. Chunks hold
+/// between `min_chunk_size` and `max_chunk_size` entries, and about
+/// `min_chunk_size + 2^(bits set in pattern)` on average.
+pub struct FastCdcChunker {
+    pub min_chunk_size: usize,
+    pub max_chunk_size: usize,
+    pub pattern: u64,
+}
+
+impl Chunker for FastCdcChunker {
+    fn chunk(&self, keys: &[Vec<u8>], values: &[Vec<u8>]) -> Vec<(usize, usize)> {
+        let average = 1usize
+            .checked_shl(self.pattern.count_ones())
+            .unwrap_or(usize::MAX)
+            .saturating_add(self.min_chunk_size);
+        let strict_mask = (self.pattern << 1) | 1;
+        let loose_mask = self.pattern >> 1;
+
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < keys.len() {
+            let mut fingerprint: u64 = 0;
+            let mut end = start;
+            while end < keys.len() {
+                fingerprint = (fingerprint << 1).wrapping_add(hash_entry(&keys[end], &values[end]));
+                end += 1;
+
+                let size = end - start;
+                if size >= self.max_chunk_size {
+                    break;
+                }
+                if size < self.min_chunk_size {
+                    continue;
+                }
+                let mask = if size < average {
+                    strict_mask
+                } else {
+                    loose_mask
+                };
+                if fingerprint & mask == 0 {
+                    break;
+                }
+            }
+            chunks.push((start, end));
+            start = end;
+        }
+        chunks
+    }
+}
+
+/// Cuts a chunk every `size` entries.
+pub struct FixedSizeChunker {
+    pub size: usize,
+}
+
+impl Chunker for FixedSizeChunker {
+    fn chunk(&self, keys: &[Vec<u8>], _values: &[Vec<u8>]) -> Vec<(usize, usize)> {
+        let size = self.size.max(1);
+        (0..keys.len())
+            .step_by(size)
+            .map(|start| (start, (start + size).min(keys.len())))
+            .collect()
+    }
+}
+
+fn hash_entry(key: &[u8], value: &[u8]) -> u64 {
+    let mut hasher = XxHash64::with_seed(HASH_SEED);
+    key.hash(&mut hasher);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(len: u32) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let keys: Vec<Vec<u8>> = (0..len).map(|i| i.to_be_bytes().to_vec()).collect();
+        let values = keys.iter().map(|key| key.repeat(2)).collect();
+        (keys, values)
+    }
+
+    fn assert_covers(chunks: &[(usize, usize)], len: usize) {
+        assert_eq!(chunks.first().map(|chunk| chunk.0), Some(0));
+        assert_eq!(chunks.last().map(|chunk| chunk.1), Some(len));
+        assert!(chunks.windows(2).all(|w| w[0].1 == w[1].0));
+        assert!(chunks.iter().all(|(start, end)| start < end));
+    }
+
+    #[test]
+    fn test_fixed_size_chunker() {
+        let (keys, values) = entries(10);
+        let chunks = FixedSizeChunker { size: 4 }.chunk(&keys, &values);
+        assert_eq!(chunks, vec![(0, 4), (4, 8), (8, 10)]);
+        assert!(FixedSizeChunker { size: 4 }.chunk(&[], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_fast_cdc_chunker() {
+        let chunker = FastCdcChunker {
+            min_chunk_size: 4,
+            max_chunk_size: 64,
+            pattern: 0b1111,
+        };
+        let (keys, values) = entries(5_000);
+        let chunks = chunker.chunk(&keys, &values);
+        assert_covers(&chunks, keys.len());
+
+        // every chunk but the last respects the size limits
+        let sizes: Vec<usize> = chunks.iter().map(|(start, end)| end - start).collect();
+        assert!(sizes[..sizes.len() - 1]
+            .iter()
+            .all(|size| (4..=64).contains(size)));
+        let average = keys.len() / chunks.len();
+        assert!((10..=30).contains(&average), "average {}", average);
+
+        // boundaries after an edit are unaffected once a boundary is shared
+        let mut edited = values.clone();
+        edited[100] = b"edited".to_vec();
+        let edited_chunks = chunker.chunk(&keys, &edited);
+        let shared = chunks
+            .iter()
+            .filter(|chunk| edited_chunks.contains(chunk))
+            .count();
+        assert!(shared + 3 >= chunks.len());
+    }
+
+    #[test]
+    fn test_rolling_hash_chunker() {
+        let chunker = RollingHashChunker {
+            base: 257,
+            modulus: 1_000_000_007,
+            min_chunk_size: 4,
+            max_chunk_size: 32,
+            pattern: 0b111,
+        };
+        let (keys, values) = entries(1_000);
+        let chunks = chunker.chunk(&keys, &values);
+        assert_covers(&chunks, keys.len());
+        assert!(chunks.len() > 1);
+    }
+}
@@ -0,0 +1,446 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Compression of encoded nodes.
+//!
+//! Storage backends that persist nodes as bytes use [`ProllyNode::encode`] and
+//! [`ProllyNode::decode`]. An encoded node starts with a header byte recording the codec
+//! its payload was compressed with, followed by the CRC32 checksum of the rest of the
+//! node, the version of the node layout and the (possibly compressed) bincode encoded
+//! node:
+//!
+//! ```text
+//! codec: u8 | crc32: u32 | layout: u8 | payload
+//! ```
+//!
+//! Bincode is not self-describing, so a node can only be decoded with the exact layout it
+//! was encoded with, and adding a field to `ProllyNode` requires a new layout version.
+//! `decode` rejects layouts it does not know with `Error::UnknownCodec`.
+//!
+//! The high bit of the header is set for nodes carrying a checksum, which `decode`
+//! verifies; nodes written before checksums were introduced have neither a checksum nor a
+//! layout version and are read with the current layout. Nodes written before the header
+//! was introduced are the bare bincode encoding of the original node layout. `decode`
+//! falls back to it for payloads that are not a valid encoded node, and gives the fields
+//! added since their default values. The header records the codec
+//! actually used, which is `None` when the node's codec is not compiled in or compression
+//! would not make the node smaller. Decoding only depends on the header, so trees can
+//! change codecs without rewriting existing nodes. The node hash covers the node contents
+//! only and does not depend on the codec.
+//!
+//! Backends can also be configured with a codec of their own, which then applies to every
+//! node they write and is recorded in their [`StorageManifest`].
+
+use crate::digest::ValueDigest;
+use crate::encoding::EncodingType;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::{NodeReader, NodeStorage};
+use bincode::Options;
+use schemars::schema::RootSchema;
+use serde::{Deserialize, Serialize};
+
+const CODEC_NONE: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+const CODEC_LZ4: u8 = 2;
+/// Set in the header of nodes whose payload is preceded by its checksum.
+const CHECKSUMMED: u8 = 0x80;
+/// The version of the layout `ProllyNode` is currently encoded with.
+const LAYOUT_VERSION: u8 = 1;
+
+/// zstd level used for nodes, favouring speed since nodes are small and written often
+#[cfg(feature = "compression_zstd")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// Codec used to compress nodes before they are written to storage.
+///
+/// `Zstd` requires the `compression_zstd` feature and `Lz4` the `compression_lz4` feature.
+/// Without them nodes are stored uncompressed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl<const N: usize> ProllyNode<N> {
+    /// Encodes the node for storage, compressing it with its configured codec.
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(None)
+    }
+
+    /// Encodes the node for storage, compressing it with `codec` if given and with its
+    /// configured codec otherwise.
+    pub fn encode_with(&self, codec: Option<Compression>) -> Vec<u8> {
+        let data = bincode::serialize(self).unwrap();
+        let compressed = match codec.unwrap_or(self.compression) {
+            Compression::None => None,
+            Compression::Zstd => compress_zstd(&data).map(|data| (CODEC_ZSTD, data)),
+            Compression::Lz4 => compress_lz4(&data).map(|data| (CODEC_LZ4, data)),
+        };
+
+        let (codec, payload) = match compressed {
+            Some((codec, compressed)) if compressed.len() < data.len() => (codec, compressed),
+            _ => (CODEC_NONE, data),
+        };
+        let mut body = Vec::with_capacity(payload.len() + 1);
+        body.push(LAYOUT_VERSION);
+        body.extend_from_slice(&payload);
+
+        let mut encoded = Vec::with_capacity(body.len() + 5);
+        encoded.push(codec | CHECKSUMMED);
+        encoded.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+        encoded.extend_from_slice(&body);
+        encoded
+    }
+
+    /// Decodes a node written by [`ProllyNode::encode`], or stored in the original layout
+    /// without a header.
+    ///
+    /// Returns `Error::UnknownCodec` if the node was compressed with a codec that is not
+    /// compiled in or encoded with an unknown layout, and `Error::Serde` if it is corrupt:
+    /// its payload does not match its checksum or cannot be decoded.
+    pub fn decode(encoded: &[u8]) -> Result<Self, Error> {
+        match encoded.first() {
+            // the header of a checksummed node is only trusted once the checksum matches
+            Some(header) if header & CHECKSUMMED != 0 => {
+                Self::decode_framed(encoded).or_else(|error| decode_original(encoded).ok_or(error))
+            }
+            _ => decode_original(encoded).map_or_else(|| Self::decode_framed(encoded), Ok),
+        }
+    }
+
+    /// Decodes a node that starts with a header.
+    fn decode_framed(encoded: &[u8]) -> Result<Self, Error> {
+        let (header, mut payload) = encoded.split_first().ok_or(Error::Serde)?;
+        let mut layout = LAYOUT_VERSION;
+        if header & CHECKSUMMED != 0 {
+            let (checksum, body) = payload.split_first_chunk::<4>().ok_or(Error::Serde)?;
+            if crc32fast::hash(body) != u32::from_be_bytes(*checksum) {
+                return Err(Error::Serde);
+            }
+            (layout, payload) = body
+                .split_first()
+                .map(|(layout, rest)| (*layout, rest))
+                .ok_or(Error::Serde)?;
+        }
+        if layout != LAYOUT_VERSION {
+            return Err(Error::UnknownCodec);
+        }
+        let data = match header & !CHECKSUMMED {
+            CODEC_NONE => payload.to_vec(),
+            CODEC_ZSTD => decompress_zstd(payload)?,
+            CODEC_LZ4 => decompress_lz4(payload)?,
+            _ => return Err(Error::UnknownCodec),
+        };
+        bincode::deserialize(&data).map_err(|_| Error::Serde)
+    }
+}
+
+/// The layout of the nodes stored before encoded nodes had a header.
+#[derive(Serialize, Deserialize)]
+struct OriginalNode {
+    keys: Vec<Vec<u8>>,
+    key_schema: Option<RootSchema>,
+    values: Vec<Vec<u8>>,
+    value_schema: Option<RootSchema>,
+    is_leaf: bool,
+    level: u8,
+    base: u64,
+    modulus: u64,
+    min_chunk_size: usize,
+    max_chunk_size: usize,
+    pattern: u64,
+    split: bool,
+    merged: bool,
+    encode_types: Vec<EncodingType>,
+    encode_values: Vec<Vec<u8>>,
+}
+
+/// Decodes a node stored in the original layout, which must span all of `encoded`.
+fn decode_original<const N: usize>(encoded: &[u8]) -> Option<ProllyNode<N>> {
+    let node: OriginalNode = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(encoded)
+        .ok()?;
+    Some(ProllyNode {
+        keys: node.keys,
+        key_schema: node.key_schema,
+        values: node.values,
+        value_schema: node.value_schema,
+        is_leaf: node.is_leaf,
+        level: node.level,
+        base: node.base,
+        modulus: node.modulus,
+        min_chunk_size: node.min_chunk_size,
+        max_chunk_size: node.max_chunk_size,
+        pattern: node.pattern,
+        split: node.split,
+        merged: node.merged,
+        encode_types: node.encode_types,
+        encode_values: node.encode_values,
+        ..Default::default()
+    })
+}
+
+/// Returns the hash a node was stored under in the original layout, before node hashes
+/// committed to subtree counts and to the lengths of keys and values.
+pub(crate) fn original_hash<const N: usize>(node: &ProllyNode<N>) -> ValueDigest<N> {
+    let mut keys_and_values = node.keys.concat();
+    keys_and_values.extend(node.values.concat());
+    ValueDigest::new(&keys_and_values)
+}
+
+/// Encodes a node in the original layout, as nodes were stored before they had a header.
+#[cfg(test)]
+pub(crate) fn encode_original<const N: usize>(node: &ProllyNode<N>) -> Vec<u8> {
+    bincode::serialize(&OriginalNode {
+        keys: node.keys.clone(),
+        key_schema: node.key_schema.clone(),
+        values: node.values.clone(),
+        value_schema: node.value_schema.clone(),
+        is_leaf: node.is_leaf,
+        level: node.level,
+        base: node.base,
+        modulus: node.modulus,
+        min_chunk_size: node.min_chunk_size,
+        max_chunk_size: node.max_chunk_size,
+        pattern: node.pattern,
+        split: node.split,
+        merged: node.merged,
+        encode_types: node.encode_types.clone(),
+        encode_values: node.encode_values.clone(),
+    })
+    .unwrap()
+}
+
+/// The config key a storage backend records its manifest under.
+pub const MANIFEST_KEY: &str = "storage_manifest";
+
+/// The settings a storage backend records alongside its nodes, so that they are restored
+/// when it is opened again.
+///
+/// Nodes record their codec in their header, so changing the codec of a backend never
+/// makes the nodes it already holds unreadable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageManifest {
+    /// The codec every node is written with, or `None` to use the codec of each node.
+    pub compression: Option<Compression>,
+}
+
+impl StorageManifest {
+    /// Loads the manifest of a storage, or the default one if none was saved.
+    pub fn load<const N: usize, S: NodeReader<N>>(storage: &S) -> Self {
+        storage
+            .get_config(MANIFEST_KEY)
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    /// Saves the manifest in a storage.
+    pub fn save<const N: usize, S: NodeStorage<N>>(&self, storage: &S) {
+        storage.save_config(MANIFEST_KEY, &serde_json::to_vec(self).unwrap());
+    }
+}
+
+#[cfg(feature = "compression_zstd")]
+fn compress_zstd(data: &[u8]) -> Option<Vec<u8>> {
+    zstd::bulk::compress(data, ZSTD_LEVEL).ok()
+}
+
+#[cfg(not(feature = "compression_zstd"))]
+fn compress_zstd(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "compression_zstd")]
+fn decompress_zstd(payload: &[u8]) -> Result<Vec<u8>, Error> {
+    zstd::stream::decode_all(payload).map_err(|_| Error::Serde)
+}
+
+#[cfg(not(feature = "compression_zstd"))]
+fn decompress_zstd(_payload: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::UnknownCodec)
+}
+
+#[cfg(feature = "compression_lz4")]
+fn compress_lz4(data: &[u8]) -> Option<Vec<u8>> {
+    Some(lz4_flex::compress_prepend_size(data))
+}
+
+#[cfg(not(feature = "compression_lz4"))]
+fn compress_lz4(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(feature = "compression_lz4")]
+fn decompress_lz4(payload: &[u8]) -> Result<Vec<u8>, Error> {
+    lz4_flex::decompress_size_prepended(payload).map_err(|_| Error::Serde)
+}
+
+#[cfg(not(feature = "compression_lz4"))]
+fn decompress_lz4(_payload: &[u8]) -> Result<Vec<u8>, Error> {
+    Err(Error::UnknownCodec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_node(compression: Compression) -> ProllyNode<32> {
+        let memory = b"the agent remembered that the user prefers short answers. ".repeat(20);
+        ProllyNode {
+            keys: (0..16u8).map(|i| vec![i]).collect(),
+            values: (0..16).map(|_| memory.clone()).collect(),
+            compression,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        for compression in [Compression::None, Compression::Zstd, Compression::Lz4] {
+            let node = text_node(compression);
+            let decoded = ProllyNode::<32>::decode(&node.encode()).unwrap();
+            assert_eq!(decoded.get_hash(), node.get_hash());
+            assert_eq!(decoded.values, node.values);
+            assert_eq!(decoded.compression, compression);
+        }
+
+        assert!(matches!(
+            ProllyNode::<32>::decode(&[9, 1, 2, 3]),
+            Err(Error::UnknownCodec)
+        ));
+
+        // a flipped bit fails the checksum
+        let node = text_node(Compression::None);
+        let mut encoded = node.encode();
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        assert!(matches!(
+            ProllyNode::<32>::decode(&encoded),
+            Err(Error::Serde)
+        ));
+
+        // nodes written without a checksum are still read
+        let legacy = [&[CODEC_NONE][..], &bincode::serialize(&node).unwrap()].concat();
+        let decoded = ProllyNode::<32>::decode(&legacy).unwrap();
+        assert_eq!(decoded.get_hash(), node.get_hash());
+    }
+
+    #[test]
+    fn test_layout_version() {
+        let node = text_node(Compression::None);
+        let encoded = node.encode();
+        assert_eq!(encoded[5], LAYOUT_VERSION);
+
+        // a node written with a layout this version does not know is refused, not misread
+        let mut body = encoded[5..].to_vec();
+        body[0] = LAYOUT_VERSION + 1;
+        let newer = [
+            &[CODEC_NONE | CHECKSUMMED][..],
+            &crc32fast::hash(&body).to_be_bytes(),
+            &body,
+        ]
+        .concat();
+        assert!(matches!(
+            ProllyNode::<32>::decode(&newer),
+            Err(Error::UnknownCodec)
+        ));
+    }
+
+    #[test]
+    fn test_decode_original_layout() {
+        use crate::storage::FileNodeStorage;
+
+        // key counts whose first byte looks like a header, with and without the checksum bit
+        for size in [1u8, 2, 130] {
+            let node = ProllyNode::<32> {
+                keys: (0..size).map(|i| vec![i]).collect(),
+                values: (0..size).map(|i| vec![i, i]).collect(),
+                ..Default::default()
+            };
+            let decoded = ProllyNode::<32>::decode(&encode_original(&node)).unwrap();
+            assert_eq!(decoded.keys, node.keys);
+            assert_eq!(decoded.values, node.values);
+            assert!(decoded.counts.is_empty());
+        }
+
+        // a node file written by a storage from before nodes had a header: flat, and named
+        // after the hash of its keys and values
+        let storage_dir = std::env::temp_dir().join("prolly_tree_original_layout_storage");
+        let _ = std::fs::remove_dir_all(&storage_dir);
+        std::fs::create_dir_all(&storage_dir).unwrap();
+        let node = text_node(Compression::None);
+        let name = format!("{:x}", original_hash(&node));
+        std::fs::write(storage_dir.join(&name), encode_original(&node)).unwrap();
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        assert!(storage.get_node_by_hash(&original_hash(&node)).is_none());
+        let stored = storage.get_node_by_hash(&node.get_hash()).unwrap();
+        assert_eq!(stored.keys, node.keys);
+        assert_eq!(stored.values, node.values);
+        std::fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_codec_is_recorded_in_header() {
+        let codec = |encoded: &[u8]| encoded[0] & !CHECKSUMMED;
+        let plain = text_node(Compression::None).encode();
+        assert_eq!(codec(&plain), CODEC_NONE);
+
+        let zstd = text_node(Compression::Zstd).encode();
+        let lz4 = text_node(Compression::Lz4).encode();
+        if cfg!(feature = "compression_zstd") {
+            assert_eq!(codec(&zstd), CODEC_ZSTD);
+            assert!(zstd.len() * 5 < plain.len());
+        } else {
+            assert_eq!(codec(&zstd), CODEC_NONE);
+        }
+        if cfg!(feature = "compression_lz4") {
+            assert_eq!(codec(&lz4), CODEC_LZ4);
+            assert!(lz4.len() * 5 < plain.len());
+        } else {
+            assert_eq!(codec(&lz4), CODEC_NONE);
+        }
+    }
+
+    #[test]
+    fn test_storage_manifest() {
+        use crate::storage::FileNodeStorage;
+
+        let storage_dir = std::env::temp_dir().join("prolly_tree_manifest_storage");
+        let _ = std::fs::remove_dir_all(&storage_dir);
+        let mut storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        assert_eq!(StorageManifest::load(&storage), StorageManifest::default());
+        let plain = text_node(Compression::None);
+        storage.insert_node(plain.get_hash(), plain.clone());
+
+        let mut storage = storage.with_compression(Some(Compression::Zstd));
+        let packed = text_node(Compression::Lz4);
+        storage.insert_node(packed.get_hash(), packed.clone());
+
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        assert_eq!(
+            StorageManifest::load(&storage).compression,
+            Some(Compression::Zstd)
+        );
+        for node in [plain, packed] {
+            let stored = storage.get_node_by_hash(&node.get_hash()).unwrap();
+            assert_eq!(stored.values, node.values);
+        }
+        std::fs::remove_dir_all(storage_dir).unwrap();
+    }
+}
@@ -11,11 +11,27 @@ WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 See the License for the specific language governing permissions and
 limitations under the License.
 */
-use crate::digest::ValueDigest;
+use crate::chunker::ChunkingStrategy;
+use crate::compression::Compression;
+use crate::digest::{HashAlgorithm, ValueDigest};
 use crate::encoding::EncodingType;
+use crate::schema::{KeyFormat, ValueFormat};
 use schemars::schema::RootSchema;
 use serde::{Deserialize, Serialize};
 
+/// Controls which values are stored in the leaves and which are stored as separate blobs
+/// referenced by their hash.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValuePolicy {
+    /// Values are stored in the leaves, unless they exceed `value_chunk_threshold`.
+    #[default]
+    Inline,
+    /// Values larger than the given number of bytes are stored as blobs.
+    ExternalAbove(usize),
+    /// Every non-empty value is stored as a blob.
+    External,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TreeConfig<const N: usize> {
     pub base: u64,
@@ -27,6 +43,37 @@ pub struct TreeConfig<const N: usize> {
     pub key_schema: Option<RootSchema>,
     pub value_schema: Option<RootSchema>,
     pub encode_types: Vec<EncodingType>,
+    /// Values larger than this many bytes are split into content-defined chunks stored
+    /// outside of the leaves. `None` stores every value inline.
+    #[serde(default)]
+    pub value_chunk_threshold: Option<usize>,
+    /// Decides which values are stored outside of the leaves as blobs. Blobs larger than
+    /// `value_chunk_threshold` are further split into chunks.
+    #[serde(default)]
+    pub value_policy: ValuePolicy,
+    /// Codec used to compress nodes before they are written to storage.
+    #[serde(default)]
+    pub compression: Compression,
+    /// Hash function used for node hashes. Proofs must be verified with the same one.
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    /// Strategy used to split nodes into chunks.
+    #[serde(default)]
+    pub chunking: ChunkingStrategy,
+    /// Stores an expiry timestamp with every value, so that keys can be inserted with a
+    /// time to live.
+    #[serde(default)]
+    pub expiring_keys: bool,
+    /// Binary layout keys must match to be inserted, also used to print keys. Unlike
+    /// `key_schema`, which describes JSON encoded keys to the Arrow encoding, it applies
+    /// to the raw key bytes. `None` accepts any key.
+    #[serde(default)]
+    pub key_format: Option<KeyFormat>,
+    /// Encoding values must match to be inserted, and which typed values are read and
+    /// written with. JSON values are also checked against `value_schema`. `None` accepts
+    /// any value.
+    #[serde(default)]
+    pub value_format: Option<ValueFormat>,
 }
 
 impl<const N: usize> Default for TreeConfig<N> {
@@ -41,6 +88,30 @@ impl<const N: usize> Default for TreeConfig<N> {
             key_schema: None,
             value_schema: None,
             encode_types: vec![],
+            value_chunk_threshold: None,
+            value_policy: ValuePolicy::Inline,
+            compression: Compression::None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            chunking: ChunkingStrategy::RollingHash,
+            expiring_keys: false,
+            key_format: None,
+            value_format: None,
+        }
+    }
+}
+
+impl<const N: usize> TreeConfig<N> {
+    /// Returns the size in bytes above which values are stored outside of the leaves,
+    /// or `None` if every value is stored inline.
+    pub fn external_value_threshold(&self) -> Option<usize> {
+        let policy = match self.value_policy {
+            ValuePolicy::Inline => None,
+            ValuePolicy::ExternalAbove(size) => Some(size),
+            ValuePolicy::External => Some(0),
+        };
+        match (policy, self.value_chunk_threshold) {
+            (Some(policy), Some(chunk)) => Some(policy.min(chunk)),
+            (policy, chunk) => policy.or(chunk),
         }
     }
 }
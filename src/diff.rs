@@ -12,9 +12,2352 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
-#[derive(Debug, PartialEq)]
+//! Comparing and merging the versions of a tree by their root hashes.
+
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::iter::prefix_range;
+use crate::node::ProllyNode;
+use crate::pin::reachable_nodes;
+use crate::proof::verifier::{child_overlaps, contains};
+use crate::storage::{NodeReader, NodeStorage};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::ops::{Bound, ControlFlow, RangeBounds};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DiffResult {
     Added(Vec<u8>, Vec<u8>),
     Removed(Vec<u8>, Vec<u8>),
     Modified(Vec<u8>, Vec<u8>, Vec<u8>),
 }
+
+impl DiffResult {
+    /// Returns the key the difference is about.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            DiffResult::Added(key, _)
+            | DiffResult::Removed(key, _)
+            | DiffResult::Modified(key, _, _) => key,
+        }
+    }
+
+    /// Returns the changed fields of a difference between JSON values.
+    ///
+    /// Added and removed keys change the whole document, at the empty path.
+    ///
+    /// # Returns
+    /// - The changes as computed by `json_diff`, or `None` if a value is not JSON.
+    pub fn field_changes(&self) -> Option<Vec<FieldChange>> {
+        let parse = |value: &[u8]| serde_json::from_slice::<Value>(value).ok();
+        match self {
+            DiffResult::Added(_, new) => Some(vec![FieldChange {
+                path: String::new(),
+                old: None,
+                new: Some(parse(new)?),
+            }]),
+            DiffResult::Removed(_, old) => Some(vec![FieldChange {
+                path: String::new(),
+                old: Some(parse(old)?),
+                new: None,
+            }]),
+            DiffResult::Modified(_, old, new) => json_diff(old, new),
+        }
+    }
+
+    /// Returns the size of the key and the values of the difference.
+    fn size(&self) -> usize {
+        match self {
+            DiffResult::Added(key, value) | DiffResult::Removed(key, value) => {
+                key.len() + value.len()
+            }
+            DiffResult::Modified(key, old, new) => key.len() + old.len() + new.len(),
+        }
+    }
+}
+
+/// A change of one field between two JSON documents.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldChange {
+    /// The JSON pointer (RFC 6901) of the field, e.g. `/profile/tags/0`; empty for the
+    /// whole document.
+    pub path: String,
+    /// The old value of the field, `None` if the field was added.
+    pub old: Option<Value>,
+    /// The new value of the field, `None` if the field was removed.
+    pub new: Option<Value>,
+}
+
+/// Compares two JSON documents field by field.
+///
+/// Objects are compared per property and arrays per index, descending into nested
+/// objects and arrays. Any other change, including a change of type, replaces the field
+/// as a whole.
+///
+/// # Returns
+/// - The changed fields in document order, or `None` if a value is not JSON.
+pub fn json_diff(old: &[u8], new: &[u8]) -> Option<Vec<FieldChange>> {
+    let old: Value = serde_json::from_slice(old).ok()?;
+    let new: Value = serde_json::from_slice(new).ok()?;
+    let mut changes = Vec::new();
+    diff_values(String::new(), Some(&old), Some(&new), &mut changes);
+    Some(changes)
+}
+
+fn diff_values(
+    path: String,
+    old: Option<&Value>,
+    new: Option<&Value>,
+    changes: &mut Vec<FieldChange>,
+) {
+    let field = |key: &str| format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                diff_values(field(key), old.get(key), new.get(key), changes);
+            }
+        }
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            for i in 0..old.len().max(new.len()) {
+                diff_values(field(&i.to_string()), old.get(i), new.get(i), changes);
+            }
+        }
+        (old, new) if old != new => changes.push(FieldChange {
+            path,
+            old: old.cloned(),
+            new: new.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+/// A set of keys that moved from one prefix to another with their values unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixRename {
+    /// The prefix the keys were removed from.
+    pub from: Vec<u8>,
+    /// The prefix the keys were added under.
+    pub to: Vec<u8>,
+    /// The number of moved keys.
+    pub keys: usize,
+}
+
+/// Detects keys that moved to another prefix and reports them as renames instead of
+/// pairs of removals and additions.
+///
+/// A removed key is matched with the added key sharing the longest suffix with it, if
+/// both hold the same value; the parts before the common suffix are the prefixes the key
+/// moved between. Prefix pairs are accepted as renames, most moved keys first, if at
+/// least `min_keys` keys moved between them.
+///
+/// # Parameters
+/// - `diffs`: The differences between two versions, e.g. as returned by `diff_roots`.
+/// - `min_keys`: The number of keys that must move between two prefixes to count as a
+///   rename; at least 2.
+///
+/// # Returns
+/// - The renames by descending number of keys, and the differences not explained by them
+///   in their original order.
+pub fn detect_renames(
+    diffs: Vec<DiffResult>,
+    min_keys: usize,
+) -> (Vec<PrefixRename>, Vec<DiffResult>) {
+    // added keys by their reversed bytes, so that keys with long common suffixes are
+    // neighbors
+    let added: BTreeMap<Vec<u8>, (&[u8], &[u8])> = diffs
+        .iter()
+        .filter_map(|diff| match diff {
+            DiffResult::Added(key, value) => Some((
+                key.iter().rev().copied().collect(),
+                (key.as_slice(), value.as_slice()),
+            )),
+            _ => None,
+        })
+        .collect();
+
+    let mut moves = Vec::new();
+    for diff in &diffs {
+        let DiffResult::Removed(key, value) = diff else {
+            continue;
+        };
+        let reversed: Vec<u8> = key.iter().rev().copied().collect();
+        let neighbors = added
+            .range(..reversed.clone())
+            .next_back()
+            .into_iter()
+            .chain(added.range(reversed.clone()..).next());
+        let best = neighbors
+            .map(|(other, &entry)| (common_prefix_len(&reversed, other), entry))
+            .filter(|&(_, (_, other_value))| other_value == value.as_slice())
+            .max_by_key(|&(common, _)| common);
+        if let Some((common, (to_key, _))) = best {
+            let from = key[..key.len() - common].to_vec();
+            let to = to_key[..to_key.len() - common].to_vec();
+            moves.push(((from, to), (key.as_slice(), to_key)));
+        }
+    }
+
+    let mut counts: BTreeMap<&(Vec<u8>, Vec<u8>), usize> = BTreeMap::new();
+    for (pair, _) in &moves {
+        *counts.entry(pair).or_default() += 1;
+    }
+    let mut accepted: Vec<_> = counts
+        .into_iter()
+        .filter(|&(_, count)| count >= min_keys.max(2))
+        .collect();
+    accepted.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    let mut renames = Vec::new();
+    let mut moved: BTreeSet<&[u8]> = BTreeSet::new();
+    for (pair, _) in accepted {
+        // an added key may be the best match of removed keys of several pairs
+        let mut claimed = BTreeSet::new();
+        let keys: Vec<(&[u8], &[u8])> = moves
+            .iter()
+            .filter(|(other, (_, to_key))| {
+                other == pair && !moved.contains(to_key) && claimed.insert(*to_key)
+            })
+            .map(|&(_, keys)| keys)
+            .collect();
+        if keys.len() >= min_keys.max(2) {
+            renames.push(PrefixRename {
+                from: pair.0.clone(),
+                to: pair.1.clone(),
+                keys: keys.len(),
+            });
+            for (from_key, to_key) in keys {
+                moved.insert(from_key);
+                moved.insert(to_key);
+            }
+        }
+    }
+
+    let rest = diffs
+        .iter()
+        .filter(|diff| match diff {
+            DiffResult::Modified(..) => true,
+            DiffResult::Added(key, _) | DiffResult::Removed(key, _) => {
+                !moved.contains(key.as_slice())
+            }
+        })
+        .cloned()
+        .collect();
+    (renames, rest)
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const RESET: &str = "\x1b[0m";
+
+/// Renders differences as a unified, line-oriented diff for terminals and logs.
+///
+/// Every change becomes one line per side: `-key = value` for the old and `+key = value`
+/// for the new value. Text is quoted and escaped so that every change stays on its own
+/// lines; binary keys and values are rendered as hex.
+#[derive(Debug, Clone)]
+pub struct DiffFormatter {
+    color: bool,
+    max_value_len: Option<usize>,
+    labels: Option<(String, String)>,
+}
+
+impl Default for DiffFormatter {
+    fn default() -> Self {
+        DiffFormatter {
+            color: false,
+            max_value_len: Some(80),
+            labels: None,
+        }
+    }
+}
+
+impl DiffFormatter {
+    /// Creates a formatter without colors that truncates values beyond 80 bytes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether removed lines are colored red and added lines green.
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Sets the number of bytes of a value shown before it is truncated, or `None` to
+    /// show values in full. Keys are never truncated.
+    pub fn with_max_value_len(mut self, max_value_len: Option<usize>) -> Self {
+        self.max_value_len = max_value_len;
+        self
+    }
+
+    /// Starts the diff with `--- from` and `+++ to` header lines.
+    pub fn with_labels(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.labels = Some((from.into(), to.into()));
+        self
+    }
+
+    /// Renders the differences, one line per side of every change.
+    pub fn format(&self, diffs: &[DiffResult]) -> String {
+        let mut out = String::new();
+        self.write(&mut out, diffs)
+            .expect("writing to a string cannot fail");
+        out
+    }
+
+    /// Writes the differences to `out`, one line per side of every change.
+    pub fn write<W: fmt::Write>(&self, out: &mut W, diffs: &[DiffResult]) -> fmt::Result {
+        if let Some((from, to)) = &self.labels {
+            writeln!(out, "--- {from}")?;
+            writeln!(out, "+++ {to}")?;
+        }
+        for diff in diffs {
+            match diff {
+                DiffResult::Added(key, new) => self.line(out, '+', key, new)?,
+                DiffResult::Removed(key, old) => self.line(out, '-', key, old)?,
+                DiffResult::Modified(key, old, new) => {
+                    self.line(out, '-', key, old)?;
+                    self.line(out, '+', key, new)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn line<W: fmt::Write>(
+        &self,
+        out: &mut W,
+        sign: char,
+        key: &[u8],
+        value: &[u8],
+    ) -> fmt::Result {
+        let shown = match self.max_value_len {
+            Some(max) if value.len() > max => &value[..max],
+            _ => value,
+        };
+        let (start, end) = match (self.color, sign) {
+            (false, _) => ("", ""),
+            (true, '-') => (RED, RESET),
+            (true, _) => (GREEN, RESET),
+        };
+        write!(out, "{start}{sign}{} = {}", render(key), render(shown))?;
+        if shown.len() < value.len() {
+            write!(out, "... ({} bytes)", value.len())?;
+        }
+        writeln!(out, "{end}")
+    }
+}
+
+/// Renders bytes as a quoted, escaped string if they are text, and as hex otherwise.
+///
+/// Bytes are treated as text if they are valid UTF-8 without control characters other
+/// than whitespace. A value cut off in the middle of a character is still text.
+fn render(bytes: &[u8]) -> String {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => Some(text),
+        Err(err) if err.error_len().is_none() => {
+            Some(std::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or_default())
+        }
+        Err(_) => None,
+    };
+    match text {
+        Some(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+            format!("{:?}", text)
+        }
+        _ => format!("0x{}", hex::encode(bytes)),
+    }
+}
+
+/// Summary of the differences between two versions of a tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffStats {
+    /// The number of added keys.
+    pub added: usize,
+    /// The number of keys whose value changed.
+    pub modified: usize,
+    /// The number of removed keys.
+    pub removed: usize,
+    /// The size of the added keys and values, plus the new values of modified keys.
+    pub bytes_added: usize,
+    /// The size of the removed keys and values, plus the old values of modified keys.
+    pub bytes_removed: usize,
+}
+
+impl DiffStats {
+    /// Counts a difference.
+    pub fn record(&mut self, diff: &DiffResult) {
+        match diff {
+            DiffResult::Added(key, value) => {
+                self.added += 1;
+                self.bytes_added += key.len() + value.len();
+            }
+            DiffResult::Removed(key, value) => {
+                self.removed += 1;
+                self.bytes_removed += key.len() + value.len();
+            }
+            DiffResult::Modified(_, old, new) => {
+                self.modified += 1;
+                self.bytes_added += new.len();
+                self.bytes_removed += old.len();
+            }
+        }
+    }
+
+    /// Returns the total number of changed keys.
+    pub fn total(&self) -> usize {
+        self.added + self.modified + self.removed
+    }
+}
+
+/// The changes between two versions of a tree, to be shipped to and applied on a replica
+/// at the old version with `ProllyTree::apply_patch`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Patch<const N: usize> {
+    /// The root hash of the version the patch applies to.
+    pub from_root: ValueDigest<N>,
+    /// The root hash of the version the patch was computed against.
+    pub to_root: ValueDigest<N>,
+    /// The changes, in ascending key order, with values as they were written.
+    pub diffs: Vec<DiffResult>,
+}
+
+impl<const N: usize> Patch<N> {
+    /// Encodes the patch in binary with bincode.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        bincode::serialize(self).map_err(|_| Error::Serde)
+    }
+
+    /// Decodes a patch written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        bincode::deserialize(bytes).map_err(|_| Error::Serde)
+    }
+
+    /// Encodes the patch as JSON.
+    pub fn to_json(&self) -> Result<String, Error> {
+        serde_json::to_string(self).map_err(|_| Error::Serde)
+    }
+
+    /// Decodes a patch written by `to_json`.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        serde_json::from_str(json).map_err(|_| Error::Serde)
+    }
+}
+
+/// How the entries of two trees line up, as reported by `zip_trees`.
+pub(crate) enum Pairing<const N: usize> {
+    /// An entry whose key only exists in the left tree.
+    Left(Vec<u8>, Vec<u8>),
+    /// An entry whose key only exists in the right tree.
+    Right(Vec<u8>, Vec<u8>),
+    /// A key present in both trees, with the left and the right value.
+    Both(Vec<u8>, Vec<u8>, Vec<u8>),
+    /// A subtree that is identical in both trees and was not descended into.
+    Shared(Box<ProllyNode<N>>),
+}
+
+/// A pending item on one side of `zip_trees`: an unexpanded subtree or a single entry.
+enum Item<const N: usize> {
+    Node(Box<ProllyNode<N>>, ValueDigest<N>),
+    Entry(Vec<u8>, Vec<u8>),
+}
+
+impl<const N: usize> Item<N> {
+    /// Entries rank below leaves so that nodes are always expanded first.
+    fn level(&self) -> i32 {
+        match self {
+            Item::Node(node, _) => node.level as i32,
+            Item::Entry(..) => -1,
+        }
+    }
+}
+
+/// The not yet visited part of a tree, with the leftmost item on top of the stack.
+struct Frontier<'a, const N: usize, S: NodeStorage<N>> {
+    storage: &'a S,
+    stack: Vec<Item<N>>,
+    /// The key range to visit; subtrees and entries outside of it are skipped.
+    range: &'a (Bound<Vec<u8>>, Bound<Vec<u8>>),
+}
+
+impl<'a, const N: usize, S: NodeStorage<N>> Frontier<'a, N, S> {
+    fn new(
+        root: &ProllyNode<N>,
+        storage: &'a S,
+        range: &'a (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    ) -> Self {
+        Frontier {
+            storage,
+            stack: vec![Item::Node(Box::new(root.clone()), root.get_hash())],
+            range,
+        }
+    }
+
+    /// Pushes the children or entries of a node just popped off the stack.
+    ///
+    /// # Returns
+    /// - `Error::MissingNode` if a child overlapping the range is not in storage.
+    fn expand(&mut self, node: Box<ProllyNode<N>>) -> Result<(), Error> {
+        let (start, end) = self.range;
+        if node.is_leaf {
+            let node = *node;
+            for (key, value) in node.keys.into_iter().zip(node.values).rev() {
+                if contains((start, end), &key) {
+                    self.stack.push(Item::Entry(key, value));
+                }
+            }
+        } else {
+            for (i, child_hash) in node.values.iter().enumerate().rev() {
+                if !child_overlaps(&node.keys, i, start, end) {
+                    continue;
+                }
+                let child_hash = ValueDigest::raw_hash(child_hash);
+                let child = self
+                    .storage
+                    .get_node_by_hash(&child_hash)
+                    .ok_or_else(|| Error::MissingNode(hex::encode(child_hash.as_bytes())))?;
+                self.stack.push(Item::Node(Box::new(child), child_hash));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Walks two trees side by side in key order and reports how their entries pair up.
+///
+/// Whenever the next unvisited subtrees of both trees have the same hash they hold the
+/// same entries, so they are reported once as `Pairing::Shared` without being loaded any
+/// further. Everything else is compared entry by entry. The trees may live in different
+/// storages.
+///
+/// # Returns
+/// - `Error::MissingNode` if a node that has to be compared is not in its storage.
+pub(crate) fn zip_trees<const N: usize, L, R, F>(
+    left: &ProllyNode<N>,
+    left_storage: &L,
+    right: &ProllyNode<N>,
+    right_storage: &R,
+    mut visit: F,
+) -> Result<(), Error>
+where
+    L: NodeStorage<N>,
+    R: NodeStorage<N>,
+    F: FnMut(Pairing<N>),
+{
+    zip_range(
+        left,
+        left_storage,
+        right,
+        right_storage,
+        &UNBOUNDED,
+        |pairing| {
+            visit(pairing);
+            ControlFlow::Continue(())
+        },
+    )
+}
+
+/// Like `zip_trees`, but only visits the entries within a key range, and stops as soon as
+/// `visit` breaks. Subtrees outside of the range are not loaded, and shared subtrees may
+/// extend beyond the range.
+pub(crate) fn zip_range<const N: usize, L, R, F>(
+    left: &ProllyNode<N>,
+    left_storage: &L,
+    right: &ProllyNode<N>,
+    right_storage: &R,
+    range: &(Bound<Vec<u8>>, Bound<Vec<u8>>),
+    mut visit: F,
+) -> Result<(), Error>
+where
+    L: NodeStorage<N>,
+    R: NodeStorage<N>,
+    F: FnMut(Pairing<N>) -> ControlFlow<()>,
+{
+    let mut left = Frontier::new(left, left_storage, range);
+    let mut right = Frontier::new(right, right_storage, range);
+
+    loop {
+        match (left.stack.last(), right.stack.last()) {
+            (None, None) => return Ok(()),
+            (Some(Item::Node(a, a_hash)), Some(Item::Node(b, b_hash)))
+                if a.level == b.level && a_hash == b_hash =>
+            {
+                right.stack.pop();
+                if let Some(Item::Node(node, _)) = left.stack.pop() {
+                    if visit(Pairing::Shared(node)).is_break() {
+                        return Ok(());
+                    }
+                }
+            }
+            (Some(Item::Entry(a, _)), Some(Item::Entry(b, _))) => match a.cmp(b) {
+                std::cmp::Ordering::Less => {
+                    if let Some(Item::Entry(key, value)) = left.stack.pop() {
+                        if visit(Pairing::Left(key, value)).is_break() {
+                            return Ok(());
+                        }
+                    }
+                }
+                std::cmp::Ordering::Greater => {
+                    if let Some(Item::Entry(key, value)) = right.stack.pop() {
+                        if visit(Pairing::Right(key, value)).is_break() {
+                            return Ok(());
+                        }
+                    }
+                }
+                std::cmp::Ordering::Equal => {
+                    if let (Some(Item::Entry(key, a)), Some(Item::Entry(_, b))) =
+                        (left.stack.pop(), right.stack.pop())
+                    {
+                        if visit(Pairing::Both(key, a, b)).is_break() {
+                            return Ok(());
+                        }
+                    }
+                }
+            },
+            (Some(Item::Entry(..)), None) => {
+                if let Some(Item::Entry(key, value)) = left.stack.pop() {
+                    if visit(Pairing::Left(key, value)).is_break() {
+                        return Ok(());
+                    }
+                }
+            }
+            (None, Some(Item::Entry(..))) => {
+                if let Some(Item::Entry(key, value)) = right.stack.pop() {
+                    if visit(Pairing::Right(key, value)).is_break() {
+                        return Ok(());
+                    }
+                }
+            }
+            (a, b) => {
+                // Expand the higher of the two nodes until both sides line up
+                let a_level = a.map_or(-2, Item::level);
+                let b_level = b.map_or(-2, Item::level);
+                if a_level >= b_level {
+                    if let Some(Item::Node(node, _)) = left.stack.pop() {
+                        left.expand(node)?;
+                    }
+                } else if let Some(Item::Node(node, _)) = right.stack.pop() {
+                    right.expand(node)?;
+                }
+            }
+        }
+    }
+}
+
+/// A key that both sides of a three-way merge changed in different ways.
+///
+/// Values are `None` where the key is absent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeConflict {
+    pub key: Vec<u8>,
+    /// The value in the common ancestor.
+    pub base: Option<Vec<u8>>,
+    /// The value on our side.
+    pub ours: Option<Vec<u8>>,
+    /// The value on their side.
+    pub theirs: Option<Vec<u8>>,
+}
+
+/// The prefix of the keys under which a tree records deferred merge conflicts.
+///
+/// Entries under this prefix are exempt from the key and value formats of the tree, and
+/// validated writes such as `try_insert` refuse keys under it.
+pub const CONFLICT_PREFIX: &[u8] = b"\xffconflicts/";
+
+/// A merge conflict recorded in a tree, to be resolved later.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConflictRecord<const N: usize> {
+    /// The conflicting key with the values on both sides.
+    pub conflict: MergeConflict,
+    /// The root hash of the version that was merged in.
+    pub theirs_root: ValueDigest<N>,
+    /// Milliseconds since the Unix epoch at which the conflict was recorded.
+    pub recorded_at: u64,
+}
+
+impl<const N: usize> ConflictRecord<N> {
+    /// Returns the key the record is stored under: the conflicting key after
+    /// `CONFLICT_PREFIX`.
+    pub fn record_key(key: &[u8]) -> Vec<u8> {
+        [CONFLICT_PREFIX, key].concat()
+    }
+
+    /// Serializes the record with bincode.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        bincode::serialize(self).map_err(|_| Error::Serde)
+    }
+
+    /// Deserializes a record written by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        bincode::deserialize(bytes).map_err(|_| Error::Serde)
+    }
+}
+
+/// The outcome of a three-way merge, relative to our side.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Merge {
+    /// The changes to apply to our side, in ascending key order: `Some(value)` to insert
+    /// the value, `None` to delete the key. Keys in conflict are not included.
+    pub changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    /// The keys changed differently on both sides, in ascending key order.
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl Merge {
+    /// Returns `true` if the merge has no conflicts.
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// Resolves the conflicts a policy has a strategy for, turning them into changes.
+    ///
+    /// Conflicts without a strategy are left in `conflicts`.
+    ///
+    /// # Parameters
+    /// - `policy`: The strategies to resolve conflicts with.
+    pub fn resolve(&mut self, policy: &MergePolicy) {
+        let mut unresolved = Vec::new();
+        for conflict in self.conflicts.drain(..) {
+            match policy.resolve(&conflict) {
+                // keeping our value needs no change
+                Some(value) if value == conflict.ours => {}
+                Some(value) => self.changes.push((conflict.key, value)),
+                None => unresolved.push(conflict),
+            }
+        }
+        self.conflicts = unresolved;
+        self.changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+}
+
+/// Reads the time a value was written, for `ConflictStrategy::LastWriteWins`.
+pub type TimestampFn = Box<dyn Fn(&[u8]) -> Option<u64> + Send + Sync>;
+
+/// Computes the merged value of a conflict, `None` to delete the key.
+pub type ResolveFn = Box<dyn Fn(&MergeConflict) -> Option<Vec<u8>> + Send + Sync>;
+
+/// How to resolve a key changed differently on both sides of a merge.
+pub enum ConflictStrategy {
+    /// Keeps our value.
+    TakeOurs,
+    /// Takes their value.
+    TakeTheirs,
+    /// Takes the value with the later timestamp. A side whose key was deleted, or whose
+    /// value has no timestamp, counts as older than the other; ties keep our value.
+    LastWriteWins(TimestampFn),
+    /// Computes the merged value.
+    Custom(ResolveFn),
+}
+
+impl ConflictStrategy {
+    /// Returns the merged value of a conflict, `None` to delete the key.
+    pub fn resolve(&self, conflict: &MergeConflict) -> Option<Vec<u8>> {
+        match self {
+            ConflictStrategy::TakeOurs => conflict.ours.clone(),
+            ConflictStrategy::TakeTheirs => conflict.theirs.clone(),
+            ConflictStrategy::LastWriteWins(timestamp) => {
+                let written = |value: &Option<Vec<u8>>| value.as_deref().and_then(timestamp);
+                if written(&conflict.theirs) > written(&conflict.ours) {
+                    conflict.theirs.clone()
+                } else {
+                    conflict.ours.clone()
+                }
+            }
+            ConflictStrategy::Custom(resolve) => resolve(conflict),
+        }
+    }
+}
+
+impl fmt::Debug for ConflictStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictStrategy::TakeOurs => write!(f, "TakeOurs"),
+            ConflictStrategy::TakeTheirs => write!(f, "TakeTheirs"),
+            ConflictStrategy::LastWriteWins(_) => write!(f, "LastWriteWins(..)"),
+            ConflictStrategy::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// The conflict strategies of a merge: one per key prefix, and a default for the keys
+/// no prefix matches.
+///
+/// The strategy of the longest matching prefix applies. Conflicts of keys without a
+/// strategy are left unresolved.
+#[derive(Debug, Default)]
+pub struct MergePolicy {
+    default: Option<ConflictStrategy>,
+    prefixes: Vec<(Vec<u8>, ConflictStrategy)>,
+}
+
+impl MergePolicy {
+    /// Creates a policy resolving every conflict with the same strategy.
+    pub fn new(strategy: ConflictStrategy) -> Self {
+        MergePolicy {
+            default: Some(strategy),
+            prefixes: Vec::new(),
+        }
+    }
+
+    /// Sets the strategy for the keys starting with a prefix.
+    ///
+    /// # Parameters
+    /// - `prefix`: The key prefix, e.g. the namespace of a table.
+    /// - `strategy`: The strategy for conflicts of keys with the prefix.
+    pub fn with_prefix(mut self, prefix: impl Into<Vec<u8>>, strategy: ConflictStrategy) -> Self {
+        let prefix = prefix.into();
+        self.prefixes.retain(|(existing, _)| *existing != prefix);
+        self.prefixes.push((prefix, strategy));
+        self
+    }
+
+    /// Returns the strategy for a key, if any.
+    pub fn strategy(&self, key: &[u8]) -> Option<&ConflictStrategy> {
+        self.prefixes
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, strategy)| strategy)
+            .or(self.default.as_ref())
+    }
+
+    /// Resolves a conflict with the strategy for its key.
+    ///
+    /// # Returns
+    /// - `None` if no strategy applies to the key, otherwise the merged value, which is
+    ///   `None` to delete the key.
+    pub fn resolve(&self, conflict: &MergeConflict) -> Option<Option<Vec<u8>>> {
+        self.strategy(&conflict.key)
+            .map(|strategy| strategy.resolve(conflict))
+    }
+}
+
+/// A change between two versions of a tree: the key, its old value and its new value.
+type Change = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
+/// Loads the root node with the given hash.
+pub(crate) fn load_root<const N: usize, S: NodeReader<N>>(
+    storage: &S,
+    root_hash: &ValueDigest<N>,
+) -> Result<ProllyNode<N>, Error> {
+    storage
+        .get_node_by_hash(root_hash)
+        .ok_or_else(|| Error::MissingNode(hex::encode(root_hash.as_bytes())))
+}
+
+/// Computes the differences between two versions of a tree, given their root hashes.
+///
+/// Only the subtrees whose hashes differ are visited, so the cost depends on the size of
+/// the change rather than on the size of the trees. Values are compared as they are
+/// stored in the leaves.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of both versions.
+/// - `from`: The root hash of the old version.
+/// - `to`: The root hash of the new version.
+///
+/// # Returns
+/// - The changes from `from` to `to` in ascending key order, or `Error::MissingNode` if a
+///   compared node is not in `storage`.
+pub fn diff_roots<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    from: &ValueDigest<N>,
+    to: &ValueDigest<N>,
+) -> Result<Vec<DiffResult>, Error> {
+    let from = load_root(storage, from)?;
+    let to = load_root(storage, to)?;
+    diff_nodes(storage, &from, &to)
+}
+
+/// Receives the events of `diff_visit` in ascending key order.
+///
+/// Every method defaults to doing nothing; returning `ControlFlow::Break` stops the diff.
+pub trait DiffVisitor<const N: usize> {
+    /// Called for a subtree that is identical in both versions and is skipped.
+    fn skip_subtree(&mut self, _node: &ProllyNode<N>) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called for a key that only exists in the new version.
+    fn added(&mut self, _key: &[u8], _value: &[u8]) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called for a key that only exists in the old version.
+    fn removed(&mut self, _key: &[u8], _value: &[u8]) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called for a key whose value differs between the versions.
+    fn modified(&mut self, _key: &[u8], _old: &[u8], _new: &[u8]) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+}
+
+/// Streams the differences between two versions of a tree to a visitor, without
+/// collecting them, e.g. to emit change events.
+///
+/// Values are those stored in the leaves.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of both versions.
+/// - `from`: The root hash of the old version.
+/// - `to`: The root hash of the new version.
+/// - `visitor`: Receives the skipped subtrees and the changes.
+///
+/// # Returns
+/// - `Error::MissingNode` if a compared node is not in `storage`.
+pub fn diff_visit<const N: usize, S: NodeStorage<N>, V: DiffVisitor<N>>(
+    storage: &S,
+    from: &ValueDigest<N>,
+    to: &ValueDigest<N>,
+    visitor: &mut V,
+) -> Result<(), Error> {
+    let from = load_root(storage, from)?;
+    let to = load_root(storage, to)?;
+    zip_range(
+        &from,
+        storage,
+        &to,
+        storage,
+        &UNBOUNDED,
+        |pairing| match pairing {
+            Pairing::Shared(node) => visitor.skip_subtree(&node),
+            Pairing::Left(key, value) => visitor.removed(&key, &value),
+            Pairing::Right(key, value) => visitor.added(&key, &value),
+            Pairing::Both(key, old, new) if old != new => visitor.modified(&key, &old, &new),
+            Pairing::Both(..) => ControlFlow::Continue(()),
+        },
+    )
+}
+
+/// Like `diff_roots`, but only compares the keys within a range.
+///
+/// Only the subtrees overlapping the range are visited, so the cost depends on the size
+/// of the change within the range.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of both versions.
+/// - `from`: The root hash of the old version.
+/// - `to`: The root hash of the new version.
+/// - `range`: The key range to compare.
+///
+/// # Returns
+/// - The changes within the range in ascending key order, or `Error::MissingNode` if a
+///   compared node is not in `storage`.
+pub fn diff_range<const N: usize, S: NodeStorage<N>, R: RangeBounds<Vec<u8>>>(
+    storage: &S,
+    from: &ValueDigest<N>,
+    to: &ValueDigest<N>,
+    range: R,
+) -> Result<Vec<DiffResult>, Error> {
+    let range = (range.start_bound().cloned(), range.end_bound().cloned());
+    let mut diffs = Vec::new();
+    visit_diffs(
+        storage,
+        &load_root(storage, from)?,
+        &load_root(storage, to)?,
+        &range,
+        |diff| {
+            diffs.push(diff);
+            ControlFlow::Continue(())
+        },
+    )?;
+    Ok(diffs)
+}
+
+/// Checks whether two versions of a tree hold the same entries within a key range.
+///
+/// Subtrees with the same hash in both versions are skipped without being loaded, and the
+/// comparison stops at the first difference, so clean ranges are cheap to confirm.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of both versions.
+/// - `a`: The root hash of one version.
+/// - `b`: The root hash of the other version.
+/// - `range`: The key range to compare.
+///
+/// # Returns
+/// - `true` if no key within the range was added, removed or modified, or
+///   `Error::MissingNode` if a compared node is not in `storage`.
+pub fn ranges_equal<const N: usize, S: NodeStorage<N>, R: RangeBounds<Vec<u8>>>(
+    storage: &S,
+    a: &ValueDigest<N>,
+    b: &ValueDigest<N>,
+    range: R,
+) -> Result<bool, Error> {
+    if a == b {
+        return Ok(true);
+    }
+    let range = (range.start_bound().cloned(), range.end_bound().cloned());
+    let mut equal = true;
+    visit_diffs(
+        storage,
+        &load_root(storage, a)?,
+        &load_root(storage, b)?,
+        &range,
+        |_| {
+            equal = false;
+            ControlFlow::Break(())
+        },
+    )?;
+    Ok(equal)
+}
+
+/// Like `diff_roots`, but only compares the keys starting with a prefix.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of both versions.
+/// - `from`: The root hash of the old version.
+/// - `to`: The root hash of the new version.
+/// - `prefix`: The key prefix to compare.
+pub fn diff_prefix<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    from: &ValueDigest<N>,
+    to: &ValueDigest<N>,
+    prefix: &[u8],
+) -> Result<Vec<DiffResult>, Error> {
+    diff_range(storage, from, to, prefix_range(prefix))
+}
+
+/// Returns one page of the differences between two versions of a tree, in key order.
+///
+/// Pages are stable: the differences between two root hashes never change, so the key
+/// of the last difference on a page is all that is needed to fetch the next one.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of both versions.
+/// - `from`: The root hash of the old version.
+/// - `to`: The root hash of the new version.
+/// - `after_key`: The key to start after, or `None` to start at the first difference.
+/// - `limit`: The maximum number of differences to return.
+///
+/// # Returns
+/// - The differences after `after_key`, fewer than `limit` only on the last page, or
+///   `Error::MissingNode` if a compared node is not in `storage`.
+pub fn diff_paginated<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    from: &ValueDigest<N>,
+    to: &ValueDigest<N>,
+    after_key: Option<&[u8]>,
+    limit: usize,
+) -> Result<Vec<DiffResult>, Error> {
+    let start = after_key.map_or(Bound::Unbounded, |key| Bound::Excluded(key.to_vec()));
+    let mut diffs = Vec::new();
+    if limit == 0 {
+        return Ok(diffs);
+    }
+    visit_diffs(
+        storage,
+        &load_root(storage, from)?,
+        &load_root(storage, to)?,
+        &(start, Bound::Unbounded),
+        |diff| {
+            diffs.push(diff);
+            if diffs.len() < limit {
+                ControlFlow::Continue(())
+            } else {
+                ControlFlow::Break(())
+            }
+        },
+    )?;
+    Ok(diffs)
+}
+
+/// Limits on the size of one chunk of a diff computed with `diff_chunk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffBudget {
+    /// The maximum number of differences per chunk.
+    pub max_items: usize,
+    /// The maximum total size of the keys and values of the differences per chunk.
+    pub max_bytes: usize,
+}
+
+impl Default for DiffBudget {
+    /// No limits.
+    fn default() -> Self {
+        DiffBudget {
+            max_items: usize::MAX,
+            max_bytes: usize::MAX,
+        }
+    }
+}
+
+/// Opaque token to resume a diff where the previous chunk ended.
+///
+/// The token records the root hashes of both versions and the last key of the chunk, so
+/// that it cannot be used to resume a different diff. It can be passed around as bytes,
+/// e.g. to the clients of a sync endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffToken<const N: usize> {
+    from: ValueDigest<N>,
+    to: ValueDigest<N>,
+    last_key: Vec<u8>,
+}
+
+impl<const N: usize> DiffToken<N> {
+    /// Returns the last key of the chunk; the next chunk starts after it.
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    /// Encodes the token as bytes: both root hashes followed by the last key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.from.as_bytes(), self.to.as_bytes(), &self.last_key].concat()
+    }
+
+    /// Decodes a token written by `to_bytes`.
+    ///
+    /// # Returns
+    /// - The token, or `Error::InvalidPageToken` if the bytes are too short to hold one.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 2 * N {
+            return Err(Error::InvalidPageToken);
+        }
+        let (from, rest) = bytes.split_at(N);
+        let (to, last_key) = rest.split_at(N);
+        Ok(DiffToken {
+            from: ValueDigest::raw_hash(from),
+            to: ValueDigest::raw_hash(to),
+            last_key: last_key.to_vec(),
+        })
+    }
+}
+
+/// A chunk of a diff returned by `diff_chunk`.
+#[derive(Debug, Clone)]
+pub struct DiffChunk<const N: usize> {
+    /// The differences of the chunk, in ascending key order.
+    pub diffs: Vec<DiffResult>,
+    /// The token to compute the next chunk with, or `None` if this is the last chunk.
+    pub next: Option<DiffToken<N>>,
+}
+
+/// Computes a diff in chunks of bounded size.
+///
+/// The first chunk is computed without a token, and every chunk but the last returns a
+/// token to resume right after its last key. Each chunk only visits the subtrees from
+/// where the previous chunk ended until its budget is used up. A chunk holds at least one
+/// difference, even if that alone exceeds the byte budget.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of both versions.
+/// - `from`: The root hash of the old version.
+/// - `to`: The root hash of the new version.
+/// - `budget`: The limits on the size of the chunk.
+/// - `token`: The token returned with the previous chunk, if any.
+///
+/// # Returns
+/// - The chunk, `Error::InvalidPageToken` if `token` was issued for other versions, or
+///   `Error::MissingNode` if a compared node is not in `storage`.
+pub fn diff_chunk<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    from: &ValueDigest<N>,
+    to: &ValueDigest<N>,
+    budget: DiffBudget,
+    token: Option<&DiffToken<N>>,
+) -> Result<DiffChunk<N>, Error> {
+    let start = match token {
+        Some(token) if token.from != *from || token.to != *to => {
+            return Err(Error::InvalidPageToken)
+        }
+        Some(token) => Bound::Excluded(token.last_key.clone()),
+        None => Bound::Unbounded,
+    };
+    let mut diffs = Vec::new();
+    let mut bytes = 0;
+    let mut more = false;
+    visit_diffs(
+        storage,
+        &load_root(storage, from)?,
+        &load_root(storage, to)?,
+        &(start, Bound::Unbounded),
+        |diff| {
+            let size = diff.size();
+            let full = diffs.len() >= budget.max_items
+                || (!diffs.is_empty() && bytes + size > budget.max_bytes);
+            if full {
+                more = true;
+                return ControlFlow::Break(());
+            }
+            bytes += size;
+            diffs.push(diff);
+            ControlFlow::Continue(())
+        },
+    )?;
+    let next = match diffs.last() {
+        Some(last) if more => Some(DiffToken {
+            from: from.clone(),
+            to: to.clone(),
+            last_key: last.key().to_vec(),
+        }),
+        _ => None,
+    };
+    Ok(DiffChunk { diffs, next })
+}
+
+/// Like `diff_roots`, for root nodes that are already loaded.
+pub(crate) fn diff_nodes<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    from: &ProllyNode<N>,
+    to: &ProllyNode<N>,
+) -> Result<Vec<DiffResult>, Error> {
+    let mut diffs = Vec::new();
+    visit_diffs(storage, from, to, &UNBOUNDED, |diff| {
+        diffs.push(diff);
+        ControlFlow::Continue(())
+    })?;
+    Ok(diffs)
+}
+
+const UNBOUNDED: (Bound<Vec<u8>>, Bound<Vec<u8>>) = (Bound::Unbounded, Bound::Unbounded);
+
+/// Calls `visit` with every difference from `from` to `to` within a key range, in
+/// ascending key order.
+///
+/// # Returns
+/// - `Error::MissingNode` if a node that has to be compared is not in `storage`.
+fn visit_diffs<const N: usize, S: NodeStorage<N>, F: FnMut(DiffResult) -> ControlFlow<()>>(
+    storage: &S,
+    from: &ProllyNode<N>,
+    to: &ProllyNode<N>,
+    range: &(Bound<Vec<u8>>, Bound<Vec<u8>>),
+    mut visit: F,
+) -> Result<(), Error> {
+    zip_range(from, storage, to, storage, range, |pairing| match pairing {
+        Pairing::Left(key, value) => visit(DiffResult::Removed(key, value)),
+        Pairing::Right(key, value) => visit(DiffResult::Added(key, value)),
+        Pairing::Both(key, old, new) if old != new => visit(DiffResult::Modified(key, old, new)),
+        _ => ControlFlow::Continue(()),
+    })
+}
+
+/// Counts the differences between two versions of a tree without collecting them.
+///
+/// Sizes are those of the values as they are stored in the leaves.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of both versions.
+/// - `from`: The root hash of the old version.
+/// - `to`: The root hash of the new version.
+///
+/// # Returns
+/// - The counts, or `Error::MissingNode` if a compared node is not in `storage`.
+pub fn diff_stats<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    from: &ValueDigest<N>,
+    to: &ValueDigest<N>,
+) -> Result<DiffStats, Error> {
+    let mut stats = DiffStats::default();
+    visit_diffs(
+        storage,
+        &load_root(storage, from)?,
+        &load_root(storage, to)?,
+        &UNBOUNDED,
+        |diff| {
+            stats.record(&diff);
+            ControlFlow::Continue(())
+        },
+    )?;
+    Ok(stats)
+}
+
+/// Like `diff_stats`, but counts the differences per key prefix.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of both versions.
+/// - `from`: The root hash of the old version.
+/// - `to`: The root hash of the new version.
+/// - `prefix_len`: The length in bytes of the prefixes to group keys by; shorter keys
+///   are their own prefix.
+///
+/// # Returns
+/// - The counts per prefix, in ascending order of prefixes with at least one change, or
+///   `Error::MissingNode` if a compared node is not in `storage`.
+pub fn diff_stats_by_prefix<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    from: &ValueDigest<N>,
+    to: &ValueDigest<N>,
+    prefix_len: usize,
+) -> Result<BTreeMap<Vec<u8>, DiffStats>, Error> {
+    let mut stats: BTreeMap<Vec<u8>, DiffStats> = BTreeMap::new();
+    visit_diffs(
+        storage,
+        &load_root(storage, from)?,
+        &load_root(storage, to)?,
+        &UNBOUNDED,
+        |diff| {
+            let key = diff.key();
+            let prefix = &key[..prefix_len.min(key.len())];
+            stats.entry(prefix.to_vec()).or_default().record(&diff);
+            ControlFlow::Continue(())
+        },
+    )?;
+    Ok(stats)
+}
+
+/// How many nodes a set of versions of a tree share through content addressing.
+///
+/// Sizes are those of the nodes as encoded by `ProllyNode::encode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SharingStats {
+    /// The number of distinct nodes reachable from any of the roots.
+    pub unique_nodes: usize,
+    /// The size of the distinct nodes, i.e. what storing all versions takes.
+    pub unique_bytes: usize,
+    /// The number of distinct nodes reachable from more than one root.
+    pub shared_nodes: usize,
+    /// The size of the distinct nodes reachable from more than one root.
+    pub shared_bytes: usize,
+    /// The number of nodes of all versions counted separately, i.e. what storing every
+    /// version on its own would take.
+    pub total_nodes: usize,
+    /// The size of the nodes of all versions counted separately.
+    pub total_bytes: usize,
+}
+
+impl SharingStats {
+    /// Returns the bytes saved by storing shared nodes once.
+    pub fn saved_bytes(&self) -> usize {
+        self.total_bytes - self.unique_bytes
+    }
+
+    /// Returns the size of the versions stored separately relative to their size with
+    /// shared nodes stored once, or `1.0` if there are no nodes.
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.unique_bytes == 0 {
+            1.0
+        } else {
+            self.total_bytes as f64 / self.unique_bytes as f64
+        }
+    }
+}
+
+/// Measures how many nodes, including the chunks of values stored outside of the leaves,
+/// a set of versions of a tree share, e.g. two branches or the versions kept by a
+/// retention policy.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of the versions.
+/// - `roots`: The root hashes of the versions. Repeated roots count as separate versions.
+/// - `expiring`: Whether the values of the tree carry expiry timestamps, which is needed
+///   to find the chunks of values stored outside of the leaves.
+///
+/// # Returns
+/// - The statistics, or `Error::MissingNode` if a root is not in `storage`.
+pub fn sharing_stats<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    roots: &[ValueDigest<N>],
+    expiring: bool,
+) -> Result<SharingStats, Error> {
+    let mut versions: HashMap<ValueDigest<N>, usize> = HashMap::new();
+    for root in roots {
+        load_root(storage, root)?;
+        for hash in reachable_nodes(storage, vec![root.clone()], expiring) {
+            *versions.entry(hash).or_default() += 1;
+        }
+    }
+
+    let mut stats = SharingStats::default();
+    for (hash, count) in versions {
+        let Some(node) = storage.get_node_by_hash(&hash) else {
+            continue;
+        };
+        let size = node.encode().len();
+        stats.unique_nodes += 1;
+        stats.unique_bytes += size;
+        stats.total_nodes += count;
+        stats.total_bytes += count * size;
+        if count > 1 {
+            stats.shared_nodes += 1;
+            stats.shared_bytes += size;
+        }
+    }
+    Ok(stats)
+}
+
+/// Collects the changes from `from` to `to`, in ascending key order.
+fn changes<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    from: &ProllyNode<N>,
+    to: &ProllyNode<N>,
+) -> Result<Vec<Change>, Error> {
+    Ok(diff_nodes(storage, from, to)?
+        .into_iter()
+        .map(|diff| match diff {
+            DiffResult::Added(key, new) => (key, None, Some(new)),
+            DiffResult::Removed(key, old) => (key, Some(old), None),
+            DiffResult::Modified(key, old, new) => (key, Some(old), Some(new)),
+        })
+        .collect())
+}
+
+/// Merges two versions of a tree that diverged from a common ancestor.
+///
+/// Only the subtrees that differ from the ancestor are visited on either side. A key
+/// changed on one side only takes that side's change; a key changed on both sides to the
+/// same value merges cleanly, and any other key changed on both sides is a conflict.
+/// Values are compared as they are stored in the leaves.
+///
+/// # Parameters
+/// - `storage`: The storage holding the nodes of all three versions.
+/// - `base`: The root hash of the common ancestor.
+/// - `ours`: The root hash of our side.
+/// - `theirs`: The root hash of their side.
+///
+/// # Returns
+/// - The changes to apply to our side and the conflicts, or `Error::MissingNode` if a
+///   compared node is not in `storage`.
+pub fn merge3<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    base: &ValueDigest<N>,
+    ours: &ValueDigest<N>,
+    theirs: &ValueDigest<N>,
+) -> Result<Merge, Error> {
+    let base = load_root(storage, base)?;
+    let ours = load_root(storage, ours)?;
+    let theirs = load_root(storage, theirs)?;
+    merge_nodes(storage, &base, &ours, &theirs)
+}
+
+/// Like `merge3`, for root nodes that are already loaded.
+pub(crate) fn merge_nodes<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    base: &ProllyNode<N>,
+    ours: &ProllyNode<N>,
+    theirs: &ProllyNode<N>,
+) -> Result<Merge, Error> {
+    let ours = changes(storage, base, ours)?;
+    let theirs = changes(storage, base, theirs)?;
+
+    let mut merge = Merge::default();
+    let mut ours = ours.into_iter().peekable();
+    for (key, base, new) in theirs {
+        while ours.next_if(|(ours_key, ..)| *ours_key < key).is_some() {}
+        match ours.next_if(|(ours_key, ..)| *ours_key == key) {
+            None => merge.changes.push((key, new)),
+            Some((_, _, ours)) if ours == new => {}
+            Some((_, _, ours)) => merge.conflicts.push(MergeConflict {
+                key,
+                base,
+                ours,
+                theirs: new,
+            }),
+        }
+    }
+    Ok(merge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::schema::KeyFormat;
+    use crate::storage::{InMemoryNodeStorage, NodeReader};
+    use crate::tree::{ProllyTree, Tree};
+    use serde_json::json;
+    use std::ops::ControlFlow;
+
+    #[test]
+    fn test_diff_formatter() {
+        let diffs = vec![
+            DiffResult::Added(b"a".to_vec(), b"new".to_vec()),
+            DiffResult::Modified(b"b".to_vec(), b"line\nbreak".to_vec(), vec![b'x'; 20]),
+            DiffResult::Removed(vec![0, 1], vec![0xff, 0x00]),
+        ];
+        let formatter = DiffFormatter::new()
+            .with_max_value_len(Some(10))
+            .with_labels("v1", "v2");
+        assert_eq!(
+            formatter.format(&diffs),
+            concat!(
+                "--- v1\n",
+                "+++ v2\n",
+                "+\"a\" = \"new\"\n",
+                "-\"b\" = \"line\\nbreak\"\n",
+                "+\"b\" = \"xxxxxxxxxx\"... (20 bytes)\n",
+                "-0x0001 = 0xff00\n",
+            )
+        );
+
+        let colored = DiffFormatter::new().with_color(true).format(&diffs[..1]);
+        assert_eq!(colored, "\x1b[32m+\"a\" = \"new\"\x1b[0m\n");
+
+        // a value cut off within a character is still text
+        let diff = DiffResult::Added(b"k".to_vec(), "é".repeat(3).into_bytes());
+        let formatter = DiffFormatter::new().with_max_value_len(Some(3));
+        assert_eq!(formatter.format(&[diff]), "+\"k\" = \"é\"... (6 bytes)\n");
+    }
+
+    #[test]
+    fn test_detect_renames() {
+        let mut diffs = Vec::new();
+        for i in 0..20 {
+            let value = vec![i % 3];
+            diffs.push(DiffResult::Removed(
+                format!("users/{i:02}").into_bytes(),
+                value.clone(),
+            ));
+            diffs.push(DiffResult::Added(
+                format!("people/{i:02}").into_bytes(),
+                value,
+            ));
+        }
+        // a moved key with a changed value is not part of the rename
+        diffs.push(DiffResult::Removed(b"users/99".to_vec(), vec![1]));
+        diffs.push(DiffResult::Added(b"people/99".to_vec(), vec![2]));
+        diffs.push(DiffResult::Modified(b"config".to_vec(), vec![1], vec![2]));
+        // a single moved key is not a rename
+        diffs.push(DiffResult::Removed(b"tmp/a".to_vec(), vec![5]));
+        diffs.push(DiffResult::Added(b"old/a".to_vec(), vec![5]));
+        diffs.sort_by(|a, b| a.key().cmp(b.key()));
+
+        let (renames, rest) = detect_renames(diffs, 10);
+        assert_eq!(
+            renames,
+            vec![PrefixRename {
+                from: b"users".to_vec(),
+                to: b"people".to_vec(),
+                keys: 20,
+            }]
+        );
+        let keys: Vec<&[u8]> = rest.iter().map(|diff| diff.key()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                b"config".as_slice(),
+                b"old/a",
+                b"people/99",
+                b"tmp/a",
+                b"users/99"
+            ]
+        );
+
+        let (renames, rest) = detect_renames(rest, 1);
+        assert!(renames.is_empty());
+        assert_eq!(rest.len(), 5);
+    }
+
+    #[test]
+    fn test_json_diff() {
+        let old = json!({
+            "name": "alice",
+            "tags": ["a", "b"],
+            "profile": {"age": 30, "city": "Paris", "a/b": 1},
+        });
+        let new = json!({
+            "name": "alice",
+            "tags": ["a", "c", "d"],
+            "profile": {"age": 31, "a/b": 2},
+            "active": true,
+        });
+        let changes = json_diff(old.to_string().as_bytes(), new.to_string().as_bytes()).unwrap();
+        let change = |path: &str, old: Option<Value>, new: Option<Value>| FieldChange {
+            path: path.to_string(),
+            old,
+            new,
+        };
+        assert_eq!(
+            changes,
+            vec![
+                change("/active", None, Some(json!(true))),
+                change("/profile/a~1b", Some(json!(1)), Some(json!(2))),
+                change("/profile/age", Some(json!(30)), Some(json!(31))),
+                change("/profile/city", Some(json!("Paris")), None),
+                change("/tags/1", Some(json!("b")), Some(json!("c"))),
+                change("/tags/2", None, Some(json!("d"))),
+            ]
+        );
+
+        assert_eq!(json_diff(b"1", b"1"), Some(Vec::new()));
+        assert_eq!(json_diff(b"{}", b"not json"), None);
+
+        let diff = DiffResult::Modified(b"k".to_vec(), b"[1]".to_vec(), b"{}".to_vec());
+        assert_eq!(
+            diff.field_changes(),
+            Some(vec![change("", Some(json!([1])), Some(json!({})))])
+        );
+        let diff = DiffResult::Added(b"k".to_vec(), b"\"x\"".to_vec());
+        assert_eq!(
+            diff.field_changes(),
+            Some(vec![change("", None, Some(json!("x")))])
+        );
+    }
+
+    /// Copies the nodes of a tree into another storage.
+    fn copy_nodes<const N: usize>(
+        tree: &ProllyTree<N, InMemoryNodeStorage<N>>,
+        storage: &mut InMemoryNodeStorage<N>,
+    ) {
+        let root = tree.get_root_hash().unwrap();
+        let mut nodes = vec![tree.storage().get_node_by_hash(&root).unwrap()];
+        while let Some(node) = nodes.pop() {
+            if !node.is_leaf {
+                nodes.extend(node.values.iter().filter_map(|hash| {
+                    tree.storage()
+                        .get_node_by_hash(&ValueDigest::raw_hash(hash))
+                }));
+            }
+            storage.insert_node(node.get_hash(), node);
+        }
+    }
+
+    #[test]
+    fn test_diff_roots() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let empty = tree.get_root_hash().unwrap();
+        for i in 0..500 {
+            tree.insert(key(i), vec![0]);
+        }
+        let old = tree.get_root_hash().unwrap();
+        tree.insert(key(7), vec![1]);
+        tree.delete(&key(300));
+        tree.insert(key(999), vec![2]);
+        let new = tree.get_root_hash().unwrap();
+
+        let diffs = diff_roots(tree.storage(), &old, &new).unwrap();
+        assert_eq!(
+            diffs,
+            vec![
+                DiffResult::Modified(key(7), vec![0], vec![1]),
+                DiffResult::Removed(key(300), vec![0]),
+                DiffResult::Added(key(999), vec![2]),
+            ]
+        );
+        assert!(diff_roots(tree.storage(), &new, &new).unwrap().is_empty());
+        let from_empty = diff_roots(tree.storage(), &empty, &new).unwrap();
+        assert_eq!(from_empty.len(), 500);
+
+        let missing = ValueDigest::new(b"missing");
+        assert!(diff_roots(tree.storage(), &old, &missing).is_err());
+    }
+
+    #[test]
+    fn test_diff_range() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |agent: &str, i: u32| [agent.as_bytes(), &i.to_be_bytes()[..]].concat();
+        for agent in ["agent/alice/", "agent/bob/", "agent/carol/"] {
+            for i in 0..300 {
+                tree.insert(key(agent, i), vec![0]);
+            }
+        }
+        let old = tree.get_root_hash().unwrap();
+        for agent in ["agent/alice/", "agent/bob/", "agent/carol/"] {
+            tree.insert(key(agent, 5), vec![1]);
+            tree.delete(&key(agent, 150));
+            tree.insert(key(agent, 1000), vec![1]);
+        }
+        let new = tree.get_root_hash().unwrap();
+
+        let all = diff_roots(tree.storage(), &old, &new).unwrap();
+        let bob = diff_prefix(tree.storage(), &old, &new, b"agent/bob/").unwrap();
+        assert_eq!(bob.len(), 3);
+        assert!(bob.iter().all(|diff| diff.key().starts_with(b"agent/bob/")));
+
+        let range = key("agent/alice/", 100)..=key("agent/bob/", 5);
+        let diffs = diff_range(tree.storage(), &old, &new, range.clone()).unwrap();
+        let expected: Vec<_> = all
+            .into_iter()
+            .filter(|diff| range.contains(&diff.key().to_vec()))
+            .collect();
+        assert_eq!(diffs, expected);
+        assert_eq!(diffs.len(), 3);
+
+        let equal = |range: (Bound<Vec<u8>>, Bound<Vec<u8>>)| {
+            ranges_equal(tree.storage(), &old, &new, range).unwrap()
+        };
+        let between = |lo: u32, hi: u32| {
+            (
+                Bound::Included(key("agent/bob/", lo)),
+                Bound::Excluded(key("agent/bob/", hi)),
+            )
+        };
+        assert!(equal(between(6, 150)));
+        assert!(!equal(between(6, 151)));
+        assert!(!equal(between(0, 6)));
+        assert!(!equal((Bound::Unbounded, Bound::Unbounded)));
+        assert!(ranges_equal(tree.storage(), &old, &old, ..).unwrap());
+    }
+
+    /// A storage that counts the nodes read from it.
+    struct ReadCounter {
+        inner: InMemoryNodeStorage<32>,
+        reads: std::sync::atomic::AtomicUsize,
+    }
+
+    impl ReadCounter {
+        fn reads(&self) -> usize {
+            self.reads.swap(0, std::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    impl NodeReader<32> for ReadCounter {
+        fn get_node_by_hash(&self, hash: &ValueDigest<32>) -> Option<ProllyNode<32>> {
+            self.reads
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            self.inner.get_node_by_hash(hash)
+        }
+
+        fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+            self.inner.get_config(key)
+        }
+    }
+
+    impl NodeStorage<32> for ReadCounter {
+        fn insert_node(&mut self, hash: ValueDigest<32>, node: ProllyNode<32>) -> Option<()> {
+            self.inner.insert_node(hash, node)
+        }
+
+        fn delete_node(&mut self, hash: &ValueDigest<32>) -> Option<()> {
+            self.inner.delete_node(hash)
+        }
+
+        fn save_config(&self, key: &str, config: &[u8]) {
+            self.inner.save_config(key, config)
+        }
+    }
+
+    #[test]
+    fn test_ranges_equal_skips_shared_subtrees() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |partition: &str, i: u32| [partition.as_bytes(), &i.to_be_bytes()].concat();
+        for partition in ["a/", "b/", "c/"] {
+            for i in 0..2000 {
+                tree.insert(key(partition, i), vec![0]);
+            }
+        }
+        let old = tree.get_root_hash().unwrap();
+        tree.insert(key("c/", 7), vec![1]);
+        let new = tree.get_root_hash().unwrap();
+        let storage = ReadCounter {
+            inner: tree.storage().clone(),
+            reads: Default::default(),
+        };
+        let nodes = reachable_nodes(&storage, vec![new.clone()], false).len();
+        storage.reads();
+
+        // a clean partition is confirmed from the few nodes the versions do not share
+        assert!(ranges_equal(&storage, &old, &new, prefix_range(b"a/")).unwrap());
+        let reads = storage.reads();
+        assert!(reads * 20 < nodes, "{} of {} nodes read", reads, nodes);
+
+        // a dirty partition is reported at its first difference
+        assert!(!ranges_equal(&storage, &old, &new, prefix_range(b"c/")).unwrap());
+        assert!(storage.reads() * 20 < nodes);
+        assert!(!ranges_equal(&storage, &old, &new, ..).unwrap());
+    }
+
+    #[test]
+    fn test_missing_node_is_reported() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..1000 {
+            tree.insert(key(i), vec![0]);
+        }
+        let old = tree.get_root_hash().unwrap();
+        let other = tree.fork();
+        tree.insert(key(0), vec![1]);
+        let new = tree.get_root_hash().unwrap();
+
+        // drop the changed child of the new root, which differs from the old one
+        let root = tree.storage().get_node_by_hash(&new).unwrap();
+        assert!(!root.is_leaf);
+        let child = ValueDigest::raw_hash(&root.values[0]);
+        tree.storage_mut().delete_node(&child);
+        let name = format!("{:x}", child);
+        let missing = |result: Result<(), Error>| match result {
+            Err(Error::MissingNode(hash)) => hash == name,
+            _ => false,
+        };
+
+        let storage = tree.storage();
+        assert!(missing(diff_roots(storage, &old, &new).map(drop)));
+        assert!(missing(diff_range(storage, &old, &new, ..).map(drop)));
+        assert!(missing(ranges_equal(storage, &old, &new, ..).map(drop)));
+        assert!(missing(merge3(storage, &old, &new, &old).map(drop)));
+
+        // ranges that do not reach the missing node are still compared
+        assert!(ranges_equal(storage, &old, &new, key(500)..).unwrap());
+
+        // nothing is merged when a node cannot be compared
+        assert!(missing(
+            tree.merge_with(&other, |_, _, theirs| theirs.to_vec())
+        ));
+        assert_eq!(tree.get_root_hash().unwrap(), new);
+    }
+
+    #[test]
+    fn test_diff_paginated() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..1000 {
+            tree.insert(key(i), vec![0]);
+        }
+        let old = tree.get_root_hash().unwrap();
+        for i in (0..1000).step_by(40) {
+            tree.insert(key(i), vec![1]);
+        }
+        let new = tree.get_root_hash().unwrap();
+        let all = diff_roots(tree.storage(), &old, &new).unwrap();
+        assert_eq!(all.len(), 25);
+
+        let mut pages = Vec::new();
+        let mut after: Option<Vec<u8>> = None;
+        loop {
+            let page = diff_paginated(tree.storage(), &old, &new, after.as_deref(), 10).unwrap();
+            after = page.last().map(|diff| diff.key().to_vec());
+            let last = page.len() < 10;
+            pages.push(page);
+            if last {
+                break;
+            }
+        }
+        assert_eq!(
+            pages.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![10, 10, 5]
+        );
+        assert_eq!(pages.concat(), all);
+        assert!(diff_paginated(tree.storage(), &old, &new, None, 0)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_diff_chunks() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..1000 {
+            tree.insert(key(i), vec![0]);
+        }
+        let old = tree.get_root_hash().unwrap();
+        for i in (0..1000).step_by(7) {
+            tree.insert(key(i), vec![1]);
+        }
+        let new = tree.get_root_hash().unwrap();
+        let all = diff_roots(tree.storage(), &old, &new).unwrap();
+
+        // every difference takes 4 + 1 + 1 bytes
+        let budget = DiffBudget {
+            max_items: 50,
+            max_bytes: 200,
+        };
+        let mut resumed = Vec::new();
+        let mut token: Option<DiffToken<32>> = None;
+        loop {
+            let chunk = diff_chunk(tree.storage(), &old, &new, budget, token.as_ref()).unwrap();
+            assert!(chunk.diffs.len() <= 33);
+            resumed.extend(chunk.diffs);
+            match chunk.next {
+                Some(next) => token = Some(DiffToken::from_bytes(&next.to_bytes()).unwrap()),
+                None => break,
+            }
+        }
+        assert_eq!(resumed, all);
+
+        // a token only resumes the diff it was issued for
+        let budget = DiffBudget {
+            max_items: 1,
+            ..Default::default()
+        };
+        let chunk = diff_chunk(tree.storage(), &old, &new, budget, None).unwrap();
+        let token = chunk.next.unwrap();
+        assert!(matches!(
+            diff_chunk(tree.storage(), &new, &old, budget, Some(&token)),
+            Err(Error::InvalidPageToken)
+        ));
+    }
+
+    #[test]
+    fn test_diff_stats() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..200u32 {
+            tree.insert([b"a/", &i.to_be_bytes()[..]].concat(), vec![0; 4]);
+            tree.insert([b"b/", &i.to_be_bytes()[..]].concat(), vec![0; 4]);
+        }
+        let old = tree.get_root_hash().unwrap();
+        tree.insert(b"a/new".to_vec(), vec![1; 10]);
+        tree.insert([b"a/", &7u32.to_be_bytes()[..]].concat(), vec![1; 8]);
+        tree.delete(&[b"b/", &9u32.to_be_bytes()[..]].concat());
+        let new = tree.get_root_hash().unwrap();
+
+        let stats = diff_stats(tree.storage(), &old, &new).unwrap();
+        assert_eq!(
+            stats,
+            DiffStats {
+                added: 1,
+                modified: 1,
+                removed: 1,
+                bytes_added: 5 + 10 + 8,
+                bytes_removed: 6 + 4 + 4,
+            }
+        );
+        assert_eq!(stats.total(), 3);
+
+        let by_prefix = diff_stats_by_prefix(tree.storage(), &old, &new, 2).unwrap();
+        assert_eq!(by_prefix.len(), 2);
+        assert_eq!(by_prefix[&b"a/".to_vec()].total(), 2);
+        assert_eq!(by_prefix[&b"b/".to_vec()].removed, 1);
+    }
+
+    #[test]
+    fn test_sharing_stats() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..1000u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![0; 16]);
+        }
+        let old = tree.get_root_hash().unwrap();
+        let single = sharing_stats(tree.storage(), std::slice::from_ref(&old), false).unwrap();
+        assert_eq!(single.shared_nodes, 0);
+        assert_eq!(single.total_nodes, single.unique_nodes);
+        assert_eq!(single.unique_nodes, tree.stats().num_nodes);
+        assert_eq!(single.saved_bytes(), 0);
+
+        tree.insert(500u32.to_be_bytes().to_vec(), vec![1; 16]);
+        let new = tree.get_root_hash().unwrap();
+        let both = sharing_stats(tree.storage(), &[old.clone(), new], false).unwrap();
+        // only the nodes around the changed leaf differ between the versions
+        assert!(both.unique_nodes > single.unique_nodes);
+        assert!(both.shared_nodes > single.unique_nodes * 9 / 10);
+        assert_eq!(both.unique_nodes + both.shared_nodes, both.total_nodes);
+        assert_eq!(both.saved_bytes(), both.shared_bytes);
+        assert!(both.dedup_ratio() > 1.5);
+
+        let missing = ValueDigest::<32>::new(b"missing");
+        assert!(matches!(
+            sharing_stats(tree.storage(), &[old, missing], false),
+            Err(Error::MissingNode(_))
+        ));
+    }
+
+    #[test]
+    fn test_patches() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..300 {
+            tree.insert(key(i), vec![0]);
+        }
+        let mut replica = tree.fork();
+        let from = tree.get_root_hash().unwrap();
+        tree.insert(key(7), vec![1]);
+        tree.delete(&key(100));
+        tree.insert(key(500), vec![2]);
+
+        let patch = tree.create_patch(&from).unwrap();
+        assert_eq!(patch.from_root, from);
+        assert_eq!(patch.to_root, tree.get_root_hash().unwrap());
+        assert_eq!(patch.diffs.len(), 3);
+        assert_eq!(
+            Patch::from_bytes(&patch.to_bytes().unwrap()).unwrap(),
+            patch
+        );
+        let json = patch.to_json().unwrap();
+        assert_eq!(Patch::<32>::from_json(&json).unwrap(), patch);
+
+        replica.apply_patch(&patch).unwrap();
+        assert!(replica.iter().eq(tree.iter()));
+
+        // the replica has moved on, so the patch no longer applies
+        assert!(matches!(
+            replica.apply_patch(&patch),
+            Err(Error::PatchMismatch(_))
+        ));
+        assert!(Patch::<32>::from_bytes(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_apply_patch_deletions() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..300 {
+            tree.insert(key(i), vec![0]);
+        }
+        let mut replica = tree.fork();
+        let mut picked = tree.fork();
+        let from = tree.get_root_hash().unwrap();
+        tree.delete(&key(100));
+        tree.delete(&key(200));
+        let patch = tree.create_patch(&from).unwrap();
+
+        // a patch of only deletions still leaves a root that can be diffed
+        replica.apply_patch(&patch).unwrap();
+        let patched = replica.get_root_hash().unwrap();
+        assert_eq!(patched, tree.get_root_hash().unwrap());
+        assert_eq!(
+            diff_roots(replica.storage(), &from, &patched).unwrap(),
+            patch.diffs
+        );
+
+        let applied = picked
+            .apply_diff_selective(&patch.diffs, |key| key == 100u32.to_be_bytes())
+            .unwrap();
+        assert_eq!(applied, 1);
+        assert_eq!(
+            diff_roots(picked.storage(), &from, &picked.get_root_hash().unwrap()).unwrap(),
+            vec![DiffResult::Removed(key(100), vec![0])]
+        );
+    }
+
+    #[test]
+    fn test_diff_visit() {
+        #[derive(Default)]
+        struct Events {
+            skipped: usize,
+            changes: Vec<String>,
+            limit: Option<usize>,
+        }
+
+        impl Events {
+            fn push(&mut self, event: String) -> ControlFlow<()> {
+                self.changes.push(event);
+                if Some(self.changes.len()) == self.limit {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            }
+        }
+
+        impl DiffVisitor<32> for Events {
+            fn skip_subtree(&mut self, _node: &ProllyNode<32>) -> ControlFlow<()> {
+                self.skipped += 1;
+                ControlFlow::Continue(())
+            }
+
+            fn added(&mut self, key: &[u8], _value: &[u8]) -> ControlFlow<()> {
+                self.push(format!("+{}", String::from_utf8_lossy(key)))
+            }
+
+            fn removed(&mut self, key: &[u8], _value: &[u8]) -> ControlFlow<()> {
+                self.push(format!("-{}", String::from_utf8_lossy(key)))
+            }
+
+            fn modified(&mut self, key: &[u8], _old: &[u8], _new: &[u8]) -> ControlFlow<()> {
+                self.push(format!("~{}", String::from_utf8_lossy(key)))
+            }
+        }
+
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..1000u32 {
+            tree.insert(format!("{i:04}").into_bytes(), vec![0]);
+        }
+        let from = tree.get_root_hash().unwrap();
+        tree.insert(b"0005".to_vec(), vec![1]);
+        tree.delete(b"0500");
+        tree.insert(b"2000".to_vec(), vec![2]);
+        let to = tree.get_root_hash().unwrap();
+
+        let mut events = Events::default();
+        diff_visit(tree.storage(), &from, &to, &mut events).unwrap();
+        assert_eq!(events.changes, vec!["~0005", "-0500", "+2000"]);
+        assert!(events.skipped > 0);
+
+        let mut events = Events {
+            limit: Some(2),
+            ..Default::default()
+        };
+        diff_visit(tree.storage(), &from, &to, &mut events).unwrap();
+        assert_eq!(events.changes, vec!["~0005", "-0500"]);
+
+        let missing = ValueDigest::new(b"missing");
+        assert!(diff_visit(tree.storage(), &missing, &to, &mut Events::default()).is_err());
+    }
+
+    #[test]
+    fn test_apply_diff_selective() {
+        let mut main = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..100u32 {
+            main.insert(format!("a/{i:03}").into_bytes(), vec![0]);
+            main.insert(format!("b/{i:03}").into_bytes(), vec![0]);
+        }
+        let mut branch = main.fork();
+        let from = branch.get_root_hash().unwrap();
+        branch.insert(b"a/005".to_vec(), vec![1]);
+        branch.delete(b"a/006");
+        branch.insert(b"a/500".to_vec(), vec![2]);
+        branch.insert(b"b/005".to_vec(), vec![3]);
+        let patch = branch.create_patch(&from).unwrap();
+
+        // main has moved on since the fork
+        main.insert(b"c/000".to_vec(), vec![4]);
+        let get = |tree: &ProllyTree<32, InMemoryNodeStorage<32>>, key: &[u8]| {
+            tree.get_floor(key)
+                .filter(|(found, _)| found == key)
+                .map(|(_, value)| value)
+        };
+        let applied = main
+            .apply_diff_selective(&patch.diffs, |key| key.starts_with(b"a/"))
+            .unwrap();
+        assert_eq!(applied, 3);
+        assert_eq!(get(&main, b"a/005"), Some(vec![1]));
+        assert_eq!(get(&main, b"a/006"), None);
+        assert_eq!(get(&main, b"a/500"), Some(vec![2]));
+        assert_eq!(get(&main, b"b/005"), Some(vec![0]));
+        assert_eq!(get(&main, b"c/000"), Some(vec![4]));
+
+        assert_eq!(
+            main.apply_diff_selective(&patch.diffs, |_| false).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_deferred_conflicts() {
+        let config = TreeConfig::<32> {
+            key_format: Some(KeyFormat::FixedWidth(4)),
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config.clone());
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..300 {
+            tree.insert(key(i), vec![0]);
+        }
+        let base = tree.get_root_hash().unwrap();
+        let mut other = tree.fork();
+        other.insert(key(10), vec![1]);
+        other.insert(key(30), b"theirs".to_vec());
+        other.delete(&key(40));
+        let theirs = other.get_root_hash().unwrap();
+        copy_nodes(&other, tree.storage_mut());
+        tree.insert(key(30), b"ours".to_vec());
+        tree.insert(key(40), b"ours".to_vec());
+
+        let recorded = tree
+            .merge3_deferred(&base, &theirs, &MergePolicy::default())
+            .unwrap();
+        assert_eq!(recorded, 2);
+        assert_eq!(tree.current_value(&key(10)), Some(vec![1]));
+        assert_eq!(tree.current_value(&key(30)), Some(b"ours".to_vec()));
+        let conflicts = tree.conflicts().unwrap();
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(conflicts[0].theirs_root, theirs);
+        assert_eq!(
+            conflicts[1].conflict,
+            MergeConflict {
+                key: key(40),
+                base: Some(vec![0]),
+                ours: Some(b"ours".to_vec()),
+                theirs: None,
+            }
+        );
+
+        // the records are exempt from the fixed key width, but users cannot write there
+        let record_key = ConflictRecord::<32>::record_key(&key(30));
+        assert!(matches!(
+            tree.try_insert(record_key.clone(), b"forged".to_vec()),
+            Err(Error::InvalidKey(_))
+        ));
+        assert!(matches!(
+            tree.try_insert_batch(&[key(50), record_key], &[vec![1], vec![1]]),
+            Err(Error::InvalidKey(_))
+        ));
+        assert_eq!(tree.current_value(&key(50)), Some(vec![0]));
+        assert_eq!(tree.conflicts().unwrap().len(), 2);
+        let rewritten = tree
+            .rewrite_with_config(InMemoryNodeStorage::<32>::default(), config)
+            .unwrap();
+        assert_eq!(rewritten.conflicts().unwrap(), conflicts);
+
+        assert!(tree
+            .resolve_conflict(&key(30), Some(b"x".to_vec()))
+            .unwrap());
+        assert_eq!(tree.current_value(&key(30)), Some(b"x".to_vec()));
+        assert!(tree.resolve_conflict(&key(40), None).unwrap());
+        assert!(tree.current_value(&key(40)).is_none());
+        assert!(!tree.resolve_conflict(&key(40), None).unwrap());
+        assert!(tree.conflicts().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge3() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..300 {
+            tree.insert(key(i), vec![0]);
+        }
+        let base = tree.get_root_hash().unwrap();
+
+        let mut other = tree.fork();
+        other.insert(key(10), vec![1]);
+        other.delete(&key(20));
+        other.insert(key(1000), vec![1]);
+        other.insert(key(30), b"theirs".to_vec());
+        other.insert(key(40), b"both".to_vec());
+        let theirs = other.get_root_hash().unwrap();
+        // the fork copied the in-memory storage, so bring their nodes over
+        copy_nodes(&other, tree.storage_mut());
+
+        tree.insert(key(30), b"ours".to_vec());
+        tree.insert(key(40), b"both".to_vec());
+        tree.delete(&key(50));
+        let ours = tree.get_root_hash().unwrap();
+
+        let merge = merge3(tree.storage(), &base, &ours, &theirs).unwrap();
+        assert_eq!(
+            merge.changes,
+            vec![
+                (key(10), Some(vec![1])),
+                (key(20), None),
+                (key(1000), Some(vec![1])),
+            ]
+        );
+        assert!(!merge.is_clean());
+        assert_eq!(
+            merge.conflicts,
+            vec![MergeConflict {
+                key: key(30),
+                base: Some(vec![0]),
+                ours: Some(b"ours".to_vec()),
+                theirs: Some(b"theirs".to_vec()),
+            }]
+        );
+
+        let conflicts = tree.merge3(&base, &theirs).unwrap();
+        assert_eq!(conflicts, merge.conflicts);
+        assert_eq!(tree.current_value(&key(10)), Some(vec![1]));
+        assert!(tree.current_value(&key(20)).is_none());
+        assert!(tree.current_value(&key(50)).is_none());
+        assert_eq!(tree.current_value(&key(1000)), Some(vec![1]));
+        assert_eq!(tree.current_value(&key(30)), Some(b"ours".to_vec()));
+        assert_eq!(tree.current_value(&key(40)), Some(b"both".to_vec()));
+
+        // merging the same changes again is a no-op
+        let merged = tree.get_root_hash();
+        assert_eq!(tree.merge3(&base, &theirs).unwrap().len(), 1);
+        assert_eq!(tree.get_root_hash(), merged);
+
+        let missing = ValueDigest::new(b"missing");
+        assert!(matches!(
+            tree.merge3(&missing, &theirs),
+            Err(Error::MissingNode(_))
+        ));
+    }
+
+    #[test]
+    fn test_merge3_with_deletions() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..300 {
+            tree.insert(key(i), vec![0]);
+        }
+        let base = tree.get_root_hash().unwrap();
+        let mut other = tree.fork();
+        other.delete(&key(100));
+        copy_nodes(&other, tree.storage_mut());
+        tree.insert(key(7), vec![1]);
+
+        // the merge ends with deleting the key removed on their side
+        let conflicts = tree.merge3(&base, &other.get_root_hash().unwrap()).unwrap();
+        assert!(conflicts.is_empty());
+        let merged = tree.get_root_hash().unwrap();
+        assert_eq!(
+            diff_roots(tree.storage(), &base, &merged).unwrap(),
+            vec![
+                DiffResult::Modified(key(7), vec![0], vec![1]),
+                DiffResult::Removed(key(100), vec![0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_strategies() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        // values are a timestamp followed by the data
+        let value = |time: u64, data: &str| [&time.to_be_bytes()[..], data.as_bytes()].concat();
+        for table in ["audit/", "profile/", "misc/"] {
+            tree.insert(table.as_bytes().to_vec(), value(0, "base"));
+        }
+        let base = tree.get_root_hash().unwrap();
+
+        let mut other = tree.fork();
+        other.insert(b"audit/".to_vec(), value(5, "theirs"));
+        other.insert(b"profile/".to_vec(), value(9, "theirs"));
+        other.insert(b"misc/".to_vec(), value(1, "theirs"));
+        let theirs = other.get_root_hash().unwrap();
+        copy_nodes(&other, tree.storage_mut());
+
+        tree.insert(b"audit/".to_vec(), value(7, "ours"));
+        tree.insert(b"profile/".to_vec(), value(7, "ours"));
+        tree.insert(b"misc/".to_vec(), value(7, "ours"));
+
+        let timestamp: TimestampFn = Box::new(|value: &[u8]| {
+            value
+                .first_chunk::<8>()
+                .map(|time| u64::from_be_bytes(*time))
+        });
+        let policy = MergePolicy::default()
+            .with_prefix("audit/", ConflictStrategy::TakeOurs)
+            .with_prefix("profile/", ConflictStrategy::LastWriteWins(timestamp));
+        assert!(matches!(
+            policy.strategy(b"audit/1"),
+            Some(ConflictStrategy::TakeOurs)
+        ));
+        assert!(policy.strategy(b"misc/").is_none());
+
+        let conflicts = tree.merge3_with(&base, &theirs, &policy).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].key, b"misc/");
+        assert_eq!(tree.current_value(b"audit/"), Some(value(7, "ours")));
+        assert_eq!(tree.current_value(b"profile/"), Some(value(9, "theirs")));
+        assert_eq!(tree.current_value(b"misc/"), Some(value(7, "ours")));
+
+        // a default strategy covers the remaining keys
+        let policy = MergePolicy::new(ConflictStrategy::Custom(Box::new(|_| None)));
+        assert!(tree
+            .merge3_with(&base, &theirs, &policy)
+            .unwrap()
+            .is_empty());
+        assert!(tree.current_value(b"misc/").is_none());
+        assert_eq!(tree.current_value(b"audit/"), None);
+    }
+}
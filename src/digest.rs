@@ -14,6 +14,27 @@ limitations under the License.
 
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use sha3::{Keccak256, Sha3_256};
+
+/// Hash function used to compute node hashes.
+///
+/// The algorithm is recorded in the tree configuration and in every node, so that hashes
+/// and proofs can be recomputed by anyone reading the tree.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+    Sha3_256,
+    /// Keccak-256 as used by Ethereum. Nodes hashed with it are laid out as described in
+    /// `proof::evm`, so that smart contracts can verify proofs.
+    Keccak256,
+    /// Poseidon over the BN254 scalar field with the circom parameters, which is cheap to
+    /// prove inside zero-knowledge circuits. Nodes hashed with it are laid out like
+    /// `Keccak256` nodes, and the bytes are absorbed as described in `poseidon_hash`.
+    #[cfg(feature = "poseidon")]
+    Poseidon,
+}
 
 /// Represents a cryptographic hash of a value in a prolly tree.
 ///
@@ -54,15 +75,34 @@ impl<const N: usize> ValueDigest<N> {
     ///
     /// A `ValueDigest` instance containing the computed hash.
     pub fn new(data: &[u8]) -> Self {
+        Self::with_algorithm(data, HashAlgorithm::Sha256)
+    }
+
+    /// Creates a new `ValueDigest` from the given data using the given hash function.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - A slice of bytes representing the input data to be hashed.
+    /// * `algorithm` - The hash function to use.
+    ///
+    /// # Returns
+    ///
+    /// A `ValueDigest` instance containing the first `N` bytes of the computed hash.
+    pub fn with_algorithm(data: &[u8], algorithm: HashAlgorithm) -> Self {
         // Ensure N is not larger than 32 to prevent out-of-bounds errors
         assert!(
             N <= 32,
-            "N must be less than or equal to 32 due to SHA-256 output size"
+            "N must be less than or equal to 32 due to the 256-bit hash output size"
         );
 
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let result = hasher.finalize();
+        let result: [u8; 32] = match algorithm {
+            HashAlgorithm::Sha256 => Sha256::digest(data).into(),
+            HashAlgorithm::Blake3 => blake3::hash(data).into(),
+            HashAlgorithm::Sha3_256 => Sha3_256::digest(data).into(),
+            HashAlgorithm::Keccak256 => Keccak256::digest(data).into(),
+            #[cfg(feature = "poseidon")]
+            HashAlgorithm::Poseidon => poseidon_hash(data),
+        };
 
         let mut hash = [0u8; N];
         hash.copy_from_slice(&result[..N]);
@@ -103,6 +143,33 @@ impl<const N: usize> ValueDigest<N> {
     }
 }
 
+/// Number of bytes absorbed per Poseidon call. 31 bytes always fit below the field modulus.
+#[cfg(feature = "poseidon")]
+const POSEIDON_CHUNK: usize = 31;
+
+/// Hashes arbitrary bytes with Poseidon, as a circuit would.
+///
+/// The state starts as the hash of the data length (a big-endian `u64`). The data is then
+/// split into chunks of 31 bytes, the last one possibly shorter, and each chunk is absorbed
+/// with `state = poseidon(state, chunk)`, reading the chunk as a big-endian field element.
+/// The result is the final state as 32 big-endian bytes.
+#[cfg(feature = "poseidon")]
+pub fn poseidon_hash(data: &[u8]) -> [u8; 32] {
+    use light_poseidon::{Poseidon, PoseidonBytesHasher};
+
+    // inputs are never empty and always below the modulus, so hashing cannot fail
+    let mut state = Poseidon::<ark_bn254::Fr>::new_circom(1)
+        .and_then(|mut poseidon| poseidon.hash_bytes_be(&[&(data.len() as u64).to_be_bytes()]))
+        .expect("Poseidon hashing failed");
+    let mut poseidon = Poseidon::<ark_bn254::Fr>::new_circom(2).expect("Poseidon hashing failed");
+    for chunk in data.chunks(POSEIDON_CHUNK) {
+        state = poseidon
+            .hash_bytes_be(&[&state, chunk])
+            .expect("Poseidon hashing failed");
+    }
+    state
+}
+
 // Implement Default trait for ValueDigest
 impl<const N: usize> Default for ValueDigest<N> {
     fn default() -> Self {
@@ -130,8 +197,10 @@ impl<'de, const N: usize> Deserialize<'de> for ValueDigest<N> {
     where
         D: serde::Deserializer<'de>,
     {
-        let bytes: &[u8] = serde::de::Deserialize::deserialize(deserializer)?;
-        let array = <[u8; N]>::try_from(bytes)
+        // Owned bytes, so that self-describing formats such as JSON, which encode the
+        // digest as a sequence of numbers, can be read back as well as binary ones
+        let bytes: Vec<u8> = serde::de::Deserialize::deserialize(deserializer)?;
+        let array = <[u8; N]>::try_from(bytes.as_slice())
             .map_err(|_| serde::de::Error::invalid_length(bytes.len(), &stringify!(N)))?;
         Ok(ValueDigest(array))
     }
@@ -158,6 +227,46 @@ mod tests {
         assert_eq!(value_digest.as_bytes(), &expected_hash);
     }
 
+    #[test]
+    fn test_value_digest_with_algorithm() {
+        let data = b"test data";
+        let sha256 = ValueDigest::<32>::with_algorithm(data, HashAlgorithm::Sha256);
+        let blake3 = ValueDigest::<32>::with_algorithm(data, HashAlgorithm::Blake3);
+        let sha3 = ValueDigest::<32>::with_algorithm(data, HashAlgorithm::Sha3_256);
+
+        assert_eq!(sha256, ValueDigest::<32>::new(data));
+        assert_eq!(blake3.as_bytes(), blake3::hash(data).as_bytes());
+        assert_eq!(sha3.as_bytes(), Sha3_256::digest(data).as_slice());
+        assert_ne!(sha256, blake3);
+        assert_ne!(sha256, sha3);
+        assert_ne!(blake3, sha3);
+    }
+
+    #[cfg(feature = "poseidon")]
+    #[test]
+    fn test_poseidon_hash() {
+        use light_poseidon::{Poseidon, PoseidonBytesHasher};
+
+        let hash = |inputs: &[&[u8]]| {
+            Poseidon::<ark_bn254::Fr>::new_circom(inputs.len())
+                .unwrap()
+                .hash_bytes_be(inputs)
+                .unwrap()
+        };
+        // two chunks: 31 bytes and 9 bytes
+        let data = [7u8; 40];
+        let state = hash(&[&40u64.to_be_bytes()]);
+        let state = hash(&[&state, &data[..31]]);
+        let state = hash(&[&state, &data[31..]]);
+        assert_eq!(poseidon_hash(&data), state);
+        assert_eq!(poseidon_hash(&[]), hash(&[&0u64.to_be_bytes()]));
+
+        // leading zero bytes are not lost, thanks to the length prefix
+        assert_ne!(poseidon_hash(&[0, 1]), poseidon_hash(&[1]));
+        let digest = ValueDigest::<32>::with_algorithm(&data, HashAlgorithm::Poseidon);
+        assert_eq!(digest.0, state);
+    }
+
     #[test]
     fn test_value_digest_as_bytes() {
         let data = b"test data";
@@ -214,10 +214,12 @@ mod tests {
 
     #[test]
     fn test_encode_json() {
-        let mut node: ProllyNode<1024> = ProllyNode::default();
-        node.keys = vec![b"key1".to_vec(), b"key2".to_vec()];
-        node.values = vec![b"value1".to_vec(), b"value2".to_vec()];
-        node.encode_types = vec![EncodingType::Json];
+        let mut node: ProllyNode<1024> = ProllyNode {
+            keys: vec![b"key1".to_vec(), b"key2".to_vec()],
+            values: vec![b"value1".to_vec(), b"value2".to_vec()],
+            encode_types: vec![EncodingType::Json],
+            ..Default::default()
+        };
 
         node.encode_all_pairs();
 
@@ -232,8 +234,6 @@ mod tests {
 
     #[test]
     fn test_encode_json_complex() {
-        let mut node: ProllyNode<1024> = ProllyNode::default();
-
         let keys = [
             ComplexKey {
                 id: 1,
@@ -261,15 +261,18 @@ mod tests {
             },
         ];
 
-        node.keys = keys
-            .iter()
-            .map(|k| serde_json::to_vec(k).unwrap())
-            .collect();
-        node.values = values
-            .iter()
-            .map(|v| serde_json::to_vec(v).unwrap())
-            .collect();
-        node.encode_types = vec![EncodingType::Json];
+        let mut node: ProllyNode<1024> = ProllyNode {
+            keys: keys
+                .iter()
+                .map(|k| serde_json::to_vec(k).unwrap())
+                .collect(),
+            values: values
+                .iter()
+                .map(|v| serde_json::to_vec(v).unwrap())
+                .collect(),
+            encode_types: vec![EncodingType::Json],
+            ..Default::default()
+        };
 
         node.encode_all_pairs();
 
@@ -286,8 +289,6 @@ mod tests {
 
     #[test]
     fn test_encode_arrow() {
-        let mut node: ProllyNode<1024> = ProllyNode::default();
-
         let keys = [
             ComplexKey {
                 id: 1,
@@ -315,20 +316,20 @@ mod tests {
             },
         ];
 
-        node.keys = keys
-            .iter()
-            .map(|k| serde_json::to_vec(k).unwrap())
-            .collect();
-        node.values = values
-            .iter()
-            .map(|v| serde_json::to_vec(v).unwrap())
-            .collect();
-        node.encode_types = vec![EncodingType::Arrow];
-
-        let key_schema = schema_for!(ComplexKey);
-        let value_schema = schema_for!(ComplexValue);
-        node.key_schema = Some(key_schema);
-        node.value_schema = Some(value_schema);
+        let mut node: ProllyNode<1024> = ProllyNode {
+            keys: keys
+                .iter()
+                .map(|k| serde_json::to_vec(k).unwrap())
+                .collect(),
+            values: values
+                .iter()
+                .map(|v| serde_json::to_vec(v).unwrap())
+                .collect(),
+            encode_types: vec![EncodingType::Arrow],
+            key_schema: Some(schema_for!(ComplexKey)),
+            value_schema: Some(schema_for!(ComplexValue)),
+            ..Default::default()
+        };
 
         node.encode_all_pairs();
 
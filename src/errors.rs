@@ -22,6 +22,12 @@ pub enum Error {
     #[error("Unsupported Key Type")]
     UnsupportedKeyType,
 
+    #[error("Invalid Key: {0}")]
+    InvalidKey(String),
+
+    #[error("Invalid Value: {0}")]
+    InvalidValue(String),
+
     #[error("Unsupported Chunking Strategy")]
     UnsupportedChunkingStrategy,
 
@@ -30,4 +36,37 @@ pub enum Error {
 
     #[error("Serde Error")]
     Serde,
+
+    #[error("Keys Are Not Sorted")]
+    UnsortedKeys,
+
+    #[error("Node Not Found: {0}")]
+    MissingNode(String),
+
+    #[error("Invalid Export: {0}")]
+    InvalidExport(String),
+
+    #[error("Expiring Keys Are Not Enabled")]
+    ExpiryDisabled,
+
+    #[error("Invalid Page Token")]
+    InvalidPageToken,
+
+    #[error("Tree Changed Since The Page Token Was Issued")]
+    RootChanged,
+
+    #[error("Invalid Proof: {0}")]
+    InvalidProof(String),
+
+    #[error("Patch Does Not Apply To Root: {0}")]
+    PatchMismatch(String),
+
+    #[error("Corrupt Node {hash} At {location}")]
+    CorruptNode { hash: String, location: String },
+
+    #[error("Storage Write Failed: {0}")]
+    WriteFailed(String),
+
+    #[error("IO Error: {0}")]
+    Io(#[from] std::io::Error),
 }
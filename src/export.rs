@@ -0,0 +1,255 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Streaming serialization format for whole trees.
+//!
+//! An export starts with a fixed header followed by a sequence of frames:
+//!
+//! ```text
+//! header: "PRLY" | version: u8 | hash size: u8
+//! frame:  tag: u8 | payload length: u32 (big endian) | payload
+//! ```
+//!
+//! The first frame holds the tree configuration as JSON. It is followed by one frame per
+//! distinct node, each carrying the node hash and the bincode encoded node. Nodes are
+//! written children first, so every node a frame refers to has already been written.
+//! The chunks of large values stored outside the leaves are written before their leaf.
+//! The stream ends with a frame holding the root hash.
+//!
+//! Importing verifies every node against its hash and refuses nodes that reference
+//! children not seen earlier in the stream.
+
+use crate::blob::{blobs_enabled, chunk_hashes};
+use crate::config::TreeConfig;
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::NodeStorage;
+use crate::ttl;
+use std::collections::HashSet;
+use std::io::{Read, Write};
+
+pub(crate) const MAGIC: &[u8; 4] = b"PRLY";
+pub(crate) const FORMAT_VERSION: u8 = 1;
+
+pub(crate) const TAG_END: u8 = 0;
+pub(crate) const TAG_CONFIG: u8 = 1;
+pub(crate) const TAG_NODE: u8 = 2;
+
+/// Writes the tree rooted at `root` to `writer`, one node at a time.
+pub(crate) fn export_tree<const N: usize, S: NodeStorage<N>, W: Write>(
+    root: &ProllyNode<N>,
+    config: &TreeConfig<N>,
+    storage: &S,
+    writer: &mut W,
+) -> Result<(), Error> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[FORMAT_VERSION, N as u8])?;
+
+    let config_data = serde_json::to_vec(config).map_err(|_| Error::Serde)?;
+    write_frame(writer, TAG_CONFIG, &config_data)?;
+
+    let mut written = HashSet::new();
+    let blobs = blobs_enabled(config).then_some(config.expiring_keys);
+    export_node(root, storage, writer, blobs, &mut written)?;
+
+    write_frame(writer, TAG_END, root.get_hash().as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn export_node<const N: usize, S: NodeStorage<N>, W: Write>(
+    node: &ProllyNode<N>,
+    storage: &S,
+    writer: &mut W,
+    blobs: Option<bool>,
+    written: &mut HashSet<ValueDigest<N>>,
+) -> Result<(), Error> {
+    let hash = node.get_hash();
+    if written.contains(&hash) {
+        return Ok(());
+    }
+
+    if !node.is_leaf {
+        for child_hash in &node.values {
+            let child_hash = ValueDigest::raw_hash(child_hash);
+            let child = storage
+                .get_node_by_hash(&child_hash)
+                .ok_or(Error::MissingNode(hex::encode(child_hash.as_bytes())))?;
+            export_node(&child, storage, writer, blobs, written)?;
+        }
+    } else if let Some(expiring) = blobs {
+        let values = node
+            .values
+            .iter()
+            .map(|value| stored_value(value, expiring));
+        for chunk_hash in values.flat_map(chunk_hashes) {
+            let chunk = storage
+                .get_node_by_hash(&chunk_hash)
+                .ok_or(Error::MissingNode(hex::encode(chunk_hash.as_bytes())))?;
+            export_node(&chunk, storage, writer, None, written)?;
+        }
+    }
+
+    let node_data = bincode::serialize(node).map_err(|_| Error::Serde)?;
+    let mut payload = Vec::with_capacity(N + node_data.len());
+    payload.extend_from_slice(hash.as_bytes());
+    payload.extend_from_slice(&node_data);
+    write_frame(writer, TAG_NODE, &payload)?;
+
+    written.insert(hash);
+    Ok(())
+}
+
+/// Strips the expiry timestamp from a value stored in a leaf.
+fn stored_value(value: &[u8], expiring: bool) -> &[u8] {
+    if expiring {
+        ttl::unwrap(value).1
+    } else {
+        value
+    }
+}
+
+fn write_frame<W: Write>(writer: &mut W, tag: u8, payload: &[u8]) -> Result<(), Error> {
+    let len = u32::try_from(payload.len()).map_err(|_| Error::Serde)?;
+    writer.write_all(&[tag])?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Reads a tree written by `export_tree` into `storage`, verifying every node on the way.
+///
+/// Returns the configuration stored in the export, with its root hash, and the root node.
+pub(crate) fn import_tree<const N: usize, S: NodeStorage<N>, R: Read>(
+    reader: &mut R,
+    storage: &mut S,
+) -> Result<(TreeConfig<N>, ProllyNode<N>), Error> {
+    let mut header = [0u8; 6];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(invalid("not a prolly tree export"));
+    }
+    if header[4] != FORMAT_VERSION {
+        return Err(invalid(format!("unsupported format version {}", header[4])));
+    }
+    if header[5] as usize != N {
+        return Err(invalid(format!(
+            "hash size {} does not match {}",
+            header[5], N
+        )));
+    }
+
+    let (tag, config_data) = read_frame(reader)?;
+    if tag != TAG_CONFIG {
+        return Err(invalid("missing tree configuration"));
+    }
+    let mut config: TreeConfig<N> =
+        serde_json::from_slice(&config_data).map_err(|_| Error::Serde)?;
+
+    let blobs = blobs_enabled(&config);
+    let mut imported = HashSet::new();
+    loop {
+        let (tag, payload) = read_frame(reader)?;
+        match tag {
+            TAG_NODE => {
+                if payload.len() < N {
+                    return Err(invalid("truncated node frame"));
+                }
+                let (hash, node_data) = payload.split_at(N);
+                let hash = ValueDigest::raw_hash(hash);
+                let node: ProllyNode<N> =
+                    bincode::deserialize(node_data).map_err(|_| Error::Serde)?;
+                if node.get_hash() != hash {
+                    return Err(invalid(format!(
+                        "hash mismatch for node {}",
+                        hex::encode(hash.as_bytes())
+                    )));
+                }
+                if !node.is_leaf {
+                    for child_hash in &node.values {
+                        if child_hash.len() != N
+                            || !imported.contains(&ValueDigest::raw_hash(child_hash))
+                        {
+                            return Err(invalid(format!(
+                                "node {} references a child that was not exported",
+                                hex::encode(hash.as_bytes())
+                            )));
+                        }
+                    }
+                } else if blobs && !node.keys.is_empty() {
+                    // value chunks are stored as leaves without keys and reference nothing
+                    let chunks = node
+                        .values
+                        .iter()
+                        .map(|value| stored_value(value, config.expiring_keys))
+                        .flat_map(chunk_hashes::<N>);
+                    for chunk_hash in chunks {
+                        if !imported.contains(&chunk_hash) {
+                            return Err(invalid(format!(
+                                "node {} references a value chunk that was not exported",
+                                hex::encode(hash.as_bytes())
+                            )));
+                        }
+                    }
+                }
+                storage.insert_node(hash.clone(), node);
+                imported.insert(hash);
+            }
+            TAG_END => {
+                if payload.len() != N {
+                    return Err(invalid("malformed root hash"));
+                }
+                let root_hash = ValueDigest::raw_hash(&payload);
+                if !imported.contains(&root_hash) {
+                    return Err(invalid("root node was not exported"));
+                }
+                if config
+                    .root_hash
+                    .as_ref()
+                    .is_some_and(|hash| hash != &root_hash)
+                {
+                    return Err(invalid("root hash does not match the configuration"));
+                }
+                let root = storage
+                    .get_node_by_hash(&root_hash)
+                    .ok_or(Error::MissingNode(hex::encode(root_hash.as_bytes())))?;
+                config.root_hash = Some(root_hash);
+                return Ok((config, root));
+            }
+            tag => return Err(invalid(format!("unknown frame tag {}", tag))),
+        }
+    }
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> Result<(u8, Vec<u8>), Error> {
+    let mut frame_header = [0u8; 5];
+    reader.read_exact(&mut frame_header)?;
+    let len = u32::from_be_bytes([
+        frame_header[1],
+        frame_header[2],
+        frame_header[3],
+        frame_header[4],
+    ]);
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Err(invalid("truncated frame"));
+    }
+    Ok((frame_header[0], payload))
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidExport(reason.into())
+}
@@ -0,0 +1,424 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Integrity checks of the nodes reachable from a root, and salvaging of the leaves that
+//! are still intact.
+
+use crate::blob::chunk_hashes;
+use crate::digest::ValueDigest;
+use crate::node::ProllyNode;
+use crate::storage::NodeStorage;
+use crate::ttl;
+
+/// A broken invariant found while checking a tree.
+///
+/// Nodes are identified by the hash their parent references them by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation<const N: usize> {
+    /// A node referenced by its parent is not in storage.
+    MissingNode { hash: ValueDigest<N> },
+    /// A node referenced by its parent is in storage but cannot be read, e.g. because its
+    /// bytes fail their checksum.
+    CorruptNode {
+        hash: ValueDigest<N>,
+        reason: String,
+    },
+    /// The content of a node does not match the hash it is stored under.
+    HashMismatch {
+        hash: ValueDigest<N>,
+        actual: ValueDigest<N>,
+    },
+    /// A node does not hold one value (and for internal nodes one count) per key.
+    MalformedNode {
+        hash: ValueDigest<N>,
+        reason: String,
+    },
+    /// The keys of a node are not in strictly ascending order.
+    UnsortedKeys { hash: ValueDigest<N>, index: usize },
+    /// A key lies outside of the key range the parent assigns to its node.
+    KeyOutOfRange { hash: ValueDigest<N>, key: Vec<u8> },
+    /// A node is not one level below its parent, or a leaf is not at level zero.
+    LevelMismatch {
+        hash: ValueDigest<N>,
+        expected: u8,
+        actual: u8,
+    },
+    /// The subtree count cached for a child does not match the number of keys below it.
+    CountMismatch {
+        hash: ValueDigest<N>,
+        index: usize,
+        cached: u64,
+        actual: u64,
+    },
+    /// A value of a leaf references a chunk that is missing or corrupt.
+    MissingChunk {
+        hash: ValueDigest<N>,
+        chunk: ValueDigest<N>,
+    },
+}
+
+/// The result of `ProllyTree::verify_integrity`.
+#[derive(Debug, Clone, Default)]
+pub struct IntegrityReport<const N: usize> {
+    /// Number of tree nodes that were loaded and checked, not counting value chunks.
+    pub nodes_checked: usize,
+    /// Number of key-value pairs found in the leaves.
+    pub keys_checked: u64,
+    /// Every violation found, in the order the nodes were visited.
+    pub violations: Vec<Violation<N>>,
+    /// Nodes containing a chunk boundary, which a freshly built tree would have split
+    /// there. Incremental updates can leave such nodes behind; they do not affect reads,
+    /// but the root hash then depends on the order of past updates.
+    pub non_canonical: Vec<ValueDigest<N>>,
+}
+
+impl<const N: usize> IntegrityReport<N> {
+    /// Returns `true` if no violations were found.
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// A key range whose entries could not be recovered by `ProllyTree::repair`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LostRange<const N: usize> {
+    /// Hash of the missing or corrupt node that covered the range.
+    pub hash: ValueDigest<N>,
+    /// Inclusive start of the range, or `None` if it is unbounded.
+    pub start: Option<Vec<u8>>,
+    /// Exclusive end of the range, or `None` if it is unbounded.
+    pub end: Option<Vec<u8>>,
+}
+
+/// The result of `ProllyTree::repair`.
+#[derive(Debug, Clone, Default)]
+pub struct RepairReport<const N: usize> {
+    /// Number of key-value pairs copied into the repaired tree.
+    pub keys_recovered: u64,
+    /// Key ranges below nodes that are missing or corrupt, in key order.
+    pub lost_ranges: Vec<LostRange<N>>,
+    /// Keys found in intact leaves whose values could not be read, because a chunk of
+    /// the value is missing or corrupt.
+    pub lost_values: Vec<Vec<u8>>,
+}
+
+impl<const N: usize> RepairReport<N> {
+    /// Returns `true` if every key of the damaged tree was recovered.
+    pub fn is_complete(&self) -> bool {
+        self.lost_ranges.is_empty() && self.lost_values.is_empty()
+    }
+}
+
+/// Options of a check that depend on the tree configuration.
+pub(crate) struct CheckOptions {
+    /// Whether leaf values may reference chunks stored outside of the leaf.
+    pub blobs: bool,
+    /// Whether leaf values carry an expiry timestamp.
+    pub expiring: bool,
+}
+
+/// Checks the tree rooted at `root` and every node reachable from it.
+pub(crate) fn check_tree<const N: usize, S: NodeStorage<N>>(
+    root: &ProllyNode<N>,
+    storage: &S,
+    options: &CheckOptions,
+) -> IntegrityReport<N> {
+    let mut checker = Checker {
+        storage,
+        options,
+        report: IntegrityReport::default(),
+    };
+    checker.check_node(root, &root.get_hash(), None, None, None);
+    checker.report
+}
+
+/// Visits, in key order, every leaf reachable from `root` through intact nodes.
+///
+/// A node is only trusted if it matches its hash, holds one value per key, and its keys are
+/// sorted and within the range assigned by its parent. The key ranges below nodes that are
+/// missing or not trusted are returned instead of being visited.
+pub(crate) fn salvage_leaves<const N: usize, S, F>(
+    root: &ProllyNode<N>,
+    storage: &S,
+    mut visit: F,
+) -> Vec<LostRange<N>>
+where
+    S: NodeStorage<N>,
+    F: FnMut(&ProllyNode<N>),
+{
+    let mut lost = Vec::new();
+    salvage_node(
+        root,
+        &root.get_hash(),
+        None,
+        None,
+        storage,
+        &mut visit,
+        &mut lost,
+    );
+    lost
+}
+
+fn salvage_node<const N: usize, S, F>(
+    node: &ProllyNode<N>,
+    hash: &ValueDigest<N>,
+    lower: Option<&[u8]>,
+    upper: Option<&[u8]>,
+    storage: &S,
+    visit: &mut F,
+    lost: &mut Vec<LostRange<N>>,
+) where
+    S: NodeStorage<N>,
+    F: FnMut(&ProllyNode<N>),
+{
+    let trusted = &node.get_hash() == hash
+        && node.keys.len() == node.values.len()
+        && (node.is_leaf || node.values.iter().all(|child| child.len() == N))
+        && node.keys.windows(2).all(|pair| pair[0] < pair[1])
+        && node.keys.iter().all(|key| {
+            lower.is_none_or(|lower| key.as_slice() >= lower)
+                && upper.is_none_or(|upper| key.as_slice() < upper)
+        });
+    if !trusted {
+        lost.push(LostRange {
+            hash: hash.clone(),
+            start: lower.map(<[u8]>::to_vec),
+            end: upper.map(<[u8]>::to_vec),
+        });
+        return;
+    }
+
+    if node.is_leaf {
+        visit(node);
+        return;
+    }
+
+    for (i, child_hash) in node.values.iter().enumerate() {
+        let child_lower = if i == 0 {
+            lower
+        } else {
+            Some(node.keys[i].as_slice())
+        };
+        let child_upper = node.keys.get(i + 1).map(Vec::as_slice).or(upper);
+        let child_hash = ValueDigest::raw_hash(child_hash);
+        match storage.try_get_node(&child_hash).ok().flatten() {
+            Some(child) => salvage_node(
+                &child,
+                &child_hash,
+                child_lower,
+                child_upper,
+                storage,
+                visit,
+                lost,
+            ),
+            None => lost.push(LostRange {
+                hash: child_hash,
+                start: child_lower.map(<[u8]>::to_vec),
+                end: child_upper.map(<[u8]>::to_vec),
+            }),
+        }
+    }
+}
+
+/// Returns `true` if a value chunk is in storage and matches its hash.
+pub(crate) fn chunk_intact<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    chunk: &ValueDigest<N>,
+) -> bool {
+    storage
+        .try_get_node(chunk)
+        .is_ok_and(|node| node.is_some_and(|node| &node.get_hash() == chunk))
+}
+
+struct Checker<'a, const N: usize, S: NodeStorage<N>> {
+    storage: &'a S,
+    options: &'a CheckOptions,
+    report: IntegrityReport<N>,
+}
+
+impl<const N: usize, S: NodeStorage<N>> Checker<'_, N, S> {
+    /// Checks a node and its subtree, returning the number of keys found below it, or
+    /// `None` if part of the subtree could not be read.
+    ///
+    /// `lower` and `upper` bound the keys of the node as assigned by its parent, and
+    /// `level` is the level the parent expects it at.
+    fn check_node(
+        &mut self,
+        node: &ProllyNode<N>,
+        hash: &ValueDigest<N>,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+        level: Option<u8>,
+    ) -> Option<u64> {
+        self.report.nodes_checked += 1;
+        let violations = &mut self.report.violations;
+
+        let actual = node.get_hash();
+        if &actual != hash {
+            violations.push(Violation::HashMismatch {
+                hash: hash.clone(),
+                actual,
+            });
+        }
+
+        // every empty node has the same hash, so the one in storage may be from any level
+        let expected_level = level.unwrap_or(node.level);
+        let misplaced = node.level != expected_level || (node.is_leaf && node.level != 0);
+        if misplaced && !node.keys.is_empty() {
+            violations.push(Violation::LevelMismatch {
+                hash: hash.clone(),
+                expected: if node.is_leaf { 0 } else { expected_level },
+                actual: node.level,
+            });
+        }
+
+        if node.keys.len() != node.values.len() {
+            violations.push(Violation::MalformedNode {
+                hash: hash.clone(),
+                reason: format!("{} keys but {} values", node.keys.len(), node.values.len()),
+            });
+            return None;
+        }
+
+        if let Some(index) = (1..node.keys.len()).find(|&i| node.keys[i - 1] >= node.keys[i]) {
+            violations.push(Violation::UnsortedKeys {
+                hash: hash.clone(),
+                index,
+            });
+        }
+
+        let out_of_range = node.keys.iter().find(|key| {
+            lower.is_some_and(|lower| key.as_slice() < lower)
+                || upper.is_some_and(|upper| key.as_slice() >= upper)
+        });
+        if let Some(key) = out_of_range {
+            violations.push(Violation::KeyOutOfRange {
+                hash: hash.clone(),
+                key: key.clone(),
+            });
+        }
+
+        if node.chunk_content().len() > 1 {
+            self.report.non_canonical.push(hash.clone());
+        }
+
+        if node.is_leaf {
+            if self.options.blobs {
+                self.check_chunks(node, hash);
+            }
+            self.report.keys_checked += node.keys.len() as u64;
+            return Some(node.keys.len() as u64);
+        }
+
+        if !node.counts.is_empty() && node.counts.len() != node.values.len() {
+            self.report.violations.push(Violation::MalformedNode {
+                hash: hash.clone(),
+                reason: format!(
+                    "{} children but {} counts",
+                    node.values.len(),
+                    node.counts.len()
+                ),
+            });
+        }
+
+        let mut total = 0;
+        let mut complete = true;
+        for (i, child_hash) in node.values.iter().enumerate() {
+            if child_hash.len() != N {
+                self.report.violations.push(Violation::MalformedNode {
+                    hash: hash.clone(),
+                    reason: format!("child {} is not a hash", i),
+                });
+                complete = false;
+                continue;
+            }
+            let child_hash = ValueDigest::raw_hash(child_hash);
+            let child = match self.storage.try_get_node(&child_hash) {
+                Ok(Some(child)) => child,
+                Ok(None) => {
+                    self.report
+                        .violations
+                        .push(Violation::MissingNode { hash: child_hash });
+                    complete = false;
+                    continue;
+                }
+                Err(err) => {
+                    self.report.violations.push(Violation::CorruptNode {
+                        hash: child_hash,
+                        reason: err.to_string(),
+                    });
+                    complete = false;
+                    continue;
+                }
+            };
+
+            // the first child also holds the keys smaller than the first separator
+            let child_lower = if i == 0 {
+                lower
+            } else {
+                Some(node.keys[i].as_slice())
+            };
+            let child_upper = node.keys.get(i + 1).map(Vec::as_slice).or(upper);
+            let Some(count) = self.check_node(
+                &child,
+                &child_hash,
+                child_lower,
+                child_upper,
+                Some(node.level.saturating_sub(1)),
+            ) else {
+                // counts cannot be compared against a subtree that is only partly readable
+                complete = false;
+                continue;
+            };
+
+            if let Some(&cached) = node.counts.get(i) {
+                if cached != count {
+                    self.report.violations.push(Violation::CountMismatch {
+                        hash: hash.clone(),
+                        index: i,
+                        cached,
+                        actual: count,
+                    });
+                }
+            }
+            total += count;
+        }
+        complete.then_some(total)
+    }
+
+    /// Checks that the chunks referenced by the values of a leaf are intact, reporting
+    /// each broken chunk once per leaf.
+    fn check_chunks(&mut self, node: &ProllyNode<N>, hash: &ValueDigest<N>) {
+        let mut broken = Vec::new();
+        for value in &node.values {
+            let stored = if self.options.expiring {
+                ttl::unwrap(value).1
+            } else {
+                value
+            };
+            for chunk in chunk_hashes::<N>(stored) {
+                if broken.contains(&chunk) {
+                    continue;
+                }
+                if !chunk_intact(self.storage, &chunk) {
+                    self.report.violations.push(Violation::MissingChunk {
+                        hash: hash.clone(),
+                        chunk: chunk.clone(),
+                    });
+                    broken.push(chunk);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,872 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use crate::blob::decode_value;
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::proof::verifier::{child_overlaps, ProofNode};
+use crate::proof::{PathSegment, Proof};
+use crate::storage::{NodeReader, NodeStorage};
+use crate::ttl;
+use std::ops::{Bound, RangeBounds};
+
+/// The number of upcoming children of a node a scanning cursor hints the storage at.
+const PREFETCH_WINDOW: usize = 16;
+
+/// A cursor that can be positioned at an arbitrary key of a prolly tree and moved
+/// forward or backward from there.
+///
+/// The cursor keeps the path from the root to the current leaf on a stack and only loads
+/// the nodes it visits from storage. Moving to a neighbouring entry only touches the nodes
+/// between the current leaf and the nearest common ancestor of the next leaf, so resuming
+/// iteration from a known key does not require rescanning from the start.
+///
+/// A fresh cursor is positioned before the first entry: calling `next()` moves it to the
+/// first entry, while `prev()` returns `None`. Once the cursor moves past either end it
+/// stays there until it is repositioned with one of the `seek` methods.
+pub struct Cursor<'a, const N: usize, S: NodeReader<N>> {
+    root: ProllyNode<N>,
+    storage: &'a S,
+    /// Path from the root to the current leaf with the current position in each node.
+    stack: Vec<(ProllyNode<N>, usize)>,
+    /// Whether an unpositioned cursor ran off the end (rather than the start) of the tree.
+    past_end: bool,
+    /// Whether leaf values are tagged and may reference chunks stored outside the leaf.
+    blobs: bool,
+    /// The time entries are checked against, if leaf values carry an expiry timestamp.
+    now: Option<u64>,
+    /// Whether the cursor hints the storage at the children it is going to visit next.
+    prefetch: bool,
+}
+
+impl<'a, const N: usize, S: NodeReader<N>> Cursor<'a, N, S> {
+    /// Creates a cursor over the tree rooted at `root`.
+    pub(crate) fn new(root: &ProllyNode<N>, storage: &'a S) -> Self {
+        Cursor {
+            root: root.clone(),
+            storage,
+            stack: Vec::new(),
+            past_end: false,
+            blobs: false,
+            now: None,
+            prefetch: false,
+        }
+    }
+
+    /// Makes the cursor return values stored out of line in their original form.
+    pub(crate) fn with_blobs(mut self, blobs: bool) -> Self {
+        self.blobs = blobs;
+        self
+    }
+
+    /// Makes the cursor skip entries that expired at `now` and strip the expiry timestamp
+    /// from the values it returns. `None` is used for trees without expiring keys.
+    pub(crate) fn with_expiry(mut self, now: Option<u64>) -> Self {
+        self.now = now;
+        self
+    }
+
+    /// Makes the cursor call `NodeReader::prefetch` with the children of each internal
+    /// node it is going to visit next, a window at a time, for scans over slow storages.
+    pub(crate) fn with_prefetch(mut self, prefetch: bool) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Positions the cursor at the first entry whose key is greater than or equal to `key`.
+    ///
+    /// # Returns
+    /// - The entry the cursor is positioned at, or `None` if every key is smaller than `key`.
+    pub fn seek(&mut self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.stack.clear();
+        let mut node = self.root.clone();
+        loop {
+            if node.is_leaf {
+                let pos = node.keys.partition_point(|k| k.as_slice() < key);
+                self.stack.push((node, pos));
+                break;
+            }
+
+            let i = node.keys.iter().rposition(|k| key >= &k[..]).unwrap_or(0);
+            if self.prefetch {
+                prefetch_children(self.storage, &node, i, true);
+            }
+            let child = node.values.get(i).and_then(|hash| self.load(hash));
+            self.stack.push((node, i));
+            match child {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+
+        if !self.is_positioned() {
+            self.step(true);
+        }
+        self.skip_expired(true);
+        self.entry()
+    }
+
+    /// Positions the cursor at the first entry of the tree.
+    ///
+    /// # Returns
+    /// - The first entry, or `None` if the tree is empty.
+    pub fn seek_first(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.stack.clear();
+        self.descend_edge(self.root.clone(), true);
+        if !self.is_positioned() {
+            self.step(true);
+        }
+        self.skip_expired(true);
+        self.entry()
+    }
+
+    /// Positions the cursor at the last entry of the tree.
+    ///
+    /// # Returns
+    /// - The last entry, or `None` if the tree is empty.
+    pub fn seek_last(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.stack.clear();
+        self.descend_edge(self.root.clone(), false);
+        if !self.is_positioned() {
+            self.step(false);
+        }
+        self.skip_expired(false);
+        self.entry()
+    }
+
+    /// Returns the entry the cursor is currently positioned at.
+    ///
+    /// The value is returned as stored in the leaf, which for values stored outside of the
+    /// leaves is a reference to their blobs rather than the value itself, and includes the
+    /// expiry timestamp of trees with expiring keys.
+    ///
+    /// # Returns
+    /// - `Some((key, value))` if the cursor is positioned at an entry, `None` otherwise.
+    pub fn current(&self) -> Option<(&[u8], &[u8])> {
+        match self.stack.last() {
+            Some((node, pos)) if node.is_leaf && *pos < node.keys.len() => {
+                Some((node.keys[*pos].as_slice(), node.values[*pos].as_slice()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Moves the cursor to the previous entry.
+    ///
+    /// # Returns
+    /// - The previous entry, or `None` if the cursor moved before the first entry.
+    pub fn prev(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        if self.stack.is_empty() {
+            return if self.past_end {
+                self.seek_last()
+            } else {
+                None
+            };
+        }
+        self.step(false);
+        self.skip_expired(false);
+        self.entry()
+    }
+
+    /// Returns the proof of the entry the cursor is positioned at: the hashes of the nodes
+    /// from the root, whose hash is passed in, to the current leaf.
+    ///
+    /// The hashes below the root are taken from the parents, so no node is hashed again.
+    pub(crate) fn proof(&self, root_hash: &ValueDigest<N>) -> Option<Proof<N>> {
+        self.current()?;
+        let mut path = Vec::with_capacity(self.stack.len());
+        path.push(PathSegment::root(root_hash.clone()));
+        for (node, pos) in &self.stack[..self.stack.len() - 1] {
+            path.push(PathSegment::child_of(node, *pos));
+        }
+        let target_hash = path.last().map(|segment| segment.node_hash.clone());
+        Some(Proof {
+            path,
+            target_hash,
+            lower: None,
+            upper: None,
+        })
+    }
+
+    fn entry(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.current().map(|(key, value)| {
+            let value = match self.now {
+                Some(_) => ttl::unwrap(value).1,
+                None => value,
+            };
+            let value = if self.blobs {
+                decode_value(value, self.storage).unwrap_or_else(|| value.to_vec())
+            } else {
+                value.to_vec()
+            };
+            (key.to_vec(), value)
+        })
+    }
+
+    fn is_positioned(&self) -> bool {
+        self.current().is_some()
+    }
+
+    /// Moves past expired entries in the given direction.
+    fn skip_expired(&mut self, forward: bool) {
+        let Some(now) = self.now else {
+            return;
+        };
+        while let Some((_, value)) = self.current() {
+            if !ttl::is_expired(value, now) {
+                return;
+            }
+            self.step(forward);
+        }
+    }
+
+    /// Moves off the current position to the next (or previous) entry, skipping empty leaves
+    /// and children that cannot be loaded. Leaves the stack empty if there is no such entry.
+    fn step(&mut self, forward: bool) {
+        loop {
+            let Some((node, pos)) = self.stack.last_mut() else {
+                self.past_end = forward;
+                return;
+            };
+
+            let len = if node.is_leaf {
+                node.keys.len()
+            } else {
+                node.values.len()
+            };
+            let next = if forward {
+                pos.checked_add(1)
+            } else {
+                pos.checked_sub(1)
+            }
+            .filter(|p| *p < len);
+
+            match next {
+                None => {
+                    self.stack.pop();
+                }
+                Some(p) => {
+                    *pos = p;
+                    if node.is_leaf {
+                        return;
+                    }
+                    let hash = node.values[p].clone();
+                    // hint at the next window of children once the scan reaches its first one
+                    let boundary = if forward { p } else { p + 1 };
+                    if self.prefetch && boundary % PREFETCH_WINDOW == 0 {
+                        prefetch_children(self.storage, node, p, forward);
+                    }
+                    if let Some(child) = self.load(&hash) {
+                        self.descend_edge(child, forward);
+                        if self.is_positioned() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Descends from `node` to its first (or last) leaf, pushing the path onto the stack.
+    fn descend_edge(&mut self, mut node: ProllyNode<N>, first: bool) {
+        loop {
+            let len = if node.is_leaf {
+                node.keys.len()
+            } else {
+                node.values.len()
+            };
+            let pos = if first { 0 } else { len.saturating_sub(1) };
+
+            if node.is_leaf {
+                self.stack.push((node, pos));
+                return;
+            }
+
+            if self.prefetch {
+                prefetch_children(self.storage, &node, pos, first);
+            }
+            let child = node.values.get(pos).and_then(|hash| self.load(hash));
+            self.stack.push((node, pos));
+            match child {
+                Some(child) => node = child,
+                None => return,
+            }
+        }
+    }
+
+    fn load(&self, hash: &[u8]) -> Option<ProllyNode<N>> {
+        self.storage.get_node_by_hash(&ValueDigest::raw_hash(hash))
+    }
+}
+
+/// Hints the storage at the window of children of an internal node a scan visits from the
+/// one at `pos` on.
+fn prefetch_children<const N: usize, S: NodeReader<N>>(
+    storage: &S,
+    node: &ProllyNode<N>,
+    pos: usize,
+    forward: bool,
+) {
+    let hashes: Vec<ValueDigest<N>> = (0..PREFETCH_WINDOW)
+        .map_while(|offset| {
+            if forward {
+                pos.checked_add(offset)
+            } else {
+                pos.checked_sub(offset)
+            }
+        })
+        .map_while(|p| node.values.get(p))
+        .map(|hash| ValueDigest::raw_hash(hash))
+        .collect();
+    if !hashes.is_empty() {
+        storage.prefetch(&hashes);
+    }
+}
+
+impl<const N: usize, S: NodeReader<N>> Iterator for Cursor<'_, N, S> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    /// Moves the cursor to the next entry and returns it.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stack.is_empty() {
+            return if self.past_end {
+                None
+            } else {
+                self.seek_first()
+            };
+        }
+        self.step(true);
+        self.skip_expired(true);
+        self.entry()
+    }
+}
+
+/// A lazy iterator over the key-value pairs of a prolly tree within a key range.
+///
+/// The iterator drives a [`Cursor`], so walking a range never materializes the whole key
+/// set in memory. It can walk the keys in ascending or descending order.
+pub struct TreeIter<'a, const N: usize, S: NodeReader<N>> {
+    cursor: Cursor<'a, N, S>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    reverse: bool,
+    started: bool,
+    done: bool,
+}
+
+impl<'a, const N: usize, S: NodeReader<N>> TreeIter<'a, N, S> {
+    /// Creates an iterator over the entries of the tree rooted at `root` that fall into `range`.
+    pub(crate) fn new<R: RangeBounds<Vec<u8>>>(
+        root: &ProllyNode<N>,
+        storage: &'a S,
+        range: R,
+        reverse: bool,
+    ) -> Self {
+        TreeIter {
+            cursor: Cursor::new(root, storage).with_prefetch(true),
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            reverse,
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Makes the iterator return values stored out of line in their original form.
+    pub(crate) fn with_blobs(mut self, blobs: bool) -> Self {
+        self.cursor = self.cursor.with_blobs(blobs);
+        self
+    }
+
+    /// Makes the iterator skip entries that expired at `now`.
+    pub(crate) fn with_expiry(mut self, now: Option<u64>) -> Self {
+        self.cursor = self.cursor.with_expiry(now);
+        self
+    }
+
+    /// Positions the cursor at the first entry of the range in iteration order.
+    fn first_entry(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        if self.reverse {
+            match self.end.clone() {
+                Bound::Unbounded => self.cursor.seek_last(),
+                Bound::Included(end) => match self.cursor.seek(&end) {
+                    None => self.cursor.seek_last(),
+                    Some((key, _)) if key > end => self.cursor.prev(),
+                    entry => entry,
+                },
+                Bound::Excluded(end) => match self.cursor.seek(&end) {
+                    None => self.cursor.seek_last(),
+                    Some(_) => self.cursor.prev(),
+                },
+            }
+        } else {
+            match self.start.clone() {
+                Bound::Unbounded => self.cursor.seek_first(),
+                Bound::Included(start) => self.cursor.seek(&start),
+                Bound::Excluded(start) => match self.cursor.seek(&start) {
+                    Some((key, _)) if key == start => self.cursor.next(),
+                    entry => entry,
+                },
+            }
+        }
+    }
+
+    fn in_range(&self, key: &[u8]) -> bool {
+        let after_start = match &self.start {
+            Bound::Included(start) => key >= start.as_slice(),
+            Bound::Excluded(start) => key > start.as_slice(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match &self.end {
+            Bound::Included(end) => key <= end.as_slice(),
+            Bound::Excluded(end) => key < end.as_slice(),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+}
+
+/// An iterator over the entries of a key range that yields the Merkle proof of every entry
+/// along with it.
+///
+/// The proofs are the same as those returned by `Tree::generate_proof`, but are taken from
+/// the path the iterator walks anyway, so proving a whole range costs a single traversal.
+pub struct ProofIter<'a, const N: usize, S: NodeStorage<N>> {
+    iter: TreeIter<'a, N, S>,
+    root_hash: ValueDigest<N>,
+}
+
+impl<'a, const N: usize, S: NodeStorage<N>> ProofIter<'a, N, S> {
+    /// Wraps an iterator over the tree rooted at the node with hash `root_hash`.
+    pub(crate) fn new(iter: TreeIter<'a, N, S>, root_hash: ValueDigest<N>) -> Self {
+        ProofIter { iter, root_hash }
+    }
+}
+
+impl<const N: usize, S: NodeStorage<N>> Iterator for ProofIter<'_, N, S> {
+    type Item = (Vec<u8>, Vec<u8>, Proof<N>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.iter.next()?;
+        let proof = self.iter.cursor.proof(&self.root_hash)?;
+        Some((key, value, proof))
+    }
+}
+
+/// An iterator over the nodes of the proof of a key range, generated as it is consumed.
+///
+/// Nodes are visited depth-first, parents before their children, and only the hashes of
+/// the nodes still to visit are kept.
+pub struct RangeProofStream<'a, const N: usize, S: NodeStorage<N>> {
+    storage: &'a S,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    root: Option<ProllyNode<N>>,
+    /// Hashes of the nodes still to visit; the next one is last.
+    pending: Vec<Vec<u8>>,
+}
+
+impl<'a, const N: usize, S: NodeStorage<N>> RangeProofStream<'a, N, S> {
+    pub(crate) fn new<R: RangeBounds<Vec<u8>>>(
+        root: &ProllyNode<N>,
+        storage: &'a S,
+        range: R,
+    ) -> Self {
+        RangeProofStream {
+            storage,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            root: Some(root.clone()),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<const N: usize, S: NodeStorage<N>> Iterator for RangeProofStream<'_, N, S> {
+    type Item = ProofNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = match self.root.take() {
+            Some(root) => root,
+            None => {
+                let hash = self.pending.pop()?;
+                self.storage
+                    .get_node_by_hash(&ValueDigest::raw_hash(&hash))?
+            }
+        };
+        if !node.is_leaf {
+            // pushed in reverse so that children are visited in key order
+            for (i, child) in node.values.iter().enumerate().rev() {
+                if child_overlaps(&node.keys, i, &self.start, &self.end) {
+                    self.pending.push(child.clone());
+                }
+            }
+        }
+        Some(ProofNode::from(&node))
+    }
+}
+
+/// Opaque token to resume a paginated scan where the previous page ended.
+///
+/// The token records the last key of the page and the root hash of the tree it was read
+/// from, so that resuming on a tree that has changed in between can be detected. It can be
+/// passed around as bytes, e.g. to the clients of a REST or gRPC front-end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageToken<const N: usize> {
+    root_hash: ValueDigest<N>,
+    last_key: Vec<u8>,
+}
+
+impl<const N: usize> PageToken<N> {
+    pub(crate) fn new(root_hash: ValueDigest<N>, last_key: Vec<u8>) -> Self {
+        PageToken {
+            root_hash,
+            last_key,
+        }
+    }
+
+    /// Returns the root hash of the tree the page was read from.
+    pub fn root_hash(&self) -> &ValueDigest<N> {
+        &self.root_hash
+    }
+
+    /// Returns the last key of the page; the next page starts after it.
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    /// Encodes the token as bytes: the root hash followed by the last key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.root_hash.as_bytes(), self.last_key.as_slice()].concat()
+    }
+
+    /// Decodes a token written by `to_bytes`.
+    ///
+    /// # Returns
+    /// - The token, or `Error::InvalidPageToken` if the bytes are too short to hold one.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < N {
+            return Err(Error::InvalidPageToken);
+        }
+        let (root_hash, last_key) = bytes.split_at(N);
+        Ok(PageToken::new(
+            ValueDigest::raw_hash(root_hash),
+            last_key.to_vec(),
+        ))
+    }
+}
+
+/// A page of entries returned by `ProllyTree::scan_page`.
+#[derive(Debug, Clone)]
+pub struct Page<const N: usize> {
+    /// The entries of the page, in ascending key order.
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The token to fetch the next page with, or `None` if this is the last page.
+    pub next: Option<PageToken<N>>,
+}
+
+/// Returns the smallest key that is larger than every key starting with `prefix`,
+/// or `None` if no such key exists (the prefix is empty or made of `0xff` bytes only).
+pub(crate) fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// Converts a key prefix into the range of keys that start with it.
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let end = match prefix_end(prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), end)
+}
+
+impl<const N: usize, S: NodeReader<N>> Iterator for TreeIter<'_, N, S> {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let entry = if !self.started {
+            self.started = true;
+            self.first_entry()
+        } else if self.reverse {
+            self.cursor.prev()
+        } else {
+            self.cursor.next()
+        };
+
+        match entry {
+            Some((key, value)) if self.in_range(&key) => Some((key, value)),
+            _ => {
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::storage::InMemoryNodeStorage;
+    use crate::tree::{ProllyTree, Tree};
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    fn build_tree(count: u32) -> ProllyTree<32, InMemoryNodeStorage<32>> {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            pattern: 0b111,
+            ..TreeConfig::default()
+        };
+        let mut tree = ProllyTree::new(storage, config);
+        for i in 0..count {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        tree
+    }
+
+    fn key(i: u32) -> Vec<u8> {
+        i.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_iter_forward_and_reverse() {
+        let tree = build_tree(500);
+        assert!(tree.depth() > 1);
+
+        let forward: Vec<Vec<u8>> = tree.iter().map(|(k, _)| k).collect();
+        let expected: Vec<Vec<u8>> = (0..500).map(key).collect();
+        assert_eq!(forward, expected);
+
+        let reverse: Vec<Vec<u8>> = tree.iter_rev().map(|(k, _)| k).collect();
+        let expected_rev: Vec<Vec<u8>> = (0..500).rev().map(key).collect();
+        assert_eq!(reverse, expected_rev);
+
+        // values are returned alongside keys
+        let (k, v) = tree.iter_rev().next().unwrap();
+        assert_eq!(k, key(499));
+        assert_eq!(v, 499u32.to_le_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_scan_ranges() {
+        let tree = build_tree(300);
+
+        let keys: Vec<Vec<u8>> = tree.scan(key(100)..key(110)).map(|(k, _)| k).collect();
+        assert_eq!(keys, (100..110).map(key).collect::<Vec<_>>());
+
+        let keys: Vec<Vec<u8>> = tree.scan_rev(key(100)..=key(110)).map(|(k, _)| k).collect();
+        assert_eq!(keys, (100..=110).rev().map(key).collect::<Vec<_>>());
+
+        // latest N entries
+        let latest: Vec<Vec<u8>> = tree.scan_rev(..key(250)).take(3).map(|(k, _)| k).collect();
+        assert_eq!(latest, vec![key(249), key(248), key(247)]);
+
+        let keys: Vec<Vec<u8>> = tree.scan_rev(key(295)..).map(|(k, _)| k).collect();
+        assert_eq!(keys, (295..300).rev().map(key).collect::<Vec<_>>());
+
+        // empty and out-of-range scans
+        assert_eq!(tree.scan(key(400)..).count(), 0);
+        assert_eq!(tree.scan_rev(key(50)..key(50)).count(), 0);
+    }
+
+    #[test]
+    fn test_iter_after_deletes() {
+        let mut tree = build_tree(200);
+        for i in (0..200).step_by(3) {
+            tree.delete(&key(i));
+        }
+
+        let expected: Vec<Vec<u8>> = (0..200).filter(|i| i % 3 != 0).map(key).collect();
+        let forward: Vec<Vec<u8>> = tree.iter().map(|(k, _)| k).collect();
+        assert_eq!(forward, expected);
+
+        let mut reverse: Vec<Vec<u8>> = tree.iter_rev().map(|(k, _)| k).collect();
+        reverse.reverse();
+        assert_eq!(reverse, expected);
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"abc"), Some(b"abd".to_vec()));
+        assert_eq!(prefix_end(&[1, 0xff]), Some(vec![2]));
+        assert_eq!(prefix_end(&[0xff, 0xff]), None);
+        assert_eq!(prefix_end(b""), None);
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            pattern: 0b111,
+            ..TreeConfig::default()
+        };
+        let mut tree = ProllyTree::new(storage, config);
+        for agent in ["alice", "bob", "carol"] {
+            for i in 0..50u8 {
+                let key = format!("agent/{}/{:03}", agent, i).into_bytes();
+                tree.insert(key, vec![i]);
+            }
+        }
+        tree.insert(vec![0xff, 0xff, 1], vec![1]);
+
+        let bob: Vec<Vec<u8>> = tree.scan_prefix(b"agent/bob/").map(|(k, _)| k).collect();
+        assert_eq!(bob.len(), 50);
+        assert!(bob.iter().all(|k| k.starts_with(b"agent/bob/")));
+        assert_eq!(bob[0], b"agent/bob/000".to_vec());
+
+        assert_eq!(tree.scan_prefix(b"agent/").count(), 150);
+        assert_eq!(tree.scan_prefix(b"agent/dave/").count(), 0);
+        assert_eq!(tree.scan_prefix(&[0xff]).count(), 1);
+        assert_eq!(tree.scan_prefix(b"").count(), 151);
+    }
+
+    #[test]
+    fn test_cursor_seek_next_prev() {
+        let tree = build_tree(300);
+        let mut cursor = tree.cursor();
+
+        // a fresh cursor sits before the first entry
+        assert!(cursor.current().is_none());
+        assert!(cursor.prev().is_none());
+        assert_eq!(cursor.next().unwrap().0, key(0));
+
+        assert_eq!(cursor.seek(&key(150)).unwrap().0, key(150));
+        assert_eq!(cursor.current().unwrap().0, key(150).as_slice());
+        assert_eq!(cursor.next().unwrap().0, key(151));
+        assert_eq!(cursor.prev().unwrap().0, key(150));
+        assert_eq!(cursor.prev().unwrap().0, key(149));
+
+        // walk across many leaf boundaries in both directions
+        for i in (100..149).rev() {
+            assert_eq!(cursor.prev().unwrap().0, key(i));
+        }
+        for i in 101..250 {
+            assert_eq!(cursor.next().unwrap().0, key(i));
+        }
+
+        // resume iteration (e.g. the next page) from an arbitrary key
+        cursor.seek(&key(290));
+        let page: Vec<Vec<u8>> = cursor.by_ref().take(5).map(|(k, _)| k).collect();
+        assert_eq!(page, (291..296).map(key).collect::<Vec<_>>());
+
+        // running off the end, then stepping back
+        assert_eq!(cursor.seek_last().unwrap().0, key(299));
+        assert!(cursor.next().is_none());
+        assert!(cursor.next().is_none());
+        assert_eq!(cursor.prev().unwrap().0, key(299));
+
+        // seeking past the largest key leaves the cursor after the end
+        assert!(cursor.seek(&key(1000)).is_none());
+        assert_eq!(cursor.prev().unwrap().0, key(299));
+
+        // seeking a missing key lands on its successor
+        let mut tree = build_tree(20);
+        tree.delete(&key(10));
+        let mut cursor = tree.cursor();
+        assert_eq!(cursor.seek(&key(10)).unwrap().0, key(11));
+        assert_eq!(cursor.prev().unwrap().0, key(9));
+    }
+
+    /// A storage recording the nodes it was hinted at and the reads of other nodes.
+    #[derive(Default)]
+    struct PrefetchLog {
+        inner: InMemoryNodeStorage<32>,
+        hinted: Mutex<HashSet<ValueDigest<32>>>,
+        hints: AtomicUsize,
+        unhinted_reads: AtomicUsize,
+    }
+
+    impl NodeReader<32> for PrefetchLog {
+        fn get_node_by_hash(&self, hash: &ValueDigest<32>) -> Option<ProllyNode<32>> {
+            if !self.hinted.lock().unwrap().contains(hash) {
+                self.unhinted_reads.fetch_add(1, Ordering::Relaxed);
+            }
+            self.inner.get_node_by_hash(hash)
+        }
+
+        fn prefetch(&self, hashes: &[ValueDigest<32>]) {
+            self.hints.fetch_add(1, Ordering::Relaxed);
+            self.hinted.lock().unwrap().extend(hashes.iter().cloned());
+        }
+
+        fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+            self.inner.get_config(key)
+        }
+    }
+
+    impl NodeStorage<32> for PrefetchLog {
+        fn insert_node(&mut self, hash: ValueDigest<32>, node: ProllyNode<32>) -> Option<()> {
+            self.inner.insert_node(hash, node)
+        }
+
+        fn delete_node(&mut self, hash: &ValueDigest<32>) -> Option<()> {
+            self.inner.delete_node(hash)
+        }
+
+        fn save_config(&self, key: &str, config: &[u8]) {
+            self.inner.save_config(key, config)
+        }
+    }
+
+    #[test]
+    fn test_scan_prefetch() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            pattern: 0b111,
+            ..TreeConfig::default()
+        };
+        let mut tree = ProllyTree::new(PrefetchLog::default(), config);
+        for i in 0..2000u32 {
+            tree.insert(key(i), i.to_le_bytes().to_vec());
+        }
+
+        for reverse in [false, true] {
+            let storage = tree.storage();
+            storage.hinted.lock().unwrap().clear();
+            storage.hints.store(0, Ordering::Relaxed);
+            storage.unhinted_reads.store(0, Ordering::Relaxed);
+            let count = if reverse {
+                tree.iter_rev().count()
+            } else {
+                tree.iter().count()
+            };
+            assert_eq!(count, 2000);
+            // every node is hinted at before it is read, a window of children at a time
+            assert_eq!(storage.unhinted_reads.load(Ordering::Relaxed), 0);
+            let leaves = storage.hinted.lock().unwrap().len();
+            assert!(storage.hints.load(Ordering::Relaxed) * 4 < leaves);
+        }
+
+        // point lookups do not prefetch
+        tree.storage().hints.store(0, Ordering::Relaxed);
+        assert!(tree.find(&key(7)).is_some());
+        assert_eq!(tree.storage().hints.load(Ordering::Relaxed), 0);
+    }
+}
@@ -22,13 +22,13 @@ limitations under the License.
 //! ## Features
 //!
 //! - **Verifiability**: The cryptographic hashing in Prolly Trees ensures data integrity and allows for
-//! verifiable proofs of inclusion/exclusion.
+//!   verifiable proofs of inclusion/exclusion.
 //! - **Performance**: The balanced tree structure provides efficient data access patterns similar to
-//! B-trees, ensuring high performance for both random and sequential access.
+//!   B-trees, ensuring high performance for both random and sequential access.
 //! - **Scalability**: Prolly Trees are suitable for large-scale applications, providing efficient index maintenance
-//! and data distribution capabilities.
+//!   and data distribution capabilities.
 //! - **Flexibility**: The probabilistic balancing allows for handling various mutation patterns without degrading
-//! performance or structure.
+//!   performance or structure.
 //!
 //! ## Usage
 //!
@@ -42,14 +42,29 @@ limitations under the License.
 //! Follow examples in the github repository to get started.
 //!
 
+pub mod access;
+pub mod batch;
+mod blob;
+mod bulk;
 #[macro_use]
 pub mod digest;
+pub mod chunker;
+pub mod compression;
 pub mod config;
-mod diff;
+pub mod diff;
 mod encoding;
 pub mod errors;
+mod export;
+pub mod integrity;
+pub mod iter;
 pub mod node;
+pub mod observer;
+pub mod pin;
 pub mod proof;
+pub mod schema;
+pub mod snapshot;
 pub mod storage;
+pub mod sync;
 mod tracing;
 pub mod tree;
+mod ttl;
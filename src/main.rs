@@ -14,7 +14,11 @@ limitations under the License.
 
 use prollytree::storage::InMemoryNodeStorage;
 
-use prollytree::config::TreeConfig;
+use prollytree::chunker::ChunkingStrategy;
+use prollytree::compression::Compression;
+use prollytree::config::{TreeConfig, ValuePolicy};
+use prollytree::digest::HashAlgorithm;
+use prollytree::schema::KeyFormat;
 use prollytree::tree::{ProllyTree, Tree};
 use std::io::{self, Write};
 use std::thread::sleep;
@@ -39,6 +43,14 @@ fn main() {
         key_schema: None,
         value_schema: None,
         encode_types: vec![],
+        value_chunk_threshold: None,
+        value_policy: ValuePolicy::Inline,
+        compression: Compression::None,
+        hash_algorithm: HashAlgorithm::Sha256,
+        chunking: ChunkingStrategy::RollingHash,
+        expiring_keys: false,
+        key_format: Some(KeyFormat::UInt(1)),
+        value_format: None,
     };
     // Create the trees
     let mut tree_increasing = ProllyTree::new(storage_increasing, config.clone());
@@ -114,7 +126,7 @@ fn main() {
 
         // Find value and generate proof for reverse order
         let proof = tree_reverse.generate_proof(&keys[i]);
-        let is_valid = tree_reverse.verify(proof.clone(), &keys[i], None);
+        let is_valid = tree_reverse.verify(proof.clone(), &keys[i], Some(&value_for_all));
         println!(
             "Proof for key \x1b[32m{:?}\x1b[0m in reverse order is valid: {}",
             keys[i], is_valid
@@ -13,19 +13,19 @@ limitations under the License.
 */
 #![allow(clippy::too_many_arguments)]
 
-use crate::digest::ValueDigest;
+use crate::chunker::{ChunkingStrategy, RollingHashChunker};
+use crate::compression::Compression;
+use crate::digest::{HashAlgorithm, ValueDigest};
 use crate::encoding::EncodingType;
-use crate::storage::NodeStorage;
+use crate::errors::Error;
+use crate::proof::verifier::node_hash;
+use crate::storage::{NodeReader, NodeStorage};
 use schemars::schema::RootSchema;
 use serde::{Deserialize, Serialize};
-use std::hash::Hash;
-use std::hash::Hasher;
-use twox_hash::XxHash64;
+use std::ops::{Bound, RangeBounds};
 
 /// initial (leaf) level from which the prolly tree is built
 const INIT_LEVEL: u8 = 0;
-/// seed for the hash function
-const HASH_SEED: u64 = 0;
 /// default base for the rolling hash
 const DEFAULT_BASE: u64 = 257;
 /// default modulus for the rolling hash
@@ -130,6 +130,11 @@ pub trait Node<const N: usize> {
     fn print_tree<S: NodeStorage<N>>(&self, storage: &S);
 }
 
+/// A node of the tree.
+///
+/// Nodes are stored with bincode, which is not self-describing: a field cannot be made
+/// optional with `#[serde(default)]`, and adding one changes the layout version written by
+/// [`ProllyNode::encode`].
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ProllyNode<const N: usize> {
     pub keys: Vec<Vec<u8>>,
@@ -147,6 +152,15 @@ pub struct ProllyNode<const N: usize> {
     pub merged: bool,
     pub encode_types: Vec<EncodingType>,
     pub encode_values: Vec<Vec<u8>>,
+    /// Number of key-value pairs stored under each child of an internal node,
+    /// kept in the same order as `values`. Empty for leaf nodes.
+    pub counts: Vec<u64>,
+    /// Codec used to compress the node when it is encoded for storage.
+    pub compression: Compression,
+    /// Hash function used to compute the hash of the node.
+    pub hash_algorithm: HashAlgorithm,
+    /// Strategy used to split the node into chunks when it is balanced.
+    pub chunking: ChunkingStrategy,
 }
 
 impl<const N: usize> Default for ProllyNode<N> {
@@ -167,6 +181,10 @@ impl<const N: usize> Default for ProllyNode<N> {
             merged: false,
             encode_types: Vec::new(),
             encode_values: Vec::new(),
+            counts: Vec::new(),
+            compression: Compression::None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            chunking: ChunkingStrategy::RollingHash,
         }
     }
 }
@@ -319,12 +337,18 @@ impl<const N: usize> ProllyNode<N> {
         is_root_node: bool,
         path_hashes: &[ValueDigest<N>],
     ) {
+        self.ensure_counts(storage);
+
         // Sort the keys and values in the node before splitting
         // Only sort the last key-value pair because the rest are already sorted
         if let (Some(last_key), Some(last_value)) = (self.keys.pop(), self.values.pop()) {
+            let last_count = self.counts.pop();
             let pos = self.keys.binary_search(&last_key).unwrap_or_else(|e| e);
             self.keys.insert(pos, last_key);
             self.values.insert(pos, last_value);
+            if let Some(last_count) = last_count {
+                self.counts.insert(pos, last_count);
+            }
         }
 
         // If the node is a leaf, check if it can be merged with its next sibling
@@ -333,6 +357,7 @@ impl<const N: usize> ProllyNode<N> {
                 storage.get_node_by_hash(&ValueDigest::raw_hash(&next_sibling_hash))
             {
                 // Try to merge the current node with the next sibling
+                next_sibling.ensure_counts(storage);
                 self.merge_with_next_sibling(&mut next_sibling);
             }
         }
@@ -347,6 +372,7 @@ impl<const N: usize> ProllyNode<N> {
         let mut siblings = Vec::new();
         let original_keys = std::mem::take(&mut self.keys);
         let original_values = std::mem::take(&mut self.values);
+        let original_counts = std::mem::take(&mut self.counts);
 
         for (start, end) in chunks {
             let sibling = ProllyNode {
@@ -365,6 +391,14 @@ impl<const N: usize> ProllyNode<N> {
                 merged: self.merged,
                 encode_types: self.encode_types.clone(),
                 encode_values: self.encode_values.clone(),
+                compression: self.compression,
+                hash_algorithm: self.hash_algorithm,
+                chunking: self.chunking,
+                counts: if self.is_leaf {
+                    Vec::new()
+                } else {
+                    original_counts[start..end].to_vec()
+                },
             };
             let sibling_hash = sibling.get_hash();
             storage.insert_node(sibling_hash.clone(), sibling.clone());
@@ -401,6 +435,13 @@ impl<const N: usize> ProllyNode<N> {
                 merged: self.merged,
                 encode_types: self.encode_types.clone(),
                 encode_values: self.encode_values.clone(),
+                compression: self.compression,
+                hash_algorithm: self.hash_algorithm,
+                chunking: self.chunking,
+                counts: siblings
+                    .iter()
+                    .map(|(sibling, _)| sibling.subtree_count(storage))
+                    .collect(),
             };
             *self = new_root;
         } else {
@@ -409,6 +450,7 @@ impl<const N: usize> ProllyNode<N> {
             for (sibling, sibling_hash) in siblings {
                 self.keys.push(sibling.keys[0].clone());
                 self.values.push(sibling_hash.as_bytes().to_vec());
+                self.counts.push(sibling.subtree_count(storage));
             }
             self.is_leaf = false;
             self.split = true;
@@ -451,157 +493,356 @@ impl<const N: usize> ProllyNode<N> {
         None
     }
 
-    fn merge_with_next_sibling(&mut self, next_sibling: &mut ProllyNode<N>) {
-        // Combine the keys and values of the current node and the next sibling
-        let mut combined_keys = self.keys.clone();
-        let mut combined_values = self.values.clone();
-        combined_keys.append(&mut next_sibling.keys.clone());
-        combined_values.append(&mut next_sibling.values.clone());
+    /// Inserts a batch of sorted key-value pairs with unique keys in a single traversal.
+    ///
+    /// The pairs are partitioned by the child covering them, so every affected node is
+    /// visited and rebalanced once per batch rather than once per key.
+    ///
+    /// # Returns
+    /// - `Error::MissingNode` if a child on the way is not in `storage`. The batch is then
+    ///   only partially applied to this node.
+    pub(crate) fn insert_sorted_batch<S: NodeStorage<N>>(
+        &mut self,
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        storage: &mut S,
+        mut path_hashes: Vec<ValueDigest<N>>,
+    ) -> Result<(), Error> {
+        let is_root_node = path_hashes.is_empty();
+        self.clear_balance_flags();
 
-        // Merge the current node with the next sibling
-        self.keys.append(&mut next_sibling.keys);
-        self.values.append(&mut next_sibling.values);
-        self.merged = true;
-    }
-}
+        if self.is_leaf {
+            for (key, value) in pairs {
+                match self.keys.binary_search(key) {
+                    Ok(pos) => self.values[pos] = value.clone(),
+                    Err(pos) => {
+                        self.keys.insert(pos, key.clone());
+                        self.values.insert(pos, value.clone());
+                    }
+                }
+            }
+        } else {
+            let mut remaining = pairs;
+            while let Some((first_key, _)) = remaining.first() {
+                // All pairs below the next separator key belong to the same child
+                let i = self.keys.iter().rposition(|k| first_key >= k).unwrap_or(0);
+                let count = match self.keys.get(i + 1) {
+                    Some(next_key) => remaining.partition_point(|(k, _)| k < next_key),
+                    None => remaining.len(),
+                };
+                let (batch, rest) = remaining.split_at(count);
+                remaining = rest;
+
+                let mut child_node = load_child(storage, &self.values[i])?;
+                // Persist the current state so the child finds its up-to-date siblings
+                storage.insert_node(self.get_hash(), self.clone());
+                path_hashes.push(self.get_hash());
+                child_node.insert_sorted_batch(batch, storage, path_hashes.clone())?;
+                path_hashes.pop();
 
-impl<const N: usize> NodeChunk for ProllyNode<N> {
-    fn chunk_content(&self) -> Vec<(usize, usize)> {
-        let mut chunks = Vec::new();
-        let mut start = 0;
-        let mut last_start = 0;
+                self.update_child(i, child_node, storage);
+            }
+        }
 
-        while start < self.keys.len() {
-            let mut end = start + self.min_chunk_size;
+        self.balance(storage, is_root_node, &path_hashes);
 
-            // Ensure that 'end' does not exceed the length of the keys vector
-            if end > self.keys.len() {
-                end = self.keys.len();
+        if is_root_node {
+            self.collapse_root(storage);
+        }
+        Ok(())
+    }
+
+    /// Deletes every key within `range` from the subtree in a single traversal.
+    ///
+    /// Only the children overlapping the range are visited, and each visited node is
+    /// rebalanced once after all of its deletions. Children left empty are unlinked.
+    ///
+    /// # Returns
+    /// - The number of key-value pairs removed, or `Error::MissingNode` if a child overlapping
+    ///   the range is not in `storage`. The range is then only partially removed.
+    pub fn delete_range<R: RangeBounds<Vec<u8>>, S: NodeStorage<N>>(
+        &mut self,
+        range: &R,
+        storage: &mut S,
+        mut path_hashes: Vec<ValueDigest<N>>,
+    ) -> Result<usize, Error> {
+        let is_root_node = path_hashes.is_empty();
+        self.clear_balance_flags();
+
+        let mut deleted = 0;
+        if self.is_leaf {
+            let start = self
+                .keys
+                .partition_point(|k| !range_starts_before(range, k));
+            let end = self.keys.partition_point(|k| range_ends_after(range, k));
+            if start < end {
+                self.keys.drain(start..end);
+                self.values.drain(start..end);
+                deleted = end - start;
             }
+        } else {
+            let mut i = match range.start_bound() {
+                Bound::Included(key) | Bound::Excluded(key) => {
+                    self.keys.iter().rposition(|k| key >= k).unwrap_or(0)
+                }
+                Bound::Unbounded => 0,
+            };
+            loop {
+                // The separator of the following child, read before this child is rebalanced
+                let next_key = self.keys.get(i + 1).cloned();
 
-            // Initialize the rolling hash for the first window
-            let mut hash = Self::initialize_rolling_hash(
-                &self.keys[start..end],
-                &self.values[start..end],
-                self.base,
-                self.modulus,
-            );
+                let mut child_node = load_child(storage, &self.values[i])?;
+                // Persist the current state so the child finds its up-to-date siblings
+                storage.insert_node(self.get_hash(), self.clone());
+                path_hashes.push(self.get_hash());
+                let child_deleted = child_node.delete_range(range, storage, path_hashes.clone())?;
+                path_hashes.pop();
 
-            while end < self.keys.len() && end - start < self.max_chunk_size {
-                // Check if the current hash matches the pattern
-                if hash & self.pattern == self.pattern {
-                    break;
+                if child_deleted > 0 {
+                    deleted += child_deleted;
+                    self.update_child(i, child_node, storage);
+                    if self.counts[i] == 0 && self.keys.len() > 1 {
+                        self.keys.remove(i);
+                        self.values.remove(i);
+                        self.counts.remove(i);
+                    }
                 }
 
-                // Slide the window by one element to the right
-                if end < self.keys.len() {
-                    hash = Self::update_rolling_hash(
-                        hash,
-                        &self.keys[start],
-                        &self.values[start],
-                        &self.keys[end],
-                        &self.values[end],
-                        self.base,
-                        self.modulus,
-                        (end - start) as u64,
-                    );
-                    start += 1;
-                    end += 1;
-                } else {
-                    break;
+                match next_key {
+                    Some(next_key) if range_ends_after(range, &next_key) => {
+                        i = self.keys.iter().rposition(|k| &next_key >= k).unwrap_or(0);
+                    }
+                    _ => break,
                 }
             }
+        }
+
+        if deleted > 0 {
+            self.balance(storage, is_root_node, &path_hashes);
 
-            chunks.push((last_start, end));
-            last_start = end;
-            start = end;
+            if is_root_node {
+                self.collapse_root(storage);
+            }
         }
+        Ok(deleted)
+    }
 
-        chunks
+    /// Finds the leaf holding a key, telling an absent key apart from a node on the way that
+    /// cannot be read.
+    ///
+    /// # Returns
+    /// - The leaf, `None` if the key is absent, `Error::MissingNode` if a node on the way
+    ///   is not in `storage`, or the error of `NodeReader::try_get_node` for a node that
+    ///   cannot be read.
+    pub fn try_find<S: NodeStorage<N>>(
+        &self,
+        key: &[u8],
+        storage: &S,
+    ) -> Result<Option<ProllyNode<N>>, Error> {
+        if self.is_leaf {
+            return Ok(self.keys.iter().any(|k| k == key).then(|| self.clone()));
+        }
+        let i = self.keys.iter().rposition(|k| key >= &k[..]).unwrap_or(0);
+        let child_hash = &self.values[i];
+        storage
+            .try_get_node(&ValueDigest::raw_hash(child_hash))?
+            .ok_or_else(|| Error::MissingNode(hex::encode(child_hash)))?
+            .try_find(key, storage)
     }
 
-    fn initialize_rolling_hash(
-        keys: &[Vec<u8>],
-        values: &[Vec<u8>],
-        base: u64,
-        modulus: u64,
-    ) -> u64 {
-        let mut hash = 0;
-        for (key, value) in keys.iter().zip(values) {
-            hash = (hash * base
-                + Self::hash_item(key, base, modulus)
-                + Self::hash_item(value, base, modulus))
-                % modulus;
+    /// Saves an updated child node and reflects the outcome of its rebalancing in this node.
+    ///
+    /// If the child was merged with its next sibling, the sibling is removed from this node.
+    /// If the child was split, its promoted keys replace the child at position `i`;
+    /// otherwise the child's new hash replaces the old one.
+    fn update_child<S: NodeStorage<N>>(
+        &mut self,
+        i: usize,
+        child_node: ProllyNode<N>,
+        storage: &mut S,
+    ) {
+        self.ensure_counts(storage);
+
+        // Save the updated child node back to the storage
+        let new_node_hash = child_node.get_hash().as_bytes().to_vec();
+        storage.insert_node(child_node.get_hash(), child_node.clone());
+
+        // Check if the child node has been merged into its parent's next sibling
+        if child_node.merged {
+            // remove the next sibling from the parent node
+            if i + 1 < self.keys.len() {
+                self.keys.remove(i + 1);
+                self.values.remove(i + 1);
+                self.counts.remove(i + 1);
+            }
+        }
+
+        // Check if the child node has been split and needs to be updated in the current node
+        if child_node.split {
+            // Move the key-value pairs from the child node to the current node at position `i`
+            self.keys.remove(i);
+            self.values.remove(i);
+            self.counts.remove(i);
+
+            // A split child holds one entry per sibling, along with the sibling's key count
+            for (j, ((key, value), count)) in child_node
+                .keys
+                .into_iter()
+                .zip(child_node.values)
+                .zip(child_node.counts)
+                .enumerate()
+            {
+                self.keys.insert(i + j, key);
+                self.values.insert(i + j, value);
+                self.counts.insert(i + j, count);
+            }
+        } else {
+            // Update this node's value with the new hash
+            self.counts[i] = child_node.subtree_count(storage);
+            self.values[i] = new_node_hash;
+        }
+    }
+
+    /// Clears the split and merged flags before the node is modified.
+    ///
+    /// The flags tell the parent how the last balance changed this node, but they are also
+    /// persisted with the node. A node loaded from storage must not report them again.
+    fn clear_balance_flags(&mut self) {
+        self.split = false;
+        self.merged = false;
+    }
+
+    /// Returns the number of key-value pairs stored in the subtree rooted at this node.
+    ///
+    /// Internal nodes answer from their cached child counts; nodes without them, e.g. nodes
+    /// assembled with the builder, fall back to loading their children from storage.
+    pub fn subtree_count<S: NodeReader<N>>(&self, storage: &S) -> u64 {
+        if self.is_leaf {
+            self.keys.len() as u64
+        } else {
+            self.child_counts(storage).iter().sum()
+        }
+    }
+
+    /// Returns the number of key-value pairs stored under each child of this node.
+    fn child_counts<S: NodeReader<N>>(&self, storage: &S) -> Vec<u64> {
+        if self.is_leaf {
+            return Vec::new();
+        }
+        if self.counts.len() == self.values.len() {
+            return self.counts.clone();
+        }
+        self.values
+            .iter()
+            .map(|child_hash| {
+                storage
+                    .get_node_by_hash(&ValueDigest::raw_hash(child_hash))
+                    .map_or(0, |child| child.subtree_count(storage))
+            })
+            .collect()
+    }
+
+    /// Makes sure the child counts of an internal node are populated.
+    fn ensure_counts<S: NodeStorage<N>>(&mut self, storage: &S) {
+        if !self.is_leaf && self.counts.len() != self.values.len() {
+            self.counts = self.child_counts(storage);
         }
-        hash
     }
 
-    fn update_rolling_hash(
-        old_hash: u64,
-        old_key: &[u8],
-        old_value: &[u8],
-        new_key: &[u8],
-        new_value: &[u8],
-        base: u64,
-        modulus: u64,
-        window_size: u64,
-    ) -> u64 {
-        let old_key_hash = Self::hash_item(old_key, base, modulus);
-        let old_value_hash = Self::hash_item(old_value, base, modulus);
-        let new_key_hash = Self::hash_item(new_key, base, modulus);
-        let new_value_hash = Self::hash_item(new_value, base, modulus);
-
-        let base_exp_window_size = Self::mod_exp(base, window_size, modulus);
-
-        let hash = (old_hash * base + new_key_hash + new_value_hash) % modulus;
-        let hash = (hash + modulus - (old_key_hash * base_exp_window_size) % modulus) % modulus;
-
-        (hash + modulus - (old_value_hash * base_exp_window_size) % modulus) % modulus
+    /// Returns the number of keys in the subtree that are strictly smaller than `key`.
+    pub fn rank<S: NodeStorage<N>>(&self, key: &[u8], storage: &S) -> u64 {
+        if self.is_leaf {
+            return self.keys.partition_point(|k| k.as_slice() < key) as u64;
+        }
+
+        let i = self.keys.iter().rposition(|k| key >= &k[..]).unwrap_or(0);
+        let counts = self.child_counts(storage);
+        let before: u64 = counts[..i].iter().sum();
+        let within = storage
+            .get_node_by_hash(&ValueDigest::raw_hash(&self.values[i]))
+            .map_or(0, |child| child.rank(key, storage));
+        before + within
     }
 
-    fn mod_exp(base: u64, exp: u64, modulus: u64) -> u64 {
-        let mut result = 1;
-        let mut base = base % modulus;
-        let mut exp = exp;
+    /// Returns the key-value pair at position `n` (zero-based) in key order.
+    pub fn select<S: NodeStorage<N>>(&self, n: u64, storage: &S) -> Option<(Vec<u8>, Vec<u8>)> {
+        if self.is_leaf {
+            let n = n as usize;
+            return (n < self.keys.len()).then(|| (self.keys[n].clone(), self.values[n].clone()));
+        }
 
-        while exp > 0 {
-            if exp % 2 == 1 {
-                result = (result * base) % modulus;
+        let mut n = n;
+        for (child_hash, count) in self.values.iter().zip(self.child_counts(storage)) {
+            if n < count {
+                return storage
+                    .get_node_by_hash(&ValueDigest::raw_hash(child_hash))
+                    .and_then(|child| child.select(n, storage));
             }
-            exp >>= 1;
-            base = (base * base) % modulus;
+            n -= count;
         }
+        None
+    }
 
-        result
+    /// Checks if the node is a non-leaf root node, and it has only one child.
+    /// If so, merges the child node with the current node.
+    fn collapse_root<S: NodeStorage<N>>(&mut self, storage: &S) {
+        if !self.is_leaf && self.keys.len() == 1 && self.level > INIT_LEVEL + 1 {
+            let child_hash = self.values[0].clone();
+            if let Some(child_node) = storage.get_node_by_hash(&ValueDigest::raw_hash(&child_hash))
+            {
+                // Merge the child node with the current node
+                self.keys.clone_from(&child_node.keys);
+                self.values.clone_from(&child_node.values);
+                self.counts = child_node.child_counts(storage);
+                self.is_leaf = child_node.is_leaf;
+                self.level = child_node.level;
+            }
+        }
     }
 
-    fn hash_item(item: &[u8], _base: u64, modulus: u64) -> u64 {
-        let mut hasher = XxHash64::with_seed(HASH_SEED);
-        item.hash(&mut hasher);
-        hasher.finish() % modulus
+    fn merge_with_next_sibling(&mut self, next_sibling: &mut ProllyNode<N>) {
+        // Combine the keys and values of the current node and the next sibling
+        let mut combined_keys = self.keys.clone();
+        let mut combined_values = self.values.clone();
+        combined_keys.append(&mut next_sibling.keys.clone());
+        combined_values.append(&mut next_sibling.values.clone());
+
+        // Merge the current node with the next sibling
+        self.keys.append(&mut next_sibling.keys);
+        self.values.append(&mut next_sibling.values);
+        self.counts.append(&mut next_sibling.counts);
+        self.merged = true;
     }
 }
 
-trait NodeChunk {
-    fn chunk_content(&self) -> Vec<(usize, usize)>;
-    fn initialize_rolling_hash(
-        keys: &[Vec<u8>],
-        values: &[Vec<u8>],
-        base: u64,
-        modulus: u64,
-    ) -> u64;
-    fn update_rolling_hash(
-        old_hash: u64,
-        old_key: &[u8],
-        old_value: &[u8],
-        new_key: &[u8],
-        new_value: &[u8],
-        base: u64,
-        modulus: u64,
-        window_size: u64,
-    ) -> u64;
-    fn mod_exp(base: u64, exp: u64, modulus: u64) -> u64;
-    fn hash_item(item: &[u8], base: u64, modulus: u64) -> u64;
+/// Loads a child node by the hash stored in its parent.
+///
+/// # Returns
+/// - The child, or `Error::MissingNode` if it is not in `storage`.
+fn load_child<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    child_hash: &[u8],
+) -> Result<ProllyNode<N>, Error> {
+    storage
+        .get_node_by_hash(&ValueDigest::raw_hash(child_hash))
+        .ok_or_else(|| Error::MissingNode(hex::encode(child_hash)))
+}
+
+/// Returns `true` if `key` is at or after the start of `range`.
+fn range_starts_before<R: RangeBounds<Vec<u8>>>(range: &R, key: &Vec<u8>) -> bool {
+    match range.start_bound() {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+/// Returns `true` if `key` is at or before the end of `range`.
+fn range_ends_after<R: RangeBounds<Vec<u8>>>(range: &R, key: &Vec<u8>) -> bool {
+    match range.end_bound() {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
 }
 
 // implement the Node trait for ProllyNode
@@ -615,6 +856,7 @@ impl<const N: usize> Node<N> for ProllyNode<N> {
     ) {
         // set is root node based on parent hash
         let is_root_node = path_hashes.is_empty();
+        self.clear_balance_flags();
 
         if self.is_leaf {
             // Check if the key already exists in the node
@@ -653,39 +895,8 @@ impl<const N: usize> Node<N> for ProllyNode<N> {
                 // Remove the current node's hash from the path
                 path_hashes.pop();
 
-                // Save the updated child node back to the storage
-                let new_node_hash = child_node.get_hash().as_bytes().to_vec();
-                storage.insert_node(child_node.get_hash(), child_node.clone());
-
-                // Check if the child node has been merged into its parent's next sibling
-                if child_node.merged {
-                    // remove the next sibling from the parent node
-                    if i + 1 < self.keys.len() {
-                        self.keys.remove(i + 1);
-                        self.values.remove(i + 1);
-                    }
-                }
-
-                // Check if the child node has been split and needs to be updated in the current node
-                //if !child_node.is_leaf && child_node.keys.len() > 1 {
-                if child_node.split {
-                    // Move the key-value pairs from the child node to the current node at position `i`
-                    self.keys.remove(i);
-                    self.values.remove(i);
-
-                    for (j, (key, value)) in child_node
-                        .keys
-                        .into_iter()
-                        .zip(child_node.values)
-                        .enumerate()
-                    {
-                        self.keys.insert(i + j, key);
-                        self.values.insert(i + j, value);
-                    }
-                } else {
-                    // Update this node's value with the new hash
-                    self.values[i] = new_node_hash;
-                }
+                // Save the updated child node and reflect its rebalancing in the current node
+                self.update_child(i, child_node, storage);
             } else {
                 // Handle the case when the child node is not found
                 println!("Child node not found: {:?}", child_hash);
@@ -696,18 +907,8 @@ impl<const N: usize> Node<N> for ProllyNode<N> {
         }
 
         // Extra check / logic before returning
-        // Check if the node is a non-leaf root node, and it has only one child
-        // If so, merge the child node with the current node
-        if !self.is_leaf && is_root_node && self.keys.len() == 1 && self.level > INIT_LEVEL + 1 {
-            let child_hash = self.values[0].clone();
-            if let Some(child_node) = storage.get_node_by_hash(&ValueDigest::raw_hash(&child_hash))
-            {
-                // Merge the child node with the current node
-                self.keys.clone_from(&child_node.keys);
-                self.values.clone_from(&child_node.values);
-                self.is_leaf = child_node.is_leaf;
-                self.level = child_node.level;
-            }
+        if is_root_node {
+            self.collapse_root(storage);
         }
     }
 
@@ -718,13 +919,15 @@ impl<const N: usize> Node<N> for ProllyNode<N> {
         storage: &mut S,
         path_hashes: Vec<ValueDigest<N>>,
     ) {
-        // Sort the keys and corresponding values
+        // Sort the keys and corresponding values, keeping the last value of duplicate keys
         let mut key_value_pairs: Vec<(Vec<u8>, Vec<u8>)> =
             keys.iter().cloned().zip(values.iter().cloned()).collect();
+        key_value_pairs.reverse();
         key_value_pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        key_value_pairs.dedup_by(|a, b| a.0 == b.0);
 
-        for (key, value) in key_value_pairs {
-            self.insert(key, value, storage, path_hashes.clone());
+        if let Err(error) = self.insert_sorted_batch(&key_value_pairs, storage, path_hashes) {
+            panic!("failed to insert batch: {error}");
         }
     }
 
@@ -736,6 +939,7 @@ impl<const N: usize> Node<N> for ProllyNode<N> {
     ) -> bool {
         // set is root node based on parent hash
         let is_root_node = path_hashes.is_empty();
+        self.clear_balance_flags();
 
         if self.is_leaf {
             // If the node is a leaf, try to find and remove the key
@@ -778,39 +982,8 @@ impl<const N: usize> Node<N> for ProllyNode<N> {
                     return false;
                 }
 
-                // Save the updated child node back to the storage
-                let new_node_hash = child_node.get_hash().as_bytes().to_vec();
-                storage.insert_node(child_node.get_hash(), child_node.clone());
-
-                // Check if the child node has been merged into its parent's next sibling
-                if child_node.merged {
-                    // remove the next sibling from the parent node
-                    if i + 1 < self.keys.len() {
-                        self.keys.remove(i + 1);
-                        self.values.remove(i + 1);
-                    }
-                }
-
-                // Check if the child node has been split and needs to be updated in the current node
-                //if !child_node.is_leaf && child_node.keys.len() > 1 {
-                if child_node.split {
-                    // Move the key-value pairs from the child node to the current node at position `i`
-                    self.keys.remove(i);
-                    self.values.remove(i);
-
-                    for (j, (key, value)) in child_node
-                        .keys
-                        .into_iter()
-                        .zip(child_node.values)
-                        .enumerate()
-                    {
-                        self.keys.insert(i + j, key);
-                        self.values.insert(i + j, value);
-                    }
-                } else {
-                    // Update this node's value with the new hash
-                    self.values[i] = new_node_hash;
-                }
+                // Save the updated child node and reflect its rebalancing in the current node
+                self.update_child(i, child_node, storage);
 
                 true
             } else {
@@ -862,21 +1035,38 @@ impl<const N: usize> Node<N> for ProllyNode<N> {
     }
 
     fn print_tree<S: NodeStorage<N>>(&self, storage: &S) {
+        self.print_tree_with(storage, |key| {
+            key.iter()
+                .map(|byte| format!("{:0}", byte))
+                .collect::<Vec<String>>()
+                .join(" ")
+        })
+    }
+}
+
+impl<const N: usize> ProllyNode<N> {
+    /// Like `print_tree`, printing every key with `format_key`.
+    pub fn print_tree_with<S, F>(&self, storage: &S, format_key: F)
+    where
+        S: NodeStorage<N>,
+        F: Fn(&[u8]) -> String,
+    {
         println!("root:");
         let output = self.formatted_traverse_3(storage, |node, prefix, is_last| {
             let keys_str = node
                 .keys
                 .iter()
-                .map(|key| {
-                    key.iter()
-                        .map(|byte| format!("{:0}", byte))
-                        .collect::<Vec<String>>()
-                        .join(" ")
-                })
+                .map(|key| format_key(key))
                 .collect::<Vec<String>>()
                 .join(", ");
-            let hash =
-                Self::initialize_rolling_hash(&self.keys, &self.values, self.base, self.modulus);
+            let hash = RollingHashChunker {
+                base: self.base,
+                modulus: self.modulus,
+                min_chunk_size: self.min_chunk_size,
+                max_chunk_size: self.max_chunk_size,
+                pattern: self.pattern,
+            }
+            .initialize_rolling_hash(&self.keys, &self.values);
             if node.is_leaf {
                 format!(
                     "{}{}[{}]\n",
@@ -903,9 +1093,7 @@ impl<const N: usize> Node<N> for ProllyNode<N> {
 // implement get hash function of the ProllyNode
 impl<const N: usize> ProllyNode<N> {
     pub fn get_hash(&self) -> ValueDigest<N> {
-        let mut keys_and_values = self.keys.concat();
-        keys_and_values.extend(&self.values.concat());
-        ValueDigest::new(&keys_and_values)
+        node_hash(&self.keys, &self.values, &self.counts, self.hash_algorithm)
     }
 }
 
@@ -1471,4 +1659,33 @@ mod tests {
         // Print chunk content
         println!("{:?}", node.chunk_content());
     }
+
+    /// Builds a node with children, and a storage from which its first child is removed.
+    fn node_with_missing_child() -> (ProllyNode<32>, InMemoryNodeStorage<32>) {
+        let mut storage = InMemoryNodeStorage::<32>::default();
+        let mut node: ProllyNode<32> = ProllyNode::init_root(vec![0], vec![100]);
+        for i in 1..=255 {
+            node.insert(vec![i], vec![100], &mut storage, Vec::new());
+        }
+        assert!(!node.is_leaf);
+        storage.delete_node(&ValueDigest::raw_hash(&node.values[0]));
+        (node, storage)
+    }
+
+    #[test]
+    fn test_insert_batch_missing_child() {
+        let (mut node, mut storage) = node_with_missing_child();
+        let pairs = vec![(vec![0], vec![1]), (vec![255], vec![1])];
+
+        let result = node.insert_sorted_batch(&pairs, &mut storage, Vec::new());
+        assert!(matches!(result, Err(Error::MissingNode(_))));
+    }
+
+    #[test]
+    fn test_delete_range_missing_child() {
+        let (mut node, mut storage) = node_with_missing_child();
+
+        let result = node.delete_range(&(..), &mut storage, Vec::new());
+        assert!(matches!(result, Err(Error::MissingNode(_))));
+    }
 }
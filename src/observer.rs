@@ -0,0 +1,62 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Observers notified of the changes applied to a tree.
+
+/// Callback invoked with `(key, old_value, new_value)` for every change applied to a tree.
+///
+/// `old_value` is `None` for keys that did not exist, and `new_value` is `None` for deleted
+/// keys. Expired keys count as absent.
+pub type ChangeObserver = Box<dyn FnMut(&[u8], Option<&[u8]>, Option<&[u8]>) + Send + Sync>;
+
+/// Identifies a registered observer, to remove it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObserverId(u64);
+
+/// The observers registered on a tree, called in registration order.
+#[derive(Default)]
+pub(crate) struct Observers {
+    next_id: u64,
+    observers: Vec<(ObserverId, ChangeObserver)>,
+}
+
+impl Observers {
+    pub(crate) fn add(&mut self, observer: ChangeObserver) -> ObserverId {
+        let id = ObserverId(self.next_id);
+        self.next_id += 1;
+        self.observers.push((id, observer));
+        id
+    }
+
+    pub(crate) fn remove(&mut self, id: ObserverId) -> bool {
+        let len = self.observers.len();
+        self.observers.retain(|(observer_id, _)| *observer_id != id);
+        self.observers.len() < len
+    }
+
+    /// Returns `true` if no observer is registered, in which case callers can skip looking
+    /// up old values.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    pub(crate) fn notify(&mut self, key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>) {
+        if old.is_none() && new.is_none() {
+            return;
+        }
+        for (_, observer) in &mut self.observers {
+            observer(key, old, new);
+        }
+    }
+}
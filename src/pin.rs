@@ -0,0 +1,160 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Reference counted pins on root hashes.
+//!
+//! Nodes are never rewritten, so a version of a tree stays readable for as long as the
+//! nodes reachable from its root are kept. `RootPins` records which versions are still
+//! in use, e.g. by long-lived snapshots, so that anything dropping nodes from a storage
+//! knows which ones it must preserve.
+
+use crate::blob::chunk_hashes;
+use crate::digest::ValueDigest;
+use crate::storage::NodeStorage;
+use crate::ttl;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// A shared set of pinned root hashes, each with a reference count.
+///
+/// Clones share the same set, so a handle can be given to every reader of a storage.
+#[derive(Debug, Clone, Default)]
+pub struct RootPins<const N: usize> {
+    counts: Arc<Mutex<HashMap<ValueDigest<N>, usize>>>,
+}
+
+impl<const N: usize> RootPins<N> {
+    /// Creates an empty set of pins.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pins a root hash, returning its number of pins.
+    pub fn pin(&self, root_hash: ValueDigest<N>) -> usize {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(root_hash).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    /// Removes one pin of a root hash.
+    ///
+    /// # Returns
+    /// - The number of pins left, or `None` if the root hash was not pinned.
+    pub fn unpin(&self, root_hash: &ValueDigest<N>) -> Option<usize> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.get_mut(root_hash)?;
+        *count -= 1;
+        let left = *count;
+        if left == 0 {
+            counts.remove(root_hash);
+        }
+        Some(left)
+    }
+
+    /// Pins a root hash until the returned guard is dropped.
+    pub fn guard(&self, root_hash: ValueDigest<N>) -> PinGuard<N> {
+        self.pin(root_hash.clone());
+        PinGuard {
+            pins: self.clone(),
+            root_hash,
+        }
+    }
+
+    /// Returns the number of pins of a root hash.
+    pub fn pin_count(&self, root_hash: &ValueDigest<N>) -> usize {
+        let counts = self.counts.lock().unwrap();
+        counts.get(root_hash).copied().unwrap_or(0)
+    }
+
+    /// Returns `true` if a root hash has at least one pin.
+    pub fn is_pinned(&self, root_hash: &ValueDigest<N>) -> bool {
+        self.pin_count(root_hash) > 0
+    }
+
+    /// Returns the pinned root hashes, in no particular order.
+    pub fn roots(&self) -> Vec<ValueDigest<N>> {
+        self.counts.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Returns the hashes of every node reachable from a pinned root, including the chunks
+    /// of values stored outside of the leaves.
+    ///
+    /// These are the nodes that must stay in `storage` for the pinned versions to remain
+    /// readable. Nodes missing from the storage or corrupt are skipped.
+    ///
+    /// # Parameters
+    /// - `storage`: The storage holding the nodes of the pinned versions.
+    /// - `expiring`: Whether the values of the trees carry expiry timestamps.
+    pub fn live_nodes<S: NodeStorage<N>>(
+        &self,
+        storage: &S,
+        expiring: bool,
+    ) -> HashSet<ValueDigest<N>> {
+        reachable_nodes(storage, self.roots(), expiring)
+    }
+}
+
+/// Returns the hashes of every node reachable from the given roots, including the chunks
+/// of values stored outside of the leaves. Nodes missing from the storage or corrupt are
+/// skipped.
+pub(crate) fn reachable_nodes<const N: usize, S: NodeStorage<N>>(
+    storage: &S,
+    roots: Vec<ValueDigest<N>>,
+    expiring: bool,
+) -> HashSet<ValueDigest<N>> {
+    let mut live = HashSet::new();
+    let mut pending = roots;
+    while let Some(hash) = pending.pop() {
+        if !live.insert(hash.clone()) {
+            continue;
+        }
+        let Ok(Some(node)) = storage.try_get_node(&hash) else {
+            continue;
+        };
+        if !node.is_leaf {
+            pending.extend(node.values.iter().map(|child| ValueDigest::raw_hash(child)));
+            continue;
+        }
+        for value in &node.values {
+            let stored = if expiring {
+                ttl::unwrap(value).1
+            } else {
+                value
+            };
+            live.extend(chunk_hashes::<N>(stored));
+        }
+    }
+    live
+}
+
+/// A pin on a root hash that is removed when the guard is dropped.
+#[derive(Debug)]
+pub struct PinGuard<const N: usize> {
+    pins: RootPins<N>,
+    root_hash: ValueDigest<N>,
+}
+
+impl<const N: usize> PinGuard<N> {
+    /// Returns the pinned root hash.
+    pub fn root_hash(&self) -> &ValueDigest<N> {
+        &self.root_hash
+    }
+}
+
+impl<const N: usize> Drop for PinGuard<N> {
+    fn drop(&mut self) {
+        self.pins.unpin(&self.root_hash);
+    }
+}
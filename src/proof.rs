@@ -12,14 +12,393 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+#[cfg(feature = "attestation")]
+pub mod attestation;
+pub mod cache;
+pub mod evm;
+pub mod verifier;
+
 use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::iter::prefix_range;
+use crate::node::ProllyNode;
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+pub use verifier::KeyProof;
+use verifier::ProofNode;
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Proof<const N: usize> {
-    pub path: Vec<ValueDigest<N>>, // Hashes of the nodes along the path
+    pub path: Vec<PathSegment<N>>, // Segments of the nodes along the path
     pub target_hash: Option<ValueDigest<N>>, // Hash of the target node (if exists)
+    /// For a key absent from the tree: the closest entry below the key, if any.
+    #[serde(default)]
+    pub lower: Option<Neighbor<N>>,
+    /// For a key absent from the tree: the closest entry above the key, if any.
+    #[serde(default)]
+    pub upper: Option<Neighbor<N>>,
+}
+
+/// An entry next to a key that is absent from the tree, included in the proof of
+/// non-existence of the key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Neighbor<const N: usize> {
+    /// The key of the entry.
+    pub key: Vec<u8>,
+    /// The nodes from the root to the leaf holding the entry.
+    pub path: Vec<PathSegment<N>>,
+}
+
+/// A node on the path of a proof, from the root down to a leaf.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PathSegment<const N: usize> {
+    /// The hash of the node.
+    pub node_hash: ValueDigest<N>,
+    /// The hashes of the other children of the parent of the node, in key order. Empty
+    /// for the root.
+    pub sibling_hashes: Vec<ValueDigest<N>>,
+    /// The index of the node among the children of its parent; 0 for the root.
+    pub position: usize,
+}
+
+impl<const N: usize> PathSegment<N> {
+    /// Creates the segment of the root node, which has no siblings.
+    pub fn root(node_hash: ValueDigest<N>) -> Self {
+        PathSegment {
+            node_hash,
+            sibling_hashes: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Creates the segment of the child at `position` of an internal node.
+    pub fn child_of(parent: &ProllyNode<N>, position: usize) -> Self {
+        let mut sibling_hashes: Vec<_> = parent
+            .values
+            .iter()
+            .map(|hash| ValueDigest::raw_hash(hash))
+            .collect();
+        let node_hash = sibling_hashes.remove(position);
+        PathSegment {
+            node_hash,
+            sibling_hashes,
+            position,
+        }
+    }
+
+    /// Returns the number of children of the parent of the node, including the node.
+    pub fn fanout(&self) -> usize {
+        self.sibling_hashes.len() + 1
+    }
+}
+
+impl<const N: usize> Proof<N> {
+    /// Returns the segments of the path, from the root down to the leaf of the key.
+    pub fn segments(&self) -> &[PathSegment<N>] {
+        &self.path
+    }
+
+    /// Returns the hashes of the nodes on the path, from the root down.
+    pub fn node_hashes(&self) -> impl Iterator<Item = &ValueDigest<N>> {
+        self.path.iter().map(|segment| &segment.node_hash)
+    }
+
+    /// Returns the hash of the root the proof was generated against.
+    pub fn root_hash(&self) -> Option<&ValueDigest<N>> {
+        self.path.first().map(|segment| &segment.node_hash)
+    }
+
+    /// Returns the hash of the leaf at the end of the path.
+    pub fn leaf_hash(&self) -> Option<&ValueDigest<N>> {
+        self.path.last().map(|segment| &segment.node_hash)
+    }
+
+    /// Returns the number of nodes on the path.
+    pub fn depth(&self) -> usize {
+        self.path.len()
+    }
+
+    /// Returns `true` if the proof shows that the key exists, `false` if it shows that the
+    /// key is absent.
+    pub fn is_inclusion(&self) -> bool {
+        self.target_hash.is_some()
+    }
+}
+
+/// The size of a proof, to compare the overhead of hash sizes and chunking configurations.
+pub trait ProofSize {
+    /// Returns the number of tree nodes the proof holds or hashes.
+    fn node_count(&self) -> usize;
+
+    /// Returns the size of the proof in bytes, as encoded for transfer.
+    fn byte_size(&self) -> usize;
+}
+
+impl<const N: usize> ProofSize for Proof<N> {
+    fn node_count(&self) -> usize {
+        self.path.len()
+            + [&self.lower, &self.upper]
+                .into_iter()
+                .flatten()
+                .map(|neighbor| neighbor.path.len())
+                .sum::<usize>()
+    }
+
+    /// Returns the length of the `to_bytes` encoding.
+    fn byte_size(&self) -> usize {
+        self.to_bytes().len()
+    }
+}
+
+impl ProofSize for RangeProof {
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns the length of the bincode encoding.
+    fn byte_size(&self) -> usize {
+        bincode::serialized_size(self).map_or(0, |size| size as usize)
+    }
+}
+
+impl ProofSize for MultiProof {
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns the length of the bincode encoding.
+    fn byte_size(&self) -> usize {
+        bincode::serialized_size(self).map_or(0, |size| size as usize)
+    }
+}
+
+impl ProofSize for CountProof {
+    fn node_count(&self) -> usize {
+        1
+    }
+
+    /// Returns the length of the bincode encoding.
+    fn byte_size(&self) -> usize {
+        bincode::serialized_size(self).map_or(0, |size| size as usize)
+    }
+}
+
+/// The size of a proof and the time it took to generate it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProofStats {
+    /// The number of tree nodes the proof holds or hashes.
+    pub nodes: usize,
+    /// The size of the proof in bytes, as encoded for transfer.
+    pub bytes: usize,
+    /// The time it took to generate the proof.
+    pub generation_time: Duration,
+}
+
+impl ProofStats {
+    /// Generates a proof and measures it.
+    ///
+    /// # Parameters
+    /// - `generate`: Generates the proof, e.g. `|| tree.generate_proof(key)`.
+    ///
+    /// # Returns
+    /// - The proof and its stats.
+    pub fn measure<P: ProofSize>(generate: impl FnOnce() -> P) -> (P, Self) {
+        let start = Instant::now();
+        let proof = generate();
+        let generation_time = start.elapsed();
+        let stats = ProofStats {
+            nodes: proof.node_count(),
+            bytes: proof.byte_size(),
+            generation_time,
+        };
+        (proof, stats)
+    }
+}
+
+/// Leading bytes of an encoded proof.
+const PROOF_MAGIC: &[u8; 4] = b"PRPF";
+/// Version of the binary proof encoding written by `Proof::to_bytes`.
+///
+/// Encoded proofs are kept in archives long after they were written, so the encoding
+/// evolves by these rules:
+/// - Any change to the layout bumps the version; the layout of a released version is
+///   never changed.
+/// - `Proof::from_bytes` decodes every released version, not only the latest. Support for
+///   a version is never dropped.
+/// - New optional parts are announced by new bits of the flags byte. Unknown versions and
+///   unknown flags are refused rather than guessed at.
+///
+/// Released versions:
+/// - 1: paths are the raw node hashes only.
+/// - 2: paths are segments holding the node hash, its position and its siblings.
+const PROOF_VERSION: u8 = 2;
+/// Oldest version of the binary proof encoding that can still be decoded.
+const MIN_PROOF_VERSION: u8 = 1;
+
+const HAS_TARGET: u8 = 1;
+const HAS_LOWER: u8 = 1 << 1;
+const HAS_UPPER: u8 = 1 << 2;
+
+impl<const N: usize> Proof<N> {
+    /// Encodes the proof in a compact, versioned binary format.
+    ///
+    /// The encoding starts with the magic bytes `PRPF`, the format version and the hash
+    /// size `N`, followed by a flags byte telling which optional parts are present (bit 0:
+    /// target hash, bit 1: lower neighbor, bit 2: upper neighbor). Then come the path, the
+    /// target hash and the neighbors, in this order. Paths are a big-endian `u32` count
+    /// of segments; a segment is the raw node hash, its position and its number of
+    /// siblings as big-endian `u32`s, and the raw sibling hashes. A neighbor is its key,
+    /// prefixed by its length as a big-endian `u32`, followed by its path.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let flags = (self.target_hash.is_some() as u8 * HAS_TARGET)
+            | (self.lower.is_some() as u8 * HAS_LOWER)
+            | (self.upper.is_some() as u8 * HAS_UPPER);
+        let mut bytes = PROOF_MAGIC.to_vec();
+        bytes.extend([PROOF_VERSION, N as u8, flags]);
+        write_path(&mut bytes, &self.path);
+        if let Some(target_hash) = &self.target_hash {
+            bytes.extend(target_hash.as_bytes());
+        }
+        for neighbor in [&self.lower, &self.upper].into_iter().flatten() {
+            bytes.extend((neighbor.key.len() as u32).to_be_bytes());
+            bytes.extend(&neighbor.key);
+            write_path(&mut bytes, &neighbor.path);
+        }
+        bytes
+    }
+
+    /// Decodes a proof written by `to_bytes` in this or any earlier version of the crate.
+    ///
+    /// The segments of version 1 proofs hold only the node hashes, with no siblings and
+    /// position 0. `Tree::verify` accepts such segments as long as the hashes match.
+    ///
+    /// # Returns
+    /// - The proof, or `Error::InvalidProof` if the bytes are not a proof in a supported
+    ///   version with hashes of size `N`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = ProofReader { bytes };
+        if reader.take(PROOF_MAGIC.len())? != PROOF_MAGIC {
+            return Err(invalid_proof("not an encoded proof"));
+        }
+        let header = reader.take(3)?;
+        let (version, hash_size, flags) = (header[0], header[1], header[2]);
+        if !(MIN_PROOF_VERSION..=PROOF_VERSION).contains(&version) {
+            return Err(invalid_proof(format!("unsupported version {}", version)));
+        }
+        if hash_size as usize != N {
+            return Err(invalid_proof(format!(
+                "hash size {} does not match {}",
+                hash_size, N
+            )));
+        }
+        if flags & !(HAS_TARGET | HAS_LOWER | HAS_UPPER) != 0 {
+            return Err(invalid_proof(format!("unknown flags {:#04x}", flags)));
+        }
+
+        let path = reader.path(version)?;
+        let target_hash = if flags & HAS_TARGET != 0 {
+            Some(reader.hash()?)
+        } else {
+            None
+        };
+        let mut neighbor = |flag: u8| -> Result<Option<Neighbor<N>>, Error> {
+            if flags & flag == 0 {
+                return Ok(None);
+            }
+            let len = reader.len()?;
+            let key = reader.take(len)?.to_vec();
+            let path = reader.path(version)?;
+            Ok(Some(Neighbor { key, path }))
+        };
+        let lower = neighbor(HAS_LOWER)?;
+        let upper = neighbor(HAS_UPPER)?;
+        if !reader.bytes.is_empty() {
+            return Err(invalid_proof("trailing bytes"));
+        }
+        Ok(Proof {
+            path,
+            target_hash,
+            lower,
+            upper,
+        })
+    }
+}
+
+fn write_path<const N: usize>(bytes: &mut Vec<u8>, path: &[PathSegment<N>]) {
+    bytes.extend((path.len() as u32).to_be_bytes());
+    for segment in path {
+        bytes.extend(segment.node_hash.as_bytes());
+        bytes.extend((segment.position as u32).to_be_bytes());
+        bytes.extend((segment.sibling_hashes.len() as u32).to_be_bytes());
+        for hash in &segment.sibling_hashes {
+            bytes.extend(hash.as_bytes());
+        }
+    }
+}
+
+fn invalid_proof(reason: impl Into<String>) -> Error {
+    Error::InvalidProof(reason.into())
+}
+
+/// Reads the parts of an encoded proof, failing on truncated input.
+struct ProofReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ProofReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < len {
+            return Err(invalid_proof("truncated proof"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn len(&mut self) -> Result<usize, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn hash<const N: usize>(&mut self) -> Result<ValueDigest<N>, Error> {
+        Ok(ValueDigest::raw_hash(self.take(N)?))
+    }
+
+    fn path<const N: usize>(&mut self, version: u8) -> Result<Vec<PathSegment<N>>, Error> {
+        let len = self.len()?;
+        if version == 1 {
+            if len.saturating_mul(N) > self.bytes.len() {
+                return Err(invalid_proof("truncated proof"));
+            }
+            return (0..len)
+                .map(|_| Ok(PathSegment::root(self.hash()?)))
+                .collect();
+        }
+        // every segment takes at least N + 8 bytes, so a larger count is truncated
+        if len.saturating_mul(N + 8) > self.bytes.len() {
+            return Err(invalid_proof("truncated proof"));
+        }
+        (0..len)
+            .map(|_| {
+                let node_hash = self.hash()?;
+                let position = self.len()?;
+                let siblings = self.len()?;
+                if siblings.saturating_mul(N) > self.bytes.len() {
+                    return Err(invalid_proof("truncated proof"));
+                }
+                let sibling_hashes = (0..siblings)
+                    .map(|_| self.hash())
+                    .collect::<Result<_, _>>()?;
+                Ok(PathSegment {
+                    node_hash,
+                    sibling_hashes,
+                    position,
+                })
+            })
+            .collect()
+    }
 }
 
 // Assuming ValueDigest has a ToString implementation or similar
@@ -29,8 +408,7 @@ impl<const N: usize> fmt::Debug for Proof<N> {
             .field(
                 "path",
                 &self
-                    .path
-                    .iter()
+                    .node_hashes()
                     .map(|digest| {
                         let bytes = digest.as_bytes();
                         if bytes.len() > 8 {
@@ -52,6 +430,220 @@ impl<const N: usize> fmt::Debug for Proof<N> {
                     }
                 }),
             )
+            .field("lower", &self.lower.as_ref().map(|neighbor| &neighbor.key))
+            .field("upper", &self.upper.as_ref().map(|neighbor| &neighbor.key))
             .finish()
     }
 }
+
+/// Proof of the complete set of entries of a tree within a key range.
+///
+/// The proof holds every node whose key range overlaps the proven range, in depth-first
+/// order from the root. A verifier that only knows the root hash can recompute the hashes
+/// of these nodes and read the entries of the range from the leaves, so an entry left out
+/// of the range is detected as well as an entry whose value was changed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RangeProof {
+    /// The lower bound of the proven range.
+    pub start: Bound<Vec<u8>>,
+    /// The upper bound of the proven range.
+    pub end: Bound<Vec<u8>>,
+    /// The nodes overlapping the range, parents before their children.
+    pub nodes: Vec<ProofNode>,
+}
+
+impl RangeProof {
+    /// Checks the proof against a root hash and returns the entries it proves.
+    ///
+    /// # Returns
+    /// - Every entry of the range in ascending key order, with values as stored in the
+    ///   leaves, or `None` if the proof does not match `root_hash` or omits a node.
+    pub fn entries<const N: usize>(
+        &self,
+        root_hash: &ValueDigest<N>,
+    ) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        verifier::range_entries(root_hash, &self.start, &self.end, &self.nodes)
+    }
+
+    /// Verifies that `entries` are exactly the entries of the range in the tree with the
+    /// given root hash.
+    ///
+    /// # Parameters
+    /// - `root_hash`: The trusted root hash of the tree.
+    /// - `entries`: The entries received for the range, in ascending key order.
+    pub fn verify<const N: usize>(
+        &self,
+        root_hash: &ValueDigest<N>,
+        entries: &[(Vec<u8>, Vec<u8>)],
+    ) -> bool {
+        self.entries(root_hash)
+            .is_some_and(|proven| proven.as_slice() == entries)
+    }
+
+    /// Returns `true` if the proven range is exactly the set of keys starting with
+    /// `prefix`, as generated by `ProllyTree::generate_prefix_proof`.
+    pub fn is_prefix(&self, prefix: &[u8]) -> bool {
+        (self.start.clone(), self.end.clone()) == prefix_range(prefix)
+    }
+}
+
+/// Proof of the values of several keys, sharing the nodes common to their paths.
+///
+/// The proof holds the union of the nodes on the paths from the root to the leaves of the
+/// keys, each node once, in depth-first order. Proving many keys at once is therefore much
+/// smaller than proving each key on its own, since the nodes near the root are shared by
+/// all paths. Keys absent from the tree are proven absent by the leaf they would be in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MultiProof {
+    /// The proven keys, in ascending order without duplicates.
+    pub keys: Vec<Vec<u8>>,
+    /// The nodes on the paths of the keys, parents before their children.
+    pub nodes: Vec<ProofNode>,
+}
+
+impl MultiProof {
+    /// Checks the proof against a root hash and returns the values of the proven keys.
+    ///
+    /// # Returns
+    /// - Every proven key in ascending order, with its value as stored in the leaves or
+    ///   `None` if the key is absent, or `None` if the proof does not match `root_hash`.
+    pub fn entries<const N: usize>(&self, root_hash: &ValueDigest<N>) -> Option<Vec<KeyProof>> {
+        verifier::key_entries(root_hash, &self.keys, &self.nodes)
+    }
+
+    /// Verifies that the proven keys have exactly the given values in the tree with the
+    /// given root hash.
+    ///
+    /// # Parameters
+    /// - `root_hash`: The trusted root hash of the tree.
+    /// - `entries`: The expected value of every proven key, `None` for absent keys, in
+    ///   ascending key order.
+    pub fn verify<const N: usize>(&self, root_hash: &ValueDigest<N>, entries: &[KeyProof]) -> bool {
+        self.entries(root_hash)
+            .is_some_and(|proven| proven.as_slice() == entries)
+    }
+}
+
+/// Proof of the total number of keys in a tree.
+///
+/// Every internal node commits to the number of keys below each of its children, so the
+/// root node alone proves the count of the whole tree.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CountProof {
+    /// The number of keys in the tree.
+    pub count: u64,
+    /// The root node of the tree.
+    pub root: ProofNode,
+}
+
+impl CountProof {
+    /// Verifies that the tree with the given root hash holds exactly `count` keys.
+    ///
+    /// # Parameters
+    /// - `root_hash`: The trusted root hash of the tree.
+    pub fn verify<const N: usize>(&self, root_hash: &ValueDigest<N>) -> bool {
+        verifier::verify_count(root_hash, &self.root, self.count)
+    }
+}
+
+impl<const N: usize> From<&ProllyNode<N>> for ProofNode {
+    fn from(node: &ProllyNode<N>) -> Self {
+        ProofNode {
+            keys: node.keys.clone(),
+            values: node.values.clone(),
+            counts: node.counts.clone(),
+            is_leaf: node.is_leaf,
+            hash_algorithm: node.hash_algorithm,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proof(neighbors: bool) -> Proof<32> {
+        let hashes: Vec<ValueDigest<32>> = (0..3u8).map(|i| ValueDigest::new(&[i])).collect();
+        let path = vec![
+            PathSegment::root(hashes[0].clone()),
+            PathSegment {
+                node_hash: hashes[1].clone(),
+                sibling_hashes: vec![hashes[0].clone(), hashes[2].clone()],
+                position: 1,
+            },
+            PathSegment {
+                node_hash: hashes[2].clone(),
+                sibling_hashes: Vec::new(),
+                position: 0,
+            },
+        ];
+        let neighbor = |key: &[u8]| Neighbor {
+            key: key.to_vec(),
+            path: path[..2].to_vec(),
+        };
+        Proof {
+            target_hash: (!neighbors).then(|| hashes[2].clone()),
+            lower: neighbors.then(|| neighbor(b"a")),
+            upper: neighbors.then(|| neighbor(b"")),
+            path,
+        }
+    }
+
+    #[test]
+    fn test_proof_stats() {
+        let (inclusion, stats) = ProofStats::measure(|| proof(false));
+        assert_eq!(stats.nodes, 3);
+        assert_eq!(stats.bytes, inclusion.to_bytes().len());
+
+        // the neighbors of an absent key add their paths
+        let (_, stats) = ProofStats::measure(|| proof(true));
+        assert_eq!(stats.nodes, 7);
+    }
+
+    #[test]
+    fn test_proof_bytes() {
+        for proof in [proof(false), proof(true)] {
+            let bytes = proof.to_bytes();
+            assert_eq!(&bytes[..4], PROOF_MAGIC);
+            assert_eq!(Proof::<32>::from_bytes(&bytes).unwrap(), proof);
+
+            // every truncation is refused rather than misread
+            for len in 0..bytes.len() {
+                assert!(matches!(
+                    Proof::<32>::from_bytes(&bytes[..len]),
+                    Err(Error::InvalidProof(_))
+                ));
+            }
+            let mut longer = bytes.clone();
+            longer.push(0);
+            assert!(Proof::<32>::from_bytes(&longer).is_err());
+        }
+
+        let proof = proof(false);
+        assert_eq!(proof.depth(), 3);
+        assert_eq!(proof.root_hash(), Some(&proof.segments()[0].node_hash));
+        assert_eq!(proof.segments()[1].fanout(), 3);
+        assert!(proof.is_inclusion());
+
+        let mut bytes = proof.to_bytes();
+        // hashes of another size
+        assert!(Proof::<20>::from_bytes(&bytes).is_err());
+        // an unknown version
+        bytes[4] = PROOF_VERSION + 1;
+        assert!(Proof::<32>::from_bytes(&bytes).is_err());
+        bytes[4] = 0;
+        assert!(Proof::<32>::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_proof_serde() {
+        let proof = proof(true);
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<Proof<32>>(&json).unwrap(), proof);
+
+        // proofs serialized before neighbors were added still decode
+        let old = r#"{"path": [], "target_hash": null}"#;
+        let decoded: Proof<32> = serde_json::from_str(old).unwrap();
+        assert!(decoded.lower.is_none() && decoded.upper.is_none());
+    }
+}
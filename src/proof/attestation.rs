@@ -0,0 +1,136 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Signed statements that a root hash was the head of a branch or commit at a point in time.
+//!
+//! An attestation lets a party that trusts the signer's public key accept a root hash (and
+//! with it every proof against that root) without access to the tree.
+
+use crate::digest::ValueDigest;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Domain separator of the signed message, so that attestation signatures cannot be replayed
+/// as signatures over other data.
+const DOMAIN: &[u8] = b"prollytree-root-attestation-v1";
+
+/// A root hash bound to a timestamp and a branch or commit id under an ed25519 signature.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootAttestation<const N: usize> {
+    /// The attested root hash.
+    pub root_hash: ValueDigest<N>,
+    /// Milliseconds since the Unix epoch at which the root was attested.
+    pub timestamp: u64,
+    /// The branch name or commit id the root belongs to.
+    pub reference: String,
+    /// The ed25519 signature over the fields above.
+    pub signature: Vec<u8>,
+}
+
+impl<const N: usize> RootAttestation<N> {
+    /// Signs a root hash for a branch or commit.
+    ///
+    /// # Parameters
+    /// - `root_hash`: The root hash to attest.
+    /// - `time`: The point in time the root is attested at.
+    /// - `reference`: The branch name or commit id of the root.
+    /// - `key`: The signer's key.
+    pub fn sign(
+        root_hash: ValueDigest<N>,
+        time: SystemTime,
+        reference: impl Into<String>,
+        key: &SigningKey,
+    ) -> Self {
+        let timestamp = time
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        let mut attestation = RootAttestation {
+            root_hash,
+            timestamp,
+            reference: reference.into(),
+            signature: Vec::new(),
+        };
+        attestation.signature = key.sign(&attestation.message()).to_bytes().to_vec();
+        attestation
+    }
+
+    /// Checks the signature against a trusted public key.
+    ///
+    /// # Returns
+    /// - `true` if `key` signed exactly this root hash, timestamp and reference.
+    pub fn verify(&self, key: &VerifyingKey) -> bool {
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        key.verify(&self.message(), &signature).is_ok()
+    }
+
+    /// Returns the point in time the root was attested at.
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp)
+    }
+
+    /// Returns the signed message: the domain separator, the hash size, the root hash, the
+    /// big-endian timestamp and the length-prefixed reference.
+    pub fn message(&self) -> Vec<u8> {
+        let mut message = Vec::with_capacity(DOMAIN.len() + 16 + N + self.reference.len());
+        message.extend_from_slice(DOMAIN);
+        message.extend_from_slice(&(N as u32).to_be_bytes());
+        message.extend_from_slice(&self.root_hash.0);
+        message.extend_from_slice(&self.timestamp.to_be_bytes());
+        message.extend_from_slice(&(self.reference.len() as u32).to_be_bytes());
+        message.extend_from_slice(self.reference.as_bytes());
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let other = SigningKey::from_bytes(&[8u8; 32]);
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let attestation =
+            RootAttestation::<32>::sign(ValueDigest::new(b"root"), time, "main", &key);
+
+        assert!(attestation.verify(&key.verifying_key()));
+        assert!(!attestation.verify(&other.verifying_key()));
+        assert_eq!(attestation.time(), time);
+
+        let json = serde_json::to_string(&attestation).unwrap();
+        let decoded: RootAttestation<32> = serde_json::from_str(&json).unwrap();
+        assert!(decoded.verify(&key.verifying_key()));
+
+        let mut tampered = attestation.clone();
+        tampered.root_hash = ValueDigest::new(b"other root");
+        assert!(!tampered.verify(&key.verifying_key()));
+
+        let mut tampered = attestation.clone();
+        tampered.timestamp += 1;
+        assert!(!tampered.verify(&key.verifying_key()));
+
+        let mut tampered = attestation.clone();
+        tampered.reference = "dev".to_string();
+        assert!(!tampered.verify(&key.verifying_key()));
+
+        let mut tampered = attestation;
+        tampered.signature.truncate(10);
+        assert!(!tampered.verify(&key.verifying_key()));
+    }
+}
@@ -0,0 +1,95 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A cache of recently generated proofs.
+
+use crate::digest::ValueDigest;
+use crate::proof::Proof;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+
+/// Least recently used cache of proofs, keyed by root hash and key.
+///
+/// A proof only depends on the root it was generated against and the key, so cached proofs
+/// never need to be invalidated: once the tree changes, requests use the new root hash and
+/// the proofs of the old root are evicted as they fall out of use. Share the cache between
+/// threads behind a `Mutex`.
+pub struct ProofCache<const N: usize> {
+    proofs: LruCache<(ValueDigest<N>, Vec<u8>), Proof<N>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl<const N: usize> ProofCache<N> {
+    /// Creates a cache holding at most `capacity` proofs.
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        ProofCache {
+            proofs: LruCache::new(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns the cached proof of a key against a root, generating and caching it first
+    /// if it is not cached.
+    ///
+    /// # Parameters
+    /// - `root_hash`: The root hash the proof is generated against.
+    /// - `key`: The proven key.
+    /// - `generate`: Generates the proof on a cache miss.
+    pub fn get_or_insert_with<F>(
+        &mut self,
+        root_hash: &ValueDigest<N>,
+        key: &[u8],
+        generate: F,
+    ) -> Proof<N>
+    where
+        F: FnOnce() -> Proof<N>,
+    {
+        let entry = (root_hash.clone(), key.to_vec());
+        if let Some(proof) = self.proofs.get(&entry) {
+            self.hits += 1;
+            return proof.clone();
+        }
+        self.misses += 1;
+        let proof = generate();
+        self.proofs.put(entry, proof.clone());
+        proof
+    }
+
+    /// Returns the number of cached proofs.
+    pub fn len(&self) -> usize {
+        self.proofs.len()
+    }
+
+    /// Returns `true` if no proof is cached.
+    pub fn is_empty(&self) -> bool {
+        self.proofs.is_empty()
+    }
+
+    /// Returns the number of requests answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Returns the number of requests that generated a proof.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Removes every cached proof.
+    pub fn clear(&mut self) {
+        self.proofs.clear();
+    }
+}
@@ -0,0 +1,224 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Inclusion proofs that a smart contract on an EVM chain can verify.
+//!
+//! Trees configured with `HashAlgorithm::Keccak256` hash every node with keccak256 over
+//! the layout of `verifier::fixed_layout`, which a contract can hash with `keccak256` and
+//! parse front to back. An `EvmProof` holds the laid out nodes from the root to the leaf
+//! of a key, and the position of the entry to follow in each. Starting with the trusted
+//! root hash as the expected hash, a contract checks every node in turn:
+//!
+//! 1. `keccak256(node)` must equal the expected hash;
+//! 2. the entry at the given position is read, skipping the entries before it by their
+//!    lengths, and the remaining entries are skipped to reach the number of counts;
+//! 3. every node but the last must have subtree counts, and the value of its entry, which
+//!    must be 32 bytes long, becomes the expected hash;
+//! 4. the last node must have no subtree counts, and its entry must hold the proven key
+//!    and value.
+//!
+//! `EvmProof::verify` implements exactly these steps, and `test_vectors` returns proofs
+//! of a fixed tree to test contract implementations against.
+
+use crate::config::TreeConfig;
+use crate::digest::{HashAlgorithm, ValueDigest};
+use crate::storage::InMemoryNodeStorage;
+use crate::tree::{ProllyTree, Tree};
+use serde::{Deserialize, Serialize};
+
+/// Proof of inclusion of a key in a tree hashed with `HashAlgorithm::Keccak256`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EvmProof {
+    /// The proven key.
+    pub key: Vec<u8>,
+    /// The value of the key, as stored in the leaf.
+    pub value: Vec<u8>,
+    /// The nodes from the root to the leaf of the key, in the layout they are hashed in.
+    pub nodes: Vec<Vec<u8>>,
+    /// The position of the entry to follow in every node: the child on the path for
+    /// internal nodes, the key for the leaf.
+    pub positions: Vec<u32>,
+}
+
+impl EvmProof {
+    /// Verifies the proof against a root hash, the way a contract does.
+    pub fn verify(&self, root_hash: &[u8; 32]) -> bool {
+        if self.nodes.is_empty() || self.nodes.len() != self.positions.len() {
+            return false;
+        }
+        let mut expected = root_hash.to_vec();
+        for (i, (node, position)) in self.nodes.iter().zip(&self.positions).enumerate() {
+            let hash = ValueDigest::<32>::with_algorithm(node, HashAlgorithm::Keccak256);
+            if hash.as_bytes() != expected.as_slice() {
+                return false;
+            }
+            let Some(ParsedNode { entries, counts }) = parse(node) else {
+                return false;
+            };
+            let Some(&(key, value)) = entries.get(*position as usize) else {
+                return false;
+            };
+            let leaf = i + 1 == self.nodes.len();
+            if leaf {
+                return counts == 0 && key == self.key.as_slice() && value == self.value.as_slice();
+            }
+            if counts == 0 || value.len() != 32 {
+                return false;
+            }
+            expected = value.to_vec();
+        }
+        false
+    }
+}
+
+/// The entries and the number of subtree counts of a node laid out by
+/// `verifier::fixed_layout`.
+struct ParsedNode<'a> {
+    entries: Vec<(&'a [u8], &'a [u8])>,
+    counts: usize,
+}
+
+/// Parses a node laid out by `verifier::fixed_layout`.
+fn parse(node: &[u8]) -> Option<ParsedNode<'_>> {
+    fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        if rest.len() < len {
+            return None;
+        }
+        let (taken, tail) = rest.split_at(len);
+        *rest = tail;
+        Some(taken)
+    }
+    fn take_len(rest: &mut &[u8]) -> Option<usize> {
+        Some(u32::from_be_bytes(take(rest, 4)?.try_into().ok()?) as usize)
+    }
+
+    let mut rest = node;
+    let entry_count = take_len(&mut rest)?;
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+        let len = take_len(&mut rest)?;
+        let key = take(&mut rest, len)?;
+        let len = take_len(&mut rest)?;
+        let value = take(&mut rest, len)?;
+        entries.push((key, value));
+    }
+    let counts = take_len(&mut rest)?;
+    take(&mut rest, counts.checked_mul(8)?)?;
+    rest.is_empty().then_some(ParsedNode { entries, counts })
+}
+
+/// A proof of the tree of `test_vectors`, with every byte string hex encoded with a `0x`
+/// prefix.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EvmTestVector {
+    pub root_hash: String,
+    pub key: String,
+    pub value: String,
+    pub nodes: Vec<String>,
+    pub positions: Vec<u32>,
+}
+
+/// Returns proofs of a few keys of a fixed tree, for testing contract implementations.
+///
+/// The tree holds the keys `key-000` to `key-199`, each with the value `value-` followed by
+/// the same number, and is built with the default configuration and
+/// `HashAlgorithm::Keccak256`. It has more than one level, so the proofs pass through
+/// internal nodes.
+pub fn test_vectors() -> Vec<EvmTestVector> {
+    let config = TreeConfig::<32> {
+        hash_algorithm: HashAlgorithm::Keccak256,
+        ..Default::default()
+    };
+    let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+    for i in 0..200 {
+        tree.insert(
+            format!("key-{:03}", i).into_bytes(),
+            format!("value-{:03}", i).into_bytes(),
+        );
+    }
+    let root_hash = tree.get_root_hash().unwrap_or_default();
+    let hex = |bytes: &[u8]| format!("0x{}", hex::encode(bytes));
+
+    [0, 1, 57, 123, 199]
+        .into_iter()
+        .filter_map(|i| tree.generate_evm_proof(format!("key-{:03}", i).as_bytes()))
+        .map(|proof| EvmTestVector {
+            root_hash: hex(root_hash.as_bytes()),
+            key: hex(&proof.key),
+            value: hex(&proof.value),
+            nodes: proof.nodes.iter().map(|node| hex(node)).collect(),
+            positions: proof.positions,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(hex: &str) -> Vec<u8> {
+        hex::decode(hex.trim_start_matches("0x")).unwrap()
+    }
+
+    #[test]
+    fn test_vectors_verify() {
+        let vectors = test_vectors();
+        assert_eq!(vectors.len(), 5);
+        for vector in &vectors {
+            let proof = EvmProof {
+                key: decode(&vector.key),
+                value: decode(&vector.value),
+                nodes: vector.nodes.iter().map(|node| decode(node)).collect(),
+                positions: vector.positions.clone(),
+            };
+            let root_hash: [u8; 32] = decode(&vector.root_hash).try_into().unwrap();
+            assert!(proof.nodes.len() > 1);
+            assert!(proof.verify(&root_hash));
+
+            let mut wrong = proof.clone();
+            wrong.value = b"value-999".to_vec();
+            assert!(!wrong.verify(&root_hash));
+            let mut wrong = proof.clone();
+            wrong.positions[0] += 1;
+            assert!(!wrong.verify(&root_hash));
+            let mut wrong = proof.clone();
+            wrong.nodes.pop();
+            wrong.positions.pop();
+            assert!(!wrong.verify(&root_hash));
+            let mut wrong = proof.clone();
+            let last = wrong.nodes.len() - 1;
+            wrong.nodes[last][10] ^= 1;
+            assert!(!wrong.verify(&root_hash));
+        }
+
+        // the vectors are stable across runs
+        assert_eq!(test_vectors(), vectors);
+    }
+
+    #[test]
+    fn test_fixed_layout_parses() {
+        let layout = crate::proof::verifier::fixed_layout(
+            &[b"a".to_vec(), b"bc".to_vec()],
+            &[b"1".to_vec(), Vec::new()],
+            &[3, 4],
+        );
+        let node = parse(&layout).unwrap();
+        assert_eq!(
+            node.entries,
+            vec![(&b"a"[..], &b"1"[..]), (&b"bc"[..], &b""[..])]
+        );
+        assert_eq!(node.counts, 2);
+        assert!(parse(&layout[..layout.len() - 1]).is_none());
+    }
+}
@@ -0,0 +1,448 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Verification of proofs knowing only the root hash of a tree.
+//!
+//! Nothing in this module depends on `ProllyTree`, `NodeStorage` or any other part of the
+//! crate but the hash functions of `digest`, and it only uses `Vec` and `core` types. A
+//! light client can verify the proofs it receives with the functions below without
+//! loading a tree, and the module is kept free of `std`-only APIs so that it can be built
+//! for `no_std` targets with `alloc`.
+
+use crate::digest::{HashAlgorithm, ValueDigest};
+use core::ops::Bound;
+use serde::{Deserialize, Serialize};
+
+/// The parts of a tree node a proof needs: what the node hash commits to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProofNode {
+    pub keys: Vec<Vec<u8>>,
+    pub values: Vec<Vec<u8>>,
+    /// Number of key-value pairs below every child; empty for leaves.
+    pub counts: Vec<u64>,
+    pub is_leaf: bool,
+    pub hash_algorithm: HashAlgorithm,
+}
+
+impl ProofNode {
+    /// Computes the hash of the node, as the tree computes it.
+    pub fn hash<const N: usize>(&self) -> ValueDigest<N> {
+        node_hash(&self.keys, &self.values, &self.counts, self.hash_algorithm)
+    }
+}
+
+/// A proven key, with its value or `None` if the key is absent.
+pub type KeyProof = (Vec<u8>, Option<Vec<u8>>);
+
+/// Computes the hash of a node from its keys, values and subtree counts.
+///
+/// Nodes are hashed in the layout of `fixed_layout` with every algorithm, so that the
+/// hash commits to where each key and value starts and ends: entries split differently
+/// across keys and values never hash alike.
+///
+/// This layout is a format break: the first releases hashed the plain concatenation of
+/// keys and values, without subtree counts, so their node hashes differ from these.
+/// `FileNodeStorage` rehashes such stores when it opens them; other stores and root
+/// hashes recorded from those releases no longer resolve.
+pub fn node_hash<const N: usize>(
+    keys: &[Vec<u8>],
+    values: &[Vec<u8>],
+    counts: &[u64],
+    algorithm: HashAlgorithm,
+) -> ValueDigest<N> {
+    ValueDigest::with_algorithm(&fixed_layout(keys, values, counts), algorithm)
+}
+
+/// Lays out a node for hashing so that it can be parsed front to back by a verifier.
+///
+/// All integers are big-endian. The layout is the number of entries as a `uint32`, then
+/// every entry as its key and its value, each prefixed by its length as a `uint32`, then
+/// the number of subtree counts as a `uint32` followed by the counts as `uint64`s. Values
+/// without a key, such as the chunks of large values, follow last, each prefixed by its
+/// length; nodes of the tree itself have none. Unlike plain concatenation, no two
+/// different nodes share a layout.
+pub fn fixed_layout(keys: &[Vec<u8>], values: &[Vec<u8>], counts: &[u64]) -> Vec<u8> {
+    let mut layout = Vec::new();
+    layout.extend((keys.len() as u32).to_be_bytes());
+    for (key, value) in keys.iter().zip(values) {
+        layout.extend((key.len() as u32).to_be_bytes());
+        layout.extend(key);
+        layout.extend((value.len() as u32).to_be_bytes());
+        layout.extend(value);
+    }
+    layout.extend((counts.len() as u32).to_be_bytes());
+    for count in counts {
+        layout.extend(count.to_be_bytes());
+    }
+    for value in values.iter().skip(keys.len()) {
+        layout.extend((value.len() as u32).to_be_bytes());
+        layout.extend(value);
+    }
+    layout
+}
+
+/// Reads the entries of a key range from the nodes of a range proof.
+///
+/// # Parameters
+/// - `root_hash`: The trusted root hash of the tree.
+/// - `start`, `end`: The bounds of the proven range.
+/// - `nodes`: The nodes overlapping the range, parents before their children.
+///
+/// # Returns
+/// - Every entry of the range in ascending key order, with values as stored in the
+///   leaves, or `None` if the nodes do not match `root_hash` or one is missing.
+pub fn range_entries<const N: usize>(
+    root_hash: &ValueDigest<N>,
+    start: &Bound<Vec<u8>>,
+    end: &Bound<Vec<u8>>,
+    nodes: &[ProofNode],
+) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut verifier = RangeVerifier::new(root_hash.clone(), start.clone(), end.clone());
+    let mut entries = Vec::new();
+    for node in nodes {
+        entries.extend(verifier.push(node)?);
+    }
+    verifier.finish().then_some(entries)
+}
+
+/// Verifies a range proof one node at a time, as the nodes of a streamed proof arrive.
+///
+/// Only the hashes of the nodes still expected are kept, a few per level of the tree, so
+/// proofs of ranges far larger than memory can be verified. Entries are returned as soon
+/// as the leaf holding them is verified, and are only complete once `finish` succeeds.
+#[derive(Clone, Debug)]
+pub struct RangeVerifier<const N: usize> {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    /// Hashes of the nodes still expected; the next one is last.
+    pending: Vec<ValueDigest<N>>,
+    failed: bool,
+}
+
+impl<const N: usize> RangeVerifier<N> {
+    /// Creates a verifier for the proof of a key range of the tree with the given root hash.
+    pub fn new(root_hash: ValueDigest<N>, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        RangeVerifier {
+            start,
+            end,
+            pending: vec![root_hash],
+            failed: false,
+        }
+    }
+
+    /// Checks the next node of the proof.
+    ///
+    /// # Returns
+    /// - The entries of the range held by the node, empty for internal nodes, or `None` if
+    ///   the node is not the one expected. The verifier refuses every node after a failure.
+    pub fn push(&mut self, node: &ProofNode) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+        let expected = self.pending.pop().filter(|_| !self.failed);
+        if !expected.is_some_and(|hash| well_formed(node, &hash)) {
+            self.failed = true;
+            return None;
+        }
+        if node.is_leaf {
+            let range = (&self.start, &self.end);
+            return Some(
+                node.keys
+                    .iter()
+                    .zip(&node.values)
+                    .filter(|(key, _)| contains(range, key))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect(),
+            );
+        }
+        // pushed in reverse so that children are expected in key order
+        for (i, child) in node.values.iter().enumerate().rev() {
+            if child_overlaps(&node.keys, i, &self.start, &self.end) {
+                self.pending.push(ValueDigest::raw_hash(child));
+            }
+        }
+        Some(Vec::new())
+    }
+
+    /// Returns `true` if every node of the proof was received and verified.
+    pub fn finish(self) -> bool {
+        !self.failed && self.pending.is_empty()
+    }
+}
+
+/// Looks up keys in the nodes of a multiproof.
+///
+/// # Parameters
+/// - `root_hash`: The trusted root hash of the tree.
+/// - `keys`: The proven keys, in ascending order without duplicates.
+/// - `nodes`: The nodes on the paths of the keys, parents before their children.
+///
+/// # Returns
+/// - Every key with its value as stored in the leaves, or `None` if it is absent; or
+///   `None` if the nodes do not match `root_hash` or one is missing.
+pub fn key_entries<const N: usize>(
+    root_hash: &ValueDigest<N>,
+    keys: &[Vec<u8>],
+    nodes: &[ProofNode],
+) -> Option<Vec<KeyProof>> {
+    fn visit<'a, const N: usize>(
+        hash: &ValueDigest<N>,
+        keys: &[Vec<u8>],
+        nodes: &mut impl Iterator<Item = &'a ProofNode>,
+        entries: &mut Vec<KeyProof>,
+    ) -> Option<()> {
+        let node = nodes.next().filter(|node| well_formed(node, hash))?;
+        if node.is_leaf {
+            for key in keys {
+                let value = node
+                    .keys
+                    .iter()
+                    .position(|k| k == key)
+                    .map(|i| node.values[i].clone());
+                entries.push((key.clone(), value));
+            }
+        } else {
+            for (i, keys) in route(&node.keys, keys) {
+                visit(
+                    &ValueDigest::<N>::raw_hash(&node.values[i]),
+                    keys,
+                    nodes,
+                    entries,
+                )?;
+            }
+        }
+        Some(())
+    }
+
+    if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return None;
+    }
+    let mut nodes = nodes.iter();
+    let mut entries = Vec::with_capacity(keys.len());
+    visit(root_hash, keys, &mut nodes, &mut entries)?;
+    nodes.next().is_none().then_some(entries)
+}
+
+/// Verifies the value of a single key, proven by the nodes on its path.
+///
+/// # Parameters
+/// - `root_hash`: The trusted root hash of the tree.
+/// - `key`: The proven key.
+/// - `expected_value`: The value the key is expected to have as stored in the leaves, or
+///   `None` to verify that the key is absent.
+/// - `nodes`: The nodes from the root to the leaf of the key.
+pub fn verify_key<const N: usize>(
+    root_hash: &ValueDigest<N>,
+    key: &[u8],
+    expected_value: Option<&[u8]>,
+    nodes: &[ProofNode],
+) -> bool {
+    key_entries(root_hash, &[key.to_vec()], nodes)
+        .is_some_and(|entries| entries[0].1.as_deref() == expected_value)
+}
+
+/// Verifies the total number of keys of a tree from its root node.
+///
+/// Internal nodes commit to the number of keys below every child, so the count of a tree
+/// is the sum of the counts of its root. The count of a tree whose root is a leaf is its
+/// number of keys, which the leaf hash commits to as well.
+///
+/// # Parameters
+/// - `root_hash`: The trusted root hash of the tree.
+/// - `root`: The root node of the tree.
+/// - `count`: The claimed number of keys.
+pub fn verify_count<const N: usize>(
+    root_hash: &ValueDigest<N>,
+    root: &ProofNode,
+    count: u64,
+) -> bool {
+    let total = if root.is_leaf {
+        root.keys.len() as u64
+    } else {
+        root.counts.iter().sum()
+    };
+    well_formed(root, root_hash) && total == count
+}
+
+/// Splits sorted keys into groups routed to the same child of an internal node with the
+/// given keys.
+///
+/// # Returns
+/// - The index of every child at least one key is routed to, with those keys.
+pub(crate) fn route<'k>(node_keys: &[Vec<u8>], keys: &'k [Vec<u8>]) -> Vec<(usize, &'k [Vec<u8>])> {
+    let child_index = |key: &[u8]| node_keys.iter().rposition(|k| key >= &k[..]).unwrap_or(0);
+    let mut groups = Vec::new();
+    let mut rest = keys;
+    while let Some(first) = rest.first() {
+        let i = child_index(first);
+        let len = rest.partition_point(|key| child_index(key) == i);
+        groups.push((i, &rest[..len]));
+        rest = &rest[len..];
+    }
+    groups
+}
+
+/// Returns `true` if the subtree under child `i` of an internal node with the given keys
+/// may hold keys within the range. The first child is assumed to extend below the first
+/// key of the node.
+pub(crate) fn child_overlaps(
+    node_keys: &[Vec<u8>],
+    i: usize,
+    start: &Bound<Vec<u8>>,
+    end: &Bound<Vec<u8>>,
+) -> bool {
+    let after_start = match (node_keys.get(i + 1), start) {
+        (Some(next), Bound::Included(start) | Bound::Excluded(start)) => next > start,
+        _ => true,
+    };
+    let before_end = match (i, end) {
+        (0, _) | (_, Bound::Unbounded) => true,
+        (_, Bound::Included(end)) => node_keys[i] <= *end,
+        (_, Bound::Excluded(end)) => node_keys[i] < *end,
+    };
+    after_start && before_end
+}
+
+/// Returns `true` if the key lies within the range.
+pub(crate) fn contains((start, end): (&Bound<Vec<u8>>, &Bound<Vec<u8>>), key: &[u8]) -> bool {
+    let after_start = match start {
+        Bound::Included(start) => key >= start.as_slice(),
+        Bound::Excluded(start) => key > start.as_slice(),
+        Bound::Unbounded => true,
+    };
+    let before_end = match end {
+        Bound::Included(end) => key <= end.as_slice(),
+        Bound::Excluded(end) => key < end.as_slice(),
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+/// Checks that a node of a proof has the expected hash and the shape of a tree node: as
+/// many keys as values, child hashes of the right size and subtree counts only in
+/// internal nodes.
+fn well_formed<const N: usize>(node: &ProofNode, hash: &ValueDigest<N>) -> bool {
+    let shaped = if node.is_leaf {
+        node.counts.is_empty()
+    } else {
+        node.counts.len() == node.values.len() && node.values.iter().all(|v| v.len() == N)
+    };
+    shaped && node.keys.len() == node.values.len() && node.hash::<N>() == *hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(keys: &[u8]) -> ProofNode {
+        ProofNode {
+            keys: keys.iter().map(|k| vec![*k]).collect(),
+            values: keys.iter().map(|k| vec![k * 2]).collect(),
+            counts: Vec::new(),
+            is_leaf: true,
+            hash_algorithm: HashAlgorithm::Sha256,
+        }
+    }
+
+    #[test]
+    fn test_verify_without_tree() {
+        let leaves = [leaf(&[1, 2, 3]), leaf(&[5, 8])];
+        let root = ProofNode {
+            keys: vec![vec![1], vec![5]],
+            values: leaves
+                .iter()
+                .map(|leaf| leaf.hash::<32>().as_bytes().to_vec())
+                .collect(),
+            counts: vec![3, 2],
+            is_leaf: false,
+            hash_algorithm: HashAlgorithm::Sha256,
+        };
+        let root_hash = root.hash::<32>();
+
+        let path = [root.clone(), leaves[1].clone()];
+        assert!(verify_key(&root_hash, &[8], Some(&[16]), &path));
+        assert!(verify_key(&root_hash, &[6], None, &path));
+        assert!(!verify_key(&root_hash, &[8], None, &path));
+        assert!(!verify_key(&root_hash, &[2], Some(&[4]), &path));
+
+        assert!(verify_count(&root_hash, &root, 5));
+        assert!(!verify_count(&root_hash, &root, 4));
+        assert!(!verify_count(&root_hash, &leaves[0], 3));
+
+        let nodes = [root.clone(), leaves[0].clone(), leaves[1].clone()];
+        assert_eq!(
+            range_entries(
+                &root_hash,
+                &Bound::Included(vec![3]),
+                &Bound::Excluded(vec![8]),
+                &nodes
+            ),
+            Some(vec![(vec![3], vec![6]), (vec![5], vec![10])])
+        );
+        // the range reaches into the second leaf, so it cannot be left out
+        assert_eq!(
+            range_entries(
+                &root_hash,
+                &Bound::Included(vec![3]),
+                &Bound::Excluded(vec![8]),
+                &nodes[..2]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resplit_entries_rejected() {
+        for hash_algorithm in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Sha3_256,
+            HashAlgorithm::Keccak256,
+        ] {
+            let leaf = ProofNode {
+                keys: vec![vec![1, 2], vec![5]],
+                values: vec![vec![3], vec![6]],
+                counts: Vec::new(),
+                is_leaf: true,
+                hash_algorithm,
+            };
+            let root_hash = leaf.hash::<32>();
+            assert!(verify_key(
+                &root_hash,
+                &[1, 2],
+                Some(&[3]),
+                std::slice::from_ref(&leaf)
+            ));
+
+            // the same bytes split differently between keys and values, or entries
+            let forged = [
+                (vec![vec![1], vec![5]], vec![vec![2, 3], vec![6]]),
+                (vec![vec![1, 2, 3]], vec![vec![5], vec![6]]),
+                (vec![vec![1, 2], vec![3, 5]], vec![Vec::new(), vec![6]]),
+            ];
+            for (keys, values) in forged {
+                let forged = ProofNode {
+                    keys: keys.clone(),
+                    values,
+                    ..leaf.clone()
+                };
+                assert_ne!(forged.hash::<32>(), root_hash);
+                assert!(!verify_key(
+                    &root_hash,
+                    &keys[0],
+                    None,
+                    std::slice::from_ref(&forged)
+                ));
+                assert!(!verify_count(&root_hash, &forged, keys.len() as u64));
+            }
+        }
+    }
+}
@@ -0,0 +1,377 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Layouts of keys and values.
+//!
+//! A tree configured with a [`KeyFormat`] rejects keys that do not match it and uses it
+//! to print keys in a readable form. A [`ValueFormat`] likewise rejects values that do
+//! not decode, and lets values be read and written as Rust types.
+
+use crate::errors::Error;
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The layout of the keys of a tree.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum KeyFormat {
+    /// Any sequence of bytes, printed as hex.
+    Bytes,
+    /// UTF-8 encoded text.
+    Utf8,
+    /// Exactly the given number of bytes, printed as hex.
+    FixedWidth(usize),
+    /// An unsigned big-endian integer of the given number of bytes, at most 16.
+    UInt(usize),
+    /// A signed two's complement big-endian integer of the given number of bytes, at
+    /// most 16.
+    Int(usize),
+    /// Components stored one after the other. Every component except the last must have
+    /// a fixed width; the last one takes the remaining bytes.
+    Tuple(Vec<KeyFormat>),
+}
+
+impl KeyFormat {
+    /// Returns the number of bytes of the keys, or `None` if their length varies.
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            KeyFormat::Bytes | KeyFormat::Utf8 => None,
+            KeyFormat::FixedWidth(width) | KeyFormat::UInt(width) | KeyFormat::Int(width) => {
+                Some(*width)
+            }
+            KeyFormat::Tuple(components) => components.iter().map(KeyFormat::width).sum(),
+        }
+    }
+
+    /// Checks that a key matches the format.
+    ///
+    /// # Returns
+    /// - `Error::InvalidKey` describing the mismatch, if any.
+    pub fn validate(&self, key: &[u8]) -> Result<(), Error> {
+        self.split(key).map(|_| ())
+    }
+
+    /// Formats a key for display, or as hex if it does not match the format.
+    pub fn format(&self, key: &[u8]) -> String {
+        self.format_checked(key)
+            .unwrap_or_else(|_| hex::encode(key))
+    }
+
+    /// Splits a key into its formatted components.
+    fn split(&self, key: &[u8]) -> Result<Vec<String>, Error> {
+        let invalid = |reason: String| Err(Error::InvalidKey(reason));
+        match self {
+            KeyFormat::Bytes => Ok(vec![hex::encode(key)]),
+            KeyFormat::Utf8 => match std::str::from_utf8(key) {
+                Ok(text) => Ok(vec![format!("{:?}", text)]),
+                Err(_) => invalid("key is not valid UTF-8".to_string()),
+            },
+            KeyFormat::FixedWidth(width) | KeyFormat::UInt(width) | KeyFormat::Int(width)
+                if key.len() != *width =>
+            {
+                invalid(format!("expected {} bytes, got {}", width, key.len()))
+            }
+            KeyFormat::FixedWidth(_) => Ok(vec![hex::encode(key)]),
+            KeyFormat::UInt(width) | KeyFormat::Int(width) if *width > 16 => {
+                invalid(format!("integers of {} bytes are not supported", width))
+            }
+            KeyFormat::UInt(_) => Ok(vec![to_u128(key).to_string()]),
+            KeyFormat::Int(_) => {
+                // sign extend from the width of the key to 128 bits
+                let shift = 128 - 8 * key.len() as u32;
+                let value = (to_u128(key) as i128).checked_shl(shift).unwrap_or(0) >> shift;
+                Ok(vec![value.to_string()])
+            }
+            KeyFormat::Tuple(components) => {
+                let mut parts = Vec::with_capacity(components.len());
+                let mut rest = key;
+                for (i, component) in components.iter().enumerate() {
+                    let len = match component.width() {
+                        Some(width) if width <= rest.len() => width,
+                        Some(width) => {
+                            return invalid(format!(
+                                "component {} needs {} bytes, {} left",
+                                i,
+                                width,
+                                rest.len()
+                            ))
+                        }
+                        None if i + 1 == components.len() => rest.len(),
+                        None => {
+                            return invalid(format!(
+                                "component {} has no fixed width and is not the last",
+                                i
+                            ))
+                        }
+                    };
+                    let (part, tail) = rest.split_at(len);
+                    parts.push(component.format_checked(part)?);
+                    rest = tail;
+                }
+                if !rest.is_empty() {
+                    return invalid(format!("{} trailing bytes", rest.len()));
+                }
+                Ok(parts)
+            }
+        }
+    }
+
+    /// Formats a key, failing if it does not match the format.
+    fn format_checked(&self, key: &[u8]) -> Result<String, Error> {
+        let parts = self.split(key)?;
+        Ok(match self {
+            KeyFormat::Tuple(_) => format!("({})", parts.join(", ")),
+            _ => parts.concat(),
+        })
+    }
+}
+
+/// The encoding of the values of a tree.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum ValueFormat {
+    /// Any sequence of bytes.
+    Bytes,
+    /// JSON documents. If the tree has a `value_schema`, values must also match it.
+    Json,
+    /// Values serialized with bincode, e.g. fixed structs.
+    Bincode,
+    /// Exactly the given number of bytes.
+    FixedWidth(usize),
+}
+
+impl ValueFormat {
+    /// Checks that a value matches the format, and for JSON values the schema if any.
+    ///
+    /// Only the parts of JSON Schema that describe the shape of a document are checked:
+    /// types, properties, required properties, array items, enumerations, references and
+    /// subschemas.
+    ///
+    /// # Returns
+    /// - `Error::InvalidValue` describing the mismatch, if any.
+    pub fn validate(&self, value: &[u8], schema: Option<&RootSchema>) -> Result<(), Error> {
+        match self {
+            ValueFormat::Bytes | ValueFormat::Bincode => Ok(()),
+            ValueFormat::FixedWidth(width) if value.len() != *width => Err(Error::InvalidValue(
+                format!("expected {} bytes, got {}", width, value.len()),
+            )),
+            ValueFormat::FixedWidth(_) => Ok(()),
+            ValueFormat::Json => {
+                let document: Value = serde_json::from_slice(value)
+                    .map_err(|err| Error::InvalidValue(err.to_string()))?;
+                match schema {
+                    Some(root) => {
+                        check_json(&document, &root.schema, root, "$").map_err(Error::InvalidValue)
+                    }
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Serializes a value in this format.
+    ///
+    /// # Returns
+    /// - The encoded value, or `Error::UnsupportedValueType` if the format does not
+    ///   describe a serialization.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            ValueFormat::Json => serde_json::to_vec(value).map_err(|_| Error::Serde),
+            ValueFormat::Bincode => bincode::serialize(value).map_err(|_| Error::Serde),
+            ValueFormat::Bytes | ValueFormat::FixedWidth(_) => Err(Error::UnsupportedValueType),
+        }
+    }
+
+    /// Deserializes a value written by `encode`.
+    pub fn decode<T: DeserializeOwned>(&self, value: &[u8]) -> Result<T, Error> {
+        match self {
+            ValueFormat::Json => serde_json::from_slice(value).map_err(|_| Error::Serde),
+            ValueFormat::Bincode => bincode::deserialize(value).map_err(|_| Error::Serde),
+            ValueFormat::Bytes | ValueFormat::FixedWidth(_) => Err(Error::UnsupportedValueType),
+        }
+    }
+}
+
+/// Checks a JSON document against a schema, returning where and how it does not match.
+fn check_json(
+    value: &Value,
+    schema: &SchemaObject,
+    root: &RootSchema,
+    path: &str,
+) -> Result<(), String> {
+    if let Some(reference) = &schema.reference {
+        let name = reference.trim_start_matches("#/definitions/");
+        return match root.definitions.get(name) {
+            Some(definition) => check_json_schema(value, definition, root, path),
+            None => Err(format!("{}: unknown reference {}", path, reference)),
+        };
+    }
+
+    if let Some(types) = &schema.instance_type {
+        let types: &[InstanceType] = match types {
+            SingleOrVec::Single(single) => std::slice::from_ref(single.as_ref()),
+            SingleOrVec::Vec(types) => types,
+        };
+        if !types
+            .iter()
+            .any(|instance_type| is_instance(value, instance_type))
+        {
+            return Err(format!("{}: expected {:?}", path, types));
+        }
+    }
+
+    if let Some(values) = &schema.enum_values {
+        if !values.contains(value) {
+            return Err(format!("{}: not one of the allowed values", path));
+        }
+    }
+
+    if let (Some(object), Value::Object(map)) = (&schema.object, value) {
+        if let Some(missing) = object.required.iter().find(|name| !map.contains_key(*name)) {
+            return Err(format!("{}: missing property {}", path, missing));
+        }
+        for (name, property) in &object.properties {
+            if let Some(field) = map.get(name) {
+                check_json_schema(field, property, root, &format!("{}.{}", path, name))?;
+            }
+        }
+    }
+
+    if let (Some(array), Value::Array(items)) = (&schema.array, value) {
+        if let Some(SingleOrVec::Single(item_schema)) = &array.items {
+            for (i, item) in items.iter().enumerate() {
+                check_json_schema(item, item_schema, root, &format!("{}[{}]", path, i))?;
+            }
+        }
+    }
+
+    if let Some(subschemas) = &schema.subschemas {
+        if let Some(all_of) = &subschemas.all_of {
+            for subschema in all_of {
+                check_json_schema(value, subschema, root, path)?;
+            }
+        }
+        for alternatives in [&subschemas.any_of, &subschemas.one_of]
+            .into_iter()
+            .flatten()
+        {
+            let matched = alternatives
+                .iter()
+                .any(|subschema| check_json_schema(value, subschema, root, path).is_ok());
+            if !matched {
+                return Err(format!("{}: matches none of the alternatives", path));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_json_schema(
+    value: &Value,
+    schema: &Schema,
+    root: &RootSchema,
+    path: &str,
+) -> Result<(), String> {
+    match schema {
+        Schema::Bool(true) => Ok(()),
+        Schema::Bool(false) => Err(format!("{}: no value is allowed", path)),
+        Schema::Object(object) => check_json(value, object, root, path),
+    }
+}
+
+fn is_instance(value: &Value, instance_type: &InstanceType) -> bool {
+    match instance_type {
+        InstanceType::Null => value.is_null(),
+        InstanceType::Boolean => value.is_boolean(),
+        InstanceType::Object => value.is_object(),
+        InstanceType::Array => value.is_array(),
+        InstanceType::Number => value.is_number(),
+        InstanceType::String => value.is_string(),
+        InstanceType::Integer => value.is_i64() || value.is_u64(),
+    }
+}
+
+fn to_u128(bytes: &[u8]) -> u128 {
+    bytes
+        .iter()
+        .fold(0u128, |value, byte| (value << 8) | u128::from(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_integers() {
+        assert_eq!(KeyFormat::UInt(2).format(&[1, 0]), "256");
+        assert_eq!(KeyFormat::Int(2).format(&[0xff, 0xfe]), "-2");
+        assert_eq!(KeyFormat::Int(1).format(&[0x7f]), "127");
+        assert_eq!(KeyFormat::Int(16).format(&[0xff; 16]), "-1");
+        assert!(KeyFormat::UInt(4).validate(&[0, 1]).is_err());
+        assert!(KeyFormat::UInt(17).validate(&[0; 17]).is_err());
+    }
+
+    #[test]
+    fn test_json_values() {
+        #[derive(Serialize, Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+        struct Memory {
+            text: String,
+            score: Option<f64>,
+            tags: Vec<String>,
+        }
+
+        let schema = schemars::schema_for!(Memory);
+        let format = ValueFormat::Json;
+        let memory = Memory {
+            text: "likes tea".to_string(),
+            score: None,
+            tags: vec!["preference".to_string()],
+        };
+        let encoded = format.encode(&memory).unwrap();
+        assert!(format.validate(&encoded, Some(&schema)).is_ok());
+        assert_eq!(format.decode::<Memory>(&encoded).unwrap(), memory);
+
+        let invalid = [
+            &br#"{"text": "no tags"}"#[..],
+            br#"{"text": 1, "tags": []}"#,
+            br#"{"text": "x", "tags": [1]}"#,
+            br#"{"text": "x", "tags": [], "score": "high"}"#,
+            b"not json",
+        ];
+        for value in invalid {
+            assert!(matches!(
+                format.validate(value, Some(&schema)),
+                Err(Error::InvalidValue(_))
+            ));
+        }
+        assert!(format.validate(b"not json", None).is_err());
+        assert!(format.validate(b"[1, 2]", None).is_ok());
+    }
+
+    #[test]
+    fn test_tuple() {
+        let format = KeyFormat::Tuple(vec![KeyFormat::UInt(4), KeyFormat::Utf8]);
+        assert_eq!(format.width(), None);
+        let key = [&7u32.to_be_bytes()[..], b"memory"].concat();
+        assert!(format.validate(&key).is_ok());
+        assert_eq!(format.format(&key), "(7, \"memory\")");
+        assert!(format.validate(&[0, 0, 7]).is_err());
+        assert!(format.validate(&[0, 0, 0, 7, 0xff]).is_err());
+
+        // a variable width component must come last
+        let format = KeyFormat::Tuple(vec![KeyFormat::Utf8, KeyFormat::UInt(4)]);
+        assert!(format.validate(b"abcd1234").is_err());
+        assert_eq!(format.format(&[0xab]), "ab");
+    }
+}
@@ -0,0 +1,122 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use crate::blob::blobs_enabled;
+use crate::config::TreeConfig;
+use crate::diff::load_root;
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::iter::{Cursor, TreeIter};
+use crate::node::ProllyNode;
+use crate::pin::{PinGuard, RootPins};
+use crate::storage::{NodeReader, ReadOnlyStorage};
+use crate::ttl;
+use std::ops::RangeBounds;
+
+/// A read-only view of a prolly tree pinned to a root hash.
+///
+/// Nodes are immutable and addressed by their hash, so mutations of the tree a snapshot
+/// was taken from only ever add new nodes. The snapshot keeps reading the nodes reachable
+/// from its own root and is not affected by later writes to the tree.
+pub struct Snapshot<const N: usize, S: NodeReader<N>> {
+    root: ProllyNode<N>,
+    storage: S,
+    blobs: bool,
+    expiring: bool,
+    pin: Option<PinGuard<N>>,
+}
+
+impl<const N: usize, S: NodeReader<N>> Snapshot<N, S> {
+    pub(crate) fn new(root: ProllyNode<N>, storage: S, blobs: bool, expiring: bool) -> Self {
+        Snapshot {
+            root,
+            storage,
+            blobs,
+            expiring,
+            pin: None,
+        }
+    }
+
+    /// Pins the root hash of the snapshot in `pins` for as long as the snapshot lives, so
+    /// that its nodes are preserved while it is read.
+    pub fn pinned(mut self, pins: &RootPins<N>) -> Self {
+        self.pin = Some(pins.guard(self.root_hash()));
+        self
+    }
+
+    /// Returns the root hash the snapshot is pinned to.
+    pub fn root_hash(&self) -> ValueDigest<N> {
+        self.root.get_hash()
+    }
+
+    /// Returns the value of a key as of the snapshot.
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.cursor()
+            .seek(key)
+            .filter(|(found, _)| found == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the number of key-value pairs in the snapshot, including expired ones.
+    pub fn len(&self) -> usize {
+        self.root.subtree_count(&self.storage) as usize
+    }
+
+    /// Returns `true` if the snapshot contains no key-value pairs.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over all key-value pairs in ascending key order.
+    pub fn iter(&self) -> TreeIter<'_, N, S> {
+        self.scan(..)
+    }
+
+    /// Returns an iterator over the key-value pairs within a key range in ascending order.
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> TreeIter<'_, N, S> {
+        TreeIter::new(&self.root, &self.storage, range, false)
+            .with_blobs(self.blobs)
+            .with_expiry(self.expiring.then(ttl::now))
+    }
+
+    /// Creates a cursor over the snapshot.
+    pub fn cursor(&self) -> Cursor<'_, N, S> {
+        Cursor::new(&self.root, &self.storage)
+            .with_blobs(self.blobs)
+            .with_expiry(self.expiring.then(ttl::now))
+    }
+}
+
+impl<const N: usize, S: NodeReader<N>> Snapshot<N, ReadOnlyStorage<S>> {
+    /// Opens the version of a tree last saved to a storage with `Tree::save_config`,
+    /// with no way of writing to the storage.
+    ///
+    /// # Returns
+    /// - The snapshot, `Error::Serde` if the storage holds no tree config with a root
+    ///   hash, or `Error::MissingNode` if the root node is not in the storage.
+    pub fn open(storage: S) -> Result<Self, Error> {
+        let config: TreeConfig<N> = storage
+            .get_config("tree_config")
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .ok_or(Error::Serde)?;
+        let root_hash = config.root_hash.clone().ok_or(Error::Serde)?;
+        let root = load_root(&storage, &root_hash)?;
+        Ok(Snapshot::new(
+            root,
+            ReadOnlyStorage::new(storage),
+            blobs_enabled(&config),
+            config.expiring_keys,
+        ))
+    }
+}
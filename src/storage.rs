@@ -12,25 +12,44 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+#[cfg(feature = "lmdb")]
+pub mod lmdb;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "object_store")]
+pub mod object_store;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "redb")]
+pub mod redb;
+#[cfg(feature = "sled")]
+pub mod sled;
+pub mod tiered;
+
+use crate::compression::{original_hash, Compression, StorageManifest};
 use crate::digest::ValueDigest;
+use crate::errors::Error;
 use crate::node::ProllyNode;
+use crate::pin::reachable_nodes;
+use crate::sync::{StorageTransport, SyncSession};
+use lru::LruCache;
 use std::collections::HashMap;
 use std::fmt;
 use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-/// A trait for storage of nodes in the ProllyTree.
+/// A trait for reading the nodes of a ProllyTree from storage.
 ///
-/// This trait defines the necessary operations for managing the storage
-/// of nodes within a ProllyTree. Implementors of this trait can provide
-/// custom storage backends, such as in-memory storage, database storage,
-/// or any other form of persistent storage.
+/// Every `NodeStorage` is a `NodeReader`. Storages that must not be written to, such as
+/// `ReadOnlyStorage`, only implement this trait, and can be read through the types that
+/// never write, such as `Snapshot`, `TreeIter` and `Cursor`.
 ///
 /// # Type Parameters
 ///
 /// - `N`: The size of the value digest.
-pub trait NodeStorage<const N: usize>: Send + Sync {
+pub trait NodeReader<const N: usize>: Send + Sync {
     /// Retrieves a node from storage by its hash.
     ///
     /// # Arguments
@@ -42,6 +61,51 @@ pub trait NodeStorage<const N: usize>: Send + Sync {
     /// The node associated with the given hash.
     fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>>;
 
+    /// Retrieves a node from storage by its hash, telling a missing node apart from a node
+    /// that cannot be read.
+    ///
+    /// The default implementation cannot tell them apart and returns `Ok(None)` for both.
+    ///
+    /// # Arguments
+    ///
+    /// * `hash` - A reference to the `ValueDigest` representing the hash of the node to retrieve.
+    ///
+    /// # Returns
+    ///
+    /// The node, `None` if it is missing, `Error::CorruptNode` with the hash and location
+    /// of a node whose bytes fail their checksum or cannot be decoded, or the error of the
+    /// backend.
+    fn try_get_node(&self, hash: &ValueDigest<N>) -> Result<Option<ProllyNode<N>>, Error> {
+        Ok(self.get_node_by_hash(hash))
+    }
+
+    /// Hints that the nodes with the given hashes are about to be read, e.g. by a scan,
+    /// so that backends with slow reads can fetch them ahead of time.
+    ///
+    /// The default implementation does nothing.
+    ///
+    /// # Arguments
+    ///
+    /// * `hashes` - The hashes of the nodes, in the order they are going to be read.
+    fn prefetch(&self, hashes: &[ValueDigest<N>]) {
+        let _ = hashes;
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>>;
+}
+
+/// A trait for storage of nodes in the ProllyTree.
+///
+/// This trait defines the necessary operations for managing the storage
+/// of nodes within a ProllyTree. Implementors of this trait can provide
+/// custom storage backends, such as in-memory storage, database storage,
+/// or any other form of persistent storage. Nodes are read through the
+/// `NodeReader` supertrait.
+///
+/// # Type Parameters
+///
+/// - `N`: The size of the value digest.
+pub trait NodeStorage<const N: usize>: NodeReader<N> {
     /// Inserts a node into storage.
     ///
     /// # Arguments
@@ -57,19 +121,71 @@ pub trait NodeStorage<const N: usize>: Send + Sync {
     /// * `hash` - A reference to the `ValueDigest` representing the hash of the node to delete.
     fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()>;
 
+    /// Inserts several nodes at once.
+    ///
+    /// Backends with transactions write the batch atomically, so that either every node
+    /// or none of them is stored. The default implementation inserts the nodes one by
+    /// one and stops at the first failure; since nodes are addressed by their hash, a
+    /// partially applied batch can simply be written again.
+    ///
+    /// # Arguments
+    ///
+    /// * `nodes` - The hashes and nodes to insert.
+    ///
+    /// # Returns
+    ///
+    /// `Error::WriteFailed` with the hash of a node that could not be written, or the
+    /// error of the backend.
+    fn put_batch(&mut self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> Result<(), Error> {
+        for (hash, node) in nodes {
+            let hex = hex::encode(hash.as_bytes());
+            self.insert_node(hash, node)
+                .ok_or(Error::WriteFailed(hex))?;
+        }
+        Ok(())
+    }
+
     fn save_config(&self, key: &str, config: &[u8]);
-    fn get_config(&self, key: &str) -> Option<Vec<u8>>;
 }
 
 /// An implementation of `NodeStorage` that stores nodes in a HashMap.
 ///
+/// The storage is unbounded unless it is given a byte budget with `with_max_bytes`. A
+/// bounded storage evicts the least recently used nodes once it exceeds its budget, but
+/// only nodes marked with `mark_persisted` as stored elsewhere, so that it can serve as a
+/// cache in front of another storage without losing nodes that exist nowhere else.
+///
+/// Nodes are held behind `Arc`s, so a clone of the storage shares them with the original:
+/// cloning copies one handle per node, which is proportional to the number of nodes but
+/// not to their size.
+///
 /// # Type Parameters
 ///
 /// - `N`: The size of the value digest.
-#[derive(Clone)]
 pub struct InMemoryNodeStorage<const N: usize> {
-    map: HashMap<ValueDigest<N>, ProllyNode<N>>,
+    map: HashMap<ValueDigest<N>, Arc<ProllyNode<N>>>,
     configs: HashMap<String, Vec<u8>>,
+    max_bytes: Option<usize>,
+    /// The encoded size of all nodes, tracked only for bounded storages.
+    bytes: usize,
+    /// The nodes that may be evicted, from the least to the most recently used.
+    evictable: Mutex<LruCache<ValueDigest<N>, ()>>,
+}
+
+impl<const N: usize> Clone for InMemoryNodeStorage<N> {
+    fn clone(&self) -> Self {
+        let mut evictable = LruCache::unbounded();
+        for (hash, _) in self.evictable.lock().unwrap().iter().rev() {
+            evictable.put(hash.clone(), ());
+        }
+        InMemoryNodeStorage {
+            map: self.map.clone(),
+            configs: self.configs.clone(),
+            max_bytes: self.max_bytes,
+            bytes: self.bytes,
+            evictable: Mutex::new(evictable),
+        }
+    }
 }
 
 impl<const N: usize> Default for InMemoryNodeStorage<N> {
@@ -83,22 +199,120 @@ impl<const N: usize> InMemoryNodeStorage<N> {
         InMemoryNodeStorage {
             map: HashMap::new(),
             configs: HashMap::new(),
+            max_bytes: None,
+            bytes: 0,
+            evictable: Mutex::new(LruCache::unbounded()),
+        }
+    }
+
+    /// Bounds the encoded size of the nodes held by the storage.
+    ///
+    /// The budget is only enforced by evicting nodes marked as persisted, so the storage
+    /// exceeds it while it holds more nodes that exist nowhere else.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self.bytes = self.map.values().map(|node| node_size(node)).sum();
+        self.evict();
+        self
+    }
+
+    /// Returns the encoded size of the nodes held by a bounded storage, or `None` if the
+    /// storage is unbounded.
+    pub fn size_in_bytes(&self) -> Option<usize> {
+        self.max_bytes.map(|_| self.bytes)
+    }
+
+    /// Marks the nodes reachable from a root, e.g. of a version written to another
+    /// storage, as persisted elsewhere, so that a bounded storage may evict them.
+    ///
+    /// # Parameters
+    /// - `root_hash`: The hash of the root node of the persisted version.
+    /// - `expiring`: Whether the values of the tree carry expiry timestamps, which is
+    ///   needed to find the chunks of values stored outside of the leaves.
+    pub fn mark_persisted(&mut self, root_hash: &ValueDigest<N>, expiring: bool) {
+        let reachable = reachable_nodes(&*self, vec![root_hash.clone()], expiring);
+        let evictable = self.evictable.get_mut().unwrap();
+        for hash in reachable {
+            if self.map.contains_key(&hash) {
+                evictable.put(hash, ());
+            }
+        }
+        self.evict();
+    }
+
+    /// Evicts the least recently used evictable nodes until the storage is within its
+    /// budget or holds no more evictable nodes.
+    fn evict(&mut self) {
+        let Some(max_bytes) = self.max_bytes else {
+            return;
+        };
+        let evictable = self.evictable.get_mut().unwrap();
+        while self.bytes > max_bytes {
+            let Some((hash, _)) = evictable.pop_lru() else {
+                return;
+            };
+            if let Some(node) = self.map.remove(&hash) {
+                self.bytes -= node_size(&node);
+            }
         }
     }
 }
 
-impl<const N: usize> NodeStorage<N> for InMemoryNodeStorage<N> {
+/// Decodes the bytes of a node read from a backend, reporting corrupt bytes as
+/// `Error::CorruptNode` at `location`.
+pub(crate) fn decode_node<const N: usize>(
+    data: &[u8],
+    hash: &ValueDigest<N>,
+    location: impl fmt::Display,
+) -> Result<ProllyNode<N>, Error> {
+    ProllyNode::decode(data).map_err(|err| match err {
+        Error::UnknownCodec => err,
+        _ => Error::CorruptNode {
+            hash: hex::encode(hash.as_bytes()),
+            location: location.to_string(),
+        },
+    })
+}
+
+/// The size of a node as counted against the budget of a bounded `InMemoryNodeStorage`.
+fn node_size<const N: usize>(node: &ProllyNode<N>) -> usize {
+    bincode::serialized_size(node).unwrap_or(0) as usize
+}
+
+impl<const N: usize> NodeReader<N> for InMemoryNodeStorage<N> {
     fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
-        self.map.get(hash).cloned()
+        let node = self.map.get(hash)?;
+        if self.max_bytes.is_some() {
+            self.evictable.lock().unwrap().promote(hash);
+        }
+        Some(ProllyNode::clone(node))
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        self.configs.get(key).cloned()
     }
+}
 
+impl<const N: usize> NodeStorage<N> for InMemoryNodeStorage<N> {
     fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
-        self.map.insert(hash, node);
+        if self.max_bytes.is_none() {
+            self.map.insert(hash, Arc::new(node));
+            return Some(());
+        }
+        self.bytes += node_size(&node);
+        if let Some(old) = self.map.insert(hash, Arc::new(node)) {
+            self.bytes -= node_size(&old);
+        }
+        self.evict();
         Some(())
     }
 
     fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
-        self.map.remove(hash);
+        let node = self.map.remove(hash);
+        self.evictable.get_mut().unwrap().pop(hash);
+        if let Some(node) = node.filter(|_| self.max_bytes.is_some()) {
+            self.bytes -= node_size(&node);
+        }
         Some(())
     }
 
@@ -106,24 +320,172 @@ impl<const N: usize> NodeStorage<N> for InMemoryNodeStorage<N> {
         let mut configs = self.configs.clone();
         configs.insert(key.to_string(), config.to_vec());
     }
-
-    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
-        self.configs.get(key).cloned()
-    }
 }
 
+/// An implementation of `NodeStorage` that stores every node in a file named after its
+/// hash, and configs in files next to them.
+///
+/// Stores written by the first releases of the crate keep their nodes in a single
+/// directory, in the original node layout and under the original node hash, which did not
+/// commit to subtree counts or to the lengths of keys and values. `FileNodeStorage::new`
+/// converts such stores in place: their nodes are rehashed, their child pointers and the
+/// root hash of the saved tree config are rewritten, and the nodes are moved into their
+/// subdirectories. Root hashes recorded elsewhere, e.g. by an application, refer to the
+/// old hashes and no longer resolve.
+#[derive(Clone)]
 pub struct FileNodeStorage<const N: usize> {
     storage_dir: PathBuf,
+    compression: Option<Compression>,
 }
 
 impl<const N: usize> FileNodeStorage<N> {
     pub fn new(storage_dir: PathBuf) -> Self {
         fs::create_dir_all(&storage_dir).unwrap();
-        FileNodeStorage { storage_dir }
+        let mut storage = FileNodeStorage {
+            storage_dir,
+            compression: None,
+        };
+        storage.compression = StorageManifest::load::<N, _>(&storage).compression;
+        storage.shard_flat_nodes().unwrap();
+        storage
     }
 
+    /// Writes every node with `codec` from now on, or with the codec of the node if
+    /// `None`, and records the choice in the `StorageManifest` of the storage.
+    pub fn with_compression(mut self, codec: Option<Compression>) -> Self {
+        self.compression = codec;
+        StorageManifest { compression: codec }.save::<N, _>(&self);
+        self
+    }
+
+    /// Returns the path of a node, in a subdirectory named after the first byte of its
+    /// hash so that no directory grows beyond a few thousand entries.
     fn node_path(&self, hash: &ValueDigest<N>) -> PathBuf {
-        self.storage_dir.join(format!("{:x}", hash))
+        let name = format!("{:x}", hash);
+        let (shard, rest) = name.split_at(2);
+        self.storage_dir.join(shard).join(rest)
+    }
+
+    /// Creates the subdirectory of a node path.
+    fn create_shard(path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path.parent().unwrap())
+    }
+
+    /// Writes a node file through a temporary file, so that concurrent readers, such as
+    /// snapshots, never observe a partially written node.
+    fn write_node_file(path: &Path, data: &[u8]) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        Self::create_shard(path)?;
+        File::create(&tmp_path)?.write_all(data)?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// Moves the node files of a storage written with all nodes in a single directory
+    /// into their subdirectories.
+    ///
+    /// Such storages predate the current node encoding, so every node is re-encoded on
+    /// the way. Nodes stored under their original hash are rehashed, together with the
+    /// pointers of their parents and the root hash of the saved tree config. The flat
+    /// files are only removed once every node and the config are written, so that an
+    /// interrupted conversion is simply run again. A file that cannot be decoded, or does
+    /// not match its name, is moved as is, so that reading it still reports the corrupt
+    /// node.
+    fn shard_flat_nodes(&self) -> io::Result<()> {
+        let mut original = HashMap::new();
+        let mut converted = Vec::new();
+        for entry in fs::read_dir(&self.storage_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let is_node = name.len() == 2 * N && name.bytes().all(|b| b.is_ascii_hexdigit());
+            if !is_node || !entry.file_type()?.is_file() {
+                continue;
+            }
+            let hash = ValueDigest::raw_hash(&hex::decode(name).unwrap());
+            let path = self.storage_dir.join(&name[..2]).join(&name[2..]);
+            match ProllyNode::<N>::decode(&fs::read(entry.path())?) {
+                Ok(node) if node.get_hash() == hash => {
+                    Self::write_node_file(&path, &node.encode_with(self.compression))?;
+                    converted.push(entry.path());
+                }
+                Ok(node) if original_hash(&node) == hash => {
+                    original.insert(hash, node);
+                    converted.push(entry.path());
+                }
+                _ => {
+                    Self::create_shard(&path)?;
+                    fs::rename(entry.path(), path)?;
+                }
+            }
+        }
+
+        let mut rehashed = HashMap::new();
+        for hash in original.keys() {
+            self.rehash_original(hash, &original, &mut rehashed)?;
+        }
+        let config = self
+            .get_config("tree_config")
+            .filter(|_| !rehashed.is_empty())
+            .and_then(|config| serde_json::from_slice::<serde_json::Value>(&config).ok());
+        if let Some(mut config) = config {
+            if let Some(root_hash) = config.get_mut("root_hash") {
+                let root: Option<ValueDigest<N>> = serde_json::from_value(root_hash.take())?;
+                let root =
+                    root.map(|root| rehashed.get(&root).map_or(root, |(hash, _)| hash.clone()));
+                *root_hash = serde_json::to_value(root)?;
+                self.save_config("tree_config", &serde_json::to_vec(&config)?);
+            }
+        }
+        for path in converted {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a node stored under its original hash, and the nodes below it, under their
+    /// current hashes.
+    ///
+    /// # Returns
+    /// - The current hash and the number of keys of the subtree, or `None` if `hash` is
+    ///   not the original hash of a node in `original`.
+    fn rehash_original(
+        &self,
+        hash: &ValueDigest<N>,
+        original: &HashMap<ValueDigest<N>, ProllyNode<N>>,
+        rehashed: &mut HashMap<ValueDigest<N>, (ValueDigest<N>, u64)>,
+    ) -> io::Result<Option<(ValueDigest<N>, u64)>> {
+        if let Some(done) = rehashed.get(hash) {
+            return Ok(Some(done.clone()));
+        }
+        let Some(mut node) = original.get(hash).cloned() else {
+            return Ok(None);
+        };
+        if !node.is_leaf {
+            let mut counts = Vec::new();
+            for child in node.values.iter_mut() {
+                let child_hash = ValueDigest::raw_hash(child);
+                if let Some((new_hash, count)) =
+                    self.rehash_original(&child_hash, original, rehashed)?
+                {
+                    *child = new_hash.as_bytes().to_vec();
+                    counts.push(count);
+                }
+            }
+            // children that were not converted are counted when the node is read
+            if counts.len() == node.values.len() {
+                node.counts = counts;
+            }
+        }
+        let count = node.subtree_count(self);
+        let new_hash = node.get_hash();
+        Self::write_node_file(
+            &self.node_path(&new_hash),
+            &node.encode_with(self.compression),
+        )?;
+        rehashed.insert(hash.clone(), (new_hash.clone(), count));
+        Ok(Some((new_hash, count)))
     }
 
     fn config_path(&self, key: &str) -> PathBuf {
@@ -140,27 +502,70 @@ impl<const N: usize> fmt::LowerHex for ValueDigest<N> {
     }
 }
 
-impl<const N: usize> NodeStorage<N> for FileNodeStorage<N> {
+impl<const N: usize> NodeReader<N> for FileNodeStorage<N> {
+    /// Returns the node with the given hash, or `None` if it is missing.
+    ///
+    /// Panics if the node cannot be read, rather than reporting a corrupt node as missing;
+    /// use `try_get_node` to handle the error.
     fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        self.try_get_node(hash)
+            .unwrap_or_else(|error| panic!("failed to read node: {error}"))
+    }
+
+    fn try_get_node(&self, hash: &ValueDigest<N>) -> Result<Option<ProllyNode<N>>, Error> {
         let path = self.node_path(hash);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        decode_node(&data, hash, path.display()).map(Some)
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.config_path(key);
         if path.exists() {
             let mut file = File::open(path).unwrap();
             let mut data = Vec::new();
             file.read_to_end(&mut data).unwrap();
-            Some(bincode::deserialize(&data).unwrap())
+            Some(data)
         } else {
             None
         }
     }
+}
 
+impl<const N: usize> NodeStorage<N> for FileNodeStorage<N> {
     fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
         let path = self.node_path(&hash);
-        let data = bincode::serialize(&node).unwrap();
-        let mut file = File::create(path).unwrap();
-        file.write_all(&data).unwrap();
+        Self::write_node_file(&path, &node.encode_with(self.compression)).unwrap();
         Some(())
     }
 
+    /// Writes every node to a temporary file before renaming any of them into place, so
+    /// that a failed batch leaves no partially written node behind.
+    fn put_batch(&mut self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> Result<(), Error> {
+        let mut written = Vec::with_capacity(nodes.len());
+        for (hash, node) in nodes {
+            let path = self.node_path(&hash);
+            let tmp_path = path.with_extension("tmp");
+            let result = Self::create_shard(&path)
+                .and_then(|_| File::create(&tmp_path))
+                .and_then(|mut file| file.write_all(&node.encode_with(self.compression)));
+            written.push((tmp_path, path));
+            if let Err(err) = result {
+                for (tmp_path, _) in written {
+                    let _ = fs::remove_file(tmp_path);
+                }
+                return Err(err.into());
+            }
+        }
+        for (tmp_path, path) in written {
+            fs::rename(tmp_path, path)?;
+        }
+        Ok(())
+    }
+
     fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
         let path = self.node_path(hash);
         if path.exists() {
@@ -176,16 +581,480 @@ impl<const N: usize> NodeStorage<N> for FileNodeStorage<N> {
         let mut file = File::create(path).unwrap();
         file.write_all(config).unwrap();
     }
+}
+
+/// A storage that wraps another storage and only gives read access to it.
+///
+/// It implements `NodeReader` but not `NodeStorage`, so it cannot back a `ProllyTree`
+/// and nothing can write through it. Opened with `Snapshot::open`, it keeps services that
+/// only read a tree, such as analytics or verification jobs, from changing it by accident.
+#[derive(Clone)]
+pub struct ReadOnlyStorage<S> {
+    inner: S,
+}
+
+impl<S> ReadOnlyStorage<S> {
+    /// Wraps a storage.
+    pub fn new(inner: S) -> Self {
+        ReadOnlyStorage { inner }
+    }
+
+    /// Returns the wrapped storage.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<const N: usize, S: NodeReader<N>> NodeReader<N> for ReadOnlyStorage<S> {
+    fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        self.inner.get_node_by_hash(hash)
+    }
+
+    fn prefetch(&self, hashes: &[ValueDigest<N>]) {
+        self.inner.prefetch(hashes)
+    }
 
     fn get_config(&self, key: &str) -> Option<Vec<u8>> {
-        let path = self.config_path(key);
-        if path.exists() {
-            let mut file = File::open(path).unwrap();
-            let mut data = Vec::new();
-            file.read_to_end(&mut data).unwrap();
-            Some(data)
-        } else {
-            None
+        self.inner.get_config(key)
+    }
+}
+
+/// The outcome of `migrate`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// The number of nodes and value chunks copied to the destination.
+    pub nodes_copied: usize,
+    /// The number of subtrees and chunks skipped because the destination held them.
+    pub nodes_skipped: usize,
+    /// The number of nodes read back from the destination and checked against their hash.
+    pub nodes_verified: usize,
+}
+
+/// Copies every node reachable from the given roots from one storage to another, e.g. to
+/// move trees to another backend without rebuilding them.
+///
+/// Nodes are checked against their hash when read from `src` and written to `dst` in one
+/// `NodeStorage::put_batch` per root, children before parents. Subtrees already in
+/// `dst` are skipped, so an interrupted migration can simply be run again. Once every root
+/// is copied, all nodes are read back from `dst` and checked against their hash.
+///
+/// # Parameters
+/// - `src`: The storage to copy from.
+/// - `dst`: The storage to copy to.
+/// - `roots`: The root hashes of the versions to copy.
+/// - `expiring`: Whether the values of the trees carry expiry timestamps, which is needed
+///   to find the chunks of values stored outside of the leaves.
+///
+/// # Returns
+/// - A report of the migration, `Error::MissingNode` if a node is missing from `src` or
+///   does not match its hash, or `Error::WriteFailed` with the hash of a node that could
+///   not be read back intact from `dst`.
+pub fn migrate<const N: usize, S: NodeStorage<N>, D: NodeStorage<N>>(
+    src: &S,
+    dst: &mut D,
+    roots: &[ValueDigest<N>],
+    expiring: bool,
+) -> Result<MigrationReport, Error> {
+    let mut report = MigrationReport::default();
+    for root in roots {
+        let mut transport = StorageTransport::new(src, root.clone());
+        let sync = SyncSession::new(&mut *dst)
+            .with_expiring(expiring)
+            .pull(&mut transport)?;
+        report.nodes_copied += sync.nodes_fetched;
+        report.nodes_skipped += sync.nodes_skipped;
+    }
+
+    for hash in reachable_nodes(src, roots.to_vec(), expiring) {
+        let intact = dst
+            .try_get_node(&hash)
+            .is_ok_and(|node| node.is_some_and(|node| node.get_hash() == hash));
+        if !intact {
+            return Err(Error::WriteFailed(hex::encode(hash.as_bytes())));
+        }
+        report.nodes_verified += 1;
+    }
+    Ok(report)
+}
+
+/// A storage that reads through to another storage but keeps all writes to itself.
+///
+/// Used to derive a modified tree from a tree without touching its storage, for example
+/// to export a part of it.
+pub(crate) struct OverlayStorage<'a, const N: usize, S: NodeStorage<N>> {
+    base: &'a S,
+    overlay: InMemoryNodeStorage<N>,
+}
+
+impl<'a, const N: usize, S: NodeStorage<N>> OverlayStorage<'a, N, S> {
+    pub(crate) fn new(base: &'a S) -> Self {
+        OverlayStorage {
+            base,
+            overlay: InMemoryNodeStorage::new(),
+        }
+    }
+}
+
+impl<const N: usize, S: NodeStorage<N>> NodeReader<N> for OverlayStorage<'_, N, S> {
+    fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        self.overlay
+            .get_node_by_hash(hash)
+            .or_else(|| self.base.get_node_by_hash(hash))
+    }
+
+    fn prefetch(&self, hashes: &[ValueDigest<N>]) {
+        self.base.prefetch(hashes)
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        self.overlay
+            .get_config(key)
+            .or_else(|| self.base.get_config(key))
+    }
+}
+
+impl<const N: usize, S: NodeStorage<N>> NodeStorage<N> for OverlayStorage<'_, N, S> {
+    fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
+        self.overlay.insert_node(hash, node)
+    }
+
+    fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
+        self.overlay.delete_node(hash)
+    }
+
+    fn put_batch(&mut self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> Result<(), Error> {
+        self.overlay.put_batch(nodes)
+    }
+
+    fn save_config(&self, key: &str, config: &[u8]) {
+        self.overlay.save_config(key, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::integrity::Violation;
+    use crate::iter::TreeIter;
+    use crate::tree::{ProllyTree, Tree};
+
+    /// Builds a tree of `count` keys in a storage.
+    fn build_tree<S: NodeStorage<32>>(storage: S, count: u32) -> ProllyTree<32, S> {
+        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+        for i in 0..count {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        tree
+    }
+
+    /// Returns the nodes reachable from a root, parents before their children.
+    fn tree_nodes<S: NodeStorage<32>>(
+        storage: &S,
+        root: &ValueDigest<32>,
+    ) -> Vec<(ValueDigest<32>, ProllyNode<32>)> {
+        let mut nodes = Vec::new();
+        let mut pending = vec![root.clone()];
+        while let Some(hash) = pending.pop() {
+            let node = storage.get_node_by_hash(&hash).unwrap();
+            if !node.is_leaf {
+                pending.extend(node.values.iter().map(|child| ValueDigest::raw_hash(child)));
+            }
+            nodes.push((hash, node));
+        }
+        nodes
+    }
+
+    #[test]
+    fn test_put_batch() {
+        let tree = build_tree(InMemoryNodeStorage::<32>::default(), 300);
+        let root = tree.get_root_hash().unwrap();
+        let nodes = tree_nodes(tree.storage(), &root);
+
+        let storage_dir = std::env::temp_dir().join("prolly_tree_put_batch_storage");
+        let _ = fs::remove_dir_all(&storage_dir);
+        let mut storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        storage.put_batch(nodes.clone()).unwrap();
+        assert!(nodes
+            .iter()
+            .all(|(hash, _)| storage.get_node_by_hash(hash).is_some()));
+        let shards = fs::read_dir(&storage_dir).unwrap();
+        assert!(shards
+            .map(|shard| shard.unwrap().path())
+            .filter(|path| path.is_dir())
+            .flat_map(|shard| fs::read_dir(shard).unwrap())
+            .all(|file| file.unwrap().path().extension().is_none()));
+
+        let mut copy = InMemoryNodeStorage::<32>::new();
+        copy.put_batch(nodes).unwrap();
+        assert!(copy.get_node_by_hash(&root).is_some());
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_storage_shards() {
+        let storage_dir = std::env::temp_dir().join("prolly_tree_shard_storage");
+        let _ = fs::remove_dir_all(&storage_dir);
+        let tree = build_tree(FileNodeStorage::<32>::new(storage_dir.clone()), 200);
+        let root = tree.get_root_hash().unwrap();
+        let name = format!("{:x}", root);
+        let sharded = storage_dir.join(&name[..2]).join(&name[2..]);
+        assert!(sharded.is_file());
+
+        // a node left in the flat layout of older versions is moved into its shard
+        fs::rename(&sharded, storage_dir.join(&name)).unwrap();
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        assert!(sharded.is_file() && !storage_dir.join(&name).exists());
+        assert_eq!(storage.get_node_by_hash(&root).unwrap().get_hash(), root);
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    /// Writes the nodes of a tree and its config as a store of the first releases did:
+    /// flat files in the original node layout, named after the original node hash, which
+    /// did not cover subtree counts. Returns the original root hash.
+    fn write_baseline_store<S: NodeStorage<32>>(
+        storage: &S,
+        root: &ValueDigest<32>,
+        dir: &Path,
+    ) -> ValueDigest<32> {
+        fn write_node<S: NodeStorage<32>>(
+            storage: &S,
+            hash: &ValueDigest<32>,
+            dir: &Path,
+        ) -> ValueDigest<32> {
+            let mut node = storage.get_node_by_hash(hash).unwrap();
+            if !node.is_leaf {
+                for child in node.values.iter_mut() {
+                    let child_hash = ValueDigest::raw_hash(child);
+                    *child = write_node(storage, &child_hash, dir).as_bytes().to_vec();
+                }
+                node.counts.clear();
+            }
+            let hash = original_hash(&node);
+            let data = crate::compression::encode_original(&node);
+            fs::write(dir.join(format!("{:x}", hash)), data).unwrap();
+            hash
+        }
+
+        fs::create_dir_all(dir).unwrap();
+        let root = write_node(storage, root, dir);
+        let config = TreeConfig::<32> {
+            root_hash: Some(root.clone()),
+            ..Default::default()
+        };
+        fs::write(
+            dir.join("config_tree_config"),
+            serde_json::to_vec(&config).unwrap(),
+        )
+        .unwrap();
+        root
+    }
+
+    #[test]
+    fn test_rehash_baseline_store() {
+        use crate::snapshot::Snapshot;
+        use crate::sync::SyncSession;
+
+        let tree = build_tree(InMemoryNodeStorage::<32>::default(), 300);
+        let root = tree.get_root_hash().unwrap();
+        let storage_dir = std::env::temp_dir().join("prolly_tree_baseline_storage");
+        let _ = fs::remove_dir_all(&storage_dir);
+        let baseline_root = write_baseline_store(tree.storage(), &root, &storage_dir);
+        assert_ne!(baseline_root, root);
+
+        // the nodes are rehashed into the tree that was stored, and the config follows
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        assert!(storage.get_node_by_hash(&baseline_root).is_none());
+        let snapshot = Snapshot::open(storage.clone()).unwrap();
+        assert_eq!(snapshot.root_hash(), root);
+        assert!(snapshot.iter().eq(tree.iter()));
+        assert_eq!(snapshot.len(), 300);
+        for (hash, node) in tree_nodes(&storage, &root) {
+            assert_eq!(node.get_hash(), hash);
+        }
+        assert!(fs::read_dir(&storage_dir)
+            .unwrap()
+            .all(|entry| entry.unwrap().file_name().len() != 64));
+
+        // so the converted store can be migrated and synced from
+        let mut copy = InMemoryNodeStorage::<32>::new();
+        let report = migrate(&storage, &mut copy, std::slice::from_ref(&root), false).unwrap();
+        assert_eq!(report.nodes_verified, tree_nodes(&storage, &root).len());
+        let mut pulled = InMemoryNodeStorage::<32>::new();
+        let mut transport = StorageTransport::new(&storage, root.clone());
+        SyncSession::new(&mut pulled).pull(&mut transport).unwrap();
+
+        // opening the converted store again changes nothing
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        assert_eq!(Snapshot::open(storage).unwrap().root_hash(), root);
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_shard_original_nodes() {
+        let tree = build_tree(InMemoryNodeStorage::<32>::default(), 300);
+        let root = tree.get_root_hash().unwrap();
+        let storage_dir = std::env::temp_dir().join("prolly_tree_original_storage");
+        let _ = fs::remove_dir_all(&storage_dir);
+        let baseline_root = write_baseline_store(tree.storage(), &root, &storage_dir);
+
+        // every node is moved into its shard in the current encoding, and the flat files go
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        assert!(!storage_dir.join(format!("{:x}", baseline_root)).exists());
+        let root_node = storage.get_node_by_hash(&root).unwrap();
+        let entries: Vec<_> = TreeIter::new(&root_node, &storage, .., false).collect();
+        assert_eq!(entries, tree.iter().collect::<Vec<_>>());
+        for (hash, node) in tree_nodes(&storage, &root) {
+            let name = format!("{:x}", hash);
+            let data = fs::read(storage_dir.join(&name[..2]).join(&name[2..])).unwrap();
+            assert_eq!(data, node.encode());
+        }
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_in_memory_clone_shares_nodes() {
+        let tree = build_tree(InMemoryNodeStorage::<32>::new(), 1000);
+        let storage = tree.storage();
+        let clone = storage.clone();
+        assert_eq!(clone.map.len(), storage.map.len());
+        assert!(storage
+            .map
+            .iter()
+            .all(|(hash, node)| Arc::ptr_eq(node, &clone.map[hash])));
+    }
+
+    #[test]
+    fn test_bounded_in_memory_storage() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..500u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![7; 32]);
+        }
+        let root = tree.get_root_hash().unwrap();
+        assert_eq!(tree.storage().size_in_bytes(), None);
+        let hashes = reachable_nodes(tree.storage(), vec![root.clone()], false);
+
+        // nodes that exist nowhere else are kept beyond the budget
+        let mut storage = InMemoryNodeStorage::<32>::new().with_max_bytes(4096);
+        for hash in &hashes {
+            let node = tree.storage().get_node_by_hash(hash).unwrap();
+            storage.insert_node(hash.clone(), node);
+        }
+        assert!(storage.size_in_bytes().unwrap() > 4096);
+        assert!(hashes
+            .iter()
+            .all(|hash| storage.get_node_by_hash(hash).is_some()));
+
+        // once persisted elsewhere, the least recently used nodes are evicted
+        storage.mark_persisted(&root, false);
+        assert!(storage.size_in_bytes().unwrap() <= 4096);
+        let kept = hashes
+            .iter()
+            .filter(|hash| storage.get_node_by_hash(hash).is_some())
+            .count();
+        assert!(kept > 0 && kept < hashes.len());
+
+        // new nodes are not evictable
+        let node = ProllyNode::<32> {
+            keys: vec![b"new".to_vec()],
+            values: vec![vec![1; 8192]],
+            ..Default::default()
+        };
+        storage.insert_node(node.get_hash(), node.clone());
+        assert!(storage.size_in_bytes().unwrap() > 8192);
+        assert!(storage.get_node_by_hash(&node.get_hash()).is_some());
+        storage.delete_node(&node.get_hash());
+        assert!(storage.size_in_bytes().unwrap() <= 4096);
+    }
+
+    #[test]
+    fn test_migrate() {
+        let config = TreeConfig {
+            value_chunk_threshold: Some(256),
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..300u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![i as u8; 8]);
+        }
+        tree.insert(
+            b"document".to_vec(),
+            (0..4000u32).map(|i| i as u8).collect(),
+        );
+        let old = tree.get_root_hash().unwrap();
+        tree.insert(7u32.to_be_bytes().to_vec(), vec![1; 8]);
+        let new = tree.get_root_hash().unwrap();
+        let roots = [old.clone(), new.clone()];
+
+        let mut storage = InMemoryNodeStorage::<32>::new();
+        let report = migrate(tree.storage(), &mut storage, &roots, false).unwrap();
+        let reachable = reachable_nodes(tree.storage(), roots.to_vec(), false);
+        assert_eq!(report.nodes_copied, reachable.len());
+        assert_eq!(report.nodes_verified, reachable.len());
+        assert!(report.nodes_skipped > 0);
+
+        // the copy reads the same entries, including the value stored in chunks
+        let entries = |storage: &InMemoryNodeStorage<32>| {
+            let root = storage.get_node_by_hash(&new).unwrap();
+            TreeIter::new(&root, storage, .., false)
+                .with_blobs(true)
+                .collect::<Vec<_>>()
+        };
+        let copied = entries(&storage);
+        assert_eq!(copied.len(), 301);
+        assert_eq!(copied, entries(tree.storage()));
+
+        // a second run copies nothing
+        let again = migrate(tree.storage(), &mut storage, &roots, false).unwrap();
+        assert_eq!(again.nodes_copied, 0);
+        assert_eq!(again.nodes_skipped, 2);
+
+        let missing = ValueDigest::<32>::new(b"missing");
+        assert!(matches!(
+            migrate(tree.storage(), &mut storage, &[missing], false),
+            Err(Error::MissingNode(_))
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_node_detection() {
+        let storage_dir = std::env::temp_dir().join("prolly_tree_corrupt_storage");
+        let _ = fs::remove_dir_all(&storage_dir);
+        let tree = build_tree(FileNodeStorage::<32>::new(storage_dir.clone()), 300);
+        assert!(tree.verify_integrity().violations.is_empty());
+
+        // flip a bit in a leaf
+        let storage = tree.storage();
+        let root = tree.get_root_hash().unwrap();
+        let root_node = storage.get_node_by_hash(&root).unwrap();
+        let leaf = ValueDigest::raw_hash(&root_node.values[0]);
+        let name = format!("{:x}", leaf);
+        let path = storage_dir.join(&name[..2]).join(&name[2..]);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&path, data).unwrap();
+
+        // the infallible read does not report the corrupt node as missing
+        let read = std::panic::catch_unwind(|| storage.get_node_by_hash(&leaf));
+        assert!(read.is_err());
+        match storage.try_get_node(&leaf) {
+            Err(Error::CorruptNode { hash, location }) => {
+                assert_eq!(hash, name);
+                assert_eq!(location, path.display().to_string());
+            }
+            other => panic!("unexpected result {:?}", other.map(|node| node.is_some())),
         }
+        assert!(storage.try_get_node(&root).unwrap().is_some());
+        let missing = ValueDigest::<32>::new(b"missing");
+        assert!(storage.try_get_node(&missing).unwrap().is_none());
+        assert!(matches!(
+            tree.verify_integrity().violations[..],
+            [Violation::CorruptNode { ref hash, .. }] if *hash == leaf
+        ));
+        fs::remove_dir_all(storage_dir).unwrap();
     }
 }
@@ -0,0 +1,266 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A node storage in an LMDB environment.
+
+use crate::compression::{Compression, StorageManifest};
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::{NodeReader, NodeStorage};
+use heed::types::{Bytes, Str};
+use heed::{Database, Env, EnvFlags, EnvOpenOptions};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const NODES: &str = "nodes";
+const CONFIGS: &str = "configs";
+
+/// A node storage keeping nodes, keyed by their hash, and configs in two databases of an
+/// LMDB environment.
+///
+/// Reads go straight to the memory-mapped B-tree without copying through a cache, which
+/// makes LMDB a good fit for workloads dominated by random reads. An environment can be
+/// shared by several processes, with one writer and any number of read-only readers.
+#[derive(Clone)]
+pub struct LmdbNodeStorage<const N: usize> {
+    env: Env,
+    nodes: Database<Bytes, Bytes>,
+    configs: Database<Str, Bytes>,
+    read_only: bool,
+    compression: Option<Compression>,
+}
+
+impl<const N: usize> LmdbNodeStorage<N> {
+    /// Opens an LMDB environment in a directory for reading and writing, creating it if it
+    /// does not exist.
+    ///
+    /// # Parameters
+    /// - `path`: The directory of the environment.
+    /// - `map_size`: The maximum size of the environment in bytes; a multiple of the OS
+    ///   page size.
+    ///
+    /// # Returns
+    /// - The storage, or `Error::Io` if the environment cannot be opened.
+    pub fn open(path: impl AsRef<Path>, map_size: usize) -> Result<Self, Error> {
+        fs::create_dir_all(&path)?;
+        let mut options = EnvOpenOptions::new();
+        options.map_size(map_size).max_dbs(2);
+        // SAFETY: the environment is only modified through LMDB, which locks it
+        let env = unsafe { options.open(path) }.map_err(lmdb_error)?;
+        let mut txn = env.write_txn().map_err(lmdb_error)?;
+        let nodes = env
+            .create_database(&mut txn, Some(NODES))
+            .map_err(lmdb_error)?;
+        let configs = env
+            .create_database(&mut txn, Some(CONFIGS))
+            .map_err(lmdb_error)?;
+        txn.commit().map_err(lmdb_error)?;
+        Ok(LmdbNodeStorage {
+            env,
+            nodes,
+            configs,
+            read_only: false,
+            compression: None,
+        }
+        .with_manifest())
+    }
+
+    /// Opens an existing LMDB environment read-only, e.g. one shared with a writing
+    /// process. Nodes written by the writer become visible with its next commit.
+    ///
+    /// Inserting or deleting nodes fails, and saving a config panics.
+    ///
+    /// # Returns
+    /// - The storage, or `Error::Io` if the environment does not exist or holds no tree.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let mut options = EnvOpenOptions::new();
+        options.max_dbs(2);
+        // SAFETY: READ_ONLY is not one of the flags that lift LMDB's safety guarantees
+        let env = unsafe { options.flags(EnvFlags::READ_ONLY).open(path) }.map_err(lmdb_error)?;
+        let txn = env.read_txn().map_err(lmdb_error)?;
+        let missing = || Error::Io(io::Error::new(io::ErrorKind::NotFound, "no node database"));
+        let nodes = env
+            .open_database(&txn, Some(NODES))
+            .map_err(lmdb_error)?
+            .ok_or_else(missing)?;
+        let configs = env
+            .open_database(&txn, Some(CONFIGS))
+            .map_err(lmdb_error)?
+            .ok_or_else(missing)?;
+        txn.commit().map_err(lmdb_error)?;
+        Ok(LmdbNodeStorage {
+            env,
+            nodes,
+            configs,
+            read_only: true,
+            compression: None,
+        }
+        .with_manifest())
+    }
+
+    fn with_manifest(mut self) -> Self {
+        self.compression = StorageManifest::load::<N, _>(&self).compression;
+        self
+    }
+
+    /// Writes every node with `codec` from now on, or with the codec of the node if
+    /// `None`, and records the choice in the `StorageManifest` of the storage.
+    ///
+    /// Panics on a read-only storage.
+    pub fn with_compression(mut self, codec: Option<Compression>) -> Self {
+        self.compression = codec;
+        StorageManifest { compression: codec }.save::<N, _>(&self);
+        self
+    }
+
+    /// Returns `true` if the storage was opened with `open_read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Closes the environment once every clone of the storage is dropped.
+    ///
+    /// An environment stays open for the lifetime of the process otherwise, and can only
+    /// be opened again in the same process with the same options.
+    pub fn close(self) {
+        self.env.prepare_for_closing();
+    }
+
+    fn write<F>(&self, apply: F) -> Result<bool, heed::Error>
+    where
+        F: FnOnce(&mut heed::RwTxn) -> Result<bool, heed::Error>,
+    {
+        let mut txn = self.env.write_txn()?;
+        let changed = apply(&mut txn)?;
+        txn.commit()?;
+        Ok(changed)
+    }
+}
+
+fn lmdb_error(err: heed::Error) -> Error {
+    match err {
+        heed::Error::Io(err) => Error::Io(err),
+        err => Error::Io(io::Error::other(err)),
+    }
+}
+
+impl<const N: usize> NodeReader<N> for LmdbNodeStorage<N> {
+    fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        let txn = self.env.read_txn().ok()?;
+        let data = self.nodes.get(&txn, hash.as_bytes()).ok()??;
+        ProllyNode::decode(data).ok()
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        let txn = self.env.read_txn().ok()?;
+        self.configs.get(&txn, key).ok()?.map(<[u8]>::to_vec)
+    }
+}
+
+impl<const N: usize> NodeStorage<N> for LmdbNodeStorage<N> {
+    fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
+        if self.read_only {
+            return None;
+        }
+        let data = node.encode_with(self.compression);
+        self.write(|txn| self.nodes.put(txn, hash.as_bytes(), &data).map(|_| true))
+            .ok()
+            .map(|_| ())
+    }
+
+    fn put_batch(&mut self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::WriteFailed("read-only LMDB storage".to_string()));
+        }
+        self.write(|txn| {
+            for (hash, node) in &nodes {
+                self.nodes
+                    .put(txn, hash.as_bytes(), &node.encode_with(self.compression))?;
+            }
+            Ok(true)
+        })
+        .map(|_| ())
+        .map_err(lmdb_error)
+    }
+
+    fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
+        if self.read_only {
+            return None;
+        }
+        self.write(|txn| self.nodes.delete(txn, hash.as_bytes()))
+            .ok()?
+            .then_some(())
+    }
+
+    fn save_config(&self, key: &str, config: &[u8]) {
+        assert!(
+            !self.read_only,
+            "cannot save a config to a read-only LMDB storage"
+        );
+        self.write(|txn| self.configs.put(txn, key, config).map(|_| true))
+            .expect("failed to write the config to LMDB");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::tree::{ProllyTree, Tree};
+
+    #[test]
+    fn test_lmdb_storage() {
+        let dir = std::env::temp_dir().join(format!("prollytree-lmdb-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let root = {
+            let storage = LmdbNodeStorage::<32>::open(&dir, 16 * 1024 * 1024).unwrap();
+            let mut tree = ProllyTree::new(storage.clone(), TreeConfig::<32>::default());
+            for i in 0..500u32 {
+                tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+            }
+            let root = tree.get_root_hash().unwrap();
+            drop(tree);
+
+            let mut storage = storage;
+            storage.save_config("tree", b"config");
+            assert_eq!(storage.get_config("tree"), Some(b"config".to_vec()));
+            assert_eq!(storage.get_config("other"), None);
+            let scratch = ValueDigest::new(b"scratch");
+            storage.insert_node(scratch.clone(), ProllyNode::default());
+            assert!(storage.delete_node(&scratch).is_some());
+            assert!(storage.delete_node(&scratch).is_none());
+            let batch = vec![(scratch.clone(), ProllyNode::default())];
+            storage.put_batch(batch).unwrap();
+            assert!(storage.get_node_by_hash(&scratch).is_some());
+            storage.close();
+            root
+        };
+
+        let mut reader = LmdbNodeStorage::<32>::open_read_only(&dir).unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.get_node_by_hash(&root).unwrap().get_hash(), root);
+        assert_eq!(reader.get_config("tree"), Some(b"config".to_vec()));
+        assert!(reader.delete_node(&root).is_none());
+        assert!(reader.get_node_by_hash(&root).is_some());
+        let batch = vec![(root.clone(), ProllyNode::default())];
+        assert!(matches!(
+            reader.put_batch(batch),
+            Err(Error::WriteFailed(_))
+        ));
+        drop(reader);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,591 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A file based node storage packing nodes into append-only, memory-mapped segment files.
+//!
+//! Unlike `FileNodeStorage`, which writes one file per node, nodes are appended to a few
+//! large segment files and located through an append-only index file. Segments are
+//! read through memory maps, so reading a node does not copy it out of a file. Deleted
+//! nodes keep taking space in their segments until the storage is compacted.
+
+use crate::compression::{Compression, StorageManifest};
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::{decode_node, NodeReader, NodeStorage};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+
+/// The size a segment grows to before nodes are appended to a new one.
+const SEGMENT_SIZE: u64 = 64 << 20;
+const INDEX_FILE: &str = "index";
+const SEGMENT_PREFIX: &str = "segment_";
+/// The number of nodes a compaction copies while holding the lock of the storage.
+const COMPACTION_BATCH: usize = 256;
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+
+/// Where a node is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    segment: u32,
+    offset: u64,
+    len: u32,
+}
+
+struct Segment {
+    file: File,
+    len: u64,
+    /// A map of the segment, remapped when a node beyond its end is read.
+    map: Option<Mmap>,
+}
+
+struct Segments<const N: usize> {
+    /// The segments by number, `None` for the segments removed by a compaction.
+    segments: Vec<Option<Segment>>,
+    index: HashMap<ValueDigest<N>, Location>,
+    index_file: File,
+}
+
+impl<const N: usize> Segments<N> {
+    /// Appends the bytes of a node to the last segment, starting a new segment if it would
+    /// grow beyond `segment_size`.
+    fn append(&mut self, dir: &Path, segment_size: u64, data: &[u8]) -> io::Result<Location> {
+        let full = match self.segments.last() {
+            Some(Some(segment)) => {
+                segment.len > 0 && segment.len + data.len() as u64 > segment_size
+            }
+            _ => true,
+        };
+        if full {
+            self.start_segment(dir)?;
+        }
+        let number = self.segments.len() - 1;
+        let segment = self.segments[number].as_mut().unwrap();
+        segment.file.write_all(data)?;
+        let location = Location {
+            segment: number as u32,
+            offset: segment.len,
+            len: data.len() as u32,
+        };
+        segment.len += data.len() as u64;
+        Ok(location)
+    }
+
+    fn start_segment(&mut self, dir: &Path) -> io::Result<()> {
+        let file = open_segment(dir, self.segments.len())?;
+        self.segments.push(Some(Segment {
+            file,
+            len: 0,
+            map: None,
+        }));
+        Ok(())
+    }
+}
+
+/// The progress of a compaction, reported after every batch of copied nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// The number of nodes copied so far.
+    pub nodes_copied: usize,
+    /// The number of nodes to copy.
+    pub nodes_total: usize,
+}
+
+/// The outcome of a compaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// The number of nodes copied into fresh segments.
+    pub nodes_copied: usize,
+    /// The number of segment files removed.
+    pub segments_removed: usize,
+    /// The size of the removed segments minus the size of the copied nodes.
+    pub bytes_reclaimed: u64,
+}
+
+/// A node storage appending nodes to memory-mapped segment files in a directory.
+///
+/// The directory holds the segments `segment_00000000`, `segment_00000001`, ..., an
+/// `index` file recording where each node is, and the configs in `config_<key>` files.
+/// Nodes are written to their segment before they are added to the index, so a node
+/// listed in the index is always complete. Deleting a node only removes it from the
+/// index; its bytes stay in the segment.
+///
+/// Clones share the open segments and the index.
+#[derive(Clone)]
+pub struct MmapNodeStorage<const N: usize> {
+    dir: PathBuf,
+    segment_size: u64,
+    inner: Arc<RwLock<Segments<N>>>,
+    compression: Option<Compression>,
+}
+
+impl<const N: usize> MmapNodeStorage<N> {
+    /// Opens or creates a storage in a directory.
+    ///
+    /// A partially written record at the end of the index, left by a crash, is dropped.
+    ///
+    /// # Returns
+    /// - The storage, or `Error::Io` if the directory cannot be read.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let Some(number) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+                .and_then(|number| number.parse::<usize>().ok())
+            else {
+                continue;
+            };
+            if segments.len() <= number {
+                segments.resize_with(number + 1, || None);
+            }
+            let file = open_segment(&dir, number)?;
+            let len = file.metadata()?.len();
+            segments[number] = Some(Segment {
+                file,
+                len,
+                map: None,
+            });
+        }
+
+        let mut index_file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(INDEX_FILE))?;
+        let mut records = Vec::new();
+        index_file.read_to_end(&mut records)?;
+        let record_len = record_len::<N>();
+        let complete = records.len() - records.len() % record_len;
+        if complete < records.len() {
+            index_file.set_len(complete as u64)?;
+        }
+        let mut index = HashMap::new();
+        for record in records[..complete].chunks_exact(record_len) {
+            let (tag, hash, location) = decode_record::<N>(record);
+            match tag {
+                PUT => index.insert(hash, location),
+                _ => index.remove(&hash),
+            };
+        }
+
+        let mut storage = MmapNodeStorage {
+            dir,
+            segment_size: SEGMENT_SIZE,
+            inner: Arc::new(RwLock::new(Segments {
+                segments,
+                index,
+                index_file,
+            })),
+            compression: None,
+        };
+        storage.compression = StorageManifest::load::<N, _>(&storage).compression;
+        Ok(storage)
+    }
+
+    /// Sets the size a segment grows to before nodes are appended to a new one.
+    pub fn with_segment_size(mut self, segment_size: u64) -> Self {
+        self.segment_size = segment_size.max(1);
+        self
+    }
+
+    /// Writes every node with `codec` from now on, or with the codec of the node if
+    /// `None`, and records the choice in the `StorageManifest` of the storage.
+    pub fn with_compression(mut self, codec: Option<Compression>) -> Self {
+        self.compression = codec;
+        StorageManifest { compression: codec }.save::<N, _>(&self);
+        self
+    }
+
+    /// Returns the number of segment files.
+    pub fn segment_count(&self) -> usize {
+        self.inner.read().unwrap().segments.iter().flatten().count()
+    }
+
+    /// Syncs the segment being appended to and the index to disk.
+    pub fn flush(&self) -> Result<(), Error> {
+        let inner = self.inner.read().unwrap();
+        if let Some(Some(segment)) = inner.segments.last() {
+            segment.file.sync_data()?;
+        }
+        inner.index_file.sync_data()?;
+        Ok(())
+    }
+
+    /// Appends nodes to the segments, then adds them to the index in a single write.
+    fn append(&self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> io::Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        let mut records = Vec::new();
+        let mut added = Vec::new();
+        for (hash, node) in nodes {
+            // nodes are addressed by their content, so a stored node never changes
+            if inner.index.contains_key(&hash) {
+                continue;
+            }
+            let data = node.encode_with(self.compression);
+            let location = inner.append(&self.dir, self.segment_size, &data)?;
+            records.extend(encode_record(PUT, &hash, location));
+            added.push((hash, location));
+        }
+        inner.index_file.write_all(&records)?;
+        inner.index.extend(added);
+        Ok(())
+    }
+
+    /// Rewrites the live nodes of the current segments into fresh segments, then removes
+    /// the old segments along with the bytes of deleted nodes, and rewrites the index
+    /// without the records of deleted nodes.
+    ///
+    /// The storage stays usable during a compaction: nodes are copied in batches, and
+    /// reads and writes only wait while a batch is copied. Nodes written meanwhile go to
+    /// the fresh segments. A crash leaves the storage readable, at worst with both copies
+    /// of some nodes.
+    ///
+    /// # Parameters
+    /// - `progress`: Called after every batch of copied nodes.
+    ///
+    /// # Returns
+    /// - The outcome of the compaction, or `Error::Io` if a file cannot be written.
+    pub fn compact(
+        &self,
+        mut progress: impl FnMut(CompactionProgress),
+    ) -> Result<CompactionStats, Error> {
+        let (sealed, live) = {
+            let mut inner = self.inner.write().unwrap();
+            inner.start_segment(&self.dir)?;
+            let sealed = inner.segments.len() - 1;
+            let live: Vec<(ValueDigest<N>, Location)> = inner
+                .index
+                .iter()
+                .filter(|(_, location)| (location.segment as usize) < sealed)
+                .map(|(hash, location)| (hash.clone(), *location))
+                .collect();
+            (sealed, live)
+        };
+
+        let mut stats = CompactionStats::default();
+        let mut bytes_copied = 0;
+        for batch in live.chunks(COMPACTION_BATCH) {
+            let copies: Vec<_> = batch
+                .iter()
+                .filter_map(|(hash, old)| Some((hash, old, self.with_bytes(*old, <[u8]>::to_vec)?)))
+                .collect();
+            let mut inner = self.inner.write().unwrap();
+            let mut records = Vec::new();
+            for (hash, old, data) in copies {
+                // the node was deleted since the compaction started
+                if inner.index.get(hash) != Some(old) {
+                    continue;
+                }
+                let location = inner.append(&self.dir, self.segment_size, &data)?;
+                records.extend(encode_record(PUT, hash, location));
+                inner.index.insert(hash.clone(), location);
+                stats.nodes_copied += 1;
+                bytes_copied += data.len() as u64;
+            }
+            inner.index_file.write_all(&records)?;
+            drop(inner);
+            progress(CompactionProgress {
+                nodes_copied: stats.nodes_copied,
+                nodes_total: live.len(),
+            });
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        // the copies must be on disk before the index that only points at them
+        for segment in inner.segments[sealed..].iter().flatten() {
+            segment.file.sync_data()?;
+        }
+        let mut records = Vec::new();
+        for (hash, location) in &inner.index {
+            records.extend(encode_record(PUT, hash, *location));
+        }
+        let index_path = self.dir.join(INDEX_FILE);
+        let tmp_path = index_path.with_extension("tmp");
+        let mut index_file = File::create(&tmp_path)?;
+        index_file.write_all(&records)?;
+        index_file.sync_all()?;
+        fs::rename(tmp_path, &index_path)?;
+        inner.index_file = OpenOptions::new().append(true).open(index_path)?;
+
+        for number in 0..sealed {
+            if let Some(segment) = inner.segments[number].take() {
+                stats.segments_removed += 1;
+                stats.bytes_reclaimed += segment.len;
+                drop(segment);
+                fs::remove_file(self.dir.join(segment_name(number)))?;
+            }
+        }
+        stats.bytes_reclaimed = stats.bytes_reclaimed.saturating_sub(bytes_copied);
+        Ok(stats)
+    }
+
+    /// Runs `compact` on a new thread, on a clone of the storage.
+    pub fn compact_in_background<F>(
+        &self,
+        progress: F,
+    ) -> JoinHandle<Result<CompactionStats, Error>>
+    where
+        F: FnMut(CompactionProgress) + Send + 'static,
+    {
+        let storage = self.clone();
+        thread::spawn(move || storage.compact(progress))
+    }
+
+    fn location(&self, hash: &ValueDigest<N>) -> Option<Location> {
+        self.inner.read().unwrap().index.get(hash).copied()
+    }
+
+    /// Passes the bytes of a node to `f`, straight from the map of its segment, mapping
+    /// the segment again if the node was written after it was mapped.
+    ///
+    /// Returns `None` if the segment was removed by a compaction.
+    fn with_bytes<T>(&self, location: Location, f: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        let start = location.offset as usize;
+        let end = start + location.len as usize;
+        {
+            let inner = self.inner.read().unwrap();
+            let segment = inner.segments.get(location.segment as usize)?.as_ref()?;
+            if let Some(map) = segment.map.as_ref().filter(|map| map.len() >= end) {
+                return Some(f(&map[start..end]));
+            }
+        }
+        let mut inner = self.inner.write().unwrap();
+        let segment = inner
+            .segments
+            .get_mut(location.segment as usize)?
+            .as_mut()?;
+        // SAFETY: segments are only ever appended to, never truncated or rewritten, and
+        // are only removed once nothing maps them any more.
+        let map = unsafe { Mmap::map(&segment.file) }.ok()?;
+        let result = map.get(start..end).map(f);
+        segment.map = Some(map);
+        result
+    }
+
+    fn config_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("config_{}", key))
+    }
+}
+
+fn segment_name(number: usize) -> String {
+    format!("{}{:08}", SEGMENT_PREFIX, number)
+}
+
+fn open_segment(dir: &Path, number: usize) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(dir.join(segment_name(number)))
+}
+
+/// The length of an index record: a tag, the hash of the node and its location.
+const fn record_len<const N: usize>() -> usize {
+    1 + N + 4 + 8 + 4
+}
+
+fn encode_record<const N: usize>(tag: u8, hash: &ValueDigest<N>, location: Location) -> Vec<u8> {
+    let mut record = Vec::with_capacity(record_len::<N>());
+    record.push(tag);
+    record.extend_from_slice(hash.as_bytes());
+    record.extend_from_slice(&location.segment.to_be_bytes());
+    record.extend_from_slice(&location.offset.to_be_bytes());
+    record.extend_from_slice(&location.len.to_be_bytes());
+    record
+}
+
+fn decode_record<const N: usize>(record: &[u8]) -> (u8, ValueDigest<N>, Location) {
+    let (tag, rest) = record.split_first().unwrap();
+    let (hash, rest) = rest.split_at(N);
+    let location = Location {
+        segment: u32::from_be_bytes(rest[..4].try_into().unwrap()),
+        offset: u64::from_be_bytes(rest[4..12].try_into().unwrap()),
+        len: u32::from_be_bytes(rest[12..16].try_into().unwrap()),
+    };
+    (*tag, ValueDigest::raw_hash(hash), location)
+}
+
+impl<const N: usize> NodeReader<N> for MmapNodeStorage<N> {
+    /// Returns the node with the given hash, or `None` if it is missing.
+    ///
+    /// Panics if the node cannot be read, rather than reporting a corrupt node as missing;
+    /// use `try_get_node` to handle the error.
+    fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        self.try_get_node(hash)
+            .unwrap_or_else(|error| panic!("failed to read node: {error}"))
+    }
+
+    fn try_get_node(&self, hash: &ValueDigest<N>) -> Result<Option<ProllyNode<N>>, Error> {
+        let decode = |location: Location| {
+            move |data: &[u8]| {
+                let place = format!(
+                    "{} offset {}",
+                    segment_name(location.segment as usize),
+                    location.offset
+                );
+                decode_node(data, hash, place)
+            }
+        };
+        let Some(location) = self.location(hash) else {
+            return Ok(None);
+        };
+        if let Some(node) = self.with_bytes(location, decode(location)) {
+            return node.map(Some);
+        }
+        // a compaction moved the node after it was looked up
+        match self.location(hash).filter(|moved| *moved != location) {
+            Some(moved) => self.with_bytes(moved, decode(moved)).transpose(),
+            None => Ok(None),
+        }
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.config_path(key)).ok()
+    }
+}
+
+impl<const N: usize> NodeStorage<N> for MmapNodeStorage<N> {
+    fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
+        self.append(vec![(hash, node)]).ok()
+    }
+
+    /// Appends all nodes before adding any of them to the index, so a failed batch leaves
+    /// no node of it behind.
+    fn put_batch(&mut self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> Result<(), Error> {
+        Ok(self.append(nodes)?)
+    }
+
+    fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
+        let mut inner = self.inner.write().unwrap();
+        let location = inner.index.remove(hash)?;
+        let record = encode_record(DELETE, hash, location);
+        inner.index_file.write_all(&record).ok()
+    }
+
+    fn save_config(&self, key: &str, config: &[u8]) {
+        let mut file = File::create(self.config_path(key)).unwrap();
+        file.write_all(config).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::tree::{ProllyTree, Tree};
+
+    #[test]
+    fn test_mmap_storage() {
+        let dir = std::env::temp_dir().join("prolly_tree_mmap_storage");
+        let _ = fs::remove_dir_all(&dir);
+        let storage = MmapNodeStorage::<32>::open(&dir)
+            .unwrap()
+            .with_segment_size(4096);
+        let mut tree = ProllyTree::new(storage, TreeConfig::<32>::default());
+        for i in 0..500u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        let root = tree.get_root_hash().unwrap();
+        assert!(tree.storage().segment_count() > 1);
+        tree.storage().flush().unwrap();
+
+        // a torn index record is dropped on reopening
+        let mut index = OpenOptions::new()
+            .append(true)
+            .open(dir.join(INDEX_FILE))
+            .unwrap();
+        index.write_all(&[PUT, 1, 2, 3]).unwrap();
+        let mut storage = MmapNodeStorage::<32>::open(&dir).unwrap();
+        let node = storage.get_node_by_hash(&root).unwrap();
+        assert_eq!(node.get_hash(), root);
+        assert!(storage.delete_node(&root).is_some());
+        assert!(storage.delete_node(&root).is_none());
+
+        let mut storage = MmapNodeStorage::<32>::open(&dir).unwrap();
+        assert!(storage.get_node_by_hash(&root).is_none());
+        storage.put_batch(vec![(root.clone(), node)]).unwrap();
+        assert!(storage.get_node_by_hash(&root).is_some());
+
+        storage.save_config("tree", b"config");
+        assert_eq!(storage.get_config("tree"), Some(b"config".to_vec()));
+        assert_eq!(storage.get_config("other"), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_compaction() {
+        let dir = std::env::temp_dir().join("prolly_tree_mmap_compaction");
+        let _ = fs::remove_dir_all(&dir);
+        let storage = MmapNodeStorage::<32>::open(&dir)
+            .unwrap()
+            .with_segment_size(4096);
+        let mut tree = ProllyTree::new(storage, TreeConfig::<32>::default());
+        for i in 0..500u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        let root = tree.get_root_hash().unwrap();
+        let live = crate::pin::reachable_nodes(tree.storage(), vec![root.clone()], false);
+        let mut storage = tree.storage().clone();
+        let garbage: Vec<ValueDigest<32>> = storage
+            .inner
+            .read()
+            .unwrap()
+            .index
+            .keys()
+            .filter(|hash| !live.contains(*hash))
+            .cloned()
+            .collect();
+        for hash in &garbage {
+            storage.delete_node(hash).unwrap();
+        }
+        let segments = storage.segment_count();
+
+        let reports = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = reports.clone();
+        let stats = storage
+            .compact_in_background(move |progress| log.lock().unwrap().push(progress))
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.nodes_copied, live.len());
+        assert_eq!(stats.segments_removed, segments);
+        assert!(stats.bytes_reclaimed > 0);
+        assert!(storage.segment_count() < segments);
+        let last = *reports.lock().unwrap().last().unwrap();
+        assert_eq!(last.nodes_copied, last.nodes_total);
+
+        // the compacted storage reopens with every live node and none of the garbage
+        let storage = MmapNodeStorage::<32>::open(&dir).unwrap();
+        assert!(live
+            .iter()
+            .all(|hash| storage.get_node_by_hash(hash).is_some()));
+        assert!(garbage
+            .iter()
+            .all(|hash| storage.get_node_by_hash(hash).is_none()));
+        let index_len = fs::metadata(dir.join(INDEX_FILE)).unwrap().len();
+        assert_eq!(index_len as usize, live.len() * record_len::<32>());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A node storage keeping nodes in an object store, such as S3, GCS or Azure Blob Storage.
+//!
+//! Any [`ObjectStore`] works; the cloud stores are enabled with the features of the
+//! `object_store` crate (`aws`, `gcp`, `azure`).
+
+use crate::compression::{Compression, StorageManifest};
+use crate::digest::ValueDigest;
+use crate::node::ProllyNode;
+use crate::storage::{NodeReader, NodeStorage};
+use lru::LruCache;
+use object_store::path::Path;
+use object_store::{ObjectStore, PutPayload};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::{Builder, Runtime};
+use tokio::task::JoinSet;
+
+/// A node storage keeping every node as one object, named by its hash, under a prefix of
+/// an object store.
+///
+/// Recently used nodes are cached in memory. Nodes never change once written, so cached
+/// nodes never go stale. The storage drives the object store on its own runtime, so its
+/// methods block and must not be called from within an async runtime.
+pub struct ObjectStoreNodeStorage<const N: usize> {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+    runtime: Runtime,
+    cache: Mutex<LruCache<ValueDigest<N>, ProllyNode<N>>>,
+    compression: Option<Compression>,
+}
+
+impl<const N: usize> ObjectStoreNodeStorage<N> {
+    /// Creates a storage under a prefix of an object store.
+    ///
+    /// # Parameters
+    /// - `store`: The object store holding the nodes.
+    /// - `prefix`: The path under which nodes and configs are stored, e.g. `trees/main`.
+    /// - `cache_capacity`: The number of nodes cached in memory.
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: &str, cache_capacity: NonZeroUsize) -> Self {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start the object store runtime");
+        let mut storage = ObjectStoreNodeStorage {
+            store,
+            prefix: Path::from(prefix),
+            runtime,
+            cache: Mutex::new(LruCache::new(cache_capacity)),
+            compression: None,
+        };
+        storage.compression = StorageManifest::load::<N, _>(&storage).compression;
+        storage
+    }
+
+    /// Writes every node with `codec` from now on, or with the codec of the node if
+    /// `None`, and records the choice in the `StorageManifest` of the storage.
+    pub fn with_compression(mut self, codec: Option<Compression>) -> Self {
+        self.compression = codec;
+        StorageManifest { compression: codec }.save::<N, _>(&self);
+        self
+    }
+
+    fn node_path(&self, hash: &ValueDigest<N>) -> Path {
+        self.prefix.child("nodes").child(format!("{:x}", hash))
+    }
+
+    fn config_path(&self, key: &str) -> Path {
+        self.prefix.child("config").child(key)
+    }
+
+    fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        self.runtime.block_on(async {
+            let object = self.store.get(path).await.ok()?;
+            object.bytes().await.ok().map(|bytes| bytes.to_vec())
+        })
+    }
+
+    fn write(&self, path: &Path, data: Vec<u8>) -> Option<()> {
+        self.runtime
+            .block_on(self.store.put(path, PutPayload::from(data)))
+            .ok()
+            .map(|_| ())
+    }
+}
+
+impl<const N: usize> NodeReader<N> for ObjectStoreNodeStorage<N> {
+    /// Returns the node with the given hash, or `None` if it is missing or cannot be read.
+    fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        if let Some(node) = self.cache.lock().unwrap().get(hash) {
+            return Some(node.clone());
+        }
+        let node = ProllyNode::decode(&self.read(&self.node_path(hash))?).ok()?;
+        self.cache.lock().unwrap().put(hash.clone(), node.clone());
+        Some(node)
+    }
+
+    /// Fetches the nodes missing from the cache concurrently and caches them, so that a
+    /// scan waits for one round trip per batch of hints rather than one per node.
+    fn prefetch(&self, hashes: &[ValueDigest<N>]) {
+        let missing: Vec<ValueDigest<N>> = {
+            let cache = self.cache.lock().unwrap();
+            hashes
+                .iter()
+                .filter(|hash| !cache.contains(*hash))
+                .cloned()
+                .collect()
+        };
+        if missing.is_empty() {
+            return;
+        }
+        let nodes = self.runtime.block_on(async {
+            let mut requests = JoinSet::new();
+            for hash in missing {
+                let store = self.store.clone();
+                let path = self.node_path(&hash);
+                requests.spawn(async move {
+                    let data = store.get(&path).await.ok()?.bytes().await.ok()?;
+                    Some((hash, ProllyNode::<N>::decode(&data).ok()?))
+                });
+            }
+            let mut nodes = Vec::new();
+            while let Some(result) = requests.join_next().await {
+                nodes.extend(result.ok().flatten());
+            }
+            nodes
+        });
+        let mut cache = self.cache.lock().unwrap();
+        for (hash, node) in nodes {
+            cache.put(hash, node);
+        }
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        self.read(&self.config_path(key))
+    }
+}
+
+impl<const N: usize> NodeStorage<N> for ObjectStoreNodeStorage<N> {
+    fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
+        self.write(&self.node_path(&hash), node.encode_with(self.compression))?;
+        self.cache.lock().unwrap().put(hash, node);
+        Some(())
+    }
+
+    fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
+        self.cache.lock().unwrap().pop(hash);
+        self.runtime
+            .block_on(self.store.delete(&self.node_path(hash)))
+            .ok()
+    }
+
+    fn save_config(&self, key: &str, config: &[u8]) {
+        self.write(&self.config_path(key), config.to_vec())
+            .expect("failed to write the config to the object store");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::tree::{ProllyTree, Tree};
+    use object_store::memory::InMemory;
+
+    #[test]
+    fn test_object_store_storage() {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let capacity = NonZeroUsize::new(16).unwrap();
+        let storage = ObjectStoreNodeStorage::<32>::new(store.clone(), "trees/main", capacity);
+        let mut tree = ProllyTree::new(storage, TreeConfig::<32>::default());
+        for i in 0..500u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        let root = tree.get_root_hash().unwrap();
+
+        // a second storage over the same bucket starts with a cold cache
+        let mut storage = ObjectStoreNodeStorage::<32>::new(store, "trees/main", capacity);
+        let node = storage.get_node_by_hash(&root).unwrap();
+        assert_eq!(node.get_hash(), root);
+        assert!(storage.delete_node(&root).is_some());
+        assert!(storage.get_node_by_hash(&root).is_none());
+
+        storage.save_config("tree", b"config");
+        assert_eq!(storage.get_config("tree"), Some(b"config".to_vec()));
+        assert_eq!(storage.get_config("other"), None);
+    }
+}
@@ -0,0 +1,227 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A node storage in PostgreSQL tables.
+
+use crate::compression::{Compression, StorageManifest};
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::{NodeReader, NodeStorage};
+use postgres::{Client, NoTls};
+use std::io;
+use std::sync::Mutex;
+
+/// A node storage keeping nodes in a table of `bytea` hashes and encoded nodes, and
+/// configs in a second table.
+///
+/// For a table named `nodes`, the tables are:
+///
+/// ```sql
+/// CREATE TABLE nodes (hash bytea PRIMARY KEY, node bytea NOT NULL);
+/// CREATE TABLE nodes_config (key text PRIMARY KEY, config bytea NOT NULL);
+/// ```
+///
+/// The tables are created if they do not exist. Queries run one at a time over a single
+/// connection.
+pub struct PostgresNodeStorage<const N: usize> {
+    client: Mutex<Client>,
+    nodes: String,
+    configs: String,
+    compression: Option<Compression>,
+}
+
+impl<const N: usize> PostgresNodeStorage<N> {
+    /// Connects to a database without TLS and opens the storage in a table.
+    ///
+    /// # Parameters
+    /// - `params`: The connection string, e.g. `host=localhost user=postgres`.
+    /// - `table`: The name of the node table; the config table gets a `_config` suffix.
+    ///
+    /// # Returns
+    /// - The storage, or `Error::Io` if the connection fails or the tables cannot be
+    ///   created.
+    pub fn connect(params: &str, table: &str) -> Result<Self, Error> {
+        let client = Client::connect(params, NoTls).map_err(postgres_error)?;
+        Self::new(client, table)
+    }
+
+    /// Opens the storage in a table over an existing connection, e.g. one using TLS.
+    ///
+    /// # Returns
+    /// - The storage, or `Error::Io` if the tables cannot be created.
+    pub fn new(mut client: Client, table: &str) -> Result<Self, Error> {
+        let nodes = quote_identifier(table);
+        let configs = quote_identifier(&format!("{table}_config"));
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {nodes} (hash bytea PRIMARY KEY, node bytea NOT NULL);
+                 CREATE TABLE IF NOT EXISTS {configs} (key text PRIMARY KEY, config bytea NOT NULL);"
+            ))
+            .map_err(postgres_error)?;
+        let mut storage = PostgresNodeStorage {
+            client: Mutex::new(client),
+            nodes,
+            configs,
+            compression: None,
+        };
+        storage.compression = StorageManifest::load::<N, _>(&storage).compression;
+        Ok(storage)
+    }
+
+    /// Writes every node with `codec` from now on, or with the codec of the node if
+    /// `None`, and records the choice in the `StorageManifest` of the storage.
+    pub fn with_compression(mut self, codec: Option<Compression>) -> Self {
+        self.compression = codec;
+        StorageManifest { compression: codec }.save::<N, _>(&self);
+        self
+    }
+}
+
+/// Quotes a table name, so that any name can be used without the risk of SQL injection.
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn postgres_error(err: postgres::Error) -> Error {
+    Error::Io(io::Error::other(err))
+}
+
+impl<const N: usize> NodeReader<N> for PostgresNodeStorage<N> {
+    fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        let query = format!("SELECT node FROM {} WHERE hash = $1", self.nodes);
+        let row = self
+            .client
+            .lock()
+            .unwrap()
+            .query_opt(&query, &[&hash.as_bytes()])
+            .ok()??;
+        ProllyNode::decode(row.get::<_, &[u8]>(0)).ok()
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        let query = format!("SELECT config FROM {} WHERE key = $1", self.configs);
+        let row = self
+            .client
+            .lock()
+            .unwrap()
+            .query_opt(&query, &[&key])
+            .ok()??;
+        Some(row.get(0))
+    }
+}
+
+impl<const N: usize> NodeStorage<N> for PostgresNodeStorage<N> {
+    fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
+        let query = format!(
+            "INSERT INTO {} (hash, node) VALUES ($1, $2) ON CONFLICT (hash) DO NOTHING",
+            self.nodes
+        );
+        self.client
+            .get_mut()
+            .unwrap()
+            .execute(
+                &query,
+                &[&hash.as_bytes(), &node.encode_with(self.compression)],
+            )
+            .ok()
+            .map(|_| ())
+    }
+
+    fn put_batch(&mut self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> Result<(), Error> {
+        let query = format!(
+            "INSERT INTO {} (hash, node) VALUES ($1, $2) ON CONFLICT (hash) DO NOTHING",
+            self.nodes
+        );
+        let client = self.client.get_mut().unwrap();
+        let mut txn = client.transaction().map_err(postgres_error)?;
+        let statement = txn.prepare(&query).map_err(postgres_error)?;
+        for (hash, node) in nodes {
+            txn.execute(
+                &statement,
+                &[&hash.as_bytes(), &node.encode_with(self.compression)],
+            )
+            .map_err(postgres_error)?;
+        }
+        txn.commit().map_err(postgres_error)
+    }
+
+    fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
+        let query = format!("DELETE FROM {} WHERE hash = $1", self.nodes);
+        let deleted = self
+            .client
+            .get_mut()
+            .unwrap()
+            .execute(&query, &[&hash.as_bytes()])
+            .ok()?;
+        (deleted > 0).then_some(())
+    }
+
+    fn save_config(&self, key: &str, config: &[u8]) {
+        let query = format!(
+            "INSERT INTO {} (key, config) VALUES ($1, $2)
+             ON CONFLICT (key) DO UPDATE SET config = EXCLUDED.config",
+            self.configs
+        );
+        self.client
+            .lock()
+            .unwrap()
+            .execute(&query, &[&key, &config])
+            .expect("failed to write the config to Postgres");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::tree::{ProllyTree, Tree};
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(quote_identifier("nodes"), "\"nodes\"");
+        assert_eq!(quote_identifier("a\"; DROP"), "\"a\"\"; DROP\"");
+    }
+
+    /// Runs against the database in `PROLLYTREE_POSTGRES_URL`, and is skipped without one.
+    #[test]
+    fn test_postgres_storage() {
+        let Ok(params) = std::env::var("PROLLYTREE_POSTGRES_URL") else {
+            return;
+        };
+        let table = format!("prollytree_test_{}", std::process::id());
+        let storage = PostgresNodeStorage::<32>::connect(&params, &table).unwrap();
+        let mut tree = ProllyTree::new(storage, TreeConfig::<32>::default());
+        for i in 0..200u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        let root = tree.get_root_hash().unwrap();
+
+        let mut storage = PostgresNodeStorage::<32>::connect(&params, &table).unwrap();
+        assert_eq!(storage.get_node_by_hash(&root).unwrap().get_hash(), root);
+        assert!(storage.delete_node(&root).is_some());
+        assert!(storage.get_node_by_hash(&root).is_none());
+        storage.save_config("tree", b"config");
+        storage.save_config("tree", b"updated");
+        assert_eq!(storage.get_config("tree"), Some(b"updated".to_vec()));
+
+        let mut client = storage.client.into_inner().unwrap();
+        client
+            .batch_execute(&format!(
+                "DROP TABLE {}; DROP TABLE {};",
+                storage.nodes, storage.configs
+            ))
+            .unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A node storage in a single redb database file.
+
+use crate::compression::{Compression, StorageManifest};
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::{NodeReader, NodeStorage};
+use redb::{Database, TableDefinition};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+const NODES: TableDefinition<&[u8], &[u8]> = TableDefinition::new("nodes");
+const CONFIGS: TableDefinition<&str, &[u8]> = TableDefinition::new("configs");
+
+/// A node storage keeping nodes, keyed by their hash, and configs in two tables of a redb
+/// database.
+///
+/// Every write is its own ACID transaction, durable once the call returns. redb runs no
+/// background threads, which suits tools embedding a tree in a single file.
+#[derive(Clone)]
+pub struct RedbNodeStorage<const N: usize> {
+    db: Arc<Database>,
+    compression: Option<Compression>,
+}
+
+impl<const N: usize> RedbNodeStorage<N> {
+    /// Opens a redb database file, creating it if it does not exist.
+    ///
+    /// # Returns
+    /// - The storage, or `Error::Io` if the database cannot be opened.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_db(Database::create(path).map_err(redb_error)?)
+    }
+
+    /// Creates a storage in an open database, e.g. one on an in-memory backend or one
+    /// shared with other tables.
+    ///
+    /// # Returns
+    /// - The storage, or `Error::Io` if its tables cannot be created.
+    pub fn from_db(db: Database) -> Result<Self, Error> {
+        let txn = db.begin_write().map_err(redb_error)?;
+        txn.open_table(NODES).map_err(redb_error)?;
+        txn.open_table(CONFIGS).map_err(redb_error)?;
+        txn.commit().map_err(redb_error)?;
+        let mut storage = RedbNodeStorage {
+            db: Arc::new(db),
+            compression: None,
+        };
+        storage.compression = StorageManifest::load::<N, _>(&storage).compression;
+        Ok(storage)
+    }
+
+    /// Writes every node with `codec` from now on, or with the codec of the node if
+    /// `None`, and records the choice in the `StorageManifest` of the storage.
+    pub fn with_compression(mut self, codec: Option<Compression>) -> Self {
+        self.compression = codec;
+        StorageManifest { compression: codec }.save::<N, _>(&self);
+        self
+    }
+
+    fn read<K: redb::Key + 'static>(
+        &self,
+        table: TableDefinition<K, &[u8]>,
+        key: K::SelfType<'_>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let txn = self.db.begin_read().map_err(redb_error)?;
+        let table = txn.open_table(table).map_err(redb_error)?;
+        let value = table.get(key).map_err(redb_error)?;
+        Ok(value.map(|value| value.value().to_vec()))
+    }
+
+    fn write<K: redb::Key + 'static>(
+        &self,
+        table: TableDefinition<K, &[u8]>,
+        key: K::SelfType<'_>,
+        value: Option<&[u8]>,
+    ) -> Result<bool, Error> {
+        let txn = self.db.begin_write().map_err(redb_error)?;
+        let mut table = txn.open_table(table).map_err(redb_error)?;
+        let existed = match value {
+            Some(value) => table.insert(key, value).map_err(redb_error)?.is_some(),
+            None => table.remove(key).map_err(redb_error)?.is_some(),
+        };
+        drop(table);
+        txn.commit().map_err(redb_error)?;
+        Ok(existed)
+    }
+}
+
+fn redb_error(err: impl Into<redb::Error>) -> Error {
+    Error::Io(io::Error::other(err.into()))
+}
+
+impl<const N: usize> NodeReader<N> for RedbNodeStorage<N> {
+    fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        let data = self.read(NODES, hash.as_bytes()).ok()??;
+        ProllyNode::decode(&data).ok()
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        self.read(CONFIGS, key).ok()?
+    }
+}
+
+impl<const N: usize> NodeStorage<N> for RedbNodeStorage<N> {
+    fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
+        self.write(
+            NODES,
+            hash.as_bytes(),
+            Some(&node.encode_with(self.compression)),
+        )
+        .ok()
+        .map(|_| ())
+    }
+
+    fn put_batch(&mut self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> Result<(), Error> {
+        let txn = self.db.begin_write().map_err(redb_error)?;
+        let mut table = txn.open_table(NODES).map_err(redb_error)?;
+        for (hash, node) in nodes {
+            table
+                .insert(
+                    hash.as_bytes(),
+                    node.encode_with(self.compression).as_slice(),
+                )
+                .map_err(redb_error)?;
+        }
+        drop(table);
+        txn.commit().map_err(redb_error)
+    }
+
+    fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
+        self.write(NODES, hash.as_bytes(), None).ok()?.then_some(())
+    }
+
+    fn save_config(&self, key: &str, config: &[u8]) {
+        self.write(CONFIGS, key, Some(config))
+            .expect("failed to write the config to redb");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::tree::{ProllyTree, Tree};
+    use redb::backends::InMemoryBackend;
+
+    #[test]
+    fn test_redb_storage() {
+        let db = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .unwrap();
+        let mut storage = RedbNodeStorage::<32>::from_db(db).unwrap();
+        let mut tree = ProllyTree::new(storage.clone(), TreeConfig::<32>::default());
+        for i in 0..500u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        let root = tree.get_root_hash().unwrap();
+
+        assert_eq!(storage.get_node_by_hash(&root).unwrap().get_hash(), root);
+        assert!(storage.delete_node(&root).is_some());
+        assert!(storage.delete_node(&root).is_none());
+        assert!(storage.get_node_by_hash(&root).is_none());
+
+        storage.save_config("tree", b"config");
+        assert_eq!(storage.get_config("tree"), Some(b"config".to_vec()));
+        let node = ProllyNode::<32> {
+            keys: vec![b"batch".to_vec()],
+            values: vec![b"batch".to_vec()],
+            ..Default::default()
+        };
+        storage
+            .put_batch(vec![(node.get_hash(), node.clone())])
+            .unwrap();
+        assert!(storage.get_node_by_hash(&node.get_hash()).is_some());
+        assert_eq!(storage.get_config("other"), None);
+    }
+}
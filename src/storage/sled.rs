@@ -0,0 +1,157 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A node storage on the embedded, pure-Rust sled database.
+
+use crate::compression::{Compression, StorageManifest};
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::{NodeReader, NodeStorage};
+use std::path::Path;
+
+const NODES: &str = "nodes";
+const CONFIGS: &str = "configs";
+
+/// A node storage keeping nodes and configs in two trees of a sled database, with nodes
+/// keyed by their hash.
+#[derive(Clone)]
+pub struct SledNodeStorage<const N: usize> {
+    db: sled::Db,
+    nodes: sled::Tree,
+    configs: sled::Tree,
+    compression: Option<Compression>,
+}
+
+impl<const N: usize> SledNodeStorage<N> {
+    /// Opens or creates a sled database in a directory.
+    ///
+    /// # Returns
+    /// - The storage, or `Error::Io` if the database cannot be opened.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_db(sled::open(path).map_err(sled_error)?)
+    }
+
+    /// Creates a storage in an open sled database, e.g. a temporary one or one shared with
+    /// other data.
+    ///
+    /// # Returns
+    /// - The storage, or `Error::Io` if its trees cannot be opened.
+    pub fn from_db(db: sled::Db) -> Result<Self, Error> {
+        let mut storage = SledNodeStorage {
+            nodes: db.open_tree(NODES).map_err(sled_error)?,
+            configs: db.open_tree(CONFIGS).map_err(sled_error)?,
+            db,
+            compression: None,
+        };
+        storage.compression = StorageManifest::load::<N, _>(&storage).compression;
+        Ok(storage)
+    }
+
+    /// Writes every node with `codec` from now on, or with the codec of the node if
+    /// `None`, and records the choice in the `StorageManifest` of the storage.
+    pub fn with_compression(mut self, codec: Option<Compression>) -> Self {
+        self.compression = codec;
+        StorageManifest { compression: codec }.save::<N, _>(&self);
+        self
+    }
+
+    /// Writes all pending changes to disk.
+    ///
+    /// sled flushes in the background; call this before relying on a write surviving a
+    /// crash.
+    pub fn flush(&self) -> Result<(), Error> {
+        self.db.flush().map(|_| ()).map_err(sled_error)
+    }
+}
+
+fn sled_error(err: sled::Error) -> Error {
+    Error::Io(err.into())
+}
+
+impl<const N: usize> NodeReader<N> for SledNodeStorage<N> {
+    fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        let data = self.nodes.get(hash.as_bytes()).ok()??;
+        ProllyNode::decode(&data).ok()
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        self.configs.get(key).ok()?.map(|config| config.to_vec())
+    }
+}
+
+impl<const N: usize> NodeStorage<N> for SledNodeStorage<N> {
+    fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
+        self.nodes
+            .insert(hash.as_bytes(), node.encode_with(self.compression))
+            .ok()?;
+        Some(())
+    }
+
+    fn put_batch(&mut self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> Result<(), Error> {
+        let mut batch = sled::Batch::default();
+        for (hash, node) in nodes {
+            batch.insert(hash.as_bytes(), node.encode_with(self.compression));
+        }
+        self.nodes.apply_batch(batch).map_err(sled_error)
+    }
+
+    fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
+        self.nodes.remove(hash.as_bytes()).ok()?.map(|_| ())
+    }
+
+    fn save_config(&self, key: &str, config: &[u8]) {
+        self.configs
+            .insert(key, config)
+            .expect("failed to write the config to sled");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::tree::{ProllyTree, Tree};
+
+    #[test]
+    fn test_sled_storage() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let storage = SledNodeStorage::<32>::from_db(db.clone()).unwrap();
+        let mut tree = ProllyTree::new(storage, TreeConfig::<32>::default());
+        for i in 0..500u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        let root = tree.get_root_hash().unwrap();
+
+        let mut storage = SledNodeStorage::<32>::from_db(db).unwrap();
+        assert_eq!(storage.get_node_by_hash(&root).unwrap().get_hash(), root);
+        assert!(storage.delete_node(&root).is_some());
+        assert!(storage.delete_node(&root).is_none());
+        assert!(storage.get_node_by_hash(&root).is_none());
+
+        storage.save_config("tree", b"config");
+        assert_eq!(storage.get_config("tree"), Some(b"config".to_vec()));
+        assert_eq!(storage.get_config("other"), None);
+        let node = ProllyNode::<32> {
+            keys: vec![b"batch".to_vec()],
+            values: vec![b"batch".to_vec()],
+            ..Default::default()
+        };
+        storage
+            .put_batch(vec![(node.get_hash(), node.clone())])
+            .unwrap();
+        assert!(storage.get_node_by_hash(&node.get_hash()).is_some());
+        storage.flush().unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A storage combining a fast backend for recent nodes with a slow one for the rest.
+
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::{NodeReader, NodeStorage};
+use lru::LruCache;
+
+/// A node storage that writes nodes to a hot backend and migrates them to a cold backend
+/// once they are no longer among the most recently written nodes.
+///
+/// Reads look in the hot backend first and fall back to the cold one, so trees do not
+/// notice where a node lives. Only nodes written through this storage are migrated; nodes
+/// already in the hot backend when it is opened stay there.
+pub struct TieredNodeStorage<const N: usize, H: NodeStorage<N>, C: NodeStorage<N>> {
+    hot: H,
+    cold: C,
+    hot_capacity: usize,
+    recent: LruCache<ValueDigest<N>, ()>,
+}
+
+impl<const N: usize, H: NodeStorage<N>, C: NodeStorage<N>> TieredNodeStorage<N, H, C> {
+    /// Creates a tiered storage.
+    ///
+    /// # Parameters
+    /// - `hot`: The fast backend recent nodes are written to, e.g. memory.
+    /// - `cold`: The slow backend nodes migrate to, e.g. files or an object store.
+    /// - `hot_capacity`: The number of recently written nodes kept in the hot backend.
+    pub fn new(hot: H, cold: C, hot_capacity: usize) -> Self {
+        TieredNodeStorage {
+            hot,
+            cold,
+            hot_capacity,
+            recent: LruCache::unbounded(),
+        }
+    }
+
+    /// Returns the hot backend.
+    pub fn hot(&self) -> &H {
+        &self.hot
+    }
+
+    /// Returns the cold backend.
+    pub fn cold(&self) -> &C {
+        &self.cold
+    }
+
+    /// Returns the number of nodes written through this storage that are still hot.
+    pub fn hot_len(&self) -> usize {
+        self.recent.len()
+    }
+
+    /// Migrates every node written through this storage to the cold backend, e.g. before
+    /// shutting down a process whose hot backend is in memory.
+    ///
+    /// # Returns
+    /// - The number of migrated nodes.
+    pub fn migrate_all(&mut self) -> usize {
+        self.migrate_down_to(0)
+    }
+
+    /// Migrates the least recently written nodes until at most `capacity` remain hot.
+    fn migrate_down_to(&mut self, capacity: usize) -> usize {
+        let mut migrated = 0;
+        while self.recent.len() > capacity {
+            let Some((hash, ())) = self.recent.pop_lru() else {
+                break;
+            };
+            if let Some(node) = self.hot.get_node_by_hash(&hash) {
+                // only drop the hot copy once the cold one is written
+                if self.cold.insert_node(hash.clone(), node).is_some() {
+                    self.hot.delete_node(&hash);
+                    migrated += 1;
+                }
+            }
+        }
+        migrated
+    }
+}
+
+impl<const N: usize, H: NodeStorage<N>, C: NodeStorage<N>> NodeReader<N>
+    for TieredNodeStorage<N, H, C>
+{
+    fn get_node_by_hash(&self, hash: &ValueDigest<N>) -> Option<ProllyNode<N>> {
+        self.hot
+            .get_node_by_hash(hash)
+            .or_else(|| self.cold.get_node_by_hash(hash))
+    }
+
+    /// Forwards the hint to the cold storage, the only one slow enough to need it.
+    fn prefetch(&self, hashes: &[ValueDigest<N>]) {
+        self.cold.prefetch(hashes)
+    }
+
+    fn get_config(&self, key: &str) -> Option<Vec<u8>> {
+        self.cold
+            .get_config(key)
+            .or_else(|| self.hot.get_config(key))
+    }
+}
+
+impl<const N: usize, H: NodeStorage<N>, C: NodeStorage<N>> NodeStorage<N>
+    for TieredNodeStorage<N, H, C>
+{
+    fn insert_node(&mut self, hash: ValueDigest<N>, node: ProllyNode<N>) -> Option<()> {
+        self.hot.insert_node(hash.clone(), node)?;
+        self.recent.put(hash, ());
+        self.migrate_down_to(self.hot_capacity);
+        Some(())
+    }
+
+    fn put_batch(&mut self, nodes: Vec<(ValueDigest<N>, ProllyNode<N>)>) -> Result<(), Error> {
+        let hashes: Vec<ValueDigest<N>> = nodes.iter().map(|(hash, _)| hash.clone()).collect();
+        self.hot.put_batch(nodes)?;
+        for hash in hashes {
+            self.recent.put(hash, ());
+        }
+        self.migrate_down_to(self.hot_capacity);
+        Ok(())
+    }
+
+    fn delete_node(&mut self, hash: &ValueDigest<N>) -> Option<()> {
+        self.recent.pop(hash);
+        let hot = self.hot.delete_node(hash);
+        let cold = self.cold.delete_node(hash);
+        hot.or(cold)
+    }
+
+    fn save_config(&self, key: &str, config: &[u8]) {
+        self.cold.save_config(key, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TreeConfig;
+    use crate::storage::InMemoryNodeStorage;
+    use crate::tree::{ProllyTree, Tree};
+
+    #[test]
+    fn test_tiered_storage() {
+        let mut storage = TieredNodeStorage::new(
+            InMemoryNodeStorage::<32>::new(),
+            InMemoryNodeStorage::<32>::new(),
+            8,
+        );
+        let nodes: Vec<ProllyNode<32>> = (0..20u8)
+            .map(|i| ProllyNode {
+                keys: vec![vec![i]],
+                values: vec![vec![i]],
+                ..Default::default()
+            })
+            .collect();
+        for node in &nodes {
+            storage.insert_node(node.get_hash(), node.clone());
+        }
+        assert_eq!(storage.hot_len(), 8);
+        for (i, node) in nodes.iter().enumerate() {
+            let hash = node.get_hash();
+            assert_eq!(storage.hot().get_node_by_hash(&hash).is_some(), i >= 12);
+            assert_eq!(storage.cold().get_node_by_hash(&hash).is_some(), i < 12);
+            assert_eq!(storage.get_node_by_hash(&hash).unwrap().keys, node.keys);
+        }
+
+        // rewriting a node makes it recent again
+        storage.insert_node(nodes[12].get_hash(), nodes[12].clone());
+        storage.insert_node(nodes[0].get_hash(), nodes[0].clone());
+        assert!(storage
+            .hot()
+            .get_node_by_hash(&nodes[12].get_hash())
+            .is_some());
+        assert!(storage
+            .hot()
+            .get_node_by_hash(&nodes[13].get_hash())
+            .is_none());
+
+        assert_eq!(storage.migrate_all(), 8);
+        assert_eq!(storage.hot_len(), 0);
+        let hash = nodes[0].get_hash();
+        assert!(storage.delete_node(&hash).is_some());
+        assert!(storage.get_node_by_hash(&hash).is_none());
+    }
+
+    #[test]
+    fn test_tiered_tree() {
+        let storage = TieredNodeStorage::new(
+            InMemoryNodeStorage::<32>::new(),
+            InMemoryNodeStorage::<32>::new(),
+            8,
+        );
+        let mut tree = ProllyTree::new(storage, TreeConfig::<32>::default());
+        for i in 0..1000u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![0]);
+        }
+        let root = tree.get_root_hash().unwrap();
+        // the root was written last, so it is hot
+        assert!(tree.storage().hot().get_node_by_hash(&root).is_some());
+        assert!(tree.verify_integrity().is_ok());
+    }
+}
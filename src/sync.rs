@@ -0,0 +1,243 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Anti-entropy synchronization of the nodes of a tree between two stores.
+//!
+//! A `SyncSession` pulls the version a remote store is at into a local store. It walks
+//! the remote tree from the root, one level per round trip, and only fetches the nodes
+//! the local store does not hold: subtrees whose hash is already present are skipped as
+//! a whole. How nodes travel between the stores is up to the `SyncTransport`.
+
+use crate::blob::chunk_hashes;
+use crate::digest::ValueDigest;
+use crate::errors::Error;
+use crate::node::ProllyNode;
+use crate::storage::NodeStorage;
+use crate::ttl;
+use std::collections::{HashMap, HashSet};
+
+/// The remote side of a sync session.
+pub trait SyncTransport<const N: usize> {
+    /// Returns the root hash of the version to sync.
+    fn root_hash(&mut self) -> Result<ValueDigest<N>, Error>;
+
+    /// Fetches nodes by their hashes, in one round trip.
+    ///
+    /// The nodes may be returned in any order; nodes that were not asked for are ignored.
+    fn fetch_nodes(&mut self, hashes: &[ValueDigest<N>]) -> Result<Vec<ProllyNode<N>>, Error>;
+}
+
+/// A transport serving a version of a tree straight from a node storage, e.g. on the
+/// remote end of a network transport or for syncing between stores in one process.
+pub struct StorageTransport<'a, const N: usize, S: NodeStorage<N>> {
+    storage: &'a S,
+    root_hash: ValueDigest<N>,
+}
+
+impl<'a, const N: usize, S: NodeStorage<N>> StorageTransport<'a, N, S> {
+    /// Serves the version of a tree with the given root hash.
+    pub fn new(storage: &'a S, root_hash: ValueDigest<N>) -> Self {
+        StorageTransport { storage, root_hash }
+    }
+}
+
+impl<const N: usize, S: NodeStorage<N>> SyncTransport<N> for StorageTransport<'_, N, S> {
+    fn root_hash(&mut self) -> Result<ValueDigest<N>, Error> {
+        Ok(self.root_hash.clone())
+    }
+
+    fn fetch_nodes(&mut self, hashes: &[ValueDigest<N>]) -> Result<Vec<ProllyNode<N>>, Error> {
+        hashes
+            .iter()
+            .map(|hash| {
+                self.storage
+                    .try_get_node(hash)?
+                    .ok_or_else(|| Error::MissingNode(hex::encode(hash.as_bytes())))
+            })
+            .collect()
+    }
+}
+
+/// The outcome of a sync session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncReport<const N: usize> {
+    /// The root hash of the remote version, now complete in the local store.
+    pub root_hash: ValueDigest<N>,
+    /// The number of nodes and value chunks fetched from the remote store.
+    pub nodes_fetched: usize,
+    /// The number of subtrees and chunks skipped because the local store held them.
+    pub nodes_skipped: usize,
+    /// The number of calls to `SyncTransport::fetch_nodes`.
+    pub round_trips: usize,
+    /// The key ranges covered by the fetched leaves, as start key and exclusive end key
+    /// (`None` for no end). Adjacent ranges are joined.
+    pub divergent_ranges: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+/// A node still to be fetched, with the exclusive upper bound of its keys.
+struct Pending<const N: usize> {
+    hash: ValueDigest<N>,
+    upper: Option<Vec<u8>>,
+    chunk: bool,
+}
+
+/// Pulls the nodes of a remote version of a tree into a local store.
+///
+/// Nodes are only written to the local store once their whole subtree has been fetched,
+/// children before parents. A node found in the local store can therefore be trusted to
+/// come with its subtree, and an interrupted session can simply be started again.
+pub struct SyncSession<'a, const N: usize, S: NodeStorage<N>> {
+    storage: &'a mut S,
+    expiring: bool,
+    batch_size: usize,
+}
+
+impl<'a, const N: usize, S: NodeStorage<N>> SyncSession<'a, N, S> {
+    /// Creates a session that syncs into `storage`.
+    pub fn new(storage: &'a mut S) -> Self {
+        SyncSession {
+            storage,
+            expiring: false,
+            batch_size: 256,
+        }
+    }
+
+    /// Sets whether the values of the tree carry expiry timestamps, which is needed to
+    /// find the chunks of values stored outside of the leaves.
+    pub fn with_expiring(mut self, expiring: bool) -> Self {
+        self.expiring = expiring;
+        self
+    }
+
+    /// Sets the maximum number of nodes requested in one round trip.
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Fetches every node of the remote version that is missing in the local store.
+    ///
+    /// # Parameters
+    /// - `transport`: The connection to the remote store.
+    ///
+    /// # Returns
+    /// - A report of the session, the error of the transport, `Error::MissingNode` if the
+    ///   remote store does not return a requested node or returns one that does not match
+    ///   the hash it was requested by, or the error of writing the fetched nodes with
+    ///   `NodeStorage::put_batch`. Nothing is written on a transport error.
+    pub fn pull<T: SyncTransport<N>>(&mut self, transport: &mut T) -> Result<SyncReport<N>, Error> {
+        let root_hash = transport.root_hash()?;
+        let mut report = SyncReport {
+            root_hash: root_hash.clone(),
+            nodes_fetched: 0,
+            nodes_skipped: 0,
+            round_trips: 0,
+            divergent_ranges: Vec::new(),
+        };
+        let mut fetched = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = vec![Pending {
+            hash: root_hash,
+            upper: None,
+            chunk: false,
+        }];
+
+        while !pending.is_empty() {
+            let mut next = Vec::new();
+            for batch in pending.chunks(self.batch_size) {
+                let missing: Vec<&Pending<N>> = batch
+                    .iter()
+                    .filter(|item| {
+                        let present = seen.contains(&item.hash)
+                            || self
+                                .storage
+                                .try_get_node(&item.hash)
+                                .is_ok_and(|node| node.is_some());
+                        if present {
+                            report.nodes_skipped += 1;
+                        }
+                        !present && seen.insert(item.hash.clone())
+                    })
+                    .collect();
+                if missing.is_empty() {
+                    continue;
+                }
+                let hashes: Vec<ValueDigest<N>> =
+                    missing.iter().map(|item| item.hash.clone()).collect();
+                report.round_trips += 1;
+                let mut nodes: HashMap<ValueDigest<N>, ProllyNode<N>> = transport
+                    .fetch_nodes(&hashes)?
+                    .into_iter()
+                    .map(|node| (node.get_hash(), node))
+                    .collect();
+                // expand in key order, whatever order the nodes arrived in
+                for item in missing {
+                    let node = nodes
+                        .remove(&item.hash)
+                        .ok_or_else(|| Error::MissingNode(hex::encode(item.hash.as_bytes())))?;
+                    if !item.chunk {
+                        self.expand(&node, item.upper.as_ref(), &mut next, &mut report);
+                    }
+                    fetched.push((item.hash.clone(), node));
+                }
+            }
+            pending = next;
+        }
+
+        report.nodes_fetched = fetched.len();
+        // children were fetched after their parents
+        fetched.reverse();
+        self.storage.put_batch(fetched)?;
+        Ok(report)
+    }
+
+    /// Queues the children of a fetched node, or the chunks and key range of a leaf.
+    fn expand(
+        &self,
+        node: &ProllyNode<N>,
+        upper: Option<&Vec<u8>>,
+        next: &mut Vec<Pending<N>>,
+        report: &mut SyncReport<N>,
+    ) {
+        if !node.is_leaf {
+            for (i, child) in node.values.iter().enumerate() {
+                next.push(Pending {
+                    hash: ValueDigest::raw_hash(child),
+                    upper: node.keys.get(i + 1).or(upper).cloned(),
+                    chunk: false,
+                });
+            }
+            return;
+        }
+        for value in &node.values {
+            let stored = if self.expiring {
+                ttl::unwrap(value).1
+            } else {
+                value
+            };
+            next.extend(chunk_hashes::<N>(stored).into_iter().map(|hash| Pending {
+                hash,
+                upper: None,
+                chunk: true,
+            }));
+        }
+        if let Some(first) = node.keys.first() {
+            let ranges = &mut report.divergent_ranges;
+            match ranges.last_mut() {
+                Some((_, end)) if end.as_ref() == Some(first) => *end = upper.cloned(),
+                _ => ranges.push((first.clone(), upper.cloned())),
+            }
+        }
+    }
+}
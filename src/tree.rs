@@ -12,12 +12,40 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+use crate::access::{AccessHeatmap, AccessTracker};
+use crate::batch::WriteBatch;
+use crate::blob::{blobs_enabled, chunk_hashes, decode_value, encode_value, external_len};
+use crate::bulk::BulkLoader;
 use crate::config::TreeConfig;
-use crate::diff::DiffResult;
-use crate::digest::ValueDigest;
+use crate::diff::{
+    diff_nodes, load_root, merge_nodes, zip_trees, ConflictRecord, DiffResult, Merge,
+    MergeConflict, MergePolicy, Pairing, Patch, CONFLICT_PREFIX,
+};
+use crate::digest::{HashAlgorithm, ValueDigest};
+use crate::errors::Error;
+use crate::export::{export_tree, import_tree};
+use crate::integrity::{
+    check_tree, chunk_intact, salvage_leaves, CheckOptions, IntegrityReport, RepairReport,
+};
+use crate::iter::{prefix_range, Cursor, Page, PageToken, ProofIter, RangeProofStream, TreeIter};
 use crate::node::{Node, ProllyNode};
-use crate::proof::Proof;
-use crate::storage::NodeStorage;
+use crate::observer::{ObserverId, Observers};
+use crate::proof::cache::ProofCache;
+use crate::proof::evm::EvmProof;
+use crate::proof::verifier::{fixed_layout, route, ProofNode};
+use crate::proof::{CountProof, MultiProof, Neighbor, PathSegment, Proof, RangeProof};
+use crate::schema::ValueFormat;
+use crate::snapshot::Snapshot;
+use crate::storage::{InMemoryNodeStorage, NodeStorage, OverlayStorage};
+use crate::sync::{SyncReport, SyncSession, SyncTransport};
+use crate::ttl;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime};
 
 /// Trait representing a Prolly tree with a fixed size N and a node storage S.
 /// This trait provides methods for creating, modifying, and querying the tree.
@@ -34,6 +62,9 @@ pub trait Tree<const N: usize, S: NodeStorage<N>> {
 
     /// Inserts a key-value pair into the tree.
     ///
+    /// The pair is not checked against the key and value formats of the tree; use
+    /// `ProllyTree::try_insert` to refuse pairs that do not match them.
+    ///
     /// # Parameters
     /// - `key`: The key to insert.
     /// - `value`: The value associated with the key.
@@ -41,9 +72,17 @@ pub trait Tree<const N: usize, S: NodeStorage<N>> {
 
     /// Inserts multiple key-value pairs into the tree in an optimized way.
     ///
+    /// The pairs are sorted and applied in a single traversal, so every affected node is
+    /// rebalanced and rehashed once per batch instead of once per key. If a key appears
+    /// more than once, the last value wins. The pairs are not checked against the key and
+    /// value formats of the tree; use `ProllyTree::try_insert_batch` to refuse them.
+    ///
     /// # Parameters
     /// - `keys`: The keys to insert.
     /// - `values`: The values associated with the keys.
+    ///
+    /// # Panics
+    /// - If a node on the way to the inserted keys is missing from storage.
     fn insert_batch(&mut self, keys: &[Vec<u8>], values: &[Vec<u8>]);
 
     /// Updates the value associated with the specified key in the tree.
@@ -56,6 +95,38 @@ pub trait Tree<const N: usize, S: NodeStorage<N>> {
     /// - `true` if the key was found and updated, `false` otherwise.
     fn update(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool;
 
+    /// Writes a value only if the current value of the key matches the expected one.
+    ///
+    /// This is a compare-and-swap: writers that read a value, compute a new one and write
+    /// it back can detect that another writer changed the key in between.
+    ///
+    /// # Parameters
+    /// - `key`: The key to write.
+    /// - `expected_old`: The value the key must currently have, or `None` if the key
+    ///   must not exist yet.
+    /// - `new_value`: The value to write.
+    ///
+    /// # Returns
+    /// - `true` if the current value matched and the write was applied, `false` otherwise.
+    fn insert_if(&mut self, key: Vec<u8>, expected_old: Option<&[u8]>, new_value: Vec<u8>) -> bool;
+
+    /// Replaces the value of a key with the result of a function of its current value.
+    ///
+    /// This is a read-modify-write in a single call, e.g. to increment a counter or append
+    /// to a list. The result is written like a normal `insert`, or like a `delete` if the
+    /// function returns `None`.
+    ///
+    /// # Parameters
+    /// - `key`: The key to write.
+    /// - `merge_fn`: Computes the new value from the current one, which is `None` if the
+    ///   key does not exist.
+    ///
+    /// # Returns
+    /// - The new value of the key.
+    fn merge<F>(&mut self, key: Vec<u8>, merge_fn: F) -> Option<Vec<u8>>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>;
+
     /// Deletes the key-value pair associated with the specified key from the tree.
     ///
     /// # Parameters
@@ -71,8 +142,38 @@ pub trait Tree<const N: usize, S: NodeStorage<N>> {
     /// - `keys`: The keys to delete.
     fn delete_batch(&mut self, keys: &[Vec<u8>]);
 
+    /// Deletes all key-value pairs within a key range.
+    ///
+    /// The range is removed in a single traversal, and every affected node is rebalanced
+    /// once instead of once per deleted key.
+    ///
+    /// # Parameters
+    /// - `range`: The range of keys to delete.
+    ///
+    /// # Returns
+    /// - The number of key-value pairs deleted.
+    ///
+    /// # Panics
+    /// - If a node overlapping the range is missing from storage.
+    fn delete_range<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> usize;
+
+    /// Removes and returns the entry with the smallest key.
+    ///
+    /// # Returns
+    /// - `Some((key, value))` of the removed entry, `None` if the tree is empty.
+    fn pop_first(&mut self) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    /// Removes and returns the entry with the largest key.
+    ///
+    /// # Returns
+    /// - `Some((key, value))` of the removed entry, `None` if the tree is empty.
+    fn pop_last(&mut self) -> Option<(Vec<u8>, Vec<u8>)>;
+
     /// Finds the node associated with the specified key in the tree.
     ///
+    /// Use `ProllyTree::try_find` to tell an absent key apart from a node that is missing
+    /// from storage or cannot be read.
+    ///
     /// # Parameters
     /// - `key`: The key to find.
     ///
@@ -80,6 +181,97 @@ pub trait Tree<const N: usize, S: NodeStorage<N>> {
     /// - `Some(ProllyNode<N>)` if the key was found, `None` otherwise.
     fn find(&self, key: &[u8]) -> Option<ProllyNode<N>>;
 
+    /// Returns an iterator over all key-value pairs in ascending key order.
+    ///
+    /// Nodes are loaded from storage lazily as the iterator advances.
+    ///
+    /// # Returns
+    /// - An iterator yielding `(key, value)` pairs.
+    fn iter(&self) -> TreeIter<'_, N, S>;
+
+    /// Returns an iterator over all key-value pairs in descending key order.
+    ///
+    /// # Returns
+    /// - An iterator yielding `(key, value)` pairs, starting from the largest key.
+    fn iter_rev(&self) -> TreeIter<'_, N, S>;
+
+    /// Returns an iterator over the key-value pairs within a key range in ascending order.
+    ///
+    /// # Parameters
+    /// - `range`: The range of keys to scan.
+    ///
+    /// # Returns
+    /// - An iterator yielding `(key, value)` pairs within the range.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> TreeIter<'_, N, S>;
+
+    /// Returns an iterator over the key-value pairs within a key range in descending order.
+    ///
+    /// This walks the tree from the upper end of the range without collecting the keys
+    /// first, which makes "latest N entries" queries cheap.
+    ///
+    /// # Parameters
+    /// - `range`: The range of keys to scan.
+    ///
+    /// # Returns
+    /// - An iterator yielding `(key, value)` pairs within the range, largest key first.
+    fn scan_rev<R: RangeBounds<Vec<u8>>>(&self, range: R) -> TreeIter<'_, N, S>;
+
+    /// Returns an iterator over the key-value pairs whose keys start with the given prefix.
+    ///
+    /// Only the subtrees covering the prefix are visited.
+    ///
+    /// # Parameters
+    /// - `prefix`: The key prefix to scan.
+    ///
+    /// # Returns
+    /// - An iterator yielding `(key, value)` pairs in ascending key order.
+    fn scan_prefix(&self, prefix: &[u8]) -> TreeIter<'_, N, S>;
+
+    /// Finds the entry with the largest key less than or equal to the given key.
+    ///
+    /// # Parameters
+    /// - `key`: The key to look up.
+    ///
+    /// # Returns
+    /// - `Some((key, value))` of the predecessor (or the key itself), `None` if every key is larger.
+    fn get_floor(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    /// Finds the entry with the smallest key greater than or equal to the given key.
+    ///
+    /// # Parameters
+    /// - `key`: The key to look up.
+    ///
+    /// # Returns
+    /// - `Some((key, value))` of the successor (or the key itself), `None` if every key is smaller.
+    fn get_ceiling(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)>;
+
+    /// Creates a cursor over the tree that can be positioned at an arbitrary key.
+    ///
+    /// # Returns
+    /// - A `Cursor` positioned before the first entry of the tree.
+    fn cursor(&self) -> Cursor<'_, N, S>;
+
+    /// Returns the number of keys in the tree that are strictly smaller than the given key.
+    ///
+    /// The lookup descends a single root-to-leaf path using the subtree counts cached in
+    /// internal nodes, so it runs in logarithmic time.
+    ///
+    /// # Parameters
+    /// - `key`: The key to rank; it does not need to be present in the tree.
+    ///
+    /// # Returns
+    /// - The zero-based position the key has, or would have, in key order.
+    fn rank(&self, key: &[u8]) -> usize;
+
+    /// Returns the key-value pair at the given position in key order.
+    ///
+    /// # Parameters
+    /// - `n`: The zero-based position of the entry.
+    ///
+    /// # Returns
+    /// - `Some((key, value))` of the `n`-th smallest key, `None` if the tree has `n` or fewer keys.
+    fn select(&self, n: usize) -> Option<(Vec<u8>, Vec<u8>)>;
+
     /// Traverses the tree and returns a string representation of its structure.
     ///
     /// # Returns
@@ -139,11 +331,13 @@ pub trait Tree<const N: usize, S: NodeStorage<N>> {
     /// - `Ok(())` if the configuration was saved successfully, `Err(&'static str)` otherwise.
     fn save_config(&self) -> Result<(), &'static str>;
 
-    /// Generates a proof of existence for a given key in the tree.
+    /// Generates a proof of existence or non-existence for a given key in the tree.
     ///
     /// This function traverses the tree from the root to the target node containing the key,
     /// collecting the hashes of all nodes along the path. The proof can be used to verify the
     /// existence of the key and its associated value without revealing other data in the tree.
+    /// If the key does not exist, the proof also holds the neighboring entries below and
+    /// above the key, with the paths to their leaves.
     ///
     /// # Arguments
     ///
@@ -154,6 +348,19 @@ pub trait Tree<const N: usize, S: NodeStorage<N>> {
     /// A `Proof` struct containing the path of hashes and the hash of the target node (if the key exists).
     fn generate_proof(&self, key: &[u8]) -> Proof<N>;
 
+    /// Verifies a proof generated by `generate_proof` against the current root.
+    ///
+    /// # Arguments
+    ///
+    /// * `proof` - The proof to verify.
+    /// * `key` - The key the proof was generated for.
+    /// * `expected_value` - The value the key is expected to have, or `None` to verify that
+    ///   the key does not exist.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the proof shows that the key has the expected value, or that the key is
+    /// absent and the neighboring entries of the proof are adjacent to it.
     fn verify(&self, proof: Proof<N>, key: &[u8], expected_value: Option<&[u8]>) -> bool;
 
     /// Computes the differences between two Prolly Trees.
@@ -184,8 +391,19 @@ pub struct TreeStats {
     pub num_nodes: usize,
     pub num_leaves: usize,
     pub num_internal_nodes: usize,
+    /// Average number of key-value pairs per leaf node.
     pub avg_node_size: f64,
     pub total_key_value_pairs: usize,
+    /// Number of levels in the tree, counting the leaves.
+    pub depth: usize,
+    /// Average number of children per internal node.
+    pub avg_fanout: f64,
+    /// Total size in bytes of all keys stored in the leaves.
+    pub total_key_bytes: usize,
+    /// Total size in bytes of all values stored in the leaves.
+    pub total_value_bytes: usize,
+    /// Average size in bytes of the keys and values in a leaf node.
+    pub avg_chunk_size: f64,
 }
 
 impl TreeStats {
@@ -196,6 +414,11 @@ impl TreeStats {
             num_internal_nodes: 0,
             avg_node_size: 0.0,
             total_key_value_pairs: 0,
+            depth: 0,
+            avg_fanout: 0.0,
+            total_key_bytes: 0,
+            total_value_bytes: 0,
+            avg_chunk_size: 0.0,
         }
     }
 }
@@ -210,6 +433,37 @@ pub struct ProllyTree<const N: usize, S: NodeStorage<N>> {
     root: ProllyNode<N>,
     storage: S,
     config: TreeConfig<N>,
+    access: Option<AccessTracker>,
+    observers: Observers,
+}
+
+/// Checks a key-value pair against the key and value formats of a configuration.
+///
+/// Keys under `CONFLICT_PREFIX` are refused: only `merge3_deferred` writes there.
+fn check_entry<const N: usize>(
+    config: &TreeConfig<N>,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(), Error> {
+    if key.starts_with(CONFLICT_PREFIX) {
+        return Err(Error::InvalidKey(
+            "keys under the conflict prefix are reserved for merge conflicts".to_string(),
+        ));
+    }
+    if let Some(format) = &config.key_format {
+        format.validate(key)?;
+    }
+    if let Some(format) = &config.value_format {
+        format.validate(value, config.value_schema.as_ref())?;
+    }
+    Ok(())
+}
+
+/// A change to a key, reported to the observers once it is applied.
+struct Change {
+    key: Vec<u8>,
+    old: Option<Vec<u8>>,
+    new: Option<Vec<u8>>,
 }
 
 impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
@@ -230,25 +484,38 @@ impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
             merged: false,
             encode_types: Vec::new(),
             encode_values: Vec::new(),
+            counts: Vec::new(),
+            compression: config.compression,
+            hash_algorithm: config.hash_algorithm,
+            chunking: config.chunking,
         };
         let root_hash = Some(root.get_hash());
         let mut tree = ProllyTree {
             root,
             storage,
             config,
+            access: None,
+            observers: Observers::default(),
         };
         tree.config.root_hash = root_hash;
+        // even the empty root can be looked up by its hash, e.g. as the base of a merge
+        tree.persist_root();
         tree
     }
     fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.record_write(&key);
+        let change = self.pending_change(&key, Some(&value));
+        let value = self.store_value(value);
         // Root node does not have a parent hash
         self.root.insert(key, value, &mut self.storage, Vec::new());
         self.persist_root();
+        self.notify(change);
     }
 
     fn insert_batch(&mut self, keys: &[Vec<u8>], values: &[Vec<u8>]) {
-        self.root
-            .insert_batch(keys, values, &mut self.storage, Vec::new());
+        let changes = self.insert_entries(keys, values);
+        self.persist_root();
+        self.notify(changes);
     }
 
     fn update(&mut self, key: Vec<u8>, value: Vec<u8>) -> bool {
@@ -260,22 +527,121 @@ impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
         }
     }
 
+    fn insert_if(&mut self, key: Vec<u8>, expected_old: Option<&[u8]>, new_value: Vec<u8>) -> bool {
+        if self.current_value(&key).as_deref() != expected_old {
+            return false;
+        }
+        self.insert(key, new_value);
+        true
+    }
+
+    fn merge<F>(&mut self, key: Vec<u8>, merge_fn: F) -> Option<Vec<u8>>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let current = self.current_value(&key);
+        let merged = merge_fn(current.as_deref());
+        match &merged {
+            Some(value) => self.insert(key, value.clone()),
+            None if current.is_some() => {
+                self.delete(&key);
+            }
+            None => {}
+        }
+        merged
+    }
+
     fn delete(&mut self, key: &[u8]) -> bool {
+        self.record_write(key);
+        let change = self.pending_change(key, None);
         let deleted = self.root.delete(key, &mut self.storage, Vec::new());
         if deleted {
             self.persist_root();
+            self.notify(change);
         }
         deleted
     }
 
     fn delete_batch(&mut self, keys: &[Vec<u8>]) {
-        self.root.delete_batch(keys, &mut self.storage, Vec::new());
+        let changes = self.delete_entries(keys);
+        self.persist_root();
+        self.notify(changes);
+    }
+
+    fn delete_range<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> usize {
+        match self.try_delete_range(range) {
+            Ok(deleted) => deleted,
+            Err(error) => panic!("failed to delete range: {error}"),
+        }
+    }
+
+    fn pop_first(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (key, value) = self.iter().next()?;
+        self.delete(&key);
+        Some((key, value))
+    }
+
+    fn pop_last(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let (key, value) = self.iter_rev().next()?;
+        self.delete(&key);
+        Some((key, value))
     }
 
     fn find(&self, key: &[u8]) -> Option<ProllyNode<N>> {
+        if let Some(access) = &self.access {
+            access.record_read(key);
+        }
         self.root.find(key, &self.storage)
     }
 
+    fn iter(&self) -> TreeIter<'_, N, S> {
+        self.scan(..)
+    }
+
+    fn iter_rev(&self) -> TreeIter<'_, N, S> {
+        self.scan_rev(..)
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> TreeIter<'_, N, S> {
+        TreeIter::new(&self.root, &self.storage, range, false)
+            .with_blobs(self.blobs())
+            .with_expiry(self.expiry_clock())
+    }
+
+    fn scan_rev<R: RangeBounds<Vec<u8>>>(&self, range: R) -> TreeIter<'_, N, S> {
+        TreeIter::new(&self.root, &self.storage, range, true)
+            .with_blobs(self.blobs())
+            .with_expiry(self.expiry_clock())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> TreeIter<'_, N, S> {
+        self.scan(prefix_range(prefix))
+    }
+
+    fn get_floor(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.scan_rev(..=key.to_vec()).next()
+    }
+
+    fn get_ceiling(&self, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.cursor().seek(key)
+    }
+
+    fn cursor(&self) -> Cursor<'_, N, S> {
+        Cursor::new(&self.root, &self.storage)
+            .with_blobs(self.blobs())
+            .with_expiry(self.expiry_clock())
+    }
+
+    fn rank(&self, key: &[u8]) -> usize {
+        self.root.rank(key, &self.storage) as usize
+    }
+
+    fn select(&self, n: usize) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.root
+            .select(n as u64, &self.storage)
+            .map(|(key, value)| (key, self.load_value(value)))
+    }
+
     fn traverse(&self) -> String {
         self.root.traverse(&self.storage)
     }
@@ -292,26 +658,7 @@ impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
     }
 
     fn size(&self) -> usize {
-        fn count_pairs<const N: usize, S: NodeStorage<N>>(
-            node: &ProllyNode<N>,
-            storage: &S,
-        ) -> usize {
-            if node.is_leaf {
-                node.keys.len()
-            } else {
-                let mut count = 0;
-                for value in &node.values {
-                    if let Some(child_node) =
-                        storage.get_node_by_hash(&ValueDigest::raw_hash(value))
-                    {
-                        count += count_pairs(&child_node, storage);
-                    }
-                }
-                count
-            }
-        }
-
-        count_pairs(&self.root, &self.storage)
+        self.len()
     }
 
     fn depth(&self) -> usize {
@@ -340,6 +687,8 @@ impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
             if node.is_leaf {
                 stats.num_leaves += 1;
                 stats.total_key_value_pairs += node.keys.len();
+                stats.total_key_bytes += node.keys.iter().map(Vec::len).sum::<usize>();
+                stats.total_value_bytes += node.values.iter().map(Vec::len).sum::<usize>();
             } else {
                 stats.num_internal_nodes += 1;
                 for value in &node.values {
@@ -354,8 +703,15 @@ impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
 
         let mut stats = TreeStats::new();
         collect_stats(&self.root, &self.storage, &mut stats);
+        stats.depth = self.depth();
         if stats.num_leaves > 0 {
             stats.avg_node_size = stats.total_key_value_pairs as f64 / stats.num_leaves as f64;
+            stats.avg_chunk_size =
+                (stats.total_key_bytes + stats.total_value_bytes) as f64 / stats.num_leaves as f64;
+        }
+        if stats.num_internal_nodes > 0 {
+            // every node except the root is the child of exactly one internal node
+            stats.avg_fanout = (stats.num_nodes - 1) as f64 / stats.num_internal_nodes as f64;
         }
         stats
     }
@@ -398,15 +754,15 @@ impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
         /// Recursive helper function to generate the proof path.
         ///
         /// This function traverses the tree from the given node to the target node containing the key,
-        /// collecting the hashes of all nodes along the path. It returns the hash of the target node
-        /// if the key exists, or `None` if the key does not exist.
+        /// collecting the segments of all nodes below it along the path. It returns the hash of the
+        /// target node if the key exists, or `None` if the key does not exist.
         ///
         /// # Arguments
         ///
         /// * `node` - The current node being traversed.
         /// * `key` - The key for which to generate the proof.
         /// * `storage` - The storage implementation to retrieve child nodes.
-        /// * `path` - The vector to store the hashes of the nodes along the path.
+        /// * `path` - The vector to store the segments of the nodes along the path.
         ///
         /// # Returns
         ///
@@ -415,10 +771,8 @@ impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
             node: &ProllyNode<N>,
             key: &[u8],
             storage: &S,
-            path: &mut Vec<ValueDigest<N>>,
+            path: &mut Vec<PathSegment<N>>,
         ) -> Option<ValueDigest<N>> {
-            path.push(node.get_hash());
-
             if node.is_leaf {
                 if node.keys.iter().any(|k| k == key) {
                     Some(node.get_hash())
@@ -427,60 +781,41 @@ impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
                 }
             } else {
                 let i = node.keys.iter().rposition(|k| key >= &k[..]).unwrap_or(0);
-                let child_hash = node.values[i].clone();
-
-                if let Some(child_node) =
-                    storage.get_node_by_hash(&ValueDigest::raw_hash(&child_hash))
-                {
-                    generate_proof_recursive(&child_node, key, storage, path)
-                } else {
-                    None
-                }
+                let segment = PathSegment::child_of(node, i);
+                let child_node = storage.get_node_by_hash(&segment.node_hash);
+                path.push(segment);
+                generate_proof_recursive(&child_node?, key, storage, path)
             }
         }
 
-        let mut path = Vec::new();
+        let mut path = vec![PathSegment::root(self.root.get_hash())];
         let target_hash = generate_proof_recursive(&self.root, key, &self.storage, &mut path);
+        let (lower, upper) = if target_hash.is_none() {
+            self.neighbors(key)
+        } else {
+            (None, None)
+        };
 
-        Proof { path, target_hash }
+        Proof {
+            path,
+            target_hash,
+            lower,
+            upper,
+        }
     }
 
     fn verify(&self, proof: Proof<N>, key: &[u8], expected_value: Option<&[u8]>) -> bool {
-        // Start with the root hash
-        let mut current_hash = self.root.get_hash();
-
-        for (i, node_hash) in proof.path.iter().enumerate() {
-            // Retrieve the node content from storage using the current hash
-            if let Some(node) = self.storage.get_node_by_hash(&current_hash) {
-                // Check if the current node's hash matches the expected hash in the path
-                if node.get_hash() != *node_hash {
-                    return false;
-                }
-
-                // If it's the last node in the path, verify the leaf node
-                if i == proof.path.len() - 1 {
-                    return if node.is_leaf {
-                        node.keys.iter().any(|k| k == key)
-                            && (expected_value.is_none()
-                                || node
-                                    .values
-                                    .iter()
-                                    .any(|v| expected_value.unwrap() == &v[..]))
-                    } else {
-                        false // Path should end at a leaf node
-                    };
-                }
-
-                // Move to the next node in the path by finding the correct child
-                let child_index = node.keys.iter().rposition(|k| key >= &k[..]).unwrap_or(0);
-                current_hash = ValueDigest::raw_hash(&node.values[child_index]);
-            } else {
-                // If the node is not found in storage, the proof is invalid
-                return false;
-            }
+        let Some((leaf, turns)) = self.follow_proof(&proof.path, key) else {
+            return false;
+        };
+        match expected_value {
+            Some(expected) => leaf
+                .keys
+                .iter()
+                .position(|k| k == key)
+                .is_some_and(|i| self.load_value(leaf.values[i].clone()) == expected),
+            None => self.verify_absence(&proof, key, &leaf, &turns),
         }
-
-        false // If we exit the loop without verifying, the proof is invalid
     }
 
     fn diff(&self, other: &Self) -> Vec<DiffResult> {
@@ -490,423 +825,4379 @@ impl<const N: usize, S: NodeStorage<N>> Tree<N, S> for ProllyTree<N, S> {
     }
 
     fn print(&mut self) {
-        self.root.print_tree(&self.storage);
+        match &self.config.key_format {
+            Some(format) => self
+                .root
+                .print_tree_with(&self.storage, |key| format.format(key)),
+            None => self.root.print_tree(&self.storage),
+        }
     }
 }
 
 impl<S: NodeStorage<N>, const N: usize> ProllyTree<N, S> {
-    fn persist_root(&mut self) {
-        // Save the updated child node back to the storage
-        self.storage
-            .insert_node(self.root.get_hash(), self.root.clone());
-    }
-}
-
-impl<const N: usize, S: NodeStorage<N>> ProllyTree<N, S> {
-    /// Recursively computes the differences between two Prolly Nodes.
+    /// Builds a tree bottom-up from key-value pairs sorted by key.
     ///
-    /// This helper function is used by `diff` to traverse the nodes of both trees
-    /// and identify changes. It compares the keys and values of the nodes and
-    /// generates appropriate `DiffResult` entries for added, removed, and modified
-    /// key-value pairs.
+    /// Leaves and internal nodes are cut and written to storage directly as the pairs
+    /// stream in, instead of inserting every key from the root. This makes the initial
+    /// import of large datasets much faster than repeated calls to `insert`.
     ///
-    /// # Arguments
+    /// # Parameters
+    /// - `storage`: The storage to use for persisting nodes.
+    /// - `config`: The configuration for the tree.
+    /// - `pairs`: The key-value pairs, in strictly ascending key order.
     ///
-    /// * `old_node` - The node from the original tree.
-    /// * `new_node` - The node from the new tree.
-    /// * `diffs` - The vector to store the differences.
-    fn diff_recursive(
-        &self,
-        old_node: &ProllyNode<N>,
-        new_node: &ProllyNode<N>,
-        diffs: &mut Vec<DiffResult>,
-    ) {
-        let mut old_iter = old_node.keys.iter().zip(old_node.values.iter()).peekable();
-        let mut new_iter = new_node.keys.iter().zip(new_node.values.iter()).peekable();
-
-        while let (Some((old_key, old_value)), Some((new_key, new_value))) =
-            (old_iter.peek(), new_iter.peek())
-        {
-            match old_key.cmp(new_key) {
-                std::cmp::Ordering::Less => {
-                    diffs.push(DiffResult::Removed(old_key.to_vec(), old_value.to_vec()));
-                    old_iter.next();
-                }
-                std::cmp::Ordering::Greater => {
-                    diffs.push(DiffResult::Added(new_key.to_vec(), new_value.to_vec()));
-                    new_iter.next();
-                }
-                std::cmp::Ordering::Equal => {
-                    if old_value != new_value {
-                        diffs.push(DiffResult::Modified(
-                            old_key.to_vec(),
-                            old_value.to_vec(),
-                            new_value.to_vec(),
-                        ));
-                    }
-                    old_iter.next();
-                    new_iter.next();
-                }
-            }
-        }
-
-        for (old_key, old_value) in old_iter {
-            diffs.push(DiffResult::Removed(old_key.clone(), old_value.clone()));
+    /// # Returns
+    /// - The loaded tree, `Error::UnsortedKeys` if the keys are not strictly ascending, or
+    ///   `Error::InvalidKey` or `Error::InvalidValue` if a pair does not match the
+    ///   configured formats.
+    pub fn from_sorted_iter<I>(
+        mut storage: S,
+        config: TreeConfig<N>,
+        pairs: I,
+    ) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut loader = BulkLoader::new(&mut storage, &config);
+        for (key, value) in pairs {
+            check_entry(&config, &key, &value)?;
+            loader.push(key, value)?;
         }
+        let root = loader.finish();
 
-        for (new_key, new_value) in new_iter {
-            diffs.push(DiffResult::Added(new_key.clone(), new_value.clone()));
-        }
+        let mut tree = Self::with_root(root, storage, config);
+        tree.persist_root();
+        Ok(tree)
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::storage::InMemoryNodeStorage;
 
-    /// Example usage of the Prolly Tree
-    #[test]
-    fn inmem_node_storage_test() {
-        // 1. Create a custom tree config
-        let config = TreeConfig {
-            base: 131,
-            modulus: 1_000_000_009,
-            min_chunk_size: 4,
-            max_chunk_size: 8 * 1024,
-            pattern: 0b101,
-            root_hash: None,
-            key_schema: None,
-            value_schema: None,
-            encode_types: vec![],
-        };
+    /// Returns the number of key-value pairs in the tree.
+    ///
+    /// The count is read from the subtree counts cached in the root node, which are kept
+    /// up to date by inserts and deletes, so the tree does not need to be traversed.
+    /// Expired keys are counted until they are removed with `purge_expired`.
+    pub fn len(&self) -> usize {
+        self.root.subtree_count(&self.storage) as usize
+    }
 
-        // 2. Create and Wrap the Storage Backend
-        let storage = InMemoryNodeStorage::<32>::default();
+    /// Returns `true` if the tree contains no key-value pairs.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-        // 3. Create the Prolly Tree
+    /// Iterates over the entries of a key range together with their Merkle proofs.
+    ///
+    /// Each proof is the one `generate_proof` returns for the key, but the whole range is
+    /// proven in a single traversal instead of one per key.
+    ///
+    /// # Parameters
+    /// - `range`: The key range to iterate over.
+    ///
+    /// # Returns
+    /// - An iterator over `(key, value, proof)` in ascending key order.
+    pub fn scan_with_proofs<R: RangeBounds<Vec<u8>>>(&self, range: R) -> ProofIter<'_, N, S> {
+        ProofIter::new(self.scan(range), self.root.get_hash())
+    }
+
+    /// Generates a proof of the complete set of entries within a key range.
+    ///
+    /// Unlike the proofs of `scan_with_proofs`, which prove each entry on its own, the
+    /// proof also shows that no entry of the range was left out. It can be verified with
+    /// `RangeProof::verify` knowing only the root hash of the tree. Values are proven as
+    /// they are stored in the leaves, which for trees with blobs or expiring keys is not
+    /// the value returned by `scan`.
+    ///
+    /// # Parameters
+    /// - `range`: The key range to prove.
+    ///
+    /// # Returns
+    /// - The proof, holding every node that overlaps the range.
+    pub fn generate_range_proof<R: RangeBounds<Vec<u8>>>(&self, range: R) -> RangeProof {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let nodes = self.stream_range_proof(range).collect();
+        RangeProof { start, end, nodes }
+    }
+
+    /// Generates a proof of the complete set of entries whose keys start with a prefix.
+    ///
+    /// Only the nodes covering the prefix are included, so a tenant can be handed a proof
+    /// of its namespace without the rest of the tree. The leaves at both ends of the
+    /// namespace may still hold a few entries of neighboring keys, which a verifier needs
+    /// to recompute their hashes. Check `RangeProof::is_prefix` before trusting that a
+    /// proof covers a namespace.
+    ///
+    /// # Parameters
+    /// - `prefix`: The key prefix of the namespace.
+    ///
+    /// # Returns
+    /// - The proof, holding every node that overlaps the namespace.
+    pub fn generate_prefix_proof(&self, prefix: &[u8]) -> RangeProof {
+        self.generate_range_proof(prefix_range(prefix))
+    }
+
+    /// Streams the nodes of the proof of a key range, in the order `generate_range_proof`
+    /// stores them.
+    ///
+    /// Nodes are loaded from storage as the iterator advances, and only the hashes of the
+    /// nodes still to come are kept, so proofs of ranges of any size can be written out
+    /// without holding them in memory. The nodes can be checked on arrival with a
+    /// `RangeVerifier`.
+    ///
+    /// # Parameters
+    /// - `range`: The key range to prove.
+    ///
+    /// # Returns
+    /// - An iterator over the nodes of the proof.
+    pub fn stream_range_proof<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+    ) -> RangeProofStream<'_, N, S> {
+        RangeProofStream::new(&self.root, &self.storage, range)
+    }
+
+    /// Signs the current root hash for a branch or commit, stamped with the current time.
+    ///
+    /// # Parameters
+    /// - `reference`: The branch name or commit id of the root.
+    /// - `key`: The signer's key.
+    #[cfg(feature = "attestation")]
+    pub fn attest_root(
+        &self,
+        reference: impl Into<String>,
+        key: &ed25519_dalek::SigningKey,
+    ) -> crate::proof::attestation::RootAttestation<N> {
+        crate::proof::attestation::RootAttestation::sign(
+            self.root.get_hash(),
+            std::time::SystemTime::now(),
+            reference,
+            key,
+        )
+    }
+
+    /// Like `generate_proof`, but answers repeated requests for a key from `cache` while
+    /// the root is unchanged.
+    ///
+    /// # Parameters
+    /// - `key`: The key to prove.
+    /// - `cache`: The cache of proofs of this tree.
+    pub fn generate_proof_cached(&self, key: &[u8], cache: &mut ProofCache<N>) -> Proof<N> {
+        cache.get_or_insert_with(&self.root.get_hash(), key, || self.generate_proof(key))
+    }
+
+    /// Generates a proof of inclusion of a key that a smart contract can verify.
+    ///
+    /// The proof holds the nodes from the root to the leaf of the key in the layout they
+    /// are hashed in, see `proof::evm`. It only verifies for trees configured with
+    /// `HashAlgorithm::Keccak256`. The value is proven as it is stored in the leaf.
+    ///
+    /// # Parameters
+    /// - `key`: The key to prove.
+    ///
+    /// # Returns
+    /// - The proof, or `None` if the key does not exist or the tree is hashed with another
+    ///   algorithm.
+    pub fn generate_evm_proof(&self, key: &[u8]) -> Option<EvmProof> {
+        if self.root.hash_algorithm != HashAlgorithm::Keccak256 {
+            return None;
+        }
+        let mut nodes = Vec::new();
+        let mut positions = Vec::new();
+        let mut node = self.root.clone();
+        while !node.is_leaf {
+            let i = node.keys.iter().rposition(|k| key >= &k[..]).unwrap_or(0);
+            nodes.push(fixed_layout(&node.keys, &node.values, &node.counts));
+            positions.push(i as u32);
+            node = self
+                .storage
+                .get_node_by_hash(&ValueDigest::raw_hash(&node.values[i]))?;
+        }
+        let i = node.keys.iter().position(|k| k == key)?;
+        nodes.push(fixed_layout(&node.keys, &node.values, &node.counts));
+        positions.push(i as u32);
+        Some(EvmProof {
+            key: key.to_vec(),
+            value: node.values[i].clone(),
+            nodes,
+            positions,
+        })
+    }
+
+    /// Generates a proof of the number of keys in the tree.
+    ///
+    /// The proof is the root node, whose hash commits to the key counts of its subtrees,
+    /// and can be verified with `CountProof::verify` knowing only the root hash. Like
+    /// `len`, the count includes expired keys until they are purged.
+    pub fn generate_count_proof(&self) -> CountProof {
+        CountProof {
+            count: self.len() as u64,
+            root: ProofNode::from(&self.root),
+        }
+    }
+
+    /// Generates a single proof of the values of several keys.
+    ///
+    /// The nodes shared by the paths of the keys are included once, so the proof is much
+    /// smaller than one `generate_proof` per key. It can be verified with
+    /// `MultiProof::verify` knowing only the root hash of the tree, and proves absent keys
+    /// absent.
+    ///
+    /// # Parameters
+    /// - `keys`: The keys to prove, in any order.
+    ///
+    /// # Returns
+    /// - The proof, holding every node on the paths of the keys.
+    pub fn generate_multiproof(&self, keys: &[Vec<u8>]) -> MultiProof {
+        self.multiproof_from(&self.root, keys)
+    }
+
+    /// Generates a proof of the value a key had in an earlier version of the tree.
+    ///
+    /// Nodes are never modified in place, so the nodes of earlier versions stay in storage
+    /// until they are deleted, and a version can be proven from its root hash alone without
+    /// rolling the tree back. The proof can be verified with `MultiProof::verify` or
+    /// `verifier::verify_key` against `root_hash`.
+    ///
+    /// # Parameters
+    /// - `root_hash`: The root hash of the version, e.g. recorded by `get_root_hash`.
+    /// - `key`: The key to prove.
+    ///
+    /// # Returns
+    /// - The proof of the key, or `Error::MissingNode` if the root of the version is no
+    ///   longer in storage.
+    pub fn generate_proof_at(
+        &self,
+        root_hash: &ValueDigest<N>,
+        key: &[u8],
+    ) -> Result<MultiProof, Error> {
+        let root = if *root_hash == self.root.get_hash() {
+            self.root.clone()
+        } else {
+            self.storage
+                .get_node_by_hash(root_hash)
+                .ok_or(Error::MissingNode(hex::encode(root_hash.as_bytes())))?
+        };
+        Ok(self.multiproof_from(&root, &[key.to_vec()]))
+    }
+
+    /// Generates a multiproof of `keys` in the version of the tree rooted at `root`.
+    fn multiproof_from(&self, root: &ProllyNode<N>, keys: &[Vec<u8>]) -> MultiProof {
+        let mut keys = keys.to_vec();
+        keys.sort();
+        keys.dedup();
+
+        let mut nodes = Vec::new();
+        let mut stack = vec![(root.clone(), keys.as_slice())];
+        while let Some((node, keys)) = stack.pop() {
+            if !node.is_leaf {
+                // pushed in reverse so that children are visited in key order
+                for (i, keys) in route(&node.keys, keys).into_iter().rev() {
+                    let child_hash = ValueDigest::raw_hash(&node.values[i]);
+                    if let Some(child) = self.storage.get_node_by_hash(&child_hash) {
+                        stack.push((child, keys));
+                    }
+                }
+            }
+            nodes.push(ProofNode::from(&node));
+        }
+        MultiProof { keys, nodes }
+    }
+
+    /// Reads one page of a key range.
+    ///
+    /// The first page is read without a token. Every page but the last returns a token
+    /// that continues the scan right after its last key. Resuming is refused if the tree
+    /// changed since the token was issued, because the pages would then not reflect a
+    /// single version of the tree.
+    ///
+    /// # Parameters
+    /// - `range`: The key range to scan; pass the same range for every page.
+    /// - `limit`: The maximum number of entries per page; zero is treated as one.
+    /// - `token`: The token returned with the previous page, if any.
+    ///
+    /// # Returns
+    /// - The page, or `Error::RootChanged` if the tree changed since `token` was issued.
+    pub fn scan_page<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        limit: usize,
+        token: Option<&PageToken<N>>,
+    ) -> Result<Page<N>, Error> {
+        let root_hash = self.root.get_hash();
+        let start = match token {
+            Some(token) if token.root_hash() != &root_hash => return Err(Error::RootChanged),
+            Some(token) => Bound::Excluded(token.last_key().to_vec()),
+            None => range.start_bound().cloned(),
+        };
+
+        let mut iter = self.scan((start, range.end_bound().cloned())).peekable();
+        let entries: Vec<_> = iter.by_ref().take(limit.max(1)).collect();
+        let next = match (entries.last(), iter.peek()) {
+            (Some((last_key, _)), Some(_)) => Some(PageToken::new(root_hash, last_key.clone())),
+            _ => None,
+        };
+        Ok(Page { entries, next })
+    }
+
+    /// Draws a uniform random sample of keys without replacement.
+    ///
+    /// Positions are drawn at random and resolved with `select`, which descends along the
+    /// cached subtree counts, so only the paths to the sampled keys are loaded. The same
+    /// seed draws the same sample from the same tree. Expired keys that have not been
+    /// purged yet are drawn but left out of the sample.
+    ///
+    /// # Parameters
+    /// - `n`: The number of keys to draw; the whole key set is returned if it is smaller.
+    /// - `seed`: The seed of the random number generator.
+    ///
+    /// # Returns
+    /// - The sampled keys in ascending order.
+    pub fn sample_keys(&self, n: usize, seed: u64) -> Vec<Vec<u8>> {
+        let len = self.len();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut positions = rand::seq::index::sample(&mut rng, len, n.min(len)).into_vec();
+        positions.sort_unstable();
+        positions
+            .into_iter()
+            .filter_map(|position| self.root.select(position as u64, &self.storage))
+            .filter(|(_, stored)| self.is_live(stored))
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// Approximates the storage size in bytes of the entries in a key range.
+    ///
+    /// This is a heuristic, not a measurement: nodes do not record the byte size of their
+    /// subtrees. The number of keys in the range is exact, read from the cached subtree
+    /// counts, but the bytes per key are extrapolated from the nodes on the paths to both
+    /// ends of the range, so the cost is proportional to the depth of the tree rather than
+    /// the size of the range. The result covers the encoded leaves and internal nodes, as
+    /// well as values stored in chunks outside of the leaves, and is only as accurate as
+    /// those nodes are typical of the range: it is off for ranges whose entries vary
+    /// widely in size, and counts chunks shared between values once per value.
+    ///
+    /// # Parameters
+    /// - `range`: The key range to size.
+    ///
+    /// # Returns
+    /// - The approximate number of bytes the range takes up in storage.
+    pub fn approximate_size<R: RangeBounds<Vec<u8>>>(&self, range: R) -> u64 {
+        // the smallest key greater than `key`
+        let successor = |key: &Vec<u8>| [key.as_slice(), &[0]].concat();
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.root.rank(key, &self.storage),
+            Bound::Excluded(key) => self.root.rank(&successor(key), &self.storage),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.root.rank(&successor(key), &self.storage),
+            Bound::Excluded(key) => self.root.rank(key, &self.storage),
+            Bound::Unbounded => self.len() as u64,
+        };
+        let count = end.saturating_sub(start);
+        if count == 0 {
+            return 0;
+        }
+
+        // bytes and keys below the sampled nodes, per level from the root
+        let mut samples: Vec<(u64, u64)> = Vec::new();
+        let edge = |bound: Bound<&Vec<u8>>| match bound {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key.clone()),
+            Bound::Unbounded => None,
+        };
+        let ends = [
+            edge(range.start_bound()).or(Some(Vec::new())),
+            edge(range.end_bound()),
+        ];
+        for key in ends {
+            let mut node = Some(self.root.clone());
+            let mut depth = 0;
+            while let Some(current) = node {
+                let keys = current.subtree_count(&self.storage);
+                let mut bytes = current.encode().len() as u64;
+                if current.is_leaf && self.blobs() {
+                    bytes += current
+                        .values
+                        .iter()
+                        .map(|stored| {
+                            if self.config.expiring_keys {
+                                external_len(ttl::unwrap(stored).1)
+                            } else {
+                                external_len(stored)
+                            }
+                        })
+                        .sum::<u64>();
+                }
+                if samples.len() == depth {
+                    samples.push((0, 0));
+                }
+                samples[depth].0 += bytes;
+                samples[depth].1 += keys;
+
+                node = match &key {
+                    _ if current.is_leaf => None,
+                    Some(key) => {
+                        let i = current.keys.iter().rposition(|k| key >= k).unwrap_or(0);
+                        current.values.get(i)
+                    }
+                    None => current.values.last(),
+                }
+                .and_then(|hash| self.storage.get_node_by_hash(&ValueDigest::raw_hash(hash)));
+                depth += 1;
+            }
+        }
+
+        let bytes_per_key: f64 = samples
+            .iter()
+            .filter(|(_, keys)| *keys > 0)
+            .map(|(bytes, keys)| *bytes as f64 / *keys as f64)
+            .sum();
+        (count as f64 * bytes_per_key).round() as u64
+    }
+
+    /// Inserts a key-value pair that expires at the given time.
+    ///
+    /// Once expired, the key is skipped by reads and removed by the next `purge_expired`.
+    ///
+    /// # Parameters
+    /// - `key`: The key to insert.
+    /// - `value`: The value associated with the key.
+    /// - `expires_at`: The time the key expires at.
+    ///
+    /// # Returns
+    /// - `Error::ExpiryDisabled` if the tree was not configured with `expiring_keys`.
+    pub fn insert_with_expiry(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: SystemTime,
+    ) -> Result<(), Error> {
+        if !self.config.expiring_keys {
+            return Err(Error::ExpiryDisabled);
+        }
+        self.check_entry(&key, &value)?;
+        self.record_write(&key);
+        let expires_at = ttl::timestamp(expires_at);
+        // a key inserted already expired is absent for observers
+        let live = expires_at > ttl::now();
+        let change = self.pending_change(&key, live.then_some(value.as_slice()));
+        let value = self.store_value_until(value, expires_at);
+        self.root.insert(key, value, &mut self.storage, Vec::new());
+        self.persist_root();
+        self.notify(change);
+        Ok(())
+    }
+
+    /// Inserts a key-value pair that expires after the given time to live.
+    ///
+    /// # Parameters
+    /// - `key`: The key to insert.
+    /// - `value`: The value associated with the key.
+    /// - `ttl`: How long the key lives, starting now.
+    ///
+    /// # Returns
+    /// - `Error::ExpiryDisabled` if the tree was not configured with `expiring_keys`.
+    pub fn insert_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        self.insert_with_expiry(key, value, SystemTime::now() + ttl)
+    }
+
+    /// Inserts a key-value pair, unless it does not match the configured key and value
+    /// formats.
+    ///
+    /// # Parameters
+    /// - `key`: The key to insert.
+    /// - `value`: The value associated with the key.
+    ///
+    /// # Returns
+    /// - `Error::InvalidKey` or `Error::InvalidValue` if the pair was refused.
+    pub fn try_insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Error> {
+        self.check_entry(&key, &value)?;
+        self.insert(key, value);
+        Ok(())
+    }
+
+    /// Inserts multiple key-value pairs like `insert_batch`, unless one of them does not
+    /// match the configured key and value formats.
+    ///
+    /// # Parameters
+    /// - `keys`: The keys to insert.
+    /// - `values`: The values associated with the keys.
+    ///
+    /// # Returns
+    /// - `Error::InvalidKey` or `Error::InvalidValue` for the first pair that was refused,
+    ///   in which case nothing is inserted.
+    ///
+    /// # Panics
+    /// - If a node on the way to the inserted keys is missing from storage.
+    pub fn try_insert_batch(&mut self, keys: &[Vec<u8>], values: &[Vec<u8>]) -> Result<(), Error> {
+        for (key, value) in keys.iter().zip(values) {
+            self.check_entry(key, value)?;
+        }
+        self.insert_batch(keys, values);
+        Ok(())
+    }
+
+    /// Deletes all key-value pairs within a key range like `delete_range`, reporting nodes
+    /// that cannot be read instead of panicking.
+    ///
+    /// # Parameters
+    /// - `range`: The range of keys to delete.
+    ///
+    /// # Returns
+    /// - The number of key-value pairs deleted, or `Error::MissingNode` if a node
+    ///   overlapping the range is not in storage, in which case nothing is deleted.
+    pub fn try_delete_range<R: RangeBounds<Vec<u8>>>(&mut self, range: R) -> Result<usize, Error> {
+        let changes: Vec<Change> = if self.observers.is_empty() {
+            Vec::new()
+        } else {
+            self.scan((range.start_bound().cloned(), range.end_bound().cloned()))
+                .map(|(key, old)| Change {
+                    key,
+                    old: Some(old),
+                    new: None,
+                })
+                .collect()
+        };
+        let mut root = self.root.clone();
+        let deleted = root.delete_range(&range, &mut self.storage, Vec::new())?;
+        if deleted > 0 {
+            self.root = root;
+            self.persist_root();
+            self.notify(changes);
+        }
+        Ok(deleted)
+    }
+
+    /// Finds the node associated with a key, reporting nodes that cannot be read instead
+    /// of treating them as absent.
+    ///
+    /// # Parameters
+    /// - `key`: The key to find.
+    ///
+    /// # Returns
+    /// - The leaf holding the key, `None` if the key is absent, `Error::MissingNode` if a
+    ///   node on the way is not in storage, or the error the storage reports for a node it
+    ///   cannot read, such as `Error::CorruptNode`.
+    pub fn try_find(&self, key: &[u8]) -> Result<Option<ProllyNode<N>>, Error> {
+        if let Some(access) = &self.access {
+            access.record_read(key);
+        }
+        self.root.try_find(key, &self.storage)
+    }
+
+    /// Serializes a value with the configured value format and inserts it.
+    ///
+    /// Trees without a value format store typed values as JSON.
+    ///
+    /// # Parameters
+    /// - `key`: The key to insert.
+    /// - `value`: The value to serialize.
+    ///
+    /// # Returns
+    /// - `Error::UnsupportedValueType` if the value format is not a serialization, or the
+    ///   error of `try_insert`.
+    pub fn insert_typed<T: Serialize>(&mut self, key: Vec<u8>, value: &T) -> Result<(), Error> {
+        let value = self.typed_format().encode(value)?;
+        self.try_insert(key, value)
+    }
+
+    /// Reads the value of a key and deserializes it with the configured value format.
+    ///
+    /// # Parameters
+    /// - `key`: The key to read.
+    ///
+    /// # Returns
+    /// - The value, `None` if the key does not exist, or an error if it cannot be
+    ///   deserialized as `T`.
+    pub fn get_as<T: DeserializeOwned>(&self, key: &[u8]) -> Result<Option<T>, Error> {
+        self.current_value(key)
+            .map(|value| self.typed_format().decode(&value))
+            .transpose()
+    }
+
+    /// The format typed values are serialized with.
+    fn typed_format(&self) -> ValueFormat {
+        self.config
+            .value_format
+            .clone()
+            .unwrap_or(ValueFormat::Json)
+    }
+
+    /// Applies the writes buffered in a batch.
+    ///
+    /// The inserts and the deletes of the batch are each applied in a single traversal,
+    /// and the new root is only persisted once all of them are in place. Until then the
+    /// tree keeps pointing at its previous root in storage. Observers are notified once
+    /// the whole batch is applied.
+    ///
+    /// # Parameters
+    /// - `batch`: The writes to apply.
+    ///
+    /// # Returns
+    /// - `Error::InvalidKey` or `Error::InvalidValue` without applying anything if an
+    ///   inserted pair does not match the configured formats.
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> Result<(), Error> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        let mut deletes = Vec::new();
+        for (key, write) in batch.into_writes() {
+            match write {
+                Some(value) => {
+                    keys.push(key);
+                    values.push(value);
+                }
+                None => deletes.push(key),
+            }
+        }
+        for (key, value) in keys.iter().zip(&values) {
+            self.check_entry(key, value)?;
+        }
+        let mut changes = Vec::new();
+        if !keys.is_empty() {
+            changes.extend(self.insert_entries(&keys, &values));
+        }
+        if !deletes.is_empty() {
+            changes.extend(self.delete_entries(&deletes));
+        }
+        self.persist_root();
+        self.notify(changes);
+        Ok(())
+    }
+
+    /// Deletes every key that has expired, in a single batch.
+    ///
+    /// # Returns
+    /// - The number of deleted keys.
+    pub fn purge_expired(&mut self) -> usize {
+        let Some(now) = self.expiry_clock() else {
+            return 0;
+        };
+        let mut expired = Vec::new();
+        let mut cursor = Cursor::new(&self.root, &self.storage);
+        while cursor.next().is_some() {
+            if let Some((key, stored)) = cursor.current() {
+                if ttl::is_expired(stored, now) {
+                    expired.push(key.to_vec());
+                }
+            }
+        }
+        if !expired.is_empty() {
+            self.delete_batch(&expired);
+        }
+        expired.len()
+    }
+
+    /// Walks every node reachable from the root and checks the invariants of the tree.
+    ///
+    /// Every node is loaded from storage and its hash recomputed. The check also covers
+    /// key ordering, the key ranges parents assign to their children, node levels, the
+    /// cached subtree counts, chunk boundaries and the chunks of values stored outside of
+    /// the leaves. Checking does not stop at the first problem, and nodes below a missing
+    /// node are not checked.
+    ///
+    /// # Returns
+    /// - A report listing every violation found.
+    pub fn verify_integrity(&self) -> IntegrityReport<N> {
+        let options = CheckOptions {
+            blobs: self.blobs(),
+            expiring: self.config.expiring_keys,
+        };
+        check_tree(&self.root, &self.storage, &options)
+    }
+
+    /// Salvages the entries of a damaged tree into a fresh tree.
+    ///
+    /// Every leaf reachable through intact nodes is copied into `storage`, in a single
+    /// bottom-up pass. Nodes that are missing, do not match their hash or break the key
+    /// ordering are skipped together with their subtrees, and the key ranges they covered
+    /// are reported as lost. Values whose chunks are missing or corrupt are dropped and
+    /// reported by key. Expiry times are preserved. This tree is not modified.
+    ///
+    /// # Parameters
+    /// - `storage`: The storage to build the repaired tree in.
+    ///
+    /// # Returns
+    /// - The repaired tree, and a report of what could not be recovered.
+    pub fn repair<T: NodeStorage<N>>(&self, mut storage: T) -> (ProllyTree<N, T>, RepairReport<N>) {
+        let mut report = RepairReport::default();
+        let mut config = self.config.clone();
+        config.root_hash = None;
+
+        let mut loader = BulkLoader::new(&mut storage, &config);
+        report.lost_ranges = salvage_leaves(&self.root, &self.storage, |leaf| {
+            for (key, stored) in leaf.keys.iter().zip(&leaf.values) {
+                let (expires_at, stored) = if self.config.expiring_keys {
+                    ttl::unwrap(stored)
+                } else {
+                    (ttl::NEVER, stored.as_slice())
+                };
+                let value = if self.blobs() {
+                    let intact = chunk_hashes::<N>(stored)
+                        .iter()
+                        .all(|chunk| chunk_intact(&self.storage, chunk));
+                    intact
+                        .then(|| decode_value(stored, &self.storage))
+                        .flatten()
+                } else {
+                    Some(stored.to_vec())
+                };
+                let pushed = value.is_some_and(|value| {
+                    loader
+                        .push_with_expiry(key.clone(), value, expires_at)
+                        .is_ok()
+                });
+                if pushed {
+                    report.keys_recovered += 1;
+                } else {
+                    report.lost_values.push(key.clone());
+                }
+            }
+        });
+        let root = loader.finish();
+
+        let mut tree = ProllyTree::with_root(root, storage, config);
+        tree.persist_root();
+        (tree, report)
+    }
+
+    /// Rebuilds the tree in `storage` with a different configuration.
+    ///
+    /// The live entries are streamed in key order into a new tree built bottom-up with
+    /// `config`, so the chunking parameters, hash algorithm, hash size or value storage
+    /// can be changed without loading the whole tree into memory. Expiry times are kept
+    /// if both trees have expiring keys. This tree is not modified.
+    ///
+    /// # Parameters
+    /// - `storage`: The storage to build the new tree in.
+    /// - `config`: The configuration of the new tree. Its `root_hash` is ignored.
+    ///
+    /// # Returns
+    /// - The rewritten tree, or `Error::InvalidKey` or `Error::InvalidValue` if an entry
+    ///   does not match the formats of `config`.
+    pub fn rewrite_with_config<const M: usize, T: NodeStorage<M>>(
+        &self,
+        mut storage: T,
+        mut config: TreeConfig<M>,
+    ) -> Result<ProllyTree<M, T>, Error> {
+        config.root_hash = None;
+
+        let mut loader = BulkLoader::new(&mut storage, &config);
+        let mut cursor = Cursor::new(&self.root, &self.storage).with_expiry(self.expiry_clock());
+        while cursor.next().is_some() {
+            let Some((key, stored)) = cursor.current() else {
+                break;
+            };
+            let (expires_at, stored) = if self.config.expiring_keys {
+                ttl::unwrap(stored)
+            } else {
+                (ttl::NEVER, stored)
+            };
+            let value = if self.blobs() {
+                decode_value(stored, &self.storage).unwrap_or_else(|| stored.to_vec())
+            } else {
+                stored.to_vec()
+            };
+            // conflict records were written by `merge3_deferred` and are carried over as is
+            if !key.starts_with(CONFLICT_PREFIX) {
+                check_entry(&config, key, &value)?;
+            }
+            loader.push_with_expiry(key.to_vec(), value, expires_at)?;
+        }
+        let root = loader.finish();
+
+        let mut tree = ProllyTree::with_root(root, storage, config);
+        tree.persist_root();
+        Ok(tree)
+    }
+
+    /// Writes the whole tree, including its configuration, to `writer`.
+    ///
+    /// Nodes are streamed one at a time in a stable, framed format, children before their
+    /// parents, so exporting a large tree does not buffer it in memory. The output can be
+    /// used for backups or to ship a snapshot of the tree to another machine.
+    ///
+    /// # Parameters
+    /// - `writer`: The destination of the export.
+    ///
+    /// # Returns
+    /// - `Ok(())` on success, or the error that stopped the export.
+    pub fn export<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let mut config = self.config.clone();
+        config.root_hash = Some(self.root.get_hash());
+        export_tree(&self.root, &config, &self.storage, &mut writer)
+    }
+
+    /// Reconstructs a tree from the output of `export`.
+    ///
+    /// Every node is checked against its hash as it is read and written to `storage`,
+    /// and nodes referring to children missing from the stream are rejected, so a
+    /// corrupted or truncated export fails instead of producing a damaged tree.
+    ///
+    /// # Parameters
+    /// - `reader`: The source of the export.
+    /// - `storage`: The storage to load the nodes into.
+    ///
+    /// # Returns
+    /// - The imported tree, or the error describing why the input was refused.
+    pub fn import<R: Read>(mut reader: R, mut storage: S) -> Result<Self, Error> {
+        let (config, root) = import_tree(&mut reader, &mut storage)?;
+        let tree = Self::with_root(root, storage, config);
+        tree.save_config().map_err(|_| Error::Serde)?;
+        Ok(tree)
+    }
+
+    /// Writes the part of the tree covering a key range to `writer`, as a self-contained
+    /// tree in the format of `export`.
+    ///
+    /// The exported tree only holds the keys within `range`. Nodes entirely within the
+    /// range keep their hashes, only the nodes at the edges of the range are rewritten.
+    /// The storage of this tree is not modified. The output can be read with `import`, or
+    /// merged into an existing tree with `merge_import`, to replicate a single namespace.
+    ///
+    /// # Parameters
+    /// - `range`: The key range to export.
+    /// - `writer`: The destination of the export.
+    pub fn export_range<R: RangeBounds<Vec<u8>>, W: Write>(
+        &self,
+        range: R,
+        mut writer: W,
+    ) -> Result<(), Error> {
+        let mut storage = OverlayStorage::new(&self.storage);
+        let mut root = self.root.clone();
+        let below = match range.start_bound() {
+            Bound::Included(start) => Some(Bound::Excluded(start.clone())),
+            Bound::Excluded(start) => Some(Bound::Included(start.clone())),
+            Bound::Unbounded => None,
+        };
+        if let Some(end) = below {
+            root.delete_range(&(Bound::Unbounded, end), &mut storage, Vec::new())?;
+        }
+        let above = match range.end_bound() {
+            Bound::Included(end) => Some(Bound::Excluded(end.clone())),
+            Bound::Excluded(end) => Some(Bound::Included(end.clone())),
+            Bound::Unbounded => None,
+        };
+        if let Some(start) = above {
+            root.delete_range(&(start, Bound::Unbounded), &mut storage, Vec::new())?;
+        }
+        storage.insert_node(root.get_hash(), root.clone());
+
+        let mut config = self.config.clone();
+        config.root_hash = Some(root.get_hash());
+        export_tree(&root, &config, &storage, &mut writer)
+    }
+
+    /// Reads an export, such as one written by `export_range`, and merges its entries into
+    /// this tree.
+    ///
+    /// The export is verified before anything is merged. Values from the export replace
+    /// the values of keys present in both; keys missing from the export are kept.
+    ///
+    /// # Parameters
+    /// - `reader`: The source of the export.
+    pub fn merge_import<R: Read>(&mut self, mut reader: R) -> Result<(), Error> {
+        let mut storage = InMemoryNodeStorage::new();
+        let (config, root) = import_tree(&mut reader, &mut storage)?;
+        let imported = ProllyTree::with_root(root, storage, config);
+        self.merge_with(&imported, |_, _, theirs| theirs.to_vec())
+    }
+
+    /// Merges another tree into this one, producing the union of both key sets.
+    ///
+    /// Subtrees with the same hash in both trees are skipped without being loaded, so
+    /// the cost depends on how much the trees differ rather than on their size. Keys
+    /// only present in `other` are copied over. For keys present in both trees with
+    /// different values, `conflict_fn` is called with the key, this tree's value and
+    /// the other tree's value, and its result is stored.
+    ///
+    /// # Parameters
+    /// - `other`: The tree to merge in; it may use a different storage.
+    /// - `conflict_fn`: Resolves the value of keys present in both trees.
+    ///
+    /// # Returns
+    /// - `Error::MissingNode` if a node that has to be compared is not in the storage of
+    ///   either tree, in which case nothing is merged.
+    pub fn merge_with<O, F>(
+        &mut self,
+        other: &ProllyTree<N, O>,
+        mut conflict_fn: F,
+    ) -> Result<(), Error>
+    where
+        O: NodeStorage<N>,
+        F: FnMut(&[u8], &[u8], &[u8]) -> Vec<u8>,
+    {
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        zip_trees(
+            &self.root,
+            &self.storage,
+            &other.root,
+            &other.storage,
+            |pairing| match pairing {
+                Pairing::Right(key, value) => {
+                    if let Some(value) = other.live_value(value) {
+                        keys.push(key);
+                        values.push(value);
+                    }
+                }
+                Pairing::Both(key, ours, theirs) if ours != theirs => {
+                    let Some(theirs) = other.live_value(theirs) else {
+                        return;
+                    };
+                    let Some(ours) = self.live_value(ours) else {
+                        // an expired key of ours is absent
+                        keys.push(key);
+                        values.push(theirs);
+                        return;
+                    };
+                    if ours == theirs {
+                        return;
+                    }
+                    let resolved = conflict_fn(&key, &ours, &theirs);
+                    if resolved != ours {
+                        keys.push(key);
+                        values.push(resolved);
+                    }
+                }
+                _ => {}
+            },
+        )?;
+
+        if !keys.is_empty() {
+            self.insert_batch(&keys, &values);
+        }
+        Ok(())
+    }
+
+    /// Merges the changes made on another version of this tree since a common ancestor.
+    ///
+    /// This is a three-way merge as computed by `diff::merge3`, with this tree as our
+    /// side. Keys changed only on their side take their value, and keys changed on both
+    /// sides in different ways keep our value and are returned as conflicts to be resolved
+    /// by the caller. Values expired on either side count as deleted.
+    ///
+    /// # Parameters
+    /// - `base`: The root hash of the common ancestor.
+    /// - `theirs`: The root hash of the version to merge in.
+    ///
+    /// # Returns
+    /// - The conflicts, or `Error::MissingNode` if a compared node is not in the storage
+    ///   of this tree.
+    pub fn merge3(
+        &mut self,
+        base: &ValueDigest<N>,
+        theirs: &ValueDigest<N>,
+    ) -> Result<Vec<MergeConflict>, Error> {
+        self.merge3_with(base, theirs, &MergePolicy::default())
+    }
+
+    /// Like `merge3`, but resolves conflicts with the strategies of a policy.
+    ///
+    /// # Parameters
+    /// - `base`: The root hash of the common ancestor.
+    /// - `theirs`: The root hash of the version to merge in.
+    /// - `policy`: The strategies to resolve conflicts with, per key prefix.
+    ///
+    /// # Returns
+    /// - The conflicts no strategy applies to, or `Error::MissingNode` if a compared node
+    ///   is not in the storage of this tree.
+    pub fn merge3_with(
+        &mut self,
+        base: &ValueDigest<N>,
+        theirs: &ValueDigest<N>,
+        policy: &MergePolicy,
+    ) -> Result<Vec<MergeConflict>, Error> {
+        let base = load_root(&self.storage, base)?;
+        let theirs = load_root(&self.storage, theirs)?;
+        let merge = merge_nodes(&self.storage, &base, &self.root, &theirs)?;
+
+        // strategies see the values as they were written
+        let live = |value: Option<Vec<u8>>| value.and_then(|value| self.live_value(value));
+        let mut merge = Merge {
+            changes: merge
+                .changes
+                .into_iter()
+                .map(|(key, value)| (key, live(value)))
+                .collect(),
+            conflicts: merge
+                .conflicts
+                .into_iter()
+                .map(|conflict| MergeConflict {
+                    key: conflict.key,
+                    base: live(conflict.base),
+                    ours: live(conflict.ours),
+                    theirs: live(conflict.theirs),
+                })
+                .filter(|conflict| conflict.ours != conflict.theirs)
+                .collect(),
+        };
+        merge.resolve(policy);
+
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        let mut deleted = Vec::new();
+        for (key, value) in merge.changes {
+            match value {
+                Some(value) => {
+                    keys.push(key);
+                    values.push(value);
+                }
+                None => deleted.push(key),
+            }
+        }
+        if !keys.is_empty() {
+            self.insert_batch(&keys, &values);
+        }
+        if !deleted.is_empty() {
+            self.delete_batch(&deleted);
+        }
+        Ok(merge.conflicts)
+    }
+
+    /// Like `merge3_with`, but records the conflicts no strategy applies to in the tree
+    /// instead of leaving them to the caller, so that they can be resolved later, e.g. by
+    /// a person reviewing the merge.
+    ///
+    /// Every conflict is stored as a `ConflictRecord` under `CONFLICT_PREFIX` followed by
+    /// the conflicting key, which keeps our value until `resolve_conflict` is called.
+    /// Recording a conflict for a key again replaces the earlier record.
+    ///
+    /// # Parameters
+    /// - `base`: The root hash of the common ancestor.
+    /// - `theirs`: The root hash of the version to merge in.
+    /// - `policy`: The strategies to resolve conflicts with, per key prefix.
+    ///
+    /// # Returns
+    /// - The number of recorded conflicts, or `Error::MissingNode` if a compared node is
+    ///   not in the storage of this tree.
+    pub fn merge3_deferred(
+        &mut self,
+        base: &ValueDigest<N>,
+        theirs: &ValueDigest<N>,
+        policy: &MergePolicy,
+    ) -> Result<usize, Error> {
+        let conflicts = self.merge3_with(base, theirs, policy)?;
+        let recorded_at = ttl::now();
+        let records: Vec<ConflictRecord<N>> = conflicts
+            .into_iter()
+            .map(|conflict| ConflictRecord {
+                conflict,
+                theirs_root: theirs.clone(),
+                recorded_at,
+            })
+            .collect();
+        self.insert_conflict_records(&records)?;
+        Ok(records.len())
+    }
+
+    /// Stores conflict records under their keys, which are exempt from the key and value
+    /// formats of the tree.
+    fn insert_conflict_records(&mut self, records: &[ConflictRecord<N>]) -> Result<(), Error> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<Vec<u8>> = records
+            .iter()
+            .map(|record| ConflictRecord::<N>::record_key(&record.conflict.key))
+            .collect();
+        let values = records
+            .iter()
+            .map(ConflictRecord::to_bytes)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.insert_batch(&keys, &values);
+        Ok(())
+    }
+
+    /// Returns the conflicts recorded by `merge3_deferred` that are not yet resolved.
+    ///
+    /// # Returns
+    /// - The records in ascending order of the conflicting keys, or `Error::Serde` if a
+    ///   record cannot be decoded.
+    pub fn conflicts(&self) -> Result<Vec<ConflictRecord<N>>, Error> {
+        self.scan(prefix_range(CONFLICT_PREFIX))
+            .filter_map(|(_, value)| self.live_value(value))
+            .map(|record| ConflictRecord::from_bytes(&record))
+            .collect()
+    }
+
+    /// Resolves a conflict recorded by `merge3_deferred` and removes its record.
+    ///
+    /// # Parameters
+    /// - `key`: The conflicting key.
+    /// - `value`: The value to keep, or `None` to delete the key.
+    ///
+    /// # Returns
+    /// - `false` if no conflict is recorded for the key, in which case nothing changes,
+    ///   or the error of the value if it does not match the formats of the tree.
+    pub fn resolve_conflict(&mut self, key: &[u8], value: Option<Vec<u8>>) -> Result<bool, Error> {
+        let record_key = ConflictRecord::<N>::record_key(key);
+        if self.current_value(&record_key).is_none() {
+            return Ok(false);
+        }
+        match value {
+            Some(value) => {
+                self.check_entry(key, &value)?;
+                self.insert(key.to_vec(), value);
+            }
+            None => {
+                self.delete(key);
+            }
+        }
+        self.delete(&record_key);
+        Ok(true)
+    }
+
+    /// Computes the patch from an earlier version of this tree to the current one.
+    ///
+    /// # Parameters
+    /// - `from`: The root hash of the earlier version, which must be in the storage of
+    ///   this tree.
+    ///
+    /// # Returns
+    /// - The patch, with values as they were written, or `Error::MissingNode` if a compared
+    ///   node is not in storage.
+    pub fn create_patch(&self, from: &ValueDigest<N>) -> Result<Patch<N>, Error> {
+        let from_root = load_root(&self.storage, from)?;
+        let diffs = diff_nodes(&self.storage, &from_root, &self.root)?
+            .into_iter()
+            .map(|diff| match diff {
+                DiffResult::Added(key, new) => DiffResult::Added(key, self.load_value(new)),
+                DiffResult::Removed(key, old) => DiffResult::Removed(key, self.load_value(old)),
+                DiffResult::Modified(key, old, new) => {
+                    DiffResult::Modified(key, self.load_value(old), self.load_value(new))
+                }
+            })
+            .collect();
+        Ok(Patch {
+            from_root: from.clone(),
+            to_root: self.root.get_hash(),
+            diffs,
+        })
+    }
+
+    /// Applies a patch created by `create_patch`, e.g. on a replica of the tree.
+    ///
+    /// The patch is only applied if this tree is at the version the patch was created
+    /// from, and if every value it inserts matches the key and value formats of the tree.
+    /// The resulting tree holds the same entries as the version the patch leads to.
+    ///
+    /// # Parameters
+    /// - `patch`: The patch to apply.
+    ///
+    /// # Returns
+    /// - `Error::PatchMismatch` if the root hash of this tree is not the `from_root` of
+    ///   the patch, or the error of the first entry that does not match the formats.
+    pub fn apply_patch(&mut self, patch: &Patch<N>) -> Result<(), Error> {
+        let root_hash = self.root.get_hash();
+        if root_hash != patch.from_root {
+            return Err(Error::PatchMismatch(hex::encode(root_hash.as_bytes())));
+        }
+        self.apply_diffs(patch.diffs.iter())
+    }
+
+    /// Applies the changes of a diff whose keys pass a filter, e.g. to promote the data
+    /// of one client from a branch onto another tree without merging everything.
+    ///
+    /// Unlike `apply_patch`, the tree does not need to be at the version the diff was
+    /// computed from: added and modified keys take their new value and removed keys are
+    /// deleted, whatever this tree holds for them.
+    ///
+    /// # Parameters
+    /// - `diffs`: The changes to pick from, e.g. the `diffs` of a patch from `create_patch`.
+    /// - `key_filter`: Returns `true` for the keys whose changes are applied.
+    ///
+    /// # Returns
+    /// - The number of applied changes, or the error of the first picked entry that does
+    ///   not match the key and value formats of the tree, in which case nothing is applied.
+    pub fn apply_diff_selective<F>(
+        &mut self,
+        diffs: &[DiffResult],
+        key_filter: F,
+    ) -> Result<usize, Error>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let picked: Vec<&DiffResult> = diffs.iter().filter(|diff| key_filter(diff.key())).collect();
+        self.apply_diffs(picked.iter().copied())?;
+        Ok(picked.len())
+    }
+
+    /// Checks the entries of the diffs against the formats of the tree and applies them.
+    fn apply_diffs<'a>(
+        &mut self,
+        diffs: impl Iterator<Item = &'a DiffResult>,
+    ) -> Result<(), Error> {
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+        let mut deleted = Vec::new();
+        for diff in diffs {
+            match diff {
+                DiffResult::Added(key, value) | DiffResult::Modified(key, _, value) => {
+                    self.check_entry(key, value)?;
+                    keys.push(key.clone());
+                    values.push(value.clone());
+                }
+                DiffResult::Removed(key, _) => deleted.push(key.clone()),
+            }
+        }
+        if !keys.is_empty() {
+            self.insert_batch(&keys, &values);
+        }
+        if !deleted.is_empty() {
+            self.delete_batch(&deleted);
+        }
+        Ok(())
+    }
+
+    /// Pulls the nodes of a remote version of this tree into the storage of this tree.
+    ///
+    /// Only the nodes missing in the storage are fetched. The root of this tree is left
+    /// unchanged; the pulled version can then be compared or merged by its root hash,
+    /// e.g. with `diff::diff_roots` or `merge3`.
+    ///
+    /// # Parameters
+    /// - `transport`: The connection to the remote store.
+    ///
+    /// # Returns
+    /// - A report of the session, as returned by `SyncSession::pull`.
+    pub fn pull<T: SyncTransport<N>>(&mut self, transport: &mut T) -> Result<SyncReport<N>, Error> {
+        SyncSession::new(&mut self.storage)
+            .with_expiring(self.config.expiring_keys)
+            .pull(transport)
+    }
+
+    /// Returns the entries of this tree whose keys are also present in `other`.
+    ///
+    /// Subtrees with the same hash in both trees are taken over as a whole without
+    /// comparing their entries.
+    ///
+    /// # Parameters
+    /// - `other`: The tree to intersect with; it may use a different storage.
+    ///
+    /// # Returns
+    /// - The common keys in ascending order, with the values from this tree.
+    ///
+    /// # Panics
+    /// - If a node that has to be compared is missing from the storage of either tree.
+    pub fn intersect<O: NodeStorage<N>>(
+        &self,
+        other: &ProllyTree<N, O>,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = Vec::new();
+        zip_trees(
+            &self.root,
+            &self.storage,
+            &other.root,
+            &other.storage,
+            |pairing| match pairing {
+                Pairing::Both(key, ours, theirs) => {
+                    if other.is_live(&theirs) {
+                        entries.extend(self.live_value(ours).map(|value| (key, value)));
+                    }
+                }
+                Pairing::Shared(node) => entries.extend(
+                    Cursor::new(&node, &self.storage)
+                        .with_blobs(self.blobs())
+                        .with_expiry(self.expiry_clock()),
+                ),
+                Pairing::Left(..) | Pairing::Right(..) => {}
+            },
+        )
+        .unwrap_or_else(|error| panic!("failed to compare trees: {error}"));
+        entries
+    }
+
+    /// Returns the entries of this tree whose keys are not present in `other`.
+    ///
+    /// Subtrees with the same hash in both trees are skipped without being loaded.
+    ///
+    /// # Parameters
+    /// - `other`: The tree to subtract; it may use a different storage.
+    ///
+    /// # Returns
+    /// - The keys only found in this tree in ascending order, with their values.
+    ///
+    /// # Panics
+    /// - If a node that has to be compared is missing from the storage of either tree.
+    pub fn difference<O: NodeStorage<N>>(
+        &self,
+        other: &ProllyTree<N, O>,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = Vec::new();
+        zip_trees(
+            &self.root,
+            &self.storage,
+            &other.root,
+            &other.storage,
+            |pairing| {
+                let (key, ours) = match pairing {
+                    Pairing::Left(key, ours) => (key, ours),
+                    // keys that expired in the other tree are absent there
+                    Pairing::Both(key, ours, theirs) if !other.is_live(&theirs) => (key, ours),
+                    _ => return,
+                };
+                entries.extend(self.live_value(ours).map(|value| (key, value)));
+            },
+        )
+        .unwrap_or_else(|error| panic!("failed to compare trees: {error}"));
+        entries
+    }
+
+    /// Splits the tree into two trees covering disjoint key ranges.
+    ///
+    /// Only the nodes on the path to `key` are rewritten; every other node keeps its hash
+    /// and is shared with the original tree. Both trees keep using the same storage
+    /// backend, cloned for the second tree.
+    ///
+    /// # Parameters
+    /// - `key`: The first key of the upper tree.
+    ///
+    /// # Returns
+    /// - A tree with the keys smaller than `key` and a tree with the remaining keys, or
+    ///   `Error::MissingNode` if a node on the path to `key` is not in storage.
+    pub fn split_at(self, key: &[u8]) -> Result<(Self, Self), Error>
+    where
+        S: Clone,
+    {
+        let mut upper = self.fork();
+        let mut lower = self;
+
+        // splitting removes no entries from the data set, so observers are not notified
+        let observers = std::mem::take(&mut lower.observers);
+        lower.try_delete_range(key.to_vec()..)?;
+        upper.try_delete_range(..key.to_vec())?;
+        lower.observers = observers;
+        lower.config.root_hash = Some(lower.root.get_hash());
+        upper.config.root_hash = Some(upper.root.get_hash());
+        Ok((lower, upper))
+    }
+
+    /// Creates a new tree that starts out with the same contents as this one.
+    ///
+    /// The fork points at the same root and uses a clone of the storage handle. Both trees
+    /// can be modified independently afterwards: nodes are never modified in place, so
+    /// every subtree neither tree has changed stays shared. The cost is that of cloning
+    /// the storage: file based storages share their nodes on disk and only clone their
+    /// location, while `InMemoryNodeStorage` copies a handle to every node it holds, which
+    /// takes time proportional to the number of nodes, though the nodes themselves are
+    /// shared. Access statistics and observers are not carried over to the fork.
+    pub fn fork(&self) -> Self
+    where
+        S: Clone,
+    {
+        Self::with_root(self.root.clone(), self.storage.clone(), self.config.clone())
+    }
+
+    /// Returns the storage of the tree, e.g. to compare versions of the tree with the
+    /// functions of the `diff` module.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Returns a read-only view of the tree as it is now.
+    ///
+    /// The snapshot is pinned to the current root hash and owns a clone of the storage
+    /// handle, so it can be read from another thread while this tree keeps being
+    /// modified. Taking a snapshot costs as much as cloning the storage: file based
+    /// storages only clone their location, while `InMemoryNodeStorage` copies a handle to
+    /// every node it holds, which takes time proportional to the number of nodes.
+    pub fn snapshot(&self) -> Snapshot<N, S>
+    where
+        S: Clone,
+    {
+        Snapshot::new(
+            self.root.clone(),
+            self.storage.clone(),
+            self.blobs(),
+            self.config.expiring_keys,
+        )
+    }
+
+    /// Returns the entries next to an absent key, with the paths to their leaves.
+    fn neighbors(&self, key: &[u8]) -> (Option<Neighbor<N>>, Option<Neighbor<N>>) {
+        let root_hash = self.root.get_hash();
+        let mut cursor = Cursor::new(&self.root, &self.storage);
+        let neighbor = |cursor: &Cursor<'_, N, S>| {
+            let (key, _) = cursor.current()?;
+            let proof = cursor.proof(&root_hash)?;
+            Some(Neighbor {
+                key: key.to_vec(),
+                path: proof.path,
+            })
+        };
+
+        let upper = cursor.seek(key).and_then(|_| neighbor(&cursor));
+        let lower = if upper.is_some() {
+            cursor.prev()
+        } else {
+            cursor.seek_last()
+        };
+        let lower = lower.and_then(|_| neighbor(&cursor));
+        (lower, upper)
+    }
+
+    /// Follows a proof path from the root towards `key`, checking the hash, position and
+    /// siblings of every node.
+    ///
+    /// # Returns
+    /// - The leaf the path ends at, and for every internal node on the way the index of
+    ///   the child taken and the number of children, or `None` if the path is invalid.
+    fn follow_proof(
+        &self,
+        path: &[PathSegment<N>],
+        key: &[u8],
+    ) -> Option<(ProllyNode<N>, Vec<(usize, usize)>)> {
+        let mut node = self.root.clone();
+        let mut turns: Vec<(usize, usize)> = Vec::new();
+        for (i, segment) in path.iter().enumerate() {
+            let expected = match turns.last() {
+                Some(&(child_index, _)) => PathSegment::child_of(&node, child_index),
+                None => PathSegment::root(node.get_hash()),
+            };
+            // segments of version 1 proofs hold only the node hash
+            let hash_only = segment.sibling_hashes.is_empty() && segment.position == 0;
+            if segment.node_hash != expected.node_hash || (!hash_only && *segment != expected) {
+                return None;
+            }
+            if i > 0 {
+                node = self.storage.get_node_by_hash(&segment.node_hash)?;
+            }
+            if node.get_hash() != segment.node_hash {
+                return None;
+            }
+            if i == path.len() - 1 {
+                // the path must end at a leaf node
+                return node.is_leaf.then_some((node, turns));
+            }
+            if node.is_leaf {
+                return None;
+            }
+            let child_index = node.keys.iter().rposition(|k| key >= &k[..]).unwrap_or(0);
+            turns.push((child_index, node.values.len()));
+        }
+        None
+    }
+
+    /// Checks a proof of non-existence, given the leaf `key` would be stored in and the
+    /// path that led to it.
+    fn verify_absence(
+        &self,
+        proof: &Proof<N>,
+        key: &[u8],
+        leaf: &ProllyNode<N>,
+        turns: &[(usize, usize)],
+    ) -> bool {
+        if leaf.keys.iter().any(|k| k == key) {
+            return false;
+        }
+        // a neighbor must be in the tree, and be the last (for the lower neighbor) or
+        // first (for the upper neighbor) entry of its leaf unless it shares the leaf of key
+        let neighbor_holds = |neighbor: &Neighbor<N>, lower: bool| {
+            if (neighbor.key.as_slice() < key) != lower {
+                return false;
+            }
+            let Some((neighbor_leaf, _)) = self.follow_proof(&neighbor.path, &neighbor.key) else {
+                return false;
+            };
+            let edge = if lower {
+                neighbor_leaf.keys.last()
+            } else {
+                neighbor_leaf.keys.first()
+            };
+            neighbor_leaf.keys.contains(&neighbor.key)
+                && (neighbor_leaf.get_hash() == leaf.get_hash() || edge == Some(&neighbor.key))
+        };
+        let lower_holds = match &proof.lower {
+            Some(lower) => neighbor_holds(lower, true),
+            // without a lower neighbor, key must be left of every entry
+            None => turns.iter().all(|&(index, _)| index == 0),
+        };
+        let upper_holds = match &proof.upper {
+            Some(upper) => neighbor_holds(upper, false),
+            // without an upper neighbor, key must be right of every entry
+            None => turns.iter().all(|&(index, children)| index + 1 == children),
+        };
+
+        // no entry of the leaf may fall between the neighbors
+        let gap = leaf.keys.iter().all(|k| {
+            proof.lower.as_ref().is_some_and(|lower| *k <= lower.key)
+                || proof.upper.as_ref().is_some_and(|upper| *k >= upper.key)
+        });
+        lower_holds && upper_holds && gap
+    }
+
+    /// Creates a tree around an existing root node.
+    fn with_root(root: ProllyNode<N>, storage: S, mut config: TreeConfig<N>) -> Self {
+        config.root_hash = Some(root.get_hash());
+        ProllyTree {
+            root,
+            storage,
+            config,
+            access: None,
+            observers: Observers::default(),
+        }
+    }
+
+    /// Whether values of this tree may be stored outside of the leaves.
+    fn blobs(&self) -> bool {
+        blobs_enabled(&self.config)
+    }
+
+    /// Returns the time expired keys are filtered against, if the tree has expiring keys.
+    fn expiry_clock(&self) -> Option<u64> {
+        self.config.expiring_keys.then(ttl::now)
+    }
+
+    /// Converts a value into the form it is stored in the leaves.
+    fn store_value(&mut self, value: Vec<u8>) -> Vec<u8> {
+        self.store_value_until(value, ttl::NEVER)
+    }
+
+    /// Converts a value expiring at the given timestamp into the form it is stored in the
+    /// leaves.
+    fn store_value_until(&mut self, value: Vec<u8>, expires_at: u64) -> Vec<u8> {
+        let value = encode_value(value, &self.config, &mut self.storage);
+        if self.config.expiring_keys {
+            ttl::wrap(value, expires_at)
+        } else {
+            value
+        }
+    }
+
+    /// Converts a value as stored in the leaves back into its original form.
+    fn load_value(&self, stored: Vec<u8>) -> Vec<u8> {
+        let stored = if self.config.expiring_keys {
+            ttl::unwrap(&stored).1.to_vec()
+        } else {
+            stored
+        };
+        if self.blobs() {
+            decode_value(&stored, &self.storage).unwrap_or(stored)
+        } else {
+            stored
+        }
+    }
+
+    /// Returns `false` if a value as stored in the leaves has expired.
+    fn is_live(&self, stored: &[u8]) -> bool {
+        self.expiry_clock()
+            .is_none_or(|now| !ttl::is_expired(stored, now))
+    }
+
+    /// Like `load_value`, but returns `None` if the value has expired.
+    fn live_value(&self, stored: Vec<u8>) -> Option<Vec<u8>> {
+        self.is_live(&stored).then(|| self.load_value(stored))
+    }
+
+    /// Checks a key-value pair against the configured key and value formats.
+    fn check_entry(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        check_entry(&self.config, key, value)
+    }
+
+    /// Returns the live value of a key, decoded.
+    pub(crate) fn current_value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_ceiling(key)
+            .filter(|(found, _)| found == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Records the current value of a key before it is changed to `new`, if any observer
+    /// is registered.
+    fn pending_change(&self, key: &[u8], new: Option<&[u8]>) -> Option<Change> {
+        if self.observers.is_empty() {
+            return None;
+        }
+        Some(Change {
+            key: key.to_vec(),
+            old: self.current_value(key),
+            new: new.map(<[u8]>::to_vec),
+        })
+    }
+
+    /// Inserts multiple key-value pairs into the root without persisting it, and returns
+    /// the changes to report once it is.
+    fn insert_entries(&mut self, keys: &[Vec<u8>], values: &[Vec<u8>]) -> Vec<Change> {
+        keys.iter().for_each(|key| self.record_write(key));
+        let changes: Vec<Change> = keys
+            .iter()
+            .zip(values)
+            .filter_map(|(key, value)| self.pending_change(key, Some(value)))
+            .collect();
+        if self.blobs() || self.config.expiring_keys {
+            let values: Vec<Vec<u8>> = values
+                .iter()
+                .map(|value| self.store_value(value.clone()))
+                .collect();
+            self.root
+                .insert_batch(keys, &values, &mut self.storage, Vec::new());
+        } else {
+            self.root
+                .insert_batch(keys, values, &mut self.storage, Vec::new());
+        }
+        changes
+    }
+
+    /// Deletes multiple keys from the root without persisting it, and returns the changes
+    /// to report once it is.
+    fn delete_entries(&mut self, keys: &[Vec<u8>]) -> Vec<Change> {
+        keys.iter().for_each(|key| self.record_write(key));
+        let changes: Vec<Change> = keys
+            .iter()
+            .filter_map(|key| self.pending_change(key, None))
+            .collect();
+        self.root.delete_batch(keys, &mut self.storage, Vec::new());
+        changes
+    }
+
+    /// Reports applied changes to the observers.
+    fn notify<I: IntoIterator<Item = Change>>(&mut self, changes: I) {
+        for change in changes {
+            self.observers
+                .notify(&change.key, change.old.as_deref(), change.new.as_deref());
+        }
+    }
+
+    /// Returns the storage of the tree for writing, e.g. to copy in the nodes of another
+    /// tree.
+    #[cfg(test)]
+    pub(crate) fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    fn persist_root(&mut self) {
+        // Save the updated child node back to the storage
+        self.storage
+            .insert_node(self.root.get_hash(), self.root.clone());
+    }
+
+    fn record_write(&self, key: &[u8]) {
+        if let Some(access) = &self.access {
+            access.record_write(key);
+        }
+    }
+
+    /// Registers an observer that is called with `(key, old_value, new_value)` after every
+    /// change applied to the tree.
+    ///
+    /// Values are passed decoded, as returned by reads. `old_value` is `None` for new keys
+    /// and `new_value` is `None` for deleted keys; keys that expire are not reported. Every
+    /// write looks up the old values while an observer is registered.
+    ///
+    /// # Parameters
+    /// - `observer`: The callback to register.
+    ///
+    /// # Returns
+    /// - The id to remove the observer with.
+    pub fn add_observer<F>(&mut self, observer: F) -> ObserverId
+    where
+        F: FnMut(&[u8], Option<&[u8]>, Option<&[u8]>) + Send + Sync + 'static,
+    {
+        self.observers.add(Box::new(observer))
+    }
+
+    /// Removes a registered observer.
+    ///
+    /// # Returns
+    /// - `true` if the observer was registered.
+    pub fn remove_observer(&mut self, id: ObserverId) -> bool {
+        self.observers.remove(id)
+    }
+
+    /// Starts counting reads and writes per key prefix.
+    ///
+    /// Tracking is opt-in because every access takes a lock on the counters. Keys are
+    /// truncated to `max_prefix_len` bytes before being counted, which bounds the memory
+    /// used by the counters. Enabling tracking again resets the counters.
+    ///
+    /// # Parameters
+    /// - `max_prefix_len`: The longest prefix (in bytes) that will be tracked.
+    pub fn enable_access_stats(&mut self, max_prefix_len: usize) {
+        self.access = Some(AccessTracker::new(max_prefix_len));
+    }
+
+    /// Stops counting accesses and discards the collected counters.
+    pub fn disable_access_stats(&mut self) {
+        self.access = None;
+    }
+
+    /// Aggregates the collected access counters by key prefix.
+    ///
+    /// # Parameters
+    /// - `depth`: The number of key bytes used to group the counters.
+    ///
+    /// # Returns
+    /// - `Some(AccessHeatmap)` if access tracking is enabled, `None` otherwise.
+    pub fn access_heatmap(&self, depth: usize) -> Option<AccessHeatmap> {
+        self.access.as_ref().map(|access| access.heatmap(depth))
+    }
+}
+
+impl<const N: usize, S: NodeStorage<N>> ProllyTree<N, S> {
+    /// Recursively computes the differences between two Prolly Nodes.
+    ///
+    /// This helper function is used by `diff` to traverse the nodes of both trees
+    /// and identify changes. It compares the keys and values of the nodes and
+    /// generates appropriate `DiffResult` entries for added, removed, and modified
+    /// key-value pairs.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_node` - The node from the original tree.
+    /// * `new_node` - The node from the new tree.
+    /// * `diffs` - The vector to store the differences.
+    fn diff_recursive(
+        &self,
+        old_node: &ProllyNode<N>,
+        new_node: &ProllyNode<N>,
+        diffs: &mut Vec<DiffResult>,
+    ) {
+        let mut old_iter = old_node.keys.iter().zip(old_node.values.iter()).peekable();
+        let mut new_iter = new_node.keys.iter().zip(new_node.values.iter()).peekable();
+
+        while let (Some((old_key, old_value)), Some((new_key, new_value))) =
+            (old_iter.peek(), new_iter.peek())
+        {
+            match old_key.cmp(new_key) {
+                std::cmp::Ordering::Less => {
+                    diffs.push(DiffResult::Removed(old_key.to_vec(), old_value.to_vec()));
+                    old_iter.next();
+                }
+                std::cmp::Ordering::Greater => {
+                    diffs.push(DiffResult::Added(new_key.to_vec(), new_value.to_vec()));
+                    new_iter.next();
+                }
+                std::cmp::Ordering::Equal => {
+                    if old_value != new_value {
+                        diffs.push(DiffResult::Modified(
+                            old_key.to_vec(),
+                            old_value.to_vec(),
+                            new_value.to_vec(),
+                        ));
+                    }
+                    old_iter.next();
+                    new_iter.next();
+                }
+            }
+        }
+
+        for (old_key, old_value) in old_iter {
+            diffs.push(DiffResult::Removed(old_key.clone(), old_value.clone()));
+        }
+
+        for (new_key, new_value) in new_iter {
+            diffs.push(DiffResult::Added(new_key.clone(), new_value.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::ChunkingStrategy;
+    use crate::compression::Compression;
+    use crate::config::ValuePolicy;
+    use crate::diff::diff_roots;
+    use crate::digest::HashAlgorithm;
+    use crate::integrity::Violation;
+    use crate::proof::verifier::{self, RangeVerifier};
+    use crate::schema::KeyFormat;
+    use crate::storage::{InMemoryNodeStorage, NodeReader};
+    use crate::sync::StorageTransport;
+    use std::num::NonZeroUsize;
+    use std::sync::{Arc, Mutex};
+
+    /// Example usage of the Prolly Tree
+    #[test]
+    fn inmem_node_storage_test() {
+        // 1. Create a custom tree config
+        let config = TreeConfig {
+            base: 131,
+            modulus: 1_000_000_009,
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b101,
+            root_hash: None,
+            key_schema: None,
+            value_schema: None,
+            encode_types: vec![],
+            value_chunk_threshold: None,
+            value_policy: ValuePolicy::Inline,
+            compression: Compression::None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            chunking: ChunkingStrategy::RollingHash,
+            expiring_keys: false,
+            key_format: None,
+            value_format: None,
+        };
+
+        // 2. Create and Wrap the Storage Backend
+        let storage = InMemoryNodeStorage::<32>::default();
+
+        // 3. Create the Prolly Tree
+        let mut tree = ProllyTree::new(storage, config);
+
+        // 4. Insert New Key-Value Pairs
+        tree.insert(b"key1".to_vec(), b"value1".to_vec());
+        tree.insert(b"key2".to_vec(), b"value2".to_vec());
+
+        // 5. Traverse the Tree with a Custom Formatter
+        let traversal = tree.formatted_traverse(|node| {
+            let keys_as_strings: Vec<String> =
+                node.keys.iter().map(|k| format!("{:?}", k)).collect();
+            format!("[L{}: {}]", node.level, keys_as_strings.join(", "))
+        });
+        println!("Traversal: {}", traversal);
+
+        // 6. Update the Value for an Existing Key
+        tree.update(b"key1".to_vec(), b"new_value1".to_vec());
+
+        // 7. Find or Search for a Key
+        if let Some(node) = tree.find(b"key1") {
+            println!("Found key1 with value: {:?}", node);
+        } else {
+            println!("key1 not found");
+        }
+
+        // 8. Delete a key-value pair
+        if tree.delete(b"key2") {
+            println!("key2 deleted");
+        } else {
+            println!("key2 not found");
+        }
+
+        // 9. Print tree stats
+        println!("Size: {}", tree.size());
+        println!("Depth: {}", tree.depth());
+        println!("Summary: {}", tree.summary());
+
+        // 10. Print Tree
+        println!("{:?}", tree.root.print_tree(&tree.storage));
+    }
+
+    #[test]
+    fn file_node_storage_test() {
+        use crate::storage::FileNodeStorage;
+        use std::fs;
+        use std::path::PathBuf;
+
+        // 1. Create a custom tree config
+        let config = TreeConfig {
+            base: 131,
+            modulus: 1_000_000_009,
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b101,
+            root_hash: None,
+            key_schema: None,
+            value_schema: None,
+            encode_types: vec![],
+            value_chunk_threshold: None,
+            value_policy: ValuePolicy::Inline,
+            compression: Compression::None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            chunking: ChunkingStrategy::RollingHash,
+            expiring_keys: false,
+            key_format: None,
+            value_format: None,
+        };
+
+        // 2. Create and Wrap the Storage Backend
+        let storage_dir = PathBuf::from("/tmp/prolly_tree_storage");
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+
+        // 3. Create the Prolly Tree
+        let mut tree = ProllyTree::new(storage, config);
+
+        // 4. Insert New Key-Value Pairs
+        tree.insert(b"key1".to_vec(), b"value1".to_vec());
+        tree.insert(b"key2".to_vec(), b"value2".to_vec());
+
+        // 5. Traverse the Tree with a Custom Formatter
+        let traversal = tree.formatted_traverse(|node| {
+            let keys_as_strings: Vec<String> =
+                node.keys.iter().map(|k| format!("{:?}", k)).collect();
+            format!("[L{}: {}]", node.level, keys_as_strings.join(", "))
+        });
+        println!("Traversal: {}", traversal);
+
+        // 6. Update the Value for an Existing Key
+        tree.update(b"key1".to_vec(), b"new_value1".to_vec());
+
+        // 7. Find or Search for a Key
+        if let Some(node) = tree.find(b"key1") {
+            println!("Found key1 with value: {:?}", node);
+        } else {
+            println!("key1 not found");
+        }
+
+        // 8. Delete a key-value pair
+        if tree.delete(b"key2") {
+            println!("key2 deleted");
+        } else {
+            println!("key2 not found");
+        }
+
+        // 9. Print tree stats
+        println!("Size: {}", tree.size());
+        println!("Depth: {}", tree.depth());
+        println!("Summary: {}", tree.summary());
+
+        // 10. Print Tree
+        println!("{:?}", tree.root.print_tree(&tree.storage));
+
+        // Clean up the storage directory
+        fs::remove_dir_all(storage_dir).unwrap();
+    }
+
+    #[test]
+    fn test_insert_and_find() {
+        let storage = InMemoryNodeStorage::<32>::default();
+
+        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+
+        tree.insert(b"key1".to_vec(), b"value1".to_vec());
+        tree.insert(b"key2".to_vec(), b"value2".to_vec());
+
+        assert!(tree.find(b"key1").is_some());
+        assert!(tree.find(b"key2").is_some());
+        assert!(tree.find(b"key3").is_none());
+    }
+
+    #[test]
+    fn test_insert_batch_and_find() {
+        let storage = InMemoryNodeStorage::<32>::default();
+
+        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+
+        let keys = vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()];
+        let values = vec![b"value1".to_vec(), b"value2".to_vec(), b"value3".to_vec()];
+
+        tree.insert_batch(&keys, &values);
+
+        assert!(tree.find(b"key1").is_some());
+        assert!(tree.find(b"key2").is_some());
+        assert!(tree.find(b"key3").is_some());
+        assert!(tree.find(b"key4").is_none());
+    }
+
+    #[test]
+    fn test_delete() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+
+        tree.insert(b"key1".to_vec(), b"value1".to_vec());
+        tree.insert(b"key2".to_vec(), b"value2".to_vec());
+
+        assert!(tree.delete(b"key1"));
+        assert!(tree.find(b"key1").is_none());
+        assert!(tree.find(b"key2").is_some());
+    }
+
+    #[test]
+    fn test_delete_batch() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+
+        let keys = vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()];
+        let values = vec![b"value1".to_vec(), b"value2".to_vec(), b"value3".to_vec()];
+
+        // Insert keys and values
+        tree.insert_batch(&keys, &values);
+
+        // Verify insertion
+        assert!(tree.find(b"key1").is_some());
+        assert!(tree.find(b"key2").is_some());
+        assert!(tree.find(b"key3").is_some());
+
+        // Delete keys in batch
+        tree.delete_batch(&keys);
+
+        // Verify deletion
+        assert!(tree.find(b"key1").is_none());
+        assert!(tree.find(b"key2").is_none());
+        assert!(tree.find(b"key3").is_none());
+    }
+
+    #[test]
+    fn test_traverse() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+
+        let key1 = b"key1".to_vec();
+        let key2 = b"key2".to_vec();
+
+        tree.insert(key1.clone(), b"value1".to_vec());
+        tree.insert(key2.clone(), b"value2".to_vec());
+
+        let traversal = tree.traverse();
+
+        // Convert byte arrays to their binary representation strings for comparison
+        let expected_key1 = format!("{:?}", key1);
+        let expected_key2 = format!("{:?}", key2);
+
+        // Check if the traversal contains the expected keys
+        assert!(traversal.contains(&expected_key1.to_string()));
+        assert!(traversal.contains(&expected_key2.to_string()));
+    }
+
+    #[test]
+    fn test_stats() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            base: 131,
+            modulus: 1_000_000_009,
+            min_chunk_size: 16,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            root_hash: None,
+            key_schema: None,
+            value_schema: None,
+            encode_types: vec![],
+            value_chunk_threshold: None,
+            value_policy: ValuePolicy::Inline,
+            compression: Compression::None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            chunking: ChunkingStrategy::RollingHash,
+            expiring_keys: false,
+            key_format: None,
+            value_format: None,
+        };
+
+        let mut tree = ProllyTree::new(storage, config);
+
+        // Insert key-value pairs using a loop
+        let max_key = 3000u32;
+
+        for i in 0..max_key {
+            // Convert to big-endian byte array to maintain order
+            let key = i.to_be_bytes().to_vec();
+            let value = i.to_be_bytes().to_vec();
+            tree.insert(key.clone(), value.clone());
+        }
+
+        println!("{:?}", tree.root.print_tree(&tree.storage));
+
+        for i in 0..max_key {
+            let key = i.to_be_bytes().to_vec();
+            assert!(tree.find(&key).is_some());
+        }
+        let non_existing_key = (max_key + 10).to_be_bytes().to_vec();
+        assert!(tree.find(&non_existing_key).is_none());
+
+        // assert that the tree has the expected key-value pairs
+        assert_eq!(tree.size(), max_key as usize);
+
+        // assert that the tree has the expected depth
+        assert_eq!(tree.depth(), 3);
+
+        println!("Size: {}", tree.size());
+        println!("Depth: {}", tree.depth());
+        println!("Summary: {}", tree.summary());
+    }
+
+    #[test]
+    fn test_generate_proof() {
+        let config = TreeConfig::default();
+        let storage = InMemoryNodeStorage::<32>::default();
+        let mut tree = ProllyTree::new(storage, config);
+
+        // Insert key-value pairs
+        for i in 0..100 {
+            let key = vec![i];
+            let value = vec![i];
+            tree.insert(key.clone(), value.clone());
+        }
+
+        // Generate proof for an existing key
+        let key_to_prove = vec![5];
+        let proof = tree.generate_proof(&key_to_prove);
+        assert_eq!(proof.root_hash(), tree.get_root_hash().as_ref());
+        assert_eq!(proof.depth(), tree.depth());
+        assert!(proof.is_inclusion());
+        for segment in &proof.segments()[1..] {
+            assert!(segment.position < segment.fanout());
+        }
+
+        // Verify the proof
+        let verified = tree.verify(proof, &key_to_prove, Some(&key_to_prove));
+        assert!(verified);
+
+        // Generate proof for a non-existing key
+        let key_to_prove_wrong = vec![120];
+        let proof_wrong = tree.generate_proof(&key_to_prove_wrong);
+
+        // Should not be verified
+        let verified_wrong = tree.verify(
+            proof_wrong.clone(),
+            &key_to_prove_wrong,
+            Some(&key_to_prove_wrong),
+        );
+        assert!(!verified_wrong);
+
+        // but proves that the key does not exist
+        assert!(tree.verify(proof_wrong, &key_to_prove_wrong, None));
+        assert!(!tree.verify(tree.generate_proof(&key_to_prove), &key_to_prove, None));
+    }
+
+    #[test]
+    fn test_verify_value_of_key() {
+        let config = TreeConfig {
+            expiring_keys: true,
+            ..TreeConfig::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        tree.insert(b"a".to_vec(), b"1".to_vec());
+        tree.insert(b"b".to_vec(), b"2".to_vec());
+        assert_eq!(tree.depth(), 1);
+
+        // the value of another key in the same leaf does not verify
+        let proof = tree.generate_proof(b"a");
+        assert!(tree.verify(proof.clone(), b"a", Some(b"1")));
+        assert!(!tree.verify(proof, b"a", Some(b"2")));
+    }
+
+    #[test]
+    fn test_proof_segments() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..1000u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        let key = 500u32.to_be_bytes();
+        let proof = tree.generate_proof(&key);
+        assert!(proof.depth() > 2);
+        assert_eq!(proof.node_hashes().count(), proof.depth());
+        assert_eq!(proof.leaf_hash(), proof.target_hash.as_ref());
+        assert!(tree
+            .storage
+            .get_node_by_hash(proof.leaf_hash().unwrap())
+            .unwrap()
+            .keys
+            .contains(&key.to_vec()));
+
+        // every segment is its node and the other children of the parent before it
+        let segments = proof.segments();
+        assert_eq!(segments[0], PathSegment::root(tree.root.get_hash()));
+        for pair in segments.windows(2) {
+            let parent = tree.storage.get_node_by_hash(&pair[0].node_hash).unwrap();
+            let child = &pair[1];
+            assert_eq!(child.fanout(), parent.values.len());
+            let mut children = child.sibling_hashes.clone();
+            children.insert(child.position, child.node_hash.clone());
+            let expected: Vec<ValueDigest<32>> = parent
+                .values
+                .iter()
+                .map(|hash| ValueDigest::raw_hash(hash))
+                .collect();
+            assert_eq!(children, expected);
+        }
+
+        // a segment that claims another position or other siblings no longer verifies
+        let value = 500u32.to_le_bytes();
+        let mut moved = proof.clone();
+        let last = moved.path.last_mut().unwrap();
+        last.position = (last.position + 1) % last.fanout();
+        assert!(!tree.verify(moved, &key, Some(&value)));
+        let mut swapped = proof.clone();
+        let last = swapped.path.last_mut().unwrap();
+        last.sibling_hashes.reverse();
+        last.sibling_hashes.push(ValueDigest::new(b"other"));
+        assert!(!tree.verify(swapped, &key, Some(&value)));
+        assert!(tree.verify(proof, &key, Some(&value)));
+    }
+
+    #[test]
+    fn test_version_1_proofs() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..200u32 {
+            tree.insert((i * 2).to_be_bytes().to_vec(), vec![1]);
+        }
+        assert!(tree.depth() > 1);
+
+        // writes a proof the way version 1 did, with hash-only paths
+        fn encode_v1(proof: &Proof<32>) -> Vec<u8> {
+            let path = |bytes: &mut Vec<u8>, path: &[PathSegment<32>]| {
+                bytes.extend((path.len() as u32).to_be_bytes());
+                for segment in path {
+                    bytes.extend(segment.node_hash.as_bytes());
+                }
+            };
+            let flags = proof.target_hash.is_some() as u8
+                | (proof.lower.is_some() as u8) << 1
+                | (proof.upper.is_some() as u8) << 2;
+            let mut bytes = b"PRPF".to_vec();
+            bytes.extend([1, 32, flags]);
+            path(&mut bytes, &proof.path);
+            if let Some(target_hash) = &proof.target_hash {
+                bytes.extend(target_hash.as_bytes());
+            }
+            for neighbor in [&proof.lower, &proof.upper].into_iter().flatten() {
+                bytes.extend((neighbor.key.len() as u32).to_be_bytes());
+                bytes.extend(&neighbor.key);
+                path(&mut bytes, &neighbor.path);
+            }
+            bytes
+        }
+
+        let present = 100u32.to_be_bytes().to_vec();
+        let absent = 101u32.to_be_bytes().to_vec();
+        let old = Proof::<32>::from_bytes(&encode_v1(&tree.generate_proof(&present))).unwrap();
+        assert!(old.segments().iter().all(|s| s.sibling_hashes.is_empty()));
+        assert!(tree.verify(old.clone(), &present, Some(&[1])));
+        assert!(!tree.verify(old, &present, Some(&[2])));
+
+        let old = Proof::<32>::from_bytes(&encode_v1(&tree.generate_proof(&absent))).unwrap();
+        assert!(tree.verify(old.clone(), &absent, None));
+
+        // the hashes are still checked
+        let mut tampered = old;
+        tampered.path[1].node_hash = tampered.path[0].node_hash.clone();
+        assert!(!tree.verify(tampered, &absent, None));
+    }
+
+    #[test]
+    fn test_exclusion_proofs() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        // an empty tree proves the absence of every key
+        assert!(tree.verify(tree.generate_proof(&[1]), &[1], None));
+
+        for i in 0..200u32 {
+            tree.insert((i * 2 + 10).to_be_bytes().to_vec(), vec![1]);
+        }
+        assert!(tree.depth() > 1);
+
+        let absent = [0u32, 11, 57, 211, 409, 1000];
+        for i in absent {
+            let key = i.to_be_bytes().to_vec();
+            let proof = tree.generate_proof(&key);
+            assert!(proof.target_hash.is_none());
+            assert_eq!(
+                proof.lower.as_ref().map(|lower| lower.key.clone()),
+                tree.get_floor(&key).map(|(key, _)| key)
+            );
+            assert_eq!(
+                proof.upper.as_ref().map(|upper| upper.key.clone()),
+                tree.get_ceiling(&key).map(|(key, _)| key)
+            );
+            assert!(tree.verify(proof, &key, None));
+        }
+
+        let key = 57u32.to_be_bytes().to_vec();
+        let proof = tree.generate_proof(&key);
+
+        // neighbors that are not adjacent to the key are refused
+        let mut forged = proof.clone();
+        forged.lower = Some(Neighbor {
+            key: vec![0, 0, 0, 10],
+            path: tree.generate_proof(&[0, 0, 0, 10]).path,
+        });
+        assert!(!tree.verify(forged, &key, None));
+        let mut forged = proof.clone();
+        forged.upper = None;
+        assert!(!tree.verify(forged, &key, None));
+        let mut forged = proof.clone();
+        std::mem::swap(&mut forged.lower, &mut forged.upper);
+        assert!(!tree.verify(forged, &key, None));
+
+        // a proof of absence no longer holds once the key is inserted
+        tree.insert(key.clone(), vec![1]);
+        assert!(!tree.verify(proof, &key, None));
+    }
+
+    #[test]
+    fn test_hash_algorithms() {
+        let mut root_hashes = Vec::new();
+        for hash_algorithm in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Sha3_256,
+        ] {
+            let config = TreeConfig {
+                hash_algorithm,
+                ..Default::default()
+            };
+            let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+            for i in 0..100 {
+                tree.insert(vec![i], vec![i]);
+            }
+
+            assert_eq!(tree.root.hash_algorithm, hash_algorithm);
+            let proof = tree.generate_proof(&[5]);
+            assert!(tree.verify(proof, &[5], Some(&[5])));
+            root_hashes.push(tree.get_root_hash().unwrap());
+        }
+
+        // the same content hashes differently under each algorithm
+        assert_ne!(root_hashes[0], root_hashes[1]);
+        assert_ne!(root_hashes[0], root_hashes[2]);
+        assert_ne!(root_hashes[1], root_hashes[2]);
+    }
+
+    #[cfg(feature = "poseidon")]
+    #[test]
+    fn test_poseidon_proofs() {
+        let config = TreeConfig {
+            hash_algorithm: HashAlgorithm::Poseidon,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..200u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![1]);
+        }
+        let root_hash = tree.get_root_hash().unwrap();
+        let key = 57u32.to_be_bytes().to_vec();
+
+        assert!(tree.verify(tree.generate_proof(&key), &key, Some(&[1])));
+        let proof = tree.generate_multiproof(std::slice::from_ref(&key));
+        assert!(proof.verify(&root_hash, &[(key, Some(vec![1]))]));
+    }
+
+    #[test]
+    fn test_chunking_strategies() {
+        for chunking in [
+            ChunkingStrategy::RollingHash,
+            ChunkingStrategy::FastCdc,
+            ChunkingStrategy::FixedSize(8),
+        ] {
+            let config = TreeConfig {
+                min_chunk_size: 4,
+                max_chunk_size: 64,
+                pattern: 0b111,
+                chunking,
+                ..Default::default()
+            };
+            let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+            for i in (0..500u32).rev() {
+                tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+            }
+            for i in (0..500u32).step_by(5) {
+                tree.delete(&i.to_be_bytes());
+            }
+
+            assert_eq!(tree.len(), 400);
+            assert!(tree.stats().depth > 1);
+            for i in 0..500u32 {
+                let found = tree.find(&i.to_be_bytes()).is_some();
+                assert_eq!(found, i % 5 != 0, "{:?} key {}", chunking, i);
+            }
+
+            // bulk loading produces the same content
+            let bulk = ProllyTree::from_sorted_iter(
+                InMemoryNodeStorage::<32>::default(),
+                tree.config.clone(),
+                tree.iter(),
+            )
+            .unwrap();
+            assert!(bulk.iter().eq(tree.iter()));
+        }
+
+        // fixed size chunking cuts leaves every 8 entries when loading sorted data
+        let config = TreeConfig {
+            chunking: ChunkingStrategy::FixedSize(8),
+            ..Default::default()
+        };
+        let entries = (0..64u32).map(|i| (i.to_be_bytes().to_vec(), vec![0]));
+        let tree =
+            ProllyTree::from_sorted_iter(InMemoryNodeStorage::<32>::default(), config, entries)
+                .unwrap();
+        assert_eq!(tree.stats().num_leaves, 8);
+    }
+
+    #[test]
+    fn test_diff() {
+        let config = TreeConfig::default();
+        let storage1 = InMemoryNodeStorage::<32>::default();
+        let mut tree1 = ProllyTree::new(storage1, config.clone());
+
+        let storage2 = InMemoryNodeStorage::<32>::default();
+        let mut tree2 = ProllyTree::new(storage2, config);
+
+        // Insert key-value pairs into tree1
+        for i in 0..50 {
+            tree1.insert(vec![i], vec![i]);
+        }
+
+        // Insert key-value pairs into tree1
+        for i in 0..50 {
+            tree2.insert(vec![i], vec![i]);
+        }
+
+        // modify some keys in tree2
+        tree2.insert(vec![10], vec![200]);
+
+        // print tree1 and tree2
+        println!("{:?}", tree1.root.print_tree(&tree1.storage));
+        println!("{:?}", tree2.root.print_tree(&tree2.storage));
+
+        // Generate diff between tree1 and tree2
+        let differences = tree1.diff(&tree2);
+
+        // Check the differences
+        // Expecting only the first L1 value would change
+        for diff in &differences {
+            match diff {
+                DiffResult::Added(key, value) => {
+                    println!("Added: key = {:?}, value = {:?}", key, value);
+                }
+                DiffResult::Removed(key, value) => {
+                    println!("Removed: key = {:?}, value = {:?}", key, value);
+                }
+                DiffResult::Modified(key, old_value, new_value) => {
+                    println!(
+                        "Modified: key = {:?}, old_value = {:?}, new_value = {:?}",
+                        key, old_value, new_value
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_pull() {
+        let config = TreeConfig {
+            value_chunk_threshold: Some(64),
+            expiring_keys: true,
+            ..Default::default()
+        };
+        let mut local = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..1000 {
+            local.insert(key(i), vec![0]);
+        }
+        let base = local.get_root_hash().unwrap();
+        let mut remote = local.fork();
+        remote.insert(key(10), vec![1]);
+        remote.delete(&key(500));
+        remote.insert(key(2000), vec![7; 1_000]);
+        let remote_root = remote.get_root_hash().unwrap();
+
+        let mut transport = StorageTransport::new(remote.storage(), remote_root.clone());
+        let report = local.pull(&mut transport).unwrap();
+        assert_eq!(report.root_hash, remote_root);
+        assert!(report.nodes_fetched > 0);
+        assert!(report.nodes_skipped > 0);
+        assert_eq!(report.divergent_ranges.len(), 3);
+        assert!(report.divergent_ranges[0].0 <= key(10));
+        assert_eq!(report.divergent_ranges[2].1, None);
+        assert_eq!(
+            diff_roots(local.storage(), &base, &remote_root).unwrap(),
+            diff_roots(remote.storage(), &base, &remote_root).unwrap()
+        );
+
+        // everything is present now
+        let again = local.pull(&mut transport).unwrap();
+        assert_eq!((again.nodes_fetched, again.round_trips), (0, 0));
+
+        local.merge3(&base, &remote_root).unwrap();
+        assert!(local.iter().eq(remote.iter()));
+
+        // a remote that lacks nodes fails without writing anything
+        let mut other = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let empty = InMemoryNodeStorage::<32>::default();
+        let mut broken = StorageTransport::new(&empty, remote_root);
+        assert!(matches!(
+            other.pull(&mut broken),
+            Err(Error::MissingNode(_))
+        ));
+    }
+
+    #[test]
+    fn test_access_heatmap() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+
+        // tracking is opt-in
+        tree.insert(b"usr/alice".to_vec(), b"1".to_vec());
+        assert!(tree.access_heatmap(3).is_none());
+
+        tree.enable_access_stats(8);
+        tree.insert(b"usr/alice".to_vec(), b"2".to_vec());
+        tree.insert(b"usr/bob".to_vec(), b"1".to_vec());
+        tree.insert(b"log/0001".to_vec(), b"1".to_vec());
+        assert!(tree.find(b"usr/alice").is_some());
+        assert!(tree.find(b"usr/carol").is_none());
+        tree.delete(b"log/0001");
+
+        let heatmap = tree.access_heatmap(3).unwrap();
+        let usr = heatmap
+            .entries
+            .iter()
+            .find(|entry| entry.prefix == hex::encode(b"usr"))
+            .unwrap();
+        assert_eq!((usr.reads, usr.writes), (2, 2));
+        let log = heatmap
+            .entries
+            .iter()
+            .find(|entry| entry.prefix == hex::encode(b"log"))
+            .unwrap();
+        assert_eq!((log.reads, log.writes), (0, 2));
+
+        tree.disable_access_stats();
+        assert!(tree.access_heatmap(3).is_none());
+    }
+
+    #[test]
+    fn test_insert_batch_matches_sequential_inserts() {
+        use rand::prelude::StdRng;
+        use rand::seq::SliceRandom;
+        use rand::SeedableRng;
+
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            pattern: 0b111,
+            ..TreeConfig::default()
+        };
+        let mut batched = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config.clone());
+        let mut sequential = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+
+        let mut keys: Vec<Vec<u8>> = (0..2000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        keys.shuffle(&mut StdRng::from_seed([7u8; 32]));
+
+        for chunk in keys.chunks(500) {
+            let values: Vec<Vec<u8>> = chunk.iter().map(|k| k.repeat(2)).collect();
+            batched.insert_batch(chunk, &values);
+            for (key, value) in chunk.iter().zip(values) {
+                sequential.insert(key.clone(), value);
+            }
+        }
+
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = sequential.iter().collect();
+        assert_eq!(expected.len(), 2000);
+        assert_eq!(batched.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(batched.size(), 2000);
+        for key in &keys {
+            assert!(batched.find(key).is_some());
+        }
+
+        // overwrite existing keys; the last value of a duplicated key wins
+        let keys = vec![vec![0, 0, 0, 1], vec![0, 0, 0, 1], vec![0, 0, 0, 2]];
+        let values = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        batched.insert_batch(&keys, &values);
+        assert_eq!(batched.size(), 2000);
+        let updated: Vec<Vec<u8>> = batched
+            .scan(vec![0, 0, 0, 1]..=vec![0, 0, 0, 2])
+            .map(|(_, v)| v)
+            .collect();
+        assert_eq!(updated, vec![b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn test_from_sorted_iter() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            pattern: 0b111,
+            ..TreeConfig::default()
+        };
+        let pairs = (0..5000u32).map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec()));
+        let mut tree = ProllyTree::from_sorted_iter(
+            InMemoryNodeStorage::<32>::default(),
+            config.clone(),
+            pairs,
+        )
+        .unwrap();
+
+        assert!(tree.depth() > 2);
+        assert_eq!(tree.size(), 5000);
+        let keys: Vec<Vec<u8>> = tree.iter().map(|(k, _)| k).collect();
+        assert_eq!(
+            keys,
+            (0..5000u32)
+                .map(|i| i.to_be_bytes().to_vec())
+                .collect::<Vec<_>>()
+        );
+        assert!(tree.find(&1234u32.to_be_bytes()).is_some());
+
+        // the loaded tree supports regular mutations
+        tree.insert(5000u32.to_be_bytes().to_vec(), vec![0]);
+        assert!(tree.delete(&10u32.to_be_bytes()));
+        assert_eq!(tree.size(), 5000);
+        assert!(tree.find(&10u32.to_be_bytes()).is_none());
+        assert!(tree.find(&5000u32.to_be_bytes()).is_some());
+
+        // empty input yields an empty tree
+        let empty = ProllyTree::from_sorted_iter(
+            InMemoryNodeStorage::<32>::default(),
+            config.clone(),
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(empty.size(), 0);
+
+        // unsorted input is rejected
+        let unsorted = vec![(vec![2], vec![2]), (vec![1], vec![1])];
+        assert!(matches!(
+            ProllyTree::from_sorted_iter(InMemoryNodeStorage::<32>::default(), config, unsorted),
+            Err(Error::UnsortedKeys)
+        ));
+    }
+
+    #[test]
+    fn test_floor_and_ceiling() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            pattern: 0b111,
+            ..TreeConfig::default()
+        };
+        let mut tree = ProllyTree::new(storage, config);
+
+        // time-indexed keys every 10 ticks
+        for t in (100..1000u64).step_by(10) {
+            tree.insert(t.to_be_bytes().to_vec(), t.to_string().into_bytes());
+        }
+
+        let key = |t: u64| t.to_be_bytes().to_vec();
+
+        // exact matches
+        assert_eq!(tree.get_floor(&key(500)).unwrap().0, key(500));
+        assert_eq!(tree.get_ceiling(&key(500)).unwrap().0, key(500));
+
+        // closest entries around a missing key
+        let (floor_key, floor_value) = tree.get_floor(&key(505)).unwrap();
+        assert_eq!(floor_key, key(500));
+        assert_eq!(floor_value, b"500".to_vec());
+        assert_eq!(tree.get_ceiling(&key(505)).unwrap().0, key(510));
+
+        // out of range
+        assert!(tree.get_floor(&key(99)).is_none());
+        assert!(tree.get_ceiling(&key(991)).is_none());
+        assert_eq!(tree.get_floor(&key(5000)).unwrap().0, key(990));
+        assert_eq!(tree.get_ceiling(&key(0)).unwrap().0, key(100));
+    }
+
+    #[test]
+    fn test_stats_sizes_and_fanout() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
         let mut tree = ProllyTree::new(storage, config);
 
-        // 4. Insert New Key-Value Pairs
-        tree.insert(b"key1".to_vec(), b"value1".to_vec());
-        tree.insert(b"key2".to_vec(), b"value2".to_vec());
+        let stats = tree.stats();
+        assert_eq!(stats.num_nodes, 1);
+        assert_eq!(stats.depth, 1);
+        assert_eq!(stats.avg_fanout, 0.0);
 
-        // 5. Traverse the Tree with a Custom Formatter
-        let traversal = tree.formatted_traverse(|node| {
-            let keys_as_strings: Vec<String> =
-                node.keys.iter().map(|k| format!("{:?}", k)).collect();
-            format!("[L{}: {}]", node.level, keys_as_strings.join(", "))
-        });
-        println!("Traversal: {}", traversal);
+        for i in 0..200u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![0; 12]);
+        }
 
-        // 6. Update the Value for an Existing Key
-        tree.update(b"key1".to_vec(), b"new_value1".to_vec());
+        let stats = tree.stats();
+        assert_eq!(stats.depth, tree.depth());
+        assert!(stats.depth > 1);
+        assert_eq!(stats.total_key_value_pairs, 200);
+        assert_eq!(stats.total_key_bytes, 200 * 4);
+        assert_eq!(stats.total_value_bytes, 200 * 12);
+        assert_eq!(
+            stats.avg_chunk_size,
+            stats.avg_node_size * 16.0,
+            "every pair is 16 bytes"
+        );
+        assert_eq!(
+            stats.avg_fanout * stats.num_internal_nodes as f64,
+            (stats.num_nodes - 1) as f64
+        );
+    }
 
-        // 7. Find or Search for a Key
-        if let Some(node) = tree.find(b"key1") {
-            println!("Found key1 with value: {:?}", node);
-        } else {
-            println!("key1 not found");
-        }
+    #[test]
+    fn test_delete_range() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(storage, config);
 
-        // 8. Delete a key-value pair
-        if tree.delete(b"key2") {
-            println!("key2 deleted");
-        } else {
-            println!("key2 not found");
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..500u32 {
+            tree.insert(key(i), i.to_string().into_bytes());
         }
 
-        // 9. Print tree stats
-        println!("Size: {}", tree.size());
-        println!("Depth: {}", tree.depth());
-        println!("Summary: {}", tree.summary());
+        let mut expected: Vec<u32> = (0..500).collect();
+        let mut check = |tree: &ProllyTree<32, InMemoryNodeStorage<32>>,
+                         deleted: usize,
+                         removed: std::ops::Range<u32>| {
+            let before = expected.len();
+            expected.retain(|i| !removed.contains(i));
+            assert_eq!(deleted, before - expected.len());
+
+            let keys: Vec<Vec<u8>> = tree.iter().map(|(k, _)| k).collect();
+            assert_eq!(keys, expected.iter().map(|i| key(*i)).collect::<Vec<_>>());
+            assert_eq!(tree.len(), expected.len());
+            for i in &expected {
+                assert!(tree.find(&key(*i)).is_some());
+            }
+        };
 
-        // 10. Print Tree
-        println!("{:?}", tree.root.print_tree(&tree.storage));
+        let deleted = tree.delete_range(key(100)..key(250));
+        check(&tree, deleted, 100..250);
+
+        let deleted = tree.delete_range(key(240)..=key(260));
+        check(&tree, deleted, 250..261);
+
+        let deleted = tree.delete_range(..key(10));
+        check(&tree, deleted, 0..10);
+
+        let deleted = tree.delete_range(key(1000)..);
+        check(&tree, deleted, 0..0);
+
+        let deleted = tree.delete_range(key(490)..);
+        check(&tree, deleted, 490..500);
+
+        // inserts keep working on the pruned tree
+        tree.insert(key(150), b"150".to_vec());
+        assert!(tree.find(&key(150)).is_some());
+
+        let deleted = tree.delete_range(..);
+        assert_eq!(deleted, expected.len() + 1);
+        assert!(tree.is_empty());
     }
 
     #[test]
-    fn file_node_storage_test() {
-        use crate::storage::FileNodeStorage;
-        use std::fs;
-        use std::path::PathBuf;
+    fn test_try_delete_range_missing_node() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..500u32 {
+            tree.insert(key(i), i.to_string().into_bytes());
+        }
+        let root = tree.get_root_hash();
+        let first = ValueDigest::<32>::raw_hash(&tree.root.values[0]);
+        tree.storage.delete_node(&first);
+
+        // nothing is deleted when a node in the range cannot be read
+        assert!(matches!(
+            tree.try_delete_range(..key(250)),
+            Err(Error::MissingNode(ref hash)) if *hash == format!("{:x}", first)
+        ));
+        assert_eq!(tree.get_root_hash(), root);
+        let deleted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tree.delete_range(..key(250))
+        }));
+        assert!(deleted.is_err());
+        assert!(matches!(
+            tree.split_at(&key(250)),
+            Err(Error::MissingNode(_))
+        ));
+    }
 
-        // 1. Create a custom tree config
+    #[test]
+    fn test_pop_first_and_last() {
+        let storage = InMemoryNodeStorage::<32>::default();
         let config = TreeConfig {
-            base: 131,
-            modulus: 1_000_000_009,
             min_chunk_size: 4,
             max_chunk_size: 8 * 1024,
-            pattern: 0b101,
-            root_hash: None,
-            key_schema: None,
-            value_schema: None,
-            encode_types: vec![],
+            pattern: 0b111,
+            ..Default::default()
         };
+        let mut tree = ProllyTree::new(storage, config);
+        assert!(tree.pop_first().is_none());
+        assert!(tree.pop_last().is_none());
 
-        // 2. Create and Wrap the Storage Backend
-        let storage_dir = PathBuf::from("/tmp/prolly_tree_storage");
-        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        // tasks keyed by priority, inserted out of order
+        for i in (0..100u32).rev() {
+            tree.insert(i.to_be_bytes().to_vec(), format!("task-{i}").into_bytes());
+        }
 
-        // 3. Create the Prolly Tree
+        for i in 0..40u32 {
+            let (key, value) = tree.pop_first().unwrap();
+            assert_eq!(key, i.to_be_bytes().to_vec());
+            assert_eq!(value, format!("task-{i}").into_bytes());
+        }
+        for i in (60..100u32).rev() {
+            let (key, _) = tree.pop_last().unwrap();
+            assert_eq!(key, i.to_be_bytes().to_vec());
+        }
+        assert_eq!(tree.len(), 20);
+
+        while tree.pop_first().is_some() {}
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_export() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
         let mut tree = ProllyTree::new(storage, config);
+        for i in 0..200u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_string().into_bytes());
+        }
 
-        // 4. Insert New Key-Value Pairs
-        tree.insert(b"key1".to_vec(), b"value1".to_vec());
-        tree.insert(b"key2".to_vec(), b"value2".to_vec());
+        let mut data = Vec::new();
+        tree.export(&mut data).unwrap();
+        assert_eq!(&data[..4], b"PRLY");
+        assert_eq!(data[5], 32);
+
+        // walk the frames: config first, root hash last, one frame per node in between
+        let mut pos = 6;
+        let mut tags = Vec::new();
+        let mut last_payload = &data[..0];
+        while pos < data.len() {
+            let len = u32::from_be_bytes(data[pos + 1..pos + 5].try_into().unwrap()) as usize;
+            tags.push(data[pos]);
+            last_payload = &data[pos + 5..pos + 5 + len];
+            pos += 5 + len;
+        }
+        assert_eq!(pos, data.len());
+        assert_eq!(tags.first(), Some(&1));
+        assert_eq!(tags.last(), Some(&0));
+        assert_eq!(tags.len() - 2, tree.stats().num_nodes);
+        assert_eq!(last_payload, tree.root.get_hash().as_bytes());
+
+        // the export is deterministic
+        let mut again = Vec::new();
+        tree.export(&mut again).unwrap();
+        assert_eq!(data, again);
+    }
 
-        // 5. Traverse the Tree with a Custom Formatter
-        let traversal = tree.formatted_traverse(|node| {
-            let keys_as_strings: Vec<String> =
-                node.keys.iter().map(|k| format!("{:?}", k)).collect();
-            format!("[L{}: {}]", node.level, keys_as_strings.join(", "))
-        });
-        println!("Traversal: {}", traversal);
+    #[test]
+    fn test_import() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(storage, config);
+        for i in 0..200u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_string().into_bytes());
+        }
+        let mut data = Vec::new();
+        tree.export(&mut data).unwrap();
+
+        let imported =
+            ProllyTree::import(data.as_slice(), InMemoryNodeStorage::<32>::default()).unwrap();
+        assert_eq!(imported.root.get_hash(), tree.root.get_hash());
+        assert_eq!(imported.config.min_chunk_size, 4);
+        assert_eq!(
+            imported.iter().collect::<Vec<_>>(),
+            tree.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(imported.config.root_hash, Some(tree.root.get_hash()));
+
+        // a tampered value inside a node frame is detected
+        let mut corrupted = data.clone();
+        let pos = corrupted.windows(3).position(|w| w == b"150").unwrap();
+        corrupted[pos] = b'9';
+        assert!(matches!(
+            ProllyTree::import(corrupted.as_slice(), InMemoryNodeStorage::<32>::default()),
+            Err(Error::InvalidExport(_))
+        ));
+
+        // so is a truncated stream
+        let truncated = &data[..data.len() - 10];
+        assert!(matches!(
+            ProllyTree::import(truncated, InMemoryNodeStorage::<32>::default()),
+            Err(Error::Io(_) | Error::InvalidExport(_))
+        ));
+
+        // and input that is not an export at all
+        assert!(matches!(
+            ProllyTree::import(&b"not an export"[..], InMemoryNodeStorage::<32>::default()),
+            Err(Error::InvalidExport(_))
+        ));
+    }
 
-        // 6. Update the Value for an Existing Key
-        tree.update(b"key1".to_vec(), b"new_value1".to_vec());
+    #[test]
+    fn test_export_range() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config.clone());
+        for namespace in ["agent-a/", "agent-b/", "agent-c/"] {
+            for i in 0..100u32 {
+                let key = [namespace.as_bytes(), &i.to_be_bytes()].concat();
+                tree.insert(key, i.to_string().into_bytes());
+            }
+        }
+        let root_hash = tree.root.get_hash();
+
+        let mut data = Vec::new();
+        tree.export_range(prefix_range(b"agent-b/"), &mut data)
+            .unwrap();
+        assert_eq!(tree.root.get_hash(), root_hash);
+
+        let imported =
+            ProllyTree::import(data.as_slice(), InMemoryNodeStorage::<32>::default()).unwrap();
+        assert!(imported.iter().eq(tree.scan_prefix(b"agent-b/")));
+        assert_eq!(imported.len(), 100);
+
+        // nodes within the range are shared with the original tree
+        let middle = [b"agent-b/".as_slice(), &50u32.to_be_bytes()].concat();
+        let leaf_hash = tree.find(&middle).unwrap().get_hash();
+        assert!(imported.storage.get_node_by_hash(&leaf_hash).is_some());
+
+        // merging the range into another tree replicates just that namespace
+        let mut replica = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        replica.insert(b"agent-b/stale".to_vec(), b"kept".to_vec());
+        replica.insert(b"agent-c/other".to_vec(), b"kept".to_vec());
+        replica.merge_import(data.as_slice()).unwrap();
+        assert_eq!(replica.len(), 102);
+        assert_eq!(replica.scan_prefix(b"agent-b/").count(), 101);
+        assert_eq!(replica.scan_prefix(b"agent-a/").count(), 0);
+
+        // bounded ranges work on both ends
+        let mut data = Vec::new();
+        let start = [b"agent-a/".as_slice(), &90u32.to_be_bytes()].concat();
+        let end = [b"agent-c/".as_slice(), &10u32.to_be_bytes()].concat();
+        tree.export_range(start.clone()..=end.clone(), &mut data)
+            .unwrap();
+        let imported =
+            ProllyTree::import(data.as_slice(), InMemoryNodeStorage::<32>::default()).unwrap();
+        assert!(imported.iter().eq(tree.scan(start..=end)));
+        assert_eq!(imported.len(), 121);
+    }
 
-        // 7. Find or Search for a Key
-        if let Some(node) = tree.find(b"key1") {
-            println!("Found key1 with value: {:?}", node);
-        } else {
-            println!("key1 not found");
+    #[test]
+    fn test_verify_integrity() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            value_chunk_threshold: Some(64),
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..300u32 {
+            let value = if i == 150 { vec![1; 1_000] } else { vec![0] };
+            tree.insert(i.to_be_bytes().to_vec(), value);
+        }
+        for i in (0..300u32).step_by(7) {
+            tree.delete(&i.to_be_bytes());
         }
 
-        // 8. Delete a key-value pair
-        if tree.delete(b"key2") {
-            println!("key2 deleted");
-        } else {
-            println!("key2 not found");
+        let report = tree.verify_integrity();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        assert_eq!(report.nodes_checked, tree.stats().num_nodes);
+        assert_eq!(report.keys_checked, tree.len() as u64);
+
+        // a node stored under the wrong hash
+        let leaf = tree.find(&10u32.to_be_bytes()).unwrap();
+        let leaf_hash = leaf.get_hash();
+        let mut tampered = leaf.clone();
+        tampered.values[0] = vec![9];
+        tree.storage.insert_node(leaf_hash.clone(), tampered);
+        let report = tree.verify_integrity();
+        assert_eq!(
+            report.violations,
+            vec![Violation::HashMismatch {
+                hash: leaf_hash.clone(),
+                actual: tree.find(&10u32.to_be_bytes()).unwrap().get_hash(),
+            }]
+        );
+
+        // a missing node
+        tree.storage.delete_node(&leaf_hash);
+        let report = tree.verify_integrity();
+        assert_eq!(
+            report.violations,
+            vec![Violation::MissingNode {
+                hash: leaf_hash.clone()
+            }]
+        );
+        assert!(report.keys_checked < tree.len() as u64);
+        tree.storage.insert_node(leaf_hash, leaf);
+
+        // a missing chunk of a large value
+        let large = tree.find(&150u32.to_be_bytes()).unwrap();
+        let pos = large
+            .keys
+            .iter()
+            .position(|key| key == &150u32.to_be_bytes())
+            .unwrap();
+        let chunk = chunk_hashes::<32>(&large.values[pos])[0].clone();
+        tree.storage.delete_node(&chunk);
+        let report = tree.verify_integrity();
+        assert_eq!(
+            report.violations,
+            vec![Violation::MissingChunk {
+                hash: large.get_hash(),
+                chunk,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_repair() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            value_chunk_threshold: Some(64),
+            expiring_keys: true,
+            ..Default::default()
+        };
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..300u32 {
+            let value = if i == 150 { vec![1; 1_000] } else { key(i) };
+            tree.insert(key(i), value);
+        }
+        tree.insert_with_expiry(key(500), b"gone".to_vec(), SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        // an intact tree is recovered as a whole
+        let (repaired, report) = tree.repair(InMemoryNodeStorage::<32>::default());
+        assert!(report.is_complete());
+        assert_eq!(report.keys_recovered, 301);
+        assert!(repaired.iter().eq(tree.iter()));
+        assert_eq!(repaired.len(), 301);
+
+        // lose a leaf and a chunk of the large value
+        let leaf = tree.find(&key(10)).unwrap();
+        tree.storage.delete_node(&leaf.get_hash());
+        let large = tree.find(&key(150)).unwrap();
+        let pos = large.keys.iter().position(|k| k == &key(150)).unwrap();
+        let chunk = chunk_hashes::<32>(ttl::unwrap(&large.values[pos]).1)[0].clone();
+        tree.storage.delete_node(&chunk);
+
+        let (mut repaired, report) = tree.repair(InMemoryNodeStorage::<32>::default());
+        assert_eq!(report.lost_ranges.len(), 1);
+        assert_eq!(report.lost_values, vec![key(150)]);
+        let lost = &report.lost_ranges[0];
+        assert_eq!(lost.hash, leaf.get_hash());
+        let in_lost_range = |k: &Vec<u8>| {
+            lost.start.as_ref().is_none_or(|start| k >= start)
+                && lost.end.as_ref().is_none_or(|end| k < end)
+        };
+        assert!(leaf.keys.iter().all(in_lost_range));
+
+        let expected: Vec<_> = (0..300u32)
+            .map(key)
+            .filter(|k| !in_lost_range(k) && k != &key(150))
+            .collect();
+        let recovered: Vec<_> = repaired.iter().map(|(k, _)| k).collect();
+        assert_eq!(recovered, expected);
+        assert_eq!(report.keys_recovered, expected.len() as u64 + 1);
+        assert!(repaired.verify_integrity().is_ok());
+
+        // expiry times survive the repair
+        assert_eq!(repaired.purge_expired(), 1);
+    }
+
+    #[test]
+    fn test_range_proofs() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..500u32 {
+            tree.insert((i * 2).to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        let root_hash = tree.get_root_hash().unwrap();
+        let key = |i: u32| i.to_be_bytes().to_vec();
+
+        let ranges = [
+            (Bound::Included(key(100)), Bound::Excluded(key(300))),
+            (Bound::Excluded(key(100)), Bound::Included(key(300))),
+            (Bound::Unbounded, Bound::Included(key(7))),
+            (Bound::Included(key(991)), Bound::Unbounded),
+            (Bound::Included(key(5000)), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Unbounded),
+        ];
+        for range in ranges {
+            let proof = tree.generate_range_proof(range.clone());
+            let entries: Vec<_> = tree.scan(range).collect();
+            assert!(proof.verify(&root_hash, &entries));
+            assert_eq!(proof.entries(&root_hash), Some(entries));
+        }
+
+        let proof = tree.generate_range_proof(key(100)..key(300));
+        let entries: Vec<_> = tree.scan(key(100)..key(300)).collect();
+        assert!(proof.nodes.len() < tree.size());
+
+        // a missing entry or a changed value is detected
+        assert!(!proof.verify(&root_hash, &entries[1..]));
+        let mut changed = entries.clone();
+        changed[3].1 = vec![0];
+        assert!(!proof.verify(&root_hash, &changed));
+
+        // so are proofs leaving out nodes or modified in transit
+        let mut truncated = proof.clone();
+        truncated.nodes.pop();
+        assert_eq!(truncated.entries(&root_hash), None);
+        let mut tampered = proof.clone();
+        let leaf = tampered.nodes.iter_mut().find(|node| node.is_leaf).unwrap();
+        leaf.keys.remove(0);
+        leaf.values.remove(0);
+        assert_eq!(tampered.entries(&root_hash), None);
+        let mut widened = proof.clone();
+        widened.end = Bound::Unbounded;
+        assert_eq!(widened.entries(&root_hash), None);
+
+        // the proof does not hold for another version of the tree
+        tree.insert(key(201), vec![1]);
+        assert!(!proof.verify(&tree.get_root_hash().unwrap(), &entries));
+    }
+
+    #[test]
+    fn test_prefix_proofs() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for tenant in ["tenant-a/", "tenant-b/", "tenant-c/"] {
+            for i in 0..300u32 {
+                tree.insert(
+                    [tenant.as_bytes(), &i.to_be_bytes()].concat(),
+                    i.to_be_bytes().to_vec(),
+                );
+            }
+        }
+        let root_hash = tree.get_root_hash().unwrap();
+
+        let proof = tree.generate_prefix_proof(b"tenant-b/");
+        let entries: Vec<_> = tree.scan_prefix(b"tenant-b/").collect();
+        assert!(proof.is_prefix(b"tenant-b/"));
+        assert!(!proof.is_prefix(b"tenant-"));
+        assert!(proof.verify(&root_hash, &entries));
+        assert!(proof.nodes.len() < tree.generate_range_proof(..).nodes.len());
+
+        // another tenant's entries do not verify against the namespace
+        let other: Vec<_> = tree.scan_prefix(b"tenant-c/").collect();
+        assert!(!proof.verify(&root_hash, &other));
+
+        let empty = tree.generate_prefix_proof(b"tenant-d/");
+        assert!(empty.verify(&root_hash, &[]));
+    }
+
+    #[test]
+    fn test_multiproofs() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..1000u32 {
+            tree.insert((i * 2).to_be_bytes().to_vec(), i.to_be_bytes().to_vec());
+        }
+        let root_hash = tree.get_root_hash().unwrap();
+
+        // every tenth key, some of them absent, in no particular order
+        let keys: Vec<Vec<u8>> = (0..200u32)
+            .rev()
+            .map(|i| (i * 10 + i % 3).to_be_bytes().to_vec())
+            .collect();
+        let proof = tree.generate_multiproof(&keys);
+        let mut entries: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                (
+                    key.clone(),
+                    tree.get_ceiling(key)
+                        .filter(|(k, _)| k == key)
+                        .map(|(_, v)| v),
+                )
+            })
+            .collect();
+        entries.sort();
+        assert!(entries.iter().any(|(_, value)| value.is_none()));
+        assert!(entries.iter().any(|(_, value)| value.is_some()));
+        assert!(proof.verify(&root_hash, &entries));
+
+        // shared nodes are included once
+        let separate: usize = keys
+            .iter()
+            .map(|key| tree.generate_proof(key).path.len())
+            .sum();
+        assert!(proof.nodes.len() * 2 < separate);
+
+        // a wrong value, a claimed absence or a tampered proof are detected
+        let mut wrong = entries.clone();
+        wrong[0].1 = Some(vec![9]);
+        assert!(!proof.verify(&root_hash, &wrong));
+        let mut wrong = entries.clone();
+        let present = wrong.iter().position(|(_, value)| value.is_some()).unwrap();
+        wrong[present].1 = None;
+        assert!(!proof.verify(&root_hash, &wrong));
+        let mut tampered = proof.clone();
+        tampered.nodes.remove(1);
+        assert_eq!(tampered.entries(&root_hash), None);
+        let mut tampered = proof.clone();
+        tampered.keys.reverse();
+        assert_eq!(tampered.entries(&root_hash), None);
+
+        // an empty tree proves every key absent
+        let empty = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        let proof = empty.generate_multiproof(&[vec![1], vec![2]]);
+        assert!(proof.verify(
+            &empty.get_root_hash().unwrap(),
+            &[(vec![1], None), (vec![2], None)]
+        ));
+    }
+
+    #[test]
+    fn test_count_proofs() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        assert!(tree
+            .generate_count_proof()
+            .verify(&tree.get_root_hash().unwrap()));
+
+        for i in 0..700u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![1]);
+        }
+        tree.delete(&5u32.to_be_bytes());
+        let root_hash = tree.get_root_hash().unwrap();
+        let proof = tree.generate_count_proof();
+        assert_eq!(proof.count, 699);
+        assert!(proof.verify(&root_hash));
+
+        // a different count or a root from another version is refused
+        let mut inflated = proof.clone();
+        inflated.count += 1;
+        assert!(!inflated.verify(&root_hash));
+        let mut forged = proof.clone();
+        forged.root.counts[0] += 1;
+        forged.count += 1;
+        assert!(!forged.verify(&root_hash));
+        tree.insert(5u32.to_be_bytes().to_vec(), vec![1]);
+        assert!(!proof.verify(&tree.get_root_hash().unwrap()));
+    }
+
+    #[test]
+    fn test_stream_range_proof() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..2000u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![i as u8]);
+        }
+        let root_hash = tree.get_root_hash().unwrap();
+        let range = 150u32.to_be_bytes().to_vec()..1700u32.to_be_bytes().to_vec();
+
+        let mut verifier = RangeVerifier::new(
+            root_hash.clone(),
+            Bound::Included(range.start.clone()),
+            Bound::Excluded(range.end.clone()),
+        );
+        let mut entries = Vec::new();
+        for node in tree.stream_range_proof(range.clone()) {
+            // segments can be written out and read back one at a time
+            let bytes = bincode::serialize(&node).unwrap();
+            let node: ProofNode = bincode::deserialize(&bytes).unwrap();
+            entries.extend(verifier.push(&node).unwrap());
+        }
+        assert!(verifier.finish());
+        assert_eq!(entries, tree.scan(range.clone()).collect::<Vec<_>>());
+
+        // a stream cut short, or with a node out of place, is refused
+        let nodes: Vec<_> = tree.stream_range_proof(range.clone()).collect();
+        let mut verifier = RangeVerifier::new(
+            root_hash.clone(),
+            Bound::Included(range.start.clone()),
+            Bound::Excluded(range.end.clone()),
+        );
+        for node in &nodes[..nodes.len() - 1] {
+            verifier.push(node).unwrap();
+        }
+        assert!(!verifier.finish());
+        let mut verifier = RangeVerifier::new(root_hash, Bound::Unbounded, Bound::Unbounded);
+        verifier.push(&nodes[0]).unwrap();
+        assert!(verifier.push(&nodes[2]).is_none());
+        assert!(verifier.push(&nodes[1]).is_none());
+    }
+
+    #[test]
+    fn test_generate_proof_at() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..300u32 {
+            tree.insert(i.to_be_bytes().to_vec(), b"old".to_vec());
+        }
+        let old_root = tree.get_root_hash().unwrap();
+        let key = 42u32.to_be_bytes().to_vec();
+
+        tree.insert(key.clone(), b"new".to_vec());
+        tree.delete(&7u32.to_be_bytes());
+
+        let proof = tree.generate_proof_at(&old_root, &key).unwrap();
+        assert!(proof.verify(&old_root, &[(key.clone(), Some(b"old".to_vec()))]));
+        let proof = tree
+            .generate_proof_at(&old_root, &7u32.to_be_bytes())
+            .unwrap();
+        assert!(proof.verify(
+            &old_root,
+            &[(7u32.to_be_bytes().to_vec(), Some(b"old".to_vec()))]
+        ));
+
+        // the current version is proven as well
+        let root = tree.get_root_hash().unwrap();
+        let proof = tree.generate_proof_at(&root, &key).unwrap();
+        assert!(verifier::verify_key(
+            &root,
+            &key,
+            Some(b"new"),
+            &proof.nodes
+        ));
+
+        // versions whose root is gone cannot be proven
+        let other = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        assert!(matches!(
+            other.generate_proof_at(&old_root, &key),
+            Err(Error::MissingNode(_))
+        ));
+    }
+
+    #[test]
+    fn test_proof_cache() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..100u8 {
+            tree.insert(vec![i], vec![i]);
+        }
+        let mut cache = ProofCache::new(NonZeroUsize::new(2).unwrap());
+
+        let proof = tree.generate_proof_cached(&[5], &mut cache);
+        assert_eq!(proof, tree.generate_proof(&[5]));
+        assert_eq!(tree.generate_proof_cached(&[5], &mut cache), proof);
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // a changed root is a different entry, so stale proofs are never returned
+        tree.insert(vec![5], vec![50]);
+        let updated = tree.generate_proof_cached(&[5], &mut cache);
+        assert_ne!(updated, proof);
+        assert!(tree.verify(updated, &[5], Some(&[50])));
+        assert_eq!(cache.misses(), 2);
+
+        // the least recently used proof is evicted
+        tree.generate_proof_cached(&[6], &mut cache);
+        assert_eq!(cache.len(), 2);
+        tree.generate_proof_cached(&[5], &mut cache);
+        assert_eq!(cache.hits(), 2);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_scan_with_proofs() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..500u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        assert!(tree.depth() > 2);
+
+        let range = 100u32.to_be_bytes().to_vec()..400u32.to_be_bytes().to_vec();
+        let mut count = 0;
+        for (key, value, proof) in tree.scan_with_proofs(range) {
+            let expected = tree.generate_proof(&key);
+            assert_eq!(proof.path, expected.path);
+            assert_eq!(proof.target_hash, expected.target_hash);
+            assert!(tree.verify(proof, &key, Some(&value)));
+            count += 1;
+        }
+        assert_eq!(count, 300);
+    }
+
+    #[test]
+    fn test_scan_page() {
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), TreeConfig::default());
+        for i in 0..50u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        let range = 5u32.to_be_bytes().to_vec()..45u32.to_be_bytes().to_vec();
+
+        let mut entries = Vec::new();
+        let mut token: Option<PageToken<32>> = None;
+        let mut pages = 0;
+        loop {
+            let page = tree.scan_page(range.clone(), 7, token.as_ref()).unwrap();
+            assert!(page.entries.len() <= 7);
+            entries.extend(page.entries);
+            pages += 1;
+            // tokens survive a round trip through bytes
+            token = match page.next {
+                Some(next) => Some(PageToken::from_bytes(&next.to_bytes()).unwrap()),
+                None => break,
+            };
+        }
+        assert_eq!(pages, 6);
+        assert!(entries.into_iter().eq(tree.scan(range.clone())));
+
+        // a token is refused once the tree changed
+        let token = tree
+            .scan_page(range.clone(), 7, None)
+            .unwrap()
+            .next
+            .unwrap();
+        tree.insert(b"new".to_vec(), b"value".to_vec());
+        assert!(matches!(
+            tree.scan_page(range, 7, Some(&token)),
+            Err(Error::RootChanged)
+        ));
+        assert!(matches!(
+            PageToken::<32>::from_bytes(&[1, 2, 3]),
+            Err(Error::InvalidPageToken)
+        ));
+    }
+
+    #[test]
+    fn test_approximate_size() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..2_000u32 {
+            tree.insert(key(i), vec![i as u8; 100]);
+        }
+
+        // the exact size of all nodes reachable from the root
+        fn node_bytes<S: NodeStorage<32>>(node: &ProllyNode<32>, storage: &S) -> u64 {
+            let children: u64 = node
+                .values
+                .iter()
+                .filter(|_| !node.is_leaf)
+                .filter_map(|hash| storage.get_node_by_hash(&ValueDigest::raw_hash(hash)))
+                .map(|child| node_bytes(&child, storage))
+                .sum();
+            node.encode().len() as u64 + children
+        }
+        let total = node_bytes(&tree.root, &tree.storage);
+
+        let estimate = tree.approximate_size(..);
+        assert!(
+            estimate.abs_diff(total) * 5 < total,
+            "{} vs {}",
+            estimate,
+            total
+        );
+
+        let half = tree.approximate_size(key(500)..key(1_500));
+        assert!(
+            half.abs_diff(total / 2) * 4 < total / 2,
+            "{} vs {}",
+            half,
+            total
+        );
+        assert!(tree.approximate_size(key(10)..=key(10)) > 100);
+        assert_eq!(tree.approximate_size(key(10)..key(10)), 0);
+        assert_eq!(tree.approximate_size(key(5_000)..), 0);
+    }
+
+    #[test]
+    fn test_fork() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..300u32 {
+            tree.insert(key(i), b"base".to_vec());
+        }
+
+        let mut fork = tree.fork();
+        assert_eq!(fork.get_root_hash(), tree.get_root_hash());
+        fork.insert(key(1_000), b"fork".to_vec());
+        fork.delete(&key(0));
+        tree.insert(key(5), b"main".to_vec());
+
+        assert_eq!(tree.len(), 300);
+        assert_eq!(fork.len(), 300);
+        assert_eq!(tree.get_ceiling(&key(5)).unwrap().1, b"main".to_vec());
+        assert_eq!(fork.get_ceiling(&key(5)).unwrap().1, b"base".to_vec());
+        assert!(tree.get_ceiling(&key(1_000)).is_none());
+
+        // the trees only differ in the changed keys
+        let diff = tree.diff(&fork);
+        assert_eq!(diff.len(), 3);
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), TreeConfig::default());
+        for i in 0..100u32 {
+            tree.insert(key(i), b"old".to_vec());
+        }
+        let root = tree.get_root_hash();
+
+        let mut batch = WriteBatch::new();
+        batch.insert(key(200), b"new".to_vec());
+        batch.insert(key(5), b"new".to_vec());
+        batch.delete(key(10));
+        batch.insert(key(20), b"new".to_vec());
+        batch.delete(key(20));
+        assert_eq!(batch.len(), 4);
+        assert_eq!(batch.get(&key(20)), Some(None));
+        assert_eq!(batch.get(&key(5)), Some(Some(&b"new"[..])));
+        assert_eq!(batch.get(&key(6)), None);
+
+        // nothing is written until the batch is applied
+        let discarded = batch.clone();
+        drop(discarded);
+        assert_eq!(tree.get_root_hash(), root);
+
+        // the root the inserts alone lead to is never persisted
+        let mut inserted_only =
+            ProllyTree::new(InMemoryNodeStorage::<32>::default(), TreeConfig::default());
+        for i in 0..100u32 {
+            inserted_only.insert(key(i), b"old".to_vec());
+        }
+        inserted_only.insert_batch(&[key(5), key(200)], &[b"new".to_vec(), b"new".to_vec()]);
+        let intermediate = inserted_only.get_root_hash().unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        tree.add_observer(move |key, _, new| {
+            recorded.lock().unwrap().push((key.to_vec(), new.is_some()));
+        });
+        tree.apply_batch(batch).unwrap();
+        assert!(tree.storage.get_node_by_hash(&intermediate).is_none());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                (key(5), true),
+                (key(200), true),
+                (key(10), false),
+                (key(20), false),
+            ]
+        );
+        assert_eq!(tree.len(), 99);
+        assert_eq!(tree.get_ceiling(&key(5)).unwrap().1, b"new".to_vec());
+        assert_eq!(tree.get_ceiling(&key(10)).unwrap().0, key(11));
+        assert_eq!(tree.get_ceiling(&key(200)).unwrap().1, b"new".to_vec());
+        let persisted = tree
+            .storage
+            .get_node_by_hash(&tree.get_root_hash().unwrap());
+        assert!(persisted.is_some());
+    }
+
+    #[test]
+    fn test_sample_keys() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..1_000u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![0]);
+        }
+
+        let sample = tree.sample_keys(100, 7);
+        assert_eq!(sample.len(), 100);
+        assert!(sample.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(tree.sample_keys(100, 7), sample);
+        assert_ne!(tree.sample_keys(100, 8), sample);
+
+        // keys from both halves of the key space are drawn
+        let middle = 500u32.to_be_bytes().to_vec();
+        let lower = sample.iter().filter(|key| **key < middle).count();
+        assert!((20..=80).contains(&lower), "{}", lower);
+
+        assert_eq!(tree.sample_keys(5_000, 1).len(), 1_000);
+        assert!(tree.sample_keys(0, 1).is_empty());
+    }
+
+    #[test]
+    fn test_key_format() {
+        let config = TreeConfig {
+            key_format: Some(KeyFormat::Tuple(vec![KeyFormat::UInt(2), KeyFormat::Utf8])),
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config.clone());
+        let key = [&1u16.to_be_bytes()[..], b"name"].concat();
+        tree.try_insert(key.clone(), b"value".to_vec()).unwrap();
+        assert!(matches!(
+            tree.try_insert(vec![1], b"value".to_vec()),
+            Err(Error::InvalidKey(_))
+        ));
+        assert_eq!(tree.len(), 1);
+
+        // a batch with an invalid key is refused as a whole
+        let mut batch = WriteBatch::new();
+        batch.insert([&2u16.to_be_bytes()[..], b"other"].concat(), vec![]);
+        batch.insert(vec![0, 2, 0xff], vec![]);
+        assert!(tree.apply_batch(batch).is_err());
+        assert_eq!(tree.len(), 1);
+
+        let pairs = vec![(vec![0, 1, 0xff], vec![])];
+        assert!(matches!(
+            ProllyTree::from_sorted_iter(InMemoryNodeStorage::<32>::default(), config, pairs),
+            Err(Error::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_rewrite_with_config() {
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..500u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![i as u8; 8]);
         }
 
-        // 9. Print tree stats
-        println!("Size: {}", tree.size());
-        println!("Depth: {}", tree.depth());
-        println!("Summary: {}", tree.summary());
+        let config = TreeConfig::<20> {
+            min_chunk_size: 2,
+            max_chunk_size: 64,
+            pattern: 0b111,
+            hash_algorithm: HashAlgorithm::Blake3,
+            ..Default::default()
+        };
+        let rewritten = tree
+            .rewrite_with_config(InMemoryNodeStorage::<20>::default(), config.clone())
+            .unwrap();
+        assert_eq!(
+            rewritten.iter().collect::<Vec<_>>(),
+            tree.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(rewritten.config.min_chunk_size, 2);
+        assert!(rewritten.verify_integrity().is_ok());
+
+        // the result is the tree a bulk load with the new configuration produces
+        let loaded =
+            ProllyTree::from_sorted_iter(InMemoryNodeStorage::<20>::default(), config, tree.iter())
+                .unwrap();
+        assert_eq!(rewritten.get_root_hash(), loaded.get_root_hash());
+
+        // entries are checked against the formats of the new configuration
+        let config = TreeConfig::<32> {
+            key_format: Some(KeyFormat::UInt(2)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            tree.rewrite_with_config(InMemoryNodeStorage::<32>::default(), config),
+            Err(Error::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_rewrite_keeps_expiry() {
+        let config = TreeConfig::<32> {
+            expiring_keys: true,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config.clone());
+        tree.insert(b"a".to_vec(), b"1".to_vec());
+        tree.insert_with_ttl(b"b".to_vec(), b"2".to_vec(), Duration::from_secs(3600))
+            .unwrap();
+        tree.insert_with_expiry(b"c".to_vec(), b"3".to_vec(), SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        let config = TreeConfig {
+            value_policy: ValuePolicy::External,
+            ..config
+        };
+        let rewritten = tree
+            .rewrite_with_config(InMemoryNodeStorage::<32>::default(), config)
+            .unwrap();
+        assert_eq!(
+            rewritten.iter().collect::<Vec<_>>(),
+            vec![
+                (b"a".to_vec(), b"1".to_vec()),
+                (b"b".to_vec(), b"2".to_vec())
+            ]
+        );
+        let (_, stored) = rewritten
+            .root
+            .keys
+            .iter()
+            .zip(&rewritten.root.values)
+            .nth(1)
+            .unwrap();
+        assert!(ttl::unwrap(stored).0 > ttl::now());
+    }
 
-        // 10. Print Tree
-        println!("{:?}", tree.root.print_tree(&tree.storage));
+    #[test]
+    fn test_typed_values() {
+        #[derive(Serialize, serde::Deserialize, schemars::JsonSchema, Debug, PartialEq)]
+        struct Counter {
+            name: String,
+            count: u64,
+        }
 
-        // Clean up the storage directory
-        fs::remove_dir_all(storage_dir).unwrap();
+        let config = TreeConfig {
+            value_schema: Some(schemars::schema_for!(Counter)),
+            value_format: Some(ValueFormat::Json),
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        let counter = Counter {
+            name: "visits".to_string(),
+            count: 3,
+        };
+        tree.insert_typed(b"a".to_vec(), &counter).unwrap();
+        assert_eq!(tree.get_as::<Counter>(b"a").unwrap(), Some(counter));
+        assert_eq!(tree.get_as::<Counter>(b"b").unwrap(), None);
+
+        // values that do not match the schema are refused
+        assert!(matches!(
+            tree.try_insert(b"b".to_vec(), br#"{"name": "visits"}"#.to_vec()),
+            Err(Error::InvalidValue(_))
+        ));
+        assert!(matches!(
+            tree.insert_typed(b"b".to_vec(), &vec![1, 2]),
+            Err(Error::InvalidValue(_))
+        ));
+        assert!(tree.get_as::<Vec<u8>>(b"a").is_err());
+        assert_eq!(tree.len(), 1);
+
+        // fixed structs stored with bincode
+        let config = TreeConfig {
+            value_format: Some(ValueFormat::Bincode),
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        tree.insert_typed(b"p".to_vec(), &(1u32, -2i64)).unwrap();
+        assert_eq!(tree.get_as::<(u32, i64)>(b"p").unwrap(), Some((1, -2)));
     }
 
     #[test]
-    fn test_insert_and_find() {
-        let storage = InMemoryNodeStorage::<32>::default();
+    fn test_key_format_insert_batch() {
+        let config = TreeConfig {
+            key_format: Some(KeyFormat::Utf8),
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        let keys = vec![b"a".to_vec(), vec![0xff]];
+        let values = vec![vec![1], vec![2]];
+        assert!(matches!(
+            tree.try_insert_batch(&keys, &values),
+            Err(Error::InvalidKey(_))
+        ));
+        assert!(tree.is_empty());
+
+        // the unchecked writes store the pairs as they are
+        tree.insert_batch(&keys, &values);
+        assert_eq!(tree.len(), 2);
+        tree.insert(vec![0xfe], vec![3]);
+        assert_eq!(tree.len(), 3);
+    }
 
-        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+    #[test]
+    fn test_merge() {
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), TreeConfig::default());
+        let increment = |old: Option<&[u8]>| {
+            let count = old.map_or(0, |old| u64::from_be_bytes(old.try_into().unwrap()));
+            Some((count + 1).to_be_bytes().to_vec())
+        };
+        for _ in 0..3 {
+            tree.merge(b"counter".to_vec(), increment);
+        }
+        let (_, value) = tree.get_ceiling(b"counter").unwrap();
+        assert_eq!(value, 3u64.to_be_bytes().to_vec());
 
-        tree.insert(b"key1".to_vec(), b"value1".to_vec());
-        tree.insert(b"key2".to_vec(), b"value2".to_vec());
+        for item in [b"a", b"b"] {
+            tree.merge(b"list".to_vec(), |old| {
+                Some([old.unwrap_or_default(), item].concat())
+            });
+        }
+        assert_eq!(tree.get_ceiling(b"list").unwrap().1, b"ab".to_vec());
 
-        assert!(tree.find(b"key1").is_some());
-        assert!(tree.find(b"key2").is_some());
-        assert!(tree.find(b"key3").is_none());
+        // returning None deletes the key
+        assert_eq!(tree.merge(b"list".to_vec(), |_| None), None);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.merge(b"missing".to_vec(), |_| None), None);
+        assert_eq!(tree.len(), 1);
     }
 
     #[test]
-    fn test_insert_batch_and_find() {
-        let storage = InMemoryNodeStorage::<32>::default();
+    fn test_observers() {
+        type Event = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+        let events: Arc<Mutex<Vec<Event>>> = Arc::default();
+        let take = || std::mem::take(&mut *events.lock().unwrap());
+        let event = |key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>| {
+            (
+                key.to_vec(),
+                old.map(<[u8]>::to_vec),
+                new.map(<[u8]>::to_vec),
+            )
+        };
 
-        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), TreeConfig::default());
+        let recorded = events.clone();
+        let id = tree.add_observer(move |key, old, new| {
+            recorded.lock().unwrap().push(event(key, old, new));
+        });
 
-        let keys = vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()];
-        let values = vec![b"value1".to_vec(), b"value2".to_vec(), b"value3".to_vec()];
+        tree.insert(b"a".to_vec(), b"1".to_vec());
+        tree.insert(b"a".to_vec(), b"2".to_vec());
+        assert!(!tree.delete(b"missing"));
+        assert!(tree.delete(b"a"));
+        assert_eq!(
+            take(),
+            vec![
+                event(b"a", None, Some(b"1")),
+                event(b"a", Some(b"1"), Some(b"2")),
+                event(b"a", Some(b"2"), None),
+            ]
+        );
+
+        let keys: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i]).collect();
+        tree.insert_batch(&keys, &keys);
+        assert_eq!(take().len(), 5);
+        assert_eq!(tree.delete_range(vec![1]..vec![3]), 2);
+        assert_eq!(
+            take(),
+            vec![event(&[1], Some(&[1]), None), event(&[2], Some(&[2]), None),]
+        );
+        assert!(tree.update(vec![4], b"four".to_vec()));
+        assert_eq!(take(), vec![event(&[4], Some(&[4]), Some(b"four"))]);
+
+        assert!(tree.remove_observer(id));
+        assert!(!tree.remove_observer(id));
+        tree.insert(b"b".to_vec(), b"1".to_vec());
+        assert!(take().is_empty());
+    }
 
-        tree.insert_batch(&keys, &values);
+    #[test]
+    fn test_merge_with() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let key = |i: u32| i.to_be_bytes().to_vec();
+
+        // two replicas that share most of their history
+        let mut ours = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config.clone());
+        let mut theirs = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..300u32 {
+            ours.insert(key(i), b"base".to_vec());
+            theirs.insert(key(i), b"base".to_vec());
+        }
+        for i in 300..320u32 {
+            ours.insert(key(i), b"ours".to_vec());
+        }
+        for i in 310..330u32 {
+            theirs.insert(key(i), b"theirs".to_vec());
+        }
+        theirs.insert(key(5), b"theirs".to_vec());
+
+        let mut conflicts = Vec::new();
+        ours.merge_with(&theirs, |k, a, b| {
+            conflicts.push(k.to_vec());
+            [a, b].concat()
+        })
+        .unwrap();
+
+        // only keys present in both with different values reach the callback
+        let mut expected_conflicts = vec![key(5)];
+        expected_conflicts.extend((310..320u32).map(key));
+        assert_eq!(conflicts, expected_conflicts);
+
+        assert_eq!(ours.len(), 330);
+        let value = |i: u32| ours.get_ceiling(&key(i)).unwrap().1;
+        assert_eq!(value(5), b"basetheirs".to_vec());
+        assert_eq!(value(6), b"base".to_vec());
+        assert_eq!(value(305), b"ours".to_vec());
+        assert_eq!(value(315), b"ourstheirs".to_vec());
+        assert_eq!(value(325), b"theirs".to_vec());
+
+        // merging the same tree again changes nothing
+        let root_hash = ours.root.get_hash();
+        let ours_copy = ProllyTree::from_sorted_iter(
+            InMemoryNodeStorage::<32>::default(),
+            ours.config.clone(),
+            ours.iter(),
+        )
+        .unwrap();
+        ours.merge_with(&ours_copy, |_, _, _| unreachable!())
+            .unwrap();
+        assert_eq!(ours.root.get_hash(), root_hash);
+    }
 
-        assert!(tree.find(b"key1").is_some());
-        assert!(tree.find(b"key2").is_some());
-        assert!(tree.find(b"key3").is_some());
-        assert!(tree.find(b"key4").is_none());
+    #[test]
+    fn test_intersect_and_difference() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let key = |i: u32| i.to_be_bytes().to_vec();
+
+        let mut a = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config.clone());
+        let mut b = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        for i in 0..400u32 {
+            a.insert(key(i), b"a".to_vec());
+            if i % 50 != 7 {
+                b.insert(key(i), b"a".to_vec());
+            }
+        }
+        for i in 400..420u32 {
+            b.insert(key(i), b"b".to_vec());
+        }
+        // same key, different value: still part of the intersection
+        b.insert(key(100), b"b".to_vec());
+
+        let only_in_a: Vec<Vec<u8>> = a.difference(&b).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(
+            only_in_a,
+            (0..400u32)
+                .filter(|i| i % 50 == 7)
+                .map(key)
+                .collect::<Vec<_>>()
+        );
+
+        let common = a.intersect(&b);
+        assert_eq!(
+            common.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(),
+            (0..400u32)
+                .filter(|i| i % 50 != 7)
+                .map(key)
+                .collect::<Vec<_>>()
+        );
+        assert!(common.iter().all(|(_, v)| v == b"a"));
+
+        let only_in_b: Vec<Vec<u8>> = b.difference(&a).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(only_in_b, (400..420u32).map(key).collect::<Vec<_>>());
+
+        // a tree intersected with itself is the whole tree, its difference is empty
+        assert_eq!(a.intersect(&a), a.iter().collect::<Vec<_>>());
+        assert!(a.difference(&a).is_empty());
     }
 
     #[test]
-    fn test_delete() {
+    fn test_split_at() {
         let storage = InMemoryNodeStorage::<32>::default();
-        let mut tree = ProllyTree::new(storage, TreeConfig::default());
-
-        tree.insert(b"key1".to_vec(), b"value1".to_vec());
-        tree.insert(b"key2".to_vec(), b"value2".to_vec());
-
-        assert!(tree.delete(b"key1"));
-        assert!(tree.find(b"key1").is_none());
-        assert!(tree.find(b"key2").is_some());
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(storage, config);
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in 0..500u32 {
+            tree.insert(key(i), i.to_string().into_bytes());
+        }
+        let leaf_hash = |tree: &ProllyTree<32, InMemoryNodeStorage<32>>, i: u32| {
+            tree.find(&key(i)).unwrap().get_hash()
+        };
+        let first_leaf = leaf_hash(&tree, 0);
+        let last_leaf = leaf_hash(&tree, 499);
+
+        let (lower, upper) = tree.split_at(&key(250)).unwrap();
+        assert_eq!(
+            lower.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+            (0..250u32).map(key).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            upper.iter().map(|(k, _)| k).collect::<Vec<_>>(),
+            (250..500u32).map(key).collect::<Vec<_>>()
+        );
+
+        // nodes away from the split point are shared with the original tree
+        assert_eq!(leaf_hash(&lower, 0), first_leaf);
+        assert_eq!(leaf_hash(&upper, 499), last_leaf);
+
+        // splitting outside the key range leaves one side empty
+        let (lower, upper) = upper.split_at(&key(0)).unwrap();
+        assert!(lower.is_empty());
+        assert_eq!(upper.len(), 250);
     }
 
     #[test]
-    fn test_delete_batch() {
+    fn test_insert_if() {
         let storage = InMemoryNodeStorage::<32>::default();
         let mut tree = ProllyTree::new(storage, TreeConfig::default());
 
-        let keys = vec![b"key1".to_vec(), b"key2".to_vec(), b"key3".to_vec()];
-        let values = vec![b"value1".to_vec(), b"value2".to_vec(), b"value3".to_vec()];
-
-        // Insert keys and values
-        tree.insert_batch(&keys, &values);
-
-        // Verify insertion
-        assert!(tree.find(b"key1").is_some());
-        assert!(tree.find(b"key2").is_some());
-        assert!(tree.find(b"key3").is_some());
+        // creating a key requires it to be absent
+        assert!(tree.insert_if(b"counter".to_vec(), None, b"1".to_vec()));
+        assert!(!tree.insert_if(b"counter".to_vec(), None, b"1".to_vec()));
 
-        // Delete keys in batch
-        tree.delete_batch(&keys);
+        // a stale expected value is rejected and leaves the value untouched
+        assert!(tree.insert_if(b"counter".to_vec(), Some(b"1"), b"2".to_vec()));
+        assert!(!tree.insert_if(b"counter".to_vec(), Some(b"1"), b"3".to_vec()));
+        assert_eq!(tree.get_ceiling(b"counter").unwrap().1, b"2".to_vec());
 
-        // Verify deletion
-        assert!(tree.find(b"key1").is_none());
-        assert!(tree.find(b"key2").is_none());
-        assert!(tree.find(b"key3").is_none());
+        // keys sharing a prefix are not mistaken for each other
+        assert!(!tree.insert_if(b"count".to_vec(), Some(b"2"), b"3".to_vec()));
+        assert_eq!(tree.len(), 1);
     }
 
     #[test]
-    fn test_traverse() {
-        let storage = InMemoryNodeStorage::<32>::default();
-        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+    fn test_snapshot() {
+        use crate::storage::FileNodeStorage;
 
-        let key1 = b"key1".to_vec();
-        let key2 = b"key2".to_vec();
+        let storage_dir = std::env::temp_dir().join("prolly_tree_snapshot_storage");
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+        for i in 0..100u32 {
+            tree.insert(i.to_be_bytes().to_vec(), b"v1".to_vec());
+        }
 
-        tree.insert(key1.clone(), b"value1".to_vec());
-        tree.insert(key2.clone(), b"value2".to_vec());
+        let snapshot = tree.snapshot();
+        let root_hash = snapshot.root_hash();
 
-        let traversal = tree.traverse();
+        let reader = std::thread::spawn(move || {
+            (0..100u32).all(|i| snapshot.get(&i.to_be_bytes()) == Some(b"v1".to_vec()))
+                && snapshot.len() == 100
+        });
+        for i in 0..100u32 {
+            tree.insert(i.to_be_bytes().to_vec(), b"v2".to_vec());
+        }
+        tree.delete(&0u32.to_be_bytes());
+        assert!(reader.join().unwrap());
 
-        // Convert byte arrays to their binary representation strings for comparison
-        let expected_key1 = format!("{:?}", key1);
-        let expected_key2 = format!("{:?}", key2);
+        // the snapshot does not see later writes
+        let snapshot = tree.snapshot();
+        assert_ne!(snapshot.root_hash(), root_hash);
+        assert_eq!(snapshot.get(&1u32.to_be_bytes()), Some(b"v2".to_vec()));
+        assert_eq!(snapshot.get(&0u32.to_be_bytes()), None);
+        assert_eq!(snapshot.iter().count(), 99);
 
-        // Check if the traversal contains the expected keys
-        assert!(traversal.contains(&expected_key1.to_string()));
-        assert!(traversal.contains(&expected_key2.to_string()));
+        std::fs::remove_dir_all(storage_dir).unwrap();
     }
 
     #[test]
-    fn test_stats() {
-        let storage = InMemoryNodeStorage::<32>::default();
-        let config = TreeConfig {
-            base: 131,
-            modulus: 1_000_000_009,
-            min_chunk_size: 16,
-            max_chunk_size: 8 * 1024,
-            pattern: 0b111,
-            root_hash: None,
-            key_schema: None,
-            value_schema: None,
-            encode_types: vec![],
-        };
+    fn test_read_only_snapshot() {
+        use crate::snapshot::Snapshot;
+        use crate::storage::{FileNodeStorage, ReadOnlyStorage};
 
-        let mut tree = ProllyTree::new(storage, config);
+        let storage_dir = std::env::temp_dir().join("prolly_tree_read_only_storage");
+        let _ = std::fs::remove_dir_all(&storage_dir);
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        assert!(matches!(Snapshot::open(storage.clone()), Err(Error::Serde)));
 
-        // Insert key-value pairs using a loop
-        let max_key = 3000u32;
+        let mut tree = ProllyTree::new(storage.clone(), TreeConfig::default());
+        for i in 0..100u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
+        }
+        tree.save_config().unwrap();
+        let snapshot = Snapshot::open(storage.clone()).unwrap();
+        assert_eq!(snapshot.root_hash(), tree.get_root_hash().unwrap());
+        assert_eq!(snapshot.len(), 100);
+        assert_eq!(
+            snapshot.get(&7u32.to_be_bytes()),
+            Some(7u32.to_le_bytes().to_vec())
+        );
+
+        // the wrapper only reads, so it can only back the read-only views
+        let read_only = ReadOnlyStorage::new(storage);
+        let root = tree.get_root_hash().unwrap();
+        assert!(read_only.get_node_by_hash(&root).is_some());
+        assert!(read_only.get_config("tree_config").is_some());
+        let snapshot = Snapshot::open(read_only).unwrap();
+        assert_eq!(snapshot.root_hash(), root);
+        assert_eq!(snapshot.iter().count(), 100);
+        std::fs::remove_dir_all(storage_dir).unwrap();
+    }
 
-        for i in 0..max_key {
-            // Convert to big-endian byte array to maintain order
-            let key = i.to_be_bytes().to_vec();
-            let value = i.to_be_bytes().to_vec();
-            tree.insert(key.clone(), value.clone());
+    #[test]
+    fn test_root_pins() {
+        use crate::pin::RootPins;
+
+        let mut tree = ProllyTree::new(
+            InMemoryNodeStorage::<32>::default(),
+            TreeConfig::<32>::default(),
+        );
+        for i in 0..300u32 {
+            tree.insert(i.to_be_bytes().to_vec(), b"v1".to_vec());
         }
+        let pins = RootPins::new();
+        let old_root = tree.get_root_hash().unwrap();
+        let snapshot = tree.snapshot().pinned(&pins);
+        assert_eq!(pins.pin_count(&old_root), 1);
+        assert_eq!(pins.pin(old_root.clone()), 2);
+        assert_eq!(pins.unpin(&old_root), Some(1));
+
+        tree.insert(7u32.to_be_bytes().to_vec(), b"v2".to_vec());
+        let new_root = tree.get_root_hash().unwrap();
+        let _guard = pins.guard(new_root.clone());
+        let live = pins.live_nodes(tree.storage(), false);
+        assert!(live.contains(&old_root) && live.contains(&new_root));
+
+        // once the snapshot is gone, only the nodes of the new version must be kept
+        drop(snapshot);
+        assert!(!pins.is_pinned(&old_root));
+        assert_eq!(pins.unpin(&old_root), None);
+        let live = pins.live_nodes(tree.storage(), false);
+        assert!(!live.contains(&old_root));
+        assert!(live.contains(&new_root));
+        assert_eq!(pins.roots(), vec![new_root]);
+    }
 
-        println!("{:?}", tree.root.print_tree(&tree.storage));
+    #[test]
+    fn test_find_corrupt_node() {
+        use crate::storage::FileNodeStorage;
 
-        for i in 0..max_key {
-            let key = i.to_be_bytes().to_vec();
-            assert!(tree.find(&key).is_some());
+        let storage_dir = std::env::temp_dir().join("prolly_tree_find_corrupt_storage");
+        let _ = std::fs::remove_dir_all(&storage_dir);
+        let storage = FileNodeStorage::<32>::new(storage_dir.clone());
+        let mut tree = ProllyTree::new(storage, TreeConfig::default());
+        for i in 0..300u32 {
+            tree.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec());
         }
-        let non_existing_key = (max_key + 10).to_be_bytes().to_vec();
-        assert!(tree.find(&non_existing_key).is_none());
-
-        // assert that the tree has the expected key-value pairs
-        assert_eq!(tree.size(), max_key as usize);
+        let first = 0u32.to_be_bytes();
+        let last = 299u32.to_be_bytes();
+        assert!(tree.try_find(&first).unwrap().is_some());
+
+        // flip a bit in the leaf holding the first key
+        let leaf = ValueDigest::<32>::raw_hash(&tree.root.values[0]);
+        let name = format!("{:x}", leaf);
+        let path = storage_dir.join(&name[..2]).join(&name[2..]);
+        let mut data = std::fs::read(&path).unwrap();
+        let last_byte = data.len() - 1;
+        data[last_byte] ^= 1;
+        std::fs::write(&path, data).unwrap();
+
+        assert!(matches!(
+            tree.try_find(&first),
+            Err(Error::CorruptNode { ref hash, .. }) if *hash == name
+        ));
+        assert!(tree.try_find(&last).unwrap().is_some());
+        // the key is not reported as absent
+        let found = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tree.find(&first)));
+        assert!(found.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            tree.try_find(&first),
+            Err(Error::MissingNode(ref hash)) if *hash == name
+        ));
+        std::fs::remove_dir_all(storage_dir).unwrap();
+    }
 
-        // assert that the tree has the expected depth
-        assert_eq!(tree.depth(), 3);
+    #[test]
+    fn test_large_values_are_chunked() {
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            value_chunk_threshold: Some(256),
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config.clone());
+
+        let document = |seed: u32| -> Vec<u8> {
+            let mut state = seed;
+            (0..8_000)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    (state >> 16) as u8
+                })
+                .collect()
+        };
+        for i in 0..20u32 {
+            tree.insert(format!("doc{i:02}").into_bytes(), document(i));
+            tree.insert(format!("tag{i:02}").into_bytes(), b"small".to_vec());
+        }
 
-        println!("Size: {}", tree.size());
-        println!("Depth: {}", tree.depth());
-        println!("Summary: {}", tree.summary());
+        // leaves only hold manifests, reads return the original values
+        let leaf = tree.find(b"doc03").unwrap();
+        assert!(leaf.values.iter().all(|value| value.len() < 2_000));
+        assert_eq!(tree.get_ceiling(b"doc03").unwrap().1, document(3));
+        assert_eq!(tree.get_floor(b"tag05").unwrap().1, b"small".to_vec());
+        assert_eq!(tree.select(0).unwrap().1, document(0));
+        assert_eq!(tree.snapshot().get(b"doc07"), Some(document(7)));
+
+        // the chunks travel with an export
+        let mut data = Vec::new();
+        tree.export(&mut data).unwrap();
+        let imported =
+            ProllyTree::import(data.as_slice(), InMemoryNodeStorage::<32>::default()).unwrap();
+        assert_eq!(
+            imported.iter().collect::<Vec<_>>(),
+            tree.iter().collect::<Vec<_>>()
+        );
+
+        // bulk loading stores values the same way as inserts
+        let loaded =
+            ProllyTree::from_sorted_iter(InMemoryNodeStorage::<32>::default(), config, tree.iter())
+                .unwrap();
+        assert_eq!(loaded.get_ceiling(b"doc19").unwrap().1, document(19));
+        assert!(loaded
+            .intersect(&tree)
+            .iter()
+            .eq(tree.iter().collect::<Vec<_>>().iter()));
     }
 
     #[test]
-    fn test_generate_proof() {
-        let config = TreeConfig::default();
+    fn test_len() {
         let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
         let mut tree = ProllyTree::new(storage, config);
+        assert!(tree.is_empty());
 
-        // Insert key-value pairs
-        for i in 0..100 {
-            let key = vec![i];
-            let value = vec![i];
-            tree.insert(key.clone(), value.clone());
+        for i in 0..300u32 {
+            tree.insert(i.to_be_bytes().to_vec(), vec![0]);
         }
+        // updating an existing key does not change the count
+        tree.update(7u32.to_be_bytes().to_vec(), vec![1]);
+        assert_eq!(tree.len(), 300);
 
-        // Generate proof for an existing key
-        let key_to_prove = vec![5];
-        let proof = tree.generate_proof(&key_to_prove);
-
-        // Verify the proof
-        let verified = tree.verify(proof, &key_to_prove, Some(&key_to_prove));
-        assert!(verified);
-
-        // Generate proof for a non-existing key
-        let key_to_prove_wrong = vec![120];
-        let proof_wrong = tree.generate_proof(&key_to_prove_wrong);
-
-        // Should not be verified
-        let verified_wrong =
-            tree.verify(proof_wrong, &key_to_prove_wrong, Some(&key_to_prove_wrong));
-        assert!(!verified_wrong);
+        for i in (0..300u32).step_by(3) {
+            tree.delete(&i.to_be_bytes());
+            assert_eq!(tree.len(), tree.iter().count());
+        }
+        // deleting a missing key does not change the count
+        tree.delete(&1000u32.to_be_bytes());
+        assert_eq!(tree.len(), 200);
+        assert_eq!(tree.len(), tree.iter().count());
+        assert!(!tree.is_empty());
     }
 
     #[test]
-    fn test_diff() {
-        let config = TreeConfig::default();
-        let storage1 = InMemoryNodeStorage::<32>::default();
-        let mut tree1 = ProllyTree::new(storage1, config.clone());
-
-        let storage2 = InMemoryNodeStorage::<32>::default();
-        let mut tree2 = ProllyTree::new(storage2, config);
+    fn test_rank_and_select() {
+        let storage = InMemoryNodeStorage::<32>::default();
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(storage, config.clone());
 
-        // Insert key-value pairs into tree1
-        for i in 0..50 {
-            tree1.insert(vec![i], vec![i]);
+        let key = |i: u32| i.to_be_bytes().to_vec();
+        for i in (0..400u32).step_by(2) {
+            tree.insert(key(i), i.to_string().into_bytes());
         }
-
-        // Insert key-value pairs into tree1
-        for i in 0..50 {
-            tree2.insert(vec![i], vec![i]);
+        for i in (0..400u32).step_by(6) {
+            tree.delete(&key(i));
         }
 
-        // modify some keys in tree2
-        tree2.insert(vec![10], vec![200]);
+        let check = |tree: &ProllyTree<32, InMemoryNodeStorage<32>>| {
+            let entries: Vec<_> = tree.iter().collect();
+            for (n, (k, v)) in entries.iter().enumerate() {
+                assert_eq!(tree.rank(k), n);
+                assert_eq!(tree.select(n), Some((k.clone(), v.clone())));
+            }
+            assert!(tree.select(entries.len()).is_none());
+            // absent keys rank where they would be inserted
+            assert_eq!(tree.rank(&key(3)), 1);
+            assert_eq!(tree.rank(&key(1000)), entries.len());
+            assert_eq!(tree.rank(&[]), 0);
+        };
+        check(&tree);
 
-        // print tree1 and tree2
-        println!("{:?}", tree1.root.print_tree(&tree1.storage));
-        println!("{:?}", tree2.root.print_tree(&tree2.storage));
+        let pairs: Vec<_> = tree.iter().collect();
+        let loaded =
+            ProllyTree::from_sorted_iter(InMemoryNodeStorage::<32>::default(), config, pairs)
+                .unwrap();
+        check(&loaded);
+    }
 
-        // Generate diff between tree1 and tree2
-        let differences = tree1.diff(&tree2);
+    #[test]
+    fn test_expiring_keys() {
+        let mut plain =
+            ProllyTree::new(InMemoryNodeStorage::<32>::default(), TreeConfig::default());
+        assert!(matches!(
+            plain.insert_with_ttl(vec![1], vec![1], Duration::from_secs(1)),
+            Err(Error::ExpiryDisabled)
+        ));
+        assert_eq!(plain.purge_expired(), 0);
 
-        // Check the differences
-        // Expecting only the first L1 value would change
-        for diff in &differences {
-            match diff {
-                DiffResult::Added(key, value) => {
-                    println!("Added: key = {:?}, value = {:?}", key, value);
-                }
-                DiffResult::Removed(key, value) => {
-                    println!("Removed: key = {:?}, value = {:?}", key, value);
-                }
-                DiffResult::Modified(key, old_value, new_value) => {
-                    println!(
-                        "Modified: key = {:?}, old_value = {:?}, new_value = {:?}",
-                        key, old_value, new_value
-                    );
-                }
+        let config = TreeConfig {
+            min_chunk_size: 4,
+            max_chunk_size: 8 * 1024,
+            pattern: 0b111,
+            value_chunk_threshold: Some(64),
+            expiring_keys: true,
+            ..Default::default()
+        };
+        let mut tree = ProllyTree::new(InMemoryNodeStorage::<32>::default(), config);
+        let expired = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        let large = vec![7u8; 1_000];
+        for i in 0..100u32 {
+            let key = i.to_be_bytes().to_vec();
+            match i % 3 {
+                0 => tree.insert(key, i.to_string().into_bytes()),
+                1 => tree
+                    .insert_with_ttl(key, large.clone(), Duration::from_secs(3_600))
+                    .unwrap(),
+                _ => tree.insert_with_expiry(key, vec![0], expired).unwrap(),
             }
         }
+
+        // expired keys are counted but never read
+        assert_eq!(tree.len(), 100);
+        let live: Vec<(Vec<u8>, Vec<u8>)> = tree.iter().collect();
+        assert_eq!(live.len(), 67);
+        assert!(live.iter().all(|(key, _)| key[3] % 3 != 2));
+        assert_eq!(live[0].1, b"0".to_vec());
+        assert_eq!(live[1].1, large);
+        assert_eq!(tree.iter_rev().count(), 67);
+        let after = tree.get_ceiling(&2u32.to_be_bytes()).unwrap();
+        assert_eq!(after.0, 3u32.to_be_bytes().to_vec());
+        let before = tree.get_floor(&2u32.to_be_bytes()).unwrap();
+        assert_eq!(before.0, 1u32.to_be_bytes().to_vec());
+        assert!(tree.snapshot().iter().eq(tree.iter()));
+
+        // an expired key can be written again
+        assert!(tree.insert_if(2u32.to_be_bytes().to_vec(), None, vec![2]));
+
+        // exports carry the expiry timestamps
+        let mut data = Vec::new();
+        tree.export(&mut data).unwrap();
+        let imported =
+            ProllyTree::import(data.as_slice(), InMemoryNodeStorage::<32>::default()).unwrap();
+        assert!(imported.iter().eq(tree.iter()));
+
+        assert_eq!(tree.purge_expired(), 32);
+        assert_eq!(tree.len(), 68);
+        assert_eq!(tree.iter().count(), 68);
+        assert_eq!(tree.purge_expired(), 0);
     }
 }
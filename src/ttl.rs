@@ -0,0 +1,94 @@
+/*
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Expiry timestamps stored alongside values.
+//!
+//! When a tree is configured with `expiring_keys`, every value stored in a leaf starts with
+//! the time the key expires at, in milliseconds since the UNIX epoch. Keys that never
+//! expire store zero. The rest of the stored value is encoded as usual:
+//!
+//! ```text
+//! expires at: u64 (big endian) | value
+//! ```
+//!
+//! Expired keys are filtered out lazily when the tree is read, and removed for good by
+//! `ProllyTree::purge_expired`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Expiry timestamp of keys that never expire.
+pub(crate) const NEVER: u64 = 0;
+
+const HEADER_LEN: usize = 8;
+
+/// Prefixes a stored value with its expiry timestamp.
+pub(crate) fn wrap(value: Vec<u8>, expires_at: u64) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(HEADER_LEN + value.len());
+    stored.extend_from_slice(&expires_at.to_be_bytes());
+    stored.extend_from_slice(&value);
+    stored
+}
+
+/// Splits a stored value into its expiry timestamp and the value.
+pub(crate) fn unwrap(stored: &[u8]) -> (u64, &[u8]) {
+    match stored.split_first_chunk::<HEADER_LEN>() {
+        Some((header, value)) => (u64::from_be_bytes(*header), value),
+        None => (NEVER, stored),
+    }
+}
+
+/// Returns `true` if a stored value has expired at `now`.
+pub(crate) fn is_expired(stored: &[u8], now: u64) -> bool {
+    let (expires_at, _) = unwrap(stored);
+    expires_at != NEVER && expires_at <= now
+}
+
+/// Converts a point in time into an expiry timestamp.
+pub(crate) fn timestamp(time: SystemTime) -> u64 {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0);
+    // times at or before the epoch have expired, but must not read as "never"
+    millis.max(1)
+}
+
+/// Returns the current time as an expiry timestamp.
+pub(crate) fn now() -> u64 {
+    timestamp(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_wrap_and_unwrap() {
+        let stored = wrap(b"value".to_vec(), 42);
+        assert_eq!(unwrap(&stored), (42, &b"value"[..]));
+        assert!(is_expired(&stored, 42));
+        assert!(!is_expired(&stored, 41));
+
+        let forever = wrap(b"value".to_vec(), NEVER);
+        assert!(!is_expired(&forever, u64::MAX));
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), 1);
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(2)), 2_000);
+        assert!(now() > timestamp(UNIX_EPOCH + Duration::from_secs(1_600_000_000)));
+    }
+}